@@ -1,6 +1,9 @@
 //! Functions to read and write debug registers.
 
-use gdbstub::target::ext::{base::singlethread::StopReason, breakpoints::WatchKind};
+use gdbstub::{
+	common::{Signal, Tid},
+	target::ext::{base::multithread::ThreadStopReason, breakpoints::WatchKind},
+};
 use x86_64::{
 	registers::debug::{
 		DebugAddressRegisterNumber, Dr6Flags, Dr7Flags, Dr7Value, HwBreakpointCondition,
@@ -14,6 +17,8 @@ pub struct HwBreakpoint {
 	addr: VirtAddr,
 	size: HwBreakpointSize,
 	condition: HwBreakpointCondition,
+	/// The kind of a watchpoint, which is reported to GDB
+	watch_kind: Option<WatchKind>,
 }
 
 impl HwBreakpoint {
@@ -22,26 +27,29 @@ impl HwBreakpoint {
 			addr: VirtAddr::new(addr),
 			size: kind.try_into()?,
 			condition: HwBreakpointCondition::InstructionExecution,
+			watch_kind: None,
 		})
 	}
 
+	/// x86 cannot break on reads only, so read watchpoints also trigger on writes.
 	pub fn new_watchpoint(addr: u64, len: u64, kind: WatchKind) -> Option<Self> {
 		let condition = match kind {
-			WatchKind::Write => Some(HwBreakpointCondition::DataWrites),
-			WatchKind::Read => None,
-			WatchKind::ReadWrite => Some(HwBreakpointCondition::DataReadsWrites),
-		}?;
+			WatchKind::Write => HwBreakpointCondition::DataWrites,
+			WatchKind::Read | WatchKind::ReadWrite => HwBreakpointCondition::DataReadsWrites,
+		};
 
 		let ret = Self {
 			addr: VirtAddr::new(addr),
 			size: usize::try_from(len).ok()?.try_into().ok()?,
 			condition,
+			watch_kind: Some(kind),
 		};
 
 		Some(ret)
 	}
 }
 
+/// The four hardware breakpoints of the debug registers DR0–DR3, which are shared by all vCPUs.
 #[derive(Clone, Copy, Debug)]
 pub struct HwBreakpoints([Option<HwBreakpoint>; 4]);
 
@@ -110,30 +118,33 @@ impl HwBreakpoints {
 		]
 	}
 
-	pub fn stop_reason(&self, dr6: Dr6Flags) -> StopReason<u64> {
-		if dr6.contains(Dr6Flags::STEP) {
-			StopReason::DoneStep
-		} else {
-			let n = (0..4)
-				.find(|&n| {
-					let n = DebugAddressRegisterNumber::new(n).unwrap();
-					dr6.contains(Dr6Flags::trap(n))
-				})
-				.unwrap();
-			let breakpoint = self.0[usize::from(n)].unwrap();
-
-			match breakpoint.condition {
-				HwBreakpointCondition::InstructionExecution => StopReason::HwBreak,
-				HwBreakpointCondition::DataWrites => StopReason::Watch {
-					kind: WatchKind::Write,
-					addr: breakpoint.addr.as_u64(),
-				},
-				HwBreakpointCondition::DataReadsWrites => StopReason::Watch {
-					kind: WatchKind::ReadWrite,
-					addr: breakpoint.addr.as_u64(),
-				},
-				HwBreakpointCondition::IoReadsWrites => unreachable!(),
-			}
+	/// Returns, why the thread `tid` has stopped with the debug status `dr6`.
+	///
+	/// The CPU may also set the B0–B3 bits of unused registers, so only the bits of hardware
+	/// breakpoints are considered. A watchpoint takes precedence over a completed single step, as
+	/// the write of a stepped instruction would be lost otherwise.
+	pub fn stop_reason(&self, dr6: Dr6Flags, tid: Tid) -> ThreadStopReason<u64> {
+		let hit = self.0.iter().enumerate().find_map(|(n, hw_breakpoint)| {
+			let n = DebugAddressRegisterNumber::new(n.try_into().unwrap()).unwrap();
+			hw_breakpoint.filter(|_| dr6.contains(Dr6Flags::trap(n)))
+		});
+
+		match hit {
+			Some(HwBreakpoint {
+				watch_kind: Some(kind),
+				addr,
+				..
+			}) => ThreadStopReason::Watch {
+				tid,
+				kind,
+				addr: addr.as_u64(),
+			},
+			_ if dr6.contains(Dr6Flags::STEP) => ThreadStopReason::DoneStep,
+			Some(_) => ThreadStopReason::HwBreak(tid),
+			None => ThreadStopReason::SignalWithThread {
+				tid,
+				signal: Signal::SIGTRAP,
+			},
 		}
 	}
 }
@@ -143,3 +154,81 @@ impl Default for HwBreakpoints {
 		Self::new()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_hw_breakpoints_capacity() {
+		let mut hw_breakpoints = HwBreakpoints::new();
+		for i in 0..4 {
+			let hw_breakpoint = HwBreakpoint::new_breakpoint(0x1000 + i, 1).unwrap();
+			hw_breakpoints.try_insert(hw_breakpoint).unwrap();
+		}
+		let hw_breakpoint = HwBreakpoint::new_breakpoint(0x2000, 1).unwrap();
+		assert!(hw_breakpoints.try_insert(hw_breakpoint).is_err());
+
+		let removed = HwBreakpoint::new_breakpoint(0x1002, 1).unwrap();
+		assert_eq!(hw_breakpoints.take(&removed), Some(removed));
+		hw_breakpoints.try_insert(hw_breakpoint).unwrap();
+		assert_eq!(hw_breakpoints.registers()[2], 0x2000);
+	}
+
+	#[test]
+	fn test_hw_watchpoint_registers() {
+		let mut hw_breakpoints = HwBreakpoints::new();
+		let watchpoint = HwBreakpoint::new_watchpoint(0x3000, 8, WatchKind::Write).unwrap();
+		hw_breakpoints.try_insert(watchpoint).unwrap();
+		let registers = hw_breakpoints.registers();
+		assert_eq!(registers[0], 0x3000);
+		// G0, R/W0 = 0b01 (data writes) and LEN0 = 0b10 (8 bytes)
+		assert_eq!(registers[7] & 0b10, 0b10);
+		assert_eq!((registers[7] >> 16) & 0b1111, 0b1001);
+		assert!(HwBreakpoint::new_watchpoint(0x3000, 3, WatchKind::Write).is_none());
+	}
+
+	#[test]
+	fn test_hw_breakpoint_stop_reason() {
+		let tid = Tid::new(1).unwrap();
+		let mut hw_breakpoints = HwBreakpoints::new();
+		hw_breakpoints
+			.try_insert(HwBreakpoint::new_breakpoint(0x1000, 1).unwrap())
+			.unwrap();
+		hw_breakpoints
+			.try_insert(HwBreakpoint::new_watchpoint(0x3000, 4, WatchKind::Read).unwrap())
+			.unwrap();
+
+		let n = DebugAddressRegisterNumber::new(0).unwrap();
+		assert!(matches!(
+			hw_breakpoints.stop_reason(Dr6Flags::trap(n), tid),
+			ThreadStopReason::HwBreak(_)
+		));
+		let n = DebugAddressRegisterNumber::new(1).unwrap();
+		assert!(matches!(
+			hw_breakpoints.stop_reason(Dr6Flags::trap(n), tid),
+			ThreadStopReason::Watch {
+				kind: WatchKind::Read,
+				addr: 0x3000,
+				..
+			}
+		));
+		assert!(matches!(
+			hw_breakpoints.stop_reason(Dr6Flags::STEP, tid),
+			ThreadStopReason::DoneStep
+		));
+		assert!(matches!(
+			hw_breakpoints.stop_reason(Dr6Flags::STEP | Dr6Flags::trap(n), tid),
+			ThreadStopReason::Watch { .. }
+		));
+		// B2 of an unused register
+		let n = DebugAddressRegisterNumber::new(2).unwrap();
+		assert!(matches!(
+			hw_breakpoints.stop_reason(Dr6Flags::trap(n), tid),
+			ThreadStopReason::SignalWithThread {
+				signal: Signal::SIGTRAP,
+				..
+			}
+		));
+	}
+}
@@ -10,20 +10,32 @@ extern crate rftrace_frontend;
 
 use std::collections::HashSet;
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use uhyvelib::cpu_features;
+use uhyvelib::exit_reasons;
+use uhyvelib::kernel_signature;
+use uhyvelib::stress_test::{self, StressTest};
 use uhyvelib::utils;
 use uhyvelib::vm;
 use uhyvelib::Uhyve;
 
 use byte_unit::Byte;
 use clap::{App, Arg};
+use core_affinity::CoreId;
 #[cfg(feature = "instrument")]
 use rftrace_frontend::Events;
 
 const MINIMAL_GUEST_SIZE: usize = 16 * 1024 * 1024;
 const DEFAULT_GUEST_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_PRETOUCH_WORKERS: usize = 4;
+const DEFAULT_KSM_REPORT_INTERVAL: u64 = 10;
+const DEFAULT_STATS_INTERVAL: u64 = 60;
 
 #[cfg(feature = "instrument")]
 static mut EVENTS: Option<&mut Events> = None;
@@ -52,8 +64,6 @@ fn main() {
 		}
 	}
 
-	env_logger::init();
-
 	let matches = App::new("uhyve")
 		.version(crate_version!())
 		.setting(clap::AppSettings::TrailingVarArg)
@@ -76,6 +86,13 @@ fn main() {
 				.long("mergeable")
 				.help("Enable kernel feature to merge same pages"),
 		)
+		.arg(
+			Arg::with_name("KSM_REPORT_INTERVAL")
+				.long("ksm-report-interval")
+				.value_name("SECS")
+				.help("Interval of the KSM statistics printed in verbose mode with --mergeable")
+				.takes_value(true),
+		)
 		.arg(
 			Arg::with_name("MEM")
 				.short("m")
@@ -85,6 +102,434 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_MEM"),
 		)
+		.arg(
+			Arg::with_name("PREALLOC")
+				.long("mem-prealloc")
+				.help("Fault in the guest memory before starting the guest"),
+		)
+		.arg(
+			Arg::with_name("PRETOUCH_WORKERS")
+				.long("memory-pretouch-workers")
+				.value_name("N")
+				.help("Number of threads used to fault in the guest memory")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("PRINT_GUEST_MEMORY_LAYOUT")
+				.long("print-guest-memory-layout")
+				.help("Print the guest physical memory layout after loading the kernel"),
+		)
+		.arg(
+			Arg::with_name("NMI_ON_SIGNAL")
+				.long("nmi-on-signal")
+				.help("Inject an NMI into the first guest CPU on receipt of SIGUSR2"),
+		)
+		.arg(
+			Arg::with_name("REGISTERS_ON_SIGNAL")
+				.long("registers-on-signal")
+				.help("Dump the registers of all guest CPUs to stderr on receipt of SIGUSR2")
+				.long_help(
+					"Dump the registers of all guest CPUs to stderr on receipt of SIGUSR2, e.g.,
+					to inspect a frozen VM without attaching GDB. The vCPUs are paused, while
+					their registers are read.",
+				)
+				.conflicts_with("NMI_ON_SIGNAL"),
+		)
+		.arg(
+			Arg::with_name("PAUSE_ON_SIGNAL")
+				.long("pause-on-signal")
+				.help("Pause or resume the guest CPUs on receipt of SIGUSR1")
+				.long_help(
+					"Pause or resume the guest CPUs on receipt of SIGUSR1. SIGUSR1 no longer
+					rebalances the vCPU threads, which have been pinned with --affinity.",
+				),
+		)
+		.arg(
+			Arg::with_name("CONSOLE_LOG_FILE")
+				.long("console-log-file")
+				.value_name("PATH")
+				.help("Copy the guest's console output to the given file")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("CONSOLE_LOG_APPEND")
+				.long("console-log-append")
+				.help("Append to the console log file instead of truncating it")
+				.requires("CONSOLE_LOG_FILE"),
+		)
+		.arg(
+			Arg::with_name("DEBUG_PORT_FILE")
+				.long("debug-port-file")
+				.value_name("PATH")
+				.help("Record the guest's debug port output with timestamps in the given file")
+				.long_help(
+					"Record everything the guest writes to its debug ports in the given file,
+					starting every line with a timestamp in microseconds. The file is appended to
+					and written synchronously. The output is only printed to stdout as well with
+					`--verbose`.",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("DEBUG_PORT_PREFIX")
+				.long("debug-port-prefix")
+				.value_name("STRING")
+				.help("Start every line of the debug port file with the given string, e.g., the VM name")
+				.takes_value(true)
+				.requires("DEBUG_PORT_FILE"),
+		)
+		.arg(
+			Arg::with_name("SNAPSHOT")
+				.long("snapshot")
+				.value_name("FILE")
+				.help("Save the VM to FILE on SIGTERM before stopping it")
+				.long_help(
+					"On SIGTERM, the vCPUs are paused and the guest memory and the registers of
+					the vCPUs are written to FILE, before the VM stops. --restore continues the
+					kernel from FILE. The state of the devices is not saved.",
+				)
+				.takes_value(true)
+				.conflicts_with("TEST_MODE"),
+		)
+		.arg(
+			Arg::with_name("RESTORE")
+				.long("restore")
+				.value_name("FILE")
+				.help("Continue the kernel saved in FILE by --snapshot instead of booting a kernel")
+				.long_help(
+					"Continue the kernel saved in FILE by --snapshot instead of booting a kernel.
+					The kernel is taken from FILE, the number of CPUs and the memory size have to
+					match those of the saved VM.",
+				)
+				.takes_value(true)
+				.conflicts_with_all(&["TEST_MODE", "KERNEL", "GDB_PORT", "GDB_SOCKET"]),
+		)
+		.arg(
+			Arg::with_name("CPU_STRESS_TEST")
+				.long("cpu-stress-test")
+				.value_name("SECS")
+				.help("Stress the vCPUs, the memory and the network with the stress-test kernel for SECS seconds")
+				.long_help(
+					"Stress the vCPUs, the memory and the network with the stress-test kernel for SECS
+					seconds. The kernel is built from test-kernel/ and given as the kernel. It runs
+					integer and floating-point arithmetic, writes and verifies 80% of the guest memory
+					and sends data over the loopback device of the guest. The throughput of each test
+					is printed and uhyve exits unsuccessfully, if a test failed.",
+				)
+				.takes_value(true)
+				.conflicts_with_all(&["TEST_MODE", "RESTORE", "SNAPSHOT", "KERNEL_ARGS_FILE"]),
+		)
+		.arg(
+			Arg::with_name("KERNEL_ARGS_FILE")
+				.long("kernel-args-file")
+				.value_name("PATH")
+				.help("Read arguments of the unikernel from the given file")
+				.long_help(
+					"Read arguments of the unikernel from the given file, in which they are
+					separated by whitespace or newlines. Lines starting with # are ignored. The
+					arguments precede those given after --, so the latter take precedence.",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("ENTROPY_FILE")
+				.long("entropy-file")
+				.value_name("PATH")
+				.help("Seed the random number generator of the unikernel from the given file (default: /dev/urandom)")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("ENTROPY_SEED")
+				.long("entropy-seed")
+				.value_name("HEX")
+				.help("Seed the random number generator of the unikernel with up to 64 hexadecimal bytes")
+				.long_help(
+					"Seed the random number generator of the unikernel with up to 64 bytes given
+					in hexadecimal, shorter seeds are padded with zeros. A fixed seed makes runs
+					of the unikernel reproducible, but its random numbers are predictable, so it
+					is insecure and must not be used with networking in production.",
+				)
+				.takes_value(true)
+				.conflicts_with("ENTROPY_FILE"),
+		)
+		.arg(
+			Arg::with_name("KERNEL_LOG_FILE")
+				.long("kernel-log-file")
+				.value_name("PATH")
+				.help("Write the structured log messages of the kernel to the given file")
+				.long_help(
+					"Write the structured log messages of the kernel to the given file. Once the
+					file exceeds 10 MiB, it is rotated to PATH.1, keeping up to five old files.
+					The messages are logged by uhyve with the target hermit in any case.",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("SOFT_IOMMU")
+				.long("soft-iommu")
+				.help("Check the DMA of virtio devices against mappings set up by the guest"),
+		)
+		.arg(
+			Arg::with_name("DIRTY_TRACKING")
+				.long("dirty-tracking")
+				.help("Track the pages written by the guest with KVM's dirty ring"),
+		)
+		.arg(
+			Arg::with_name("DIRTY_LOG")
+				.long("dirty-log")
+				.help("Log the pages written by the guest in KVM's dirty bitmap"),
+		)
+		.arg(
+			Arg::with_name("DEADLINE_RUNTIME")
+				.long("deadline-runtime")
+				.value_name("NS")
+				.help("Schedule vCPU threads with SCHED_DEADLINE and this runtime (requires CAP_SYS_NICE)")
+				.takes_value(true)
+				.requires_all(&["DEADLINE_DEADLINE", "DEADLINE_PERIOD"]),
+		)
+		.arg(
+			Arg::with_name("DEADLINE_DEADLINE")
+				.long("deadline-deadline")
+				.value_name("NS")
+				.help("Relative deadline of the vCPU threads for SCHED_DEADLINE")
+				.takes_value(true)
+				.requires("DEADLINE_RUNTIME"),
+		)
+		.arg(
+			Arg::with_name("DEADLINE_PERIOD")
+				.long("deadline-period")
+				.value_name("NS")
+				.help("Period of the vCPU threads for SCHED_DEADLINE")
+				.takes_value(true)
+				.requires("DEADLINE_RUNTIME"),
+		)
+		.arg(
+			Arg::with_name("MEMORY_BACKEND")
+				.long("memory-backend")
+				.value_name("BACKEND")
+				.help("Backing of the guest memory: anonymous (default) or file:<PATH> to preserve it across runs")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("STATS_INTERVAL")
+				.long("stats-interval")
+				.value_name("SECS")
+				.help("Interval of the network statistics in verbose mode (default: 60)")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("STATS_LOG")
+				.long("stats-log")
+				.value_name("PATH")
+				.help("Write the network statistics to a CSV file")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("NET_METRICS_FILE")
+				.long("net-metrics-file")
+				.value_name("PATH")
+				.help("Write histograms of the network packet sizes in the Prometheus text format")
+				.long_help(
+					"Write the histograms uhyve_network_tx_packet_size_bytes and
+					uhyve_network_rx_packet_size_bytes of the sizes of the sent and received
+					packets in the Prometheus text format, e.g., for the textfile collector of the
+					node exporter. The file is replaced every --stats-interval.",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("PTP")
+				.long("ptp")
+				.help("Publish cross timestamps of the guest's kvmclock and the host's realtime clock to the guest"),
+		)
+		.arg(
+			Arg::with_name("DISK")
+				.long("disk")
+				.value_name("PATH")
+				.help("Attach a virtio block device, which is backed by the given raw image file")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("DISK_READONLY")
+				.long("disk-readonly")
+				.help("Never write to the disk image, the guest's writes go to a temporary overlay")
+				.requires("DISK"),
+		)
+		.arg(
+			Arg::with_name("DISK_OVERLAY")
+				.long("disk-overlay")
+				.value_name("PATH")
+				.help("Write the changed sectors of the disk to the given copy-on-write overlay")
+				.long_help(
+					"Write the changed sectors of the disk to the given copy-on-write overlay,
+					which is created if it does not exist. The disk image is only read, so
+					several VMs may share it, each with its own overlay.",
+				)
+				.takes_value(true)
+				.requires("DISK"),
+		)
+		.arg(
+			Arg::with_name("BALLOON")
+				.long("balloon")
+				.help("Attach a virtio memory balloon, through which the guest returns memory")
+				.long_help(
+					"Attach a virtio memory balloon. The pages, which the guest puts into the
+					balloon, are given back to the host. The guest reports its memory statistics
+					through the balloon.",
+				),
+		)
+		.arg(
+			Arg::with_name("BALLOON_CONTROL")
+				.long("balloon-control")
+				.value_name("FILE")
+				.help("Read the size of the balloon from the given file, e.g. 512MiB")
+				.long_help(
+					"Read the size of the balloon from the given file, e.g. 512MiB, which is
+					checked every second. The latest memory statistics of the guest are written to
+					FILE.stats.",
+				)
+				.takes_value(true)
+				.requires("BALLOON"),
+		)
+		.arg(
+			Arg::with_name("BALLOON_TARGET")
+				.long("balloon-target")
+				.value_name("SIZE")
+				.help("Inflate the balloon to the given size after the boot, e.g. 512MiB")
+				.long_help(
+					"Inflate the balloon to the given size, e.g. 512MiB, once the guest has
+					reported its first memory statistics. The size, which the guest has reached
+					after 30 seconds, is logged.",
+				)
+				.takes_value(true)
+				.requires("BALLOON"),
+		)
+		.arg(
+			Arg::with_name("MEMORY_PRESSURE_HINTS")
+				.long("memory-pressure-hints")
+				.help("Periodically mark guest memory, which has not been written, as cold (MADV_COLD)"),
+		)
+		.arg(
+			Arg::with_name("PREEMPTION_TIMER")
+				.long("preemption-timer")
+				.value_name("TSC_CYCLES")
+				.help("Interrupt each vCPU after this many TSC cycles to let other host threads run")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("VERBOSE_EXITS")
+				.long("verbose-exits")
+				.help("Log every exit of the vCPUs with their registers (requires RUST_LOG=debug)"),
+		)
+		.arg(
+			Arg::with_name("EXIT_FILTER")
+				.long("exit-filter")
+				.value_name("REASON,REASON")
+				.help("Log only these exits with --verbose-exits, e.g. KVM_EXIT_IO,KVM_EXIT_MMIO")
+				.takes_value(true)
+				.requires("VERBOSE_EXITS"),
+		)
+		.arg(
+			Arg::with_name("NOTIFY_VMEXIT_WINDOW")
+				.long("notify-vmexit-window")
+				.value_name("CYCLES")
+				.help("Force an exit of a vCPU, which runs this many cycles without an exit (0 disables it)")
+				.long_help(
+					"Force an exit of a vCPU, which runs this many cycles without any intercepted
+					instruction, so that an errant guest cannot stall the host (Intel only, requires
+					KVM_CAP_X86_NOTIFY_VMEXIT). 0 disables the notify window.",
+				)
+				.takes_value(true)
+				.default_value("1000000"),
+		)
+		.arg(
+			Arg::with_name("NO_AUTO_MIGRATE")
+				.long("no-auto-migrate")
+				.help("Do not migrate pinned vCPUs away from host CPUs, which are taken offline"),
+		)
+		.arg(
+			Arg::with_name("STRICT_MEMORY_MODEL")
+				.long("strict-memory-model")
+				.help("Refuse to start, if the guest memory exceeds 90% of the available host memory"),
+		)
+		.arg(
+			Arg::with_name("HOST_ALLOWED_PATHS")
+				.long("host-allowed-paths")
+				.value_name("DIR")
+				.help("Only let the guest open and unlink host files below this directory (repeatable)")
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1),
+		)
+		.arg(
+			Arg::with_name("SHM_ID")
+				.long("shm-id")
+				.value_name("KEY")
+				.help("Publish the state of the VM in the shared memory /dev/shm/uhyve-<KEY>")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("CLOCK_OFFSET")
+				.long("clock-offset")
+				.value_name("NANOSECONDS")
+				.help("Shift the guest's clock into the future (or the past, if negative)")
+				.long_help(
+					"Shift the guest's clock by this many nanoseconds into the future (or the past,
+					if negative). The offset is applied to the boot time passed to the kernel and
+					to the kvmclock. Clocks derived from the TSC and the boot time show the offset
+					as well, because the TSC itself is not shifted and only measures the time
+					since boot.",
+				)
+				.takes_value(true)
+				.allow_hyphen_values(true),
+		)
+		.arg(
+			Arg::with_name("TSC_KHZ")
+				.long("tsc-khz")
+				.value_name("KHZ")
+				.help("TSC frequency of the guest CPUs in kHz")
+				.long_help(
+					"Scales the TSC of all guest CPUs to KHZ kHz, which is also passed to the
+					kernel as its CPU frequency. Frequencies other than the host's require TSC
+					scaling of the host CPU. By default, the guest CPUs run with the host's TSC
+					frequency, which is logged at startup.",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("TSC_OFFSET")
+				.long("tsc-offset")
+				.value_name("TICKS")
+				.help("Offset of the TSC of the guest CPUs to the host's TSC")
+				.long_help(
+					"Sets the same offset of TICKS to the host's TSC on all guest CPUs, so that
+					their TSCs stay synchronized, when they are migrated between host cores with
+					different TSC values. Requires Linux 5.16.",
+				)
+				.takes_value(true)
+				.allow_hyphen_values(true),
+		)
+		.arg(
+			Arg::with_name("TUI")
+				.long("tui")
+				.help("Show a dashboard of the vCPUs, the network and the memory in the terminal")
+				.long_help(
+					"Show a dashboard of the vCPUs, the network and the memory in the terminal.
+					The dashboard also shows the last log messages, which are printed when the VM
+					stops. Press q to stop the VM, p to pause and r to resume the vCPUs. Requires
+					the tui feature.",
+				),
+		)
+		.arg(
+			Arg::with_name("FAULT_TOLERANT")
+				.long("fault-tolerant")
+				.help("Ignore accesses of the guest to unmapped physical addresses")
+				.long_help(
+					"Ignore accesses of the guest to physical addresses, which are neither memory
+					nor a device. Reads return all ones and writes are dropped. Without this flag,
+					the vCPU stops and uhyve explains the access.",
+				),
+		)
 		.arg(
 			Arg::with_name("CPUS")
 				.short("c")
@@ -94,6 +539,51 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_CPUS"),
 		)
+		.arg(
+			Arg::with_name("CPU_FEATURES")
+				.long("cpu-features")
+				.value_name("+FEATURE,-FEATURE")
+				.help("Adds or removes CPUID features of the guest CPUs")
+				.long_help(
+					"A comma-separated list of CPUID features, which are added (+) to or
+					removed (-) from the features passed through from the host, e.g.
+					+avx512f,-rtm. The features are named as in /proc/cpuinfo. Features, which
+					the host does not support, are reported at startup.",
+				)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("CPUID")
+				.long("cpuid")
+				.value_name("LEAF:SUBLEAF:REG:set=MASK:clear=MASK")
+				.help("Set or clear bits of a CPUID leaf of the guest CPUs, may be given multiple times")
+				.long_help(
+					"Sets and clears the bits of a register (eax, ebx, ecx or edx) of a CPUID leaf
+					and subleaf, e.g. --cpuid 0x7:0:ebx:set=0x20:clear=0x800. The numbers are
+					decimal or hexadecimal with the prefix 0x. The overrides are applied after
+					--cpu-features. Bits, which the host does not support, are reported at
+					startup.",
+				)
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1),
+		)
+		.arg(
+			Arg::with_name("ALLOW_MSR")
+				.long("allow-msr")
+				.value_name("HEX")
+				.help("Forward the accesses to an MSR to the host CPU, may be given multiple times")
+				.long_help(
+					"Forwards the reads and writes of the guest to the MSR with the hexadecimal
+					index HEX, e.g. --allow-msr 0xce for MSR_PLATFORM_INFO, to the host CPU, on
+					which the vCPU runs, instead of injecting a #GP or emulating the MSR in KVM.
+					Requires the kernel module msr and the filter of Linux 5.10+ for MSRs, which
+					KVM emulates.",
+				)
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1),
+		)
 		.arg(
 			Arg::with_name("CPU_AFFINITY")
 				.short("a")
@@ -103,10 +593,23 @@ fn main() {
 				.long_help(
 					"A list of CPUs delimited by commas onto which
 					 the virtual CPUs should be bound. This may improve 
-					performance.
+					performance. Ranges (0-3) and ranges with a step
+					(0-7:2 for every second CPU) are supported.
 					",
 				),
 		)
+		.arg(
+			Arg::with_name("CPU_AFFINITY_SMT_AWARE")
+				.long("cpu-affinity-smt-aware")
+				.help("Warn about SMT siblings in --affinity and pin the vCPUs to separate cores first")
+				.long_help(
+					"Reads the SMT siblings of the CPUs of --affinity, warns about siblings and
+					orders the CPUs, so that the first thread of every core is used before the
+					second thread of any core. Thus, vCPUs with consecutive ids only share a core,
+					if there are more vCPUs than cores.",
+				)
+				.requires("CPU_AFFINITY"),
+		)
 		.arg(
 			Arg::with_name("GDB_PORT")
 				.short("s")
@@ -116,14 +619,175 @@ fn main() {
 				.takes_value(true)
 				.env("HERMIT_GDB_PORT"),
 		)
+		.arg(
+			Arg::with_name("GDB_SOCKET")
+				.long("gdb-socket")
+				.value_name("PATH")
+				.help("Enables GDB-Stub on a Unix socket instead of a TCP port")
+				.long_help(
+					"Enables GDB-Stub on the Unix socket PATH, to which GDB connects with
+					`target remote PATH`. Only one of --gdb_port and --gdb-socket may be given. The
+					socket is removed, when uhyve exits.",
+				)
+				.takes_value(true)
+				.conflicts_with("GDB_PORT"),
+		)
+		.arg(
+			Arg::with_name("DEBUG_ON_STARTUP")
+				.long("debug-on-startup")
+				.help("Stops at the entry point of the kernel, when GDB continues the first time")
+				.long_help(
+					"Inserts a single-use software breakpoint at the entry point of the kernel, so
+					that the first `continue` of GDB stops there, e.g., to set breakpoints in a
+					script without knowing the entry address. Requires --gdb_port or --gdb-socket.
+					uhyve always waits for GDB to connect, before the kernel runs.",
+				),
+		)
+		.arg(
+			Arg::with_name("APIC_TIMER_HZ")
+				.long("apic-timer-hz")
+				.value_name("HZ")
+				.help("Frequency of the guest's APIC timer")
+				.takes_value(true)
+				.env("HERMIT_APIC_TIMER_HZ"),
+		)
 		.arg(
 			Arg::with_name("NETIF")
 				.long("nic")
 				.value_name("NETIF")
-				.help("Name of the network interface")
+				.help("Network interface of the guest, may be given multiple times")
+				.long_help(
+					"Network interface of the guest in the form
+					NAME[,ip=IP][,gateway=IP][,mask=IP][,mac=MAC], where NAME is a TAP device or a
+					bridge, to which a new TAP device is attached. NAME `virtio` only configures the
+					virtio-net device and NAME `slirp` connects the guest to a user-mode network,
+					which needs no privileges, with the additional option `dns=IP` (requires the
+					feature `slirp`). May be given up to four times, the first interface is the
+					default one of the kernel and `--mac` applies to it, if it has no address of
+					its own.",
+				)
 				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1)
 				.env("HERMIT_NETIF"),
 		)
+		.arg(
+			Arg::with_name("NIC_MODE")
+				.long("nic-mode")
+				.value_name("MODE")
+				.help("Exchange Ethernet frames (tap) or IP packets (tun) with the host")
+				.long_help(
+					"Exchange Ethernet frames (tap) or IP packets (tun) with the TUN/TAP devices of
+					--nic. In tun mode, the guest has to use raw IP networking. The host side of the
+					TUN device gets the address `gateway` of the interface, 10.0.0.1 by default, in a
+					/30 subnet, and the guest the other address of the subnet, unless it has an
+					address of its own. TUN devices cannot be attached to a bridge.",
+				)
+				.takes_value(true)
+				.possible_values(&["tap", "tun"])
+				.default_value("tap"),
+		)
+		.arg(
+			Arg::with_name("NUMA")
+				.long("numa")
+				.value_name("CPUS:SIZE")
+				.help("Add a NUMA node with host CPUs and memory, may be given multiple times")
+				.long_help(
+					"Add a NUMA node, whose memory is allocated on the host nodes of the given host
+					CPUs, e.g. --numa 0-3:2G --numa 4-7:2G. The nodes cover the guest memory in order
+					and their sizes have to add up to the memory size. Pin the vCPUs of a node to its
+					CPUs with --affinity. The guest does not see the topology, as uhyve provides no
+					ACPI tables.",
+				)
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1),
+		)
+		.arg(
+			Arg::with_name("GUEST_IP_FORWARD")
+				.long("guest-ip-forward")
+				.help("Route IP packets between the TUN devices of the network interfaces")
+				.long_help(
+					"Routes the IP packets, which the TUN device of a network interface receives
+					for the network of another interface, directly to the TUN device of that
+					interface according to --guest-route, instead of passing them through the
+					guest. Requires --nic-mode tun.",
+				),
+		)
+		.arg(
+			Arg::with_name("GUEST_ROUTE")
+				.long("guest-route")
+				.value_name("CIDR:NIC_INDEX")
+				.help("Route a network to a network interface, may be given multiple times")
+				.long_help(
+					"Routes a network to the network interface with the given index in --nic, e.g.
+					--guest-route 10.0.1.0/24:1. The longest matching route is taken. Packets for
+					other networks reach the guest as usual.",
+				)
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1)
+				.requires("GUEST_IP_FORWARD"),
+		)
+		.arg(
+			Arg::with_name("PORT_FORWARD")
+				.long("port-forward")
+				.value_name("HOST:GUEST[/PROTO]")
+				.help("Forward a port of the host to the first network interface, may be given multiple times")
+				.long_help(
+					"Forward a TCP or UDP port of the host to a port of the first network interface
+					of the guest, TCP by default. For a TAP device, the interface needs an address and
+					the forwarding uses iptables, which requires CAP_NET_ADMIN.",
+				)
+				.takes_value(true)
+				.multiple(true)
+				.number_of_values(1),
+		)
+		.arg(
+			Arg::with_name("NETWORK_BRIDGE")
+				.long("network-bridge")
+				.value_name("BRIDGE")
+				.help("Attach a new TAP device to this bridge, which is created if necessary")
+				.takes_value(true)
+				.conflicts_with("NETIF"),
+		)
+		.arg(
+			Arg::with_name("REMOVE_BRIDGE_ON_EXIT")
+				.long("remove-bridge-on-exit")
+				.help("Remove the bridge on exit, if it has been created by uhyve")
+				.requires("NETWORK_BRIDGE"),
+		)
+		.arg(
+			Arg::with_name("TUN_TX_QUEUE_SIZE")
+				.long("tun-tx-queue-size")
+				.value_name("BYTES")
+				.help("Size of the send buffer of the TUN/TAP device")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("NET_VNET_HDR")
+				.long("net-vnet-hdr")
+				.help("Exchange packets with the TAP device with a virtio_net_hdr in front of them")
+				.long_help(
+					"Opens the TAP device with IFF_VNET_HDR, so that the kernel prepends a 12 byte
+					virtio_net_hdr to every packet and expects one in front of every packet, which
+					uhyve writes. uhyve adds and strips the headers itself, the guest still
+					exchanges plain Ethernet frames.",
+				),
+		)
+		.arg(
+			Arg::with_name("NET_QUEUE_DEPTH")
+				.long("net-queue-depth")
+				.value_name("N")
+				.help("Number of packets in each shared network queue (power of two, 1-1024)")
+				.long_help(
+					"Number of packets in each shared network queue, a power of two between 1 and
+					1024. Shallow queues keep the latency low, deep queues absorb bursts. The depth
+					is passed to the kernel in the boot info. Kernels, which do not read it, only
+					support the default of 8.",
+				)
+				.takes_value(true),
+		)
 		/*.arg(
 			Arg::with_name("IP")
 				.long("ip")
@@ -147,19 +811,106 @@ fn main() {
 				.help("Network mask")
 				.takes_value(true)
 				.env("HERMIT_MASK"),
+		)*/
+		.arg(
+			Arg::with_name("IP6")
+				.long("ip6")
+				.value_name("IP6")
+				.help("IPv6 address of the guest")
+				.takes_value(true)
+				.env("HERMIT_IP6"),
+		)
+		.arg(
+			Arg::with_name("PREFIX6")
+				.long("prefix6")
+				.value_name("LENGTH")
+				.help("Prefix length of the IPv6 address [default: 64]")
+				.takes_value(true)
+				.requires("IP6")
+				.env("HERMIT_PREFIX6"),
+		)
+		.arg(
+			Arg::with_name("NET_IRQ_RESAMPLE")
+				.long("net-irq-resample")
+				.help("Keep the network interrupt pending, until the guest has read all packets")
+				.long_help(
+					"Keep the network interrupt pending, until the guest has read all packets.
+					The interrupt is re-asserted after every acknowledgement, while packets are
+					left in the queue. Requires KVM_CAP_IRQFD_RESAMPLE and a kernel, which
+					configures the interrupt as level-triggered.",
+				),
+		)
+		.arg(
+			Arg::with_name("PCAP")
+				.long("pcap")
+				.value_name("PATH")
+				.help("Capture the packets of the network interfaces in the given pcap file")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("VHOST_NET")
+				.long("vhost-net")
+				.help("Process the virtqueues of virtio-net in the host kernel through vhost-net")
+				.long_help(
+					"Process the virtqueues of virtio-net in the host kernel through
+					/dev/vhost-net, so that packets are not copied by uhyve. Falls back to the
+					emulation in uhyve, if vhost-net is not available.",
+				),
 		)
 		.arg(
 			Arg::with_name("MAC")
 				.long("mac")
 				.value_name("MAC")
-				.help("MAC address of the network interface")
+				.help("MAC address of the first network interface")
+				.long_help(
+					"MAC address of the first network interface, e.g., 52:54:00:12:34:56. Without it,
+					the address is derived from the path of the kernel, so every image keeps its
+					address across runs.",
+				)
 				.takes_value(true)
-				.env("HERMIT_MASK"),
-		)*/
+				.env("HERMIT_MAC"),
+		)
+		.arg(
+			Arg::with_name("KERNEL_VERIFY_SIGNATURE")
+				.long("kernel-verify-signature")
+				.value_name("SIG_FILE")
+				.help("Refuse to load the kernel, unless it matches this Ed25519 signature")
+				.takes_value(true)
+				.requires("KERNEL_PUBLIC_KEY")
+				.conflicts_with("TEST_MODE"),
+		)
+		.arg(
+			Arg::with_name("KERNEL_PUBLIC_KEY")
+				.long("kernel-public-key")
+				.value_name("KEY_FILE")
+				.help("Public key, which verifies the kernel signature")
+				.takes_value(true)
+				.requires("KERNEL_VERIFY_SIGNATURE"),
+		)
+		.arg(
+			Arg::with_name("GENERATE_KERNEL_KEY_PAIR")
+				.long("generate-kernel-key-pair")
+				.value_name("PREFIX")
+				.help("Write a new key pair for signing kernels to <PREFIX>.pub and <PREFIX>.sec and exit")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::with_name("SIGN_KERNEL")
+				.long("sign-kernel")
+				.value_name("SEC_FILE")
+				.help("Sign the kernel with this secret key, write the signature to <KERNEL>.sig and exit")
+				.takes_value(true)
+				.conflicts_with("TEST_MODE"),
+		)
+		.arg(
+			Arg::with_name("TEST_MODE")
+				.long("test-mode")
+				.help("Run the kernels read from stdin (one path per line) one after another"),
+		)
 		.arg(
 			Arg::with_name("KERNEL")
 				.help("Sets path to the kernel")
-				.required(true)
+				.required_unless_one(&["TEST_MODE", "GENERATE_KERNEL_KEY_PAIR", "RESTORE"])
 				.index(1),
 		)
 		.arg(
@@ -171,12 +922,55 @@ fn main() {
 		)
 		.get_matches();
 
-	let path = PathBuf::from_str(
-		matches
-			.value_of("KERNEL")
-			.expect("Expect path to the kernel!"),
-	)
-	.expect("Invalid kernel path");
+	if matches.is_present("TUI") {
+		// The log messages would overwrite the dashboard.
+		#[cfg(all(target_os = "linux", feature = "tui"))]
+		uhyvelib::linux::tui::init_logger();
+	} else {
+		env_logger::init();
+	}
+
+	if let Some(prefix) = matches.value_of("GENERATE_KERNEL_KEY_PAIR") {
+		kernel_signature::generate_key_pair(Path::new(prefix))
+			.expect("Unable to generate the key pair");
+		println!("Wrote {0}.pub and {0}.sec", prefix);
+		std::process::exit(0);
+	}
+
+	let test_kernels = matches.is_present("TEST_MODE").then(|| {
+		io::stdin()
+			.lock()
+			.lines()
+			.map(|line| line.expect("Unable to read kernel paths from stdin"))
+			.filter(|line| !line.trim().is_empty())
+			.map(|line| PathBuf::from(line.trim()))
+			.collect::<Vec<_>>()
+	});
+	let path = match &test_kernels {
+		Some(kernels) => match kernels.first() {
+			Some(path) => path.clone(),
+			None => std::process::exit(0),
+		},
+		// a restored VM runs the kernel of the snapshot
+		None if matches.is_present("RESTORE") => PathBuf::new(),
+		None => PathBuf::from_str(
+			matches
+				.value_of("KERNEL")
+				.expect("Expect path to the kernel!"),
+		)
+		.expect("Invalid kernel path"),
+	};
+
+	if let Some(secret_key) = matches.value_of("SIGN_KERNEL") {
+		let kernel = fs::read(&path).expect("Unable to read the kernel");
+		let secret_key = fs::read(secret_key).expect("Unable to read the secret key");
+		let signature = kernel_signature::sign(&kernel, &secret_key).expect("Invalid secret key");
+		let signature_path = kernel_signature::signature_path(&path);
+		fs::write(&signature_path, signature.to_bytes()).expect("Unable to write the signature");
+		println!("Wrote {}", signature_path.display());
+		std::process::exit(0);
+	}
+
 	let mem_size: usize = matches
 		.value_of("MEM")
 		.map(|s| {
@@ -196,10 +990,21 @@ fn main() {
 			}
 		})
 		.unwrap_or(DEFAULT_GUEST_SIZE);
+	let stress_test = matches.value_of("CPU_STRESS_TEST").map(|secs| {
+		let duration =
+			Duration::from_secs(secs.parse().expect("Invalid duration of the stress test"));
+		StressTest::new(duration, mem_size)
+			.expect("Unable to write the arguments of the stress test")
+	});
+	let stress_test_args = stress_test.as_ref().map(StressTest::kernel_args_file);
+	let prealloc = matches.is_present("PREALLOC");
+	let pretouch_workers = matches
+		.value_of("PRETOUCH_WORKERS")
+		.map(|workers| workers.parse().expect("Invalid number of pretouch workers"))
+		.unwrap_or(DEFAULT_PRETOUCH_WORKERS);
 	let num_cpus = matches
 		.value_of("CPUS")
-		.map(|cpus| cpus.parse().ok())
-		.flatten()
+		.and_then(|cpus| cpus.parse().ok())
 		.unwrap_or(1);
 
 	let cpu_affinity = matches.values_of("CPU_AFFINITY").map(|affinity| {
@@ -216,13 +1021,65 @@ fn main() {
 			.filter(|core_id| parsed_affinity.contains(&core_id.id))
 			.collect::<Vec<_>>();
 		assert_eq!(core_ids.len(), num_cpus as usize);
-		core_ids
+		let cpus = core_ids
+			.iter()
+			.map(|core_id| core_id.id)
+			.collect::<Vec<_>>();
+		utils::validate_affinity(&cpus);
+		if matches.is_present("CPU_AFFINITY_SMT_AWARE") {
+			utils::smt_aware_affinity(&cpus)
+				.into_iter()
+				.map(|id| CoreId { id })
+				.collect()
+		} else {
+			core_ids
+		}
 	});
 
-	let ip = None; //matches.value_of("IP").or(None);
-	let gateway = None; // matches.value_of("GATEWAY").or(None);
-	let mask = None; //matches.value_of("MASK").or(None);
-	let nic = None; //matches.value_of("NETIF").or(None);
+	let nics = matches
+		.values_of("NETIF")
+		.map(|nics| {
+			nics.map(|nic| {
+				utils::parse_nic(nic)
+					.unwrap_or_else(|err| panic!("Invalid network interface {:?}: {}", nic, err))
+			})
+			.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	let nic_mode = vm::NicMode::parse(matches.value_of("NIC_MODE").unwrap()).unwrap();
+	let port_forwards = matches
+		.values_of("PORT_FORWARD")
+		.map(|forwards| {
+			forwards
+				.map(|forward| {
+					utils::parse_port_forward(forward).unwrap_or_else(|err| {
+						panic!("Invalid port forwarding {:?}: {}", forward, err)
+					})
+				})
+				.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	let guest_routes = matches
+		.values_of("GUEST_ROUTE")
+		.map(|routes| {
+			routes
+				.map(|route| {
+					utils::parse_guest_route(route)
+						.unwrap_or_else(|err| panic!("Invalid route {:?}: {}", route, err))
+				})
+				.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	let ip6 = matches
+		.value_of("IP6")
+		.map(|ip6| Ipv6Addr::from_str(ip6).expect("Unable to parse the IPv6 address"));
+	let prefix_len6 = matches.value_of("PREFIX6").map(|prefix_len| {
+		prefix_len
+			.parse::<u8>()
+			.ok()
+			.filter(|&prefix_len| prefix_len <= 128)
+			.expect("The IPv6 prefix length has to be between 0 and 128")
+	});
 
 	let mut mergeable = envmnt::is_or("HERMIT_MERGEABLE", false);
 	if matches.is_present("MERGEABLE") {
@@ -233,6 +1090,10 @@ fn main() {
 	let hugepage_default = utils::transparent_hugepages_available().unwrap_or(true);
 	info!("Default hugepages set to: {}", hugepage_default);
 	// HERMIT_HUGEPAGES overrides the default we detected.
+	let ksm_report_interval = matches
+		.value_of("KSM_REPORT_INTERVAL")
+		.map(|secs| secs.parse().expect("Could not parse KSM report interval"))
+		.unwrap_or(DEFAULT_KSM_REPORT_INTERVAL);
 	let mut hugepage = envmnt::is_or("HERMIT_HUGEPAGE", hugepage_default);
 	if matches.is_present("DISABLE_HUGEPAGE") {
 		hugepage = false;
@@ -249,6 +1110,184 @@ fn main() {
 				.ok()
 				.map(|p| p.parse::<u16>().expect("Could not parse gdb port"))
 		});
+	let nmi_on_signal = matches.is_present("NMI_ON_SIGNAL");
+	let registers_on_signal = matches.is_present("REGISTERS_ON_SIGNAL");
+	let console_log_file = matches.value_of("CONSOLE_LOG_FILE").map(Path::new);
+	let kernel_log_file = matches.value_of("KERNEL_LOG_FILE").map(Path::new);
+	let debug_port_file = matches.value_of("DEBUG_PORT_FILE").map(Path::new);
+	let debug_port_prefix = matches.value_of("DEBUG_PORT_PREFIX");
+	let pcap = matches.value_of("PCAP").map(Path::new);
+	let kernel_args_file = stress_test_args
+		.as_deref()
+		.or_else(|| matches.value_of("KERNEL_ARGS_FILE").map(Path::new));
+	let console_log_append = matches.is_present("CONSOLE_LOG_APPEND");
+	let soft_iommu = matches.is_present("SOFT_IOMMU");
+	let dirty_tracking = matches.is_present("DIRTY_TRACKING");
+	let network_bridge = matches.value_of("NETWORK_BRIDGE");
+	let remove_bridge_on_exit = matches.is_present("REMOVE_BRIDGE_ON_EXIT");
+	let kernel_signature = matches
+		.value_of("KERNEL_VERIFY_SIGNATURE")
+		.map(|signature| vm::KernelSignature {
+			signature: Path::new(signature),
+			public_key: Path::new(matches.value_of("KERNEL_PUBLIC_KEY").unwrap()),
+		});
+	let shm_id = matches.value_of("SHM_ID");
+	let clock_offset = matches.value_of("CLOCK_OFFSET").map_or(0, |offset| {
+		offset.parse::<i64>().expect("Could not parse clock offset")
+	});
+	let tsc_khz = matches.value_of("TSC_KHZ").map(|khz| {
+		khz.parse::<u32>()
+			.expect("Could not parse the TSC frequency")
+	});
+	let tsc_offset = matches.value_of("TSC_OFFSET").map(|offset| {
+		offset
+			.parse::<i64>()
+			.expect("Could not parse the TSC offset")
+	});
+	let tun_tx_queue_size = matches.value_of("TUN_TX_QUEUE_SIZE").map(|bytes| {
+		bytes
+			.parse::<u32>()
+			.expect("Could not parse TUN/TAP send buffer size")
+	});
+	let net_queue_depth = matches.value_of("NET_QUEUE_DEPTH").map(|depth| {
+		depth
+			.parse::<usize>()
+			.expect("Could not parse the network queue depth")
+	});
+	let mac = matches
+		.value_of("MAC")
+		.map(|mac| utils::parse_mac(mac).expect("Invalid MAC address"));
+	let memory_pressure_hints = matches.is_present("MEMORY_PRESSURE_HINTS");
+	let ptp = matches.is_present("PTP");
+	let block = matches.value_of("DISK").map(Path::new);
+	let block_readonly = matches.is_present("DISK_READONLY");
+	let block_overlay = matches.value_of("DISK_OVERLAY").map(Path::new);
+	let balloon_control = matches.value_of("BALLOON_CONTROL").map(Path::new);
+	let balloon_target = matches.value_of("BALLOON_TARGET").map(|size| {
+		Byte::from_str(size)
+			.expect("Invalid size of the balloon")
+			.get_bytes()
+			.try_into()
+			.unwrap_or(usize::MAX)
+	});
+	let auto_migrate = !matches.is_present("NO_AUTO_MIGRATE");
+	let strict_memory_model = matches.is_present("STRICT_MEMORY_MODEL");
+	let host_allowed_paths = matches
+		.values_of("HOST_ALLOWED_PATHS")
+		.map(|paths| paths.map(PathBuf::from).collect::<Vec<_>>());
+	let preemption_timer = matches.value_of("PREEMPTION_TIMER").map(|cycles| {
+		cycles
+			.parse::<u64>()
+			.expect("Could not parse preemption timer")
+	});
+	let notify_vmexit_window = matches
+		.value_of("NOTIFY_VMEXIT_WINDOW")
+		.unwrap()
+		.parse::<u32>()
+		.expect("Could not parse notify VM exit window");
+	let stats_interval =
+		matches
+			.value_of("STATS_INTERVAL")
+			.map_or(DEFAULT_STATS_INTERVAL, |secs| {
+				secs.parse::<u64>()
+					.expect("Could not parse statistics interval")
+			});
+	let stats_log = matches.value_of("STATS_LOG").map(Path::new);
+	let entropy = match (
+		matches.value_of("ENTROPY_FILE"),
+		matches.value_of("ENTROPY_SEED"),
+	) {
+		(Some(path), _) => vm::EntropySource::File(Path::new(path)),
+		(None, Some(seed)) => {
+			vm::EntropySource::Seed(utils::parse_entropy_seed(seed).expect("Invalid entropy seed"))
+		}
+		(None, None) => vm::EntropySource::Os,
+	};
+	let cpu_features = matches
+		.value_of("CPU_FEATURES")
+		.map(|features| {
+			cpu_features::parse_cpu_features(features)
+				.unwrap_or_else(|err| panic!("Invalid CPU features: {}", err))
+		})
+		.unwrap_or_default();
+	let cpuid_overrides = matches
+		.values_of("CPUID")
+		.map(|overrides| {
+			overrides
+				.map(|spec| {
+					cpu_features::parse_cpuid_override(spec)
+						.unwrap_or_else(|err| panic!("Invalid --cpuid {:?}: {}", spec, err))
+				})
+				.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	let msr_whitelist = matches
+		.values_of("ALLOW_MSR")
+		.map(|msrs| {
+			msrs.map(|msr| {
+				utils::parse_msr_index(msr)
+					.unwrap_or_else(|err| panic!("Invalid --allow-msr {:?}: {}", msr, err))
+			})
+			.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	let exit_filter = matches
+		.value_of("EXIT_FILTER")
+		.map(|filter| {
+			exit_reasons::parse_exit_filter(filter)
+				.unwrap_or_else(|err| panic!("Invalid exit filter: {}", err))
+		})
+		.unwrap_or_default();
+	let mut numa_start = 0;
+	let numa_nodes = matches
+		.values_of("NUMA")
+		.map(|nodes| {
+			nodes
+				.map(|node| {
+					let (cpus, size) = node
+						.rsplit_once(':')
+						.unwrap_or_else(|| panic!("Invalid NUMA node {:?}", node));
+					let cpus = utils::parse_ranges([cpus])
+						.collect::<Result<Vec<_>, _>>()
+						.unwrap_or_else(|err| {
+							panic!("Invalid CPUs of NUMA node {:?}: {}", node, err)
+						});
+					let size: usize = Byte::from_str(size)
+						.expect("Invalid memory size of NUMA node")
+						.get_bytes()
+						.try_into()
+						.unwrap();
+					let memory = numa_start..numa_start + size;
+					numa_start = memory.end;
+					vm::NumaNode { cpus, memory }
+				})
+				.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	let memory_backend = matches
+		.value_of("MEMORY_BACKEND")
+		.map_or(vm::MemoryBackend::Anonymous, |backend| {
+			vm::MemoryBackend::parse(backend).expect("Invalid memory backend")
+		});
+	let deadline = matches.value_of("DEADLINE_RUNTIME").map(|runtime| {
+		let parse = |value: &str| {
+			value
+				.parse::<u64>()
+				.expect("Could not parse SCHED_DEADLINE parameter")
+		};
+		vm::DeadlineParameters {
+			runtime: parse(runtime),
+			deadline: parse(matches.value_of("DEADLINE_DEADLINE").unwrap()),
+			period: parse(matches.value_of("DEADLINE_PERIOD").unwrap()),
+		}
+	});
+	// always enabled in debug builds
+	let print_memory_layout =
+		cfg!(debug_assertions) || matches.is_present("PRINT_GUEST_MEMORY_LAYOUT");
+	let apic_timer_hz = matches.value_of("APIC_TIMER_HZ").map(|hz| {
+		hz.parse::<u32>()
+			.expect("Could not parse APIC timer frequency")
+	});
 
 	let params = vm::Parameter {
 		mem_size,
@@ -256,15 +1295,139 @@ fn main() {
 		verbose,
 		hugepage,
 		mergeable,
-		ip,
-		gateway,
-		mask,
-		nic,
+		nics: &nics,
 		gdbport,
+		apic_timer_hz,
+		prealloc,
+		pretouch_workers,
+		print_memory_layout,
+		ksm_report_interval,
+		nmi_on_signal,
+		console_log_file,
+		console_log_append,
+		soft_iommu,
+		dirty_tracking,
+		deadline,
+		memory_backend,
+		stats_interval,
+		stats_log,
+		memory_pressure_hints,
+		network_bridge,
+		remove_bridge_on_exit,
+		preemption_timer,
+		auto_migrate,
+		strict_memory_model,
+		host_allowed_paths: host_allowed_paths.as_deref(),
+		tun_tx_queue_size,
+		kernel_signature,
+		shm_id,
+		clock_offset,
+		tui: matches.is_present("TUI"),
+		net_queue_depth,
+		fault_tolerant: matches.is_present("FAULT_TOLERANT"),
+		mac,
+		ip6,
+		prefix_len6,
+		kernel_log_file,
+		net_irq_resample: matches.is_present("NET_IRQ_RESAMPLE"),
+		debug_port_file,
+		debug_port_prefix,
+		vhost_net: matches.is_present("VHOST_NET"),
+		pcap,
+		kernel_args_file,
+		port_forwards: &port_forwards,
+		ptp,
+		block,
+		block_readonly,
+		entropy,
+		block_overlay,
+		cpu_features: &cpu_features,
+		numa_nodes: &numa_nodes,
+		balloon: matches.is_present("BALLOON"),
+		balloon_control,
+		balloon_target,
+		net_vnet_hdr: matches.is_present("NET_VNET_HDR"),
+		dirty_log: matches.is_present("DIRTY_LOG"),
+		snapshot: matches.value_of("SNAPSHOT").map(Path::new),
+		pause_on_signal: matches.is_present("PAUSE_ON_SIGNAL"),
+		nic_mode,
+		notify_vmexit_window,
+		verbose_exits: matches.is_present("VERBOSE_EXITS"),
+		exit_filter: &exit_filter,
+		net_metrics_file: matches.value_of("NET_METRICS_FILE").map(Path::new),
+		gdb_socket: matches.value_of("GDB_SOCKET").map(Path::new),
+		registers_on_signal,
+		cpuid_overrides: &cpuid_overrides,
+		guest_ip_forward: matches.is_present("GUEST_IP_FORWARD"),
+		guest_routes: &guest_routes,
+		debug_on_startup: matches.is_present("DEBUG_ON_STARTUP"),
+		msr_whitelist: &msr_whitelist,
+		tsc_khz,
+		tsc_offset,
 	};
 
-	let code = Uhyve::new(path, &params)
-		.expect("Unable to create VM! Is the hypervisor interface (e.g. KVM) activated?")
-		.run(cpu_affinity);
+	let vm = match matches.value_of("RESTORE") {
+		#[cfg(target_os = "linux")]
+		Some(snapshot) => Uhyve::restore(Path::new(snapshot), &params),
+		#[cfg(not(target_os = "linux"))]
+		Some(_) => panic!("Snapshots are not supported on macos"),
+		None => Uhyve::new(path, &params),
+	}
+	.expect("Unable to create VM! Is the hypervisor interface (e.g. KVM) activated?");
+	let start = Instant::now();
+	let code = match test_kernels {
+		Some(kernels) => run_tests(vm, kernels, cpu_affinity),
+		None => run(vm, cpu_affinity),
+	};
+	if stress_test.is_some() {
+		println!(
+			"Stress test {} after {:.1}s",
+			stress_test::result(code),
+			start.elapsed().as_secs_f64()
+		);
+		// `exit` does not drop the temporary files of the stress test
+		drop(stress_test);
+	}
 	std::process::exit(code);
 }
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+fn run(vm: Uhyve, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
+	tokio::runtime::Builder::new_multi_thread()
+		.build()
+		.expect("Unable to start the tokio runtime")
+		.block_on(vm.run_async(cpu_affinity))
+		.expect("Unable to run the VM")
+}
+
+#[cfg(not(all(target_os = "linux", feature = "tokio")))]
+fn run(vm: Uhyve, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
+	vm.run(cpu_affinity)
+}
+
+/// Runs the kernels one after another and prints the result of each.
+///
+/// Returns 0, if all kernels exited successfully.
+#[cfg(target_os = "linux")]
+fn run_tests(vm: Uhyve, kernels: Vec<PathBuf>, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
+	let mut failed = 0;
+	vm.run_tests(kernels, cpu_affinity, |kernel, result| {
+		match result {
+			Ok(0) => {
+				println!("PASSED");
+				return;
+			}
+			Ok(code) => println!("FAILED {}", code),
+			Err(err) => println!("FAILED ({})", err),
+		}
+		debug!("{} failed", kernel.display());
+		failed += 1;
+	});
+
+	i32::from(failed > 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_tests(_vm: Uhyve, _kernels: Vec<PathBuf>, _cpu_affinity: Option<Vec<CoreId>>) -> i32 {
+	unimplemented!("The test mode is only supported on Linux")
+}
@@ -0,0 +1,141 @@
+//! Capturing of the guest's console output in a file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Size of the buffer, after which the file is flushed even without a newline.
+const CONSOLE_LOG_BUFFER_SIZE: usize = 4096;
+
+/// A copy of everything the guest writes to its console.
+///
+/// The file is flushed on every newline, so that the output survives a crash of the VM.
+#[derive(Debug)]
+pub struct ConsoleLog {
+	file: Mutex<BufWriter<File>>,
+}
+
+impl ConsoleLog {
+	/// Opens the log file, truncating it unless `append` is set.
+	pub fn open(path: &Path, append: bool) -> io::Result<Self> {
+		let file = OpenOptions::new()
+			.create(true)
+			.write(true)
+			.append(append)
+			.truncate(!append)
+			.open(path)?;
+
+		Ok(Self {
+			file: Mutex::new(BufWriter::with_capacity(CONSOLE_LOG_BUFFER_SIZE, file)),
+		})
+	}
+
+	pub fn write(&self, buf: &[u8]) -> io::Result<()> {
+		let mut file = self.file.lock().unwrap();
+		file.write_all(buf)?;
+		if buf.contains(&b'\n') {
+			file.flush()?;
+		}
+		Ok(())
+	}
+}
+
+/// A record of everything the guest writes to its debug ports, see `--debug-port-file`.
+///
+/// Every line starts with the host time in microseconds and the optional prefix. The file is
+/// opened with `O_DSYNC`, so a line is on the disk, before the guest continues.
+#[derive(Debug)]
+pub struct DebugPortLog {
+	state: Mutex<DebugPortState>,
+	prefix: String,
+	echo: bool,
+}
+
+#[derive(Debug)]
+struct DebugPortState {
+	file: File,
+	/// Whether the next byte starts a new line
+	line_start: bool,
+}
+
+impl DebugPortLog {
+	/// Opens the log file for appending, `echo` additionally keeps printing the output to stdout.
+	pub fn open(path: &Path, prefix: Option<&str>, echo: bool) -> io::Result<Self> {
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.custom_flags(libc::O_DSYNC)
+			.open(path)?;
+
+		Ok(Self {
+			state: Mutex::new(DebugPortState {
+				file,
+				line_start: true,
+			}),
+			prefix: prefix.map_or_else(String::new, |prefix| format!("{} ", prefix)),
+			echo,
+		})
+	}
+
+	/// Returns, whether the output is printed to stdout as well.
+	pub fn echo(&self) -> bool {
+		self.echo
+	}
+
+	pub fn write(&self, buf: &[u8]) -> io::Result<()> {
+		let now = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.unwrap_or_default();
+		self.write_at(buf, now)
+	}
+
+	fn write_at(&self, buf: &[u8], now: Duration) -> io::Result<()> {
+		let mut state = self.state.lock().unwrap();
+		let mut record = Vec::with_capacity(buf.len() + 32);
+		for line in buf.split_inclusive(|&byte| byte == b'\n') {
+			if state.line_start {
+				write!(
+					record,
+					"{}[{}.{:06}] ",
+					self.prefix,
+					now.as_secs(),
+					now.subsec_micros()
+				)?;
+			}
+			record.extend_from_slice(line);
+			state.line_start = line.ends_with(b"\n");
+		}
+		// a single write, so that every call is synchronized only once
+		state.file.write_all(&record)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_fs::TempDir;
+	use std::fs;
+
+	#[test]
+	fn test_debug_port_log() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("debug.log");
+		fs::write(&path, "previous run\n").unwrap();
+
+		let log = DebugPortLog::open(&path, Some("vm0"), false).unwrap();
+		log.write_at(b"Hello", Duration::new(12, 3000)).unwrap();
+		log.write_at(b" world\nsecond\n", Duration::new(13, 0))
+			.unwrap();
+		log.write_at(b"third", Duration::new(14, 999_999_000))
+			.unwrap();
+
+		assert!(!log.echo());
+		assert_eq!(
+			fs::read_to_string(&path).unwrap(),
+			"previous run\nvm0 [12.000003] Hello world\nvm0 [13.000000] second\nvm0 [14.999999] third"
+		);
+	}
+}
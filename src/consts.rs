@@ -11,6 +11,8 @@ pub const BOOT_PML4: u64 = 0x10000;
 pub const BOOT_PDPTE: u64 = 0x11000;
 pub const BOOT_PDE: u64 = 0x12000;
 pub const BOOT_INFO_ADDR: u64 = 0x9000;
+/// Page with the cross timestamps of `--ptp`
+pub const PTP_CLOCK_PAGE: u64 = 0x8000;
 pub const EFER_SCE: u64 = 1; /* System Call Extensions */
 pub const EFER_LME: u64 = 1 << 8; /* Long mode enable */
 pub const EFER_LMA: u64 = 1 << 10; /* Long mode active (read-only) */
@@ -20,9 +22,15 @@ pub const IOAPIC_SIZE: u64 = 0x1000;
 pub const KERNEL_STACK_SIZE: u64 = 32_768;
 pub const SHUTDOWN_PORT: u16 = 0xf4;
 pub const SHAREDQUEUE_START: usize = 0x80000;
+/// End of the shared network queues, where the kernel starts
+pub const SHAREDQUEUE_END: usize = 0x400000;
+/// Maximum number of network interfaces of the guest
+pub const UHYVE_MAX_NICS: usize = 4;
 pub const UHYVE_NET_MTU: usize = 1500;
 pub const UHYVE_QUEUE_SIZE: usize = 8;
+pub const UHYVE_MAX_QUEUE_SIZE: usize = 1024;
 pub const UHYVE_IRQ_NET: u32 = 11;
+pub const UHYVE_IRQ_BALLOON: u32 = 10;
 
 pub const GUEST_PAGE_SIZE: u64 = 0x200000; /* 2 MB pages in guest */
 
@@ -45,3 +53,10 @@ pub const UHYVE_PORT_CMDVAL: u16 = 0x780;
 
 pub const UHYVE_UART_PORT: u16 = 0x800;
 pub const UHYVE_PORT_UNLINK: u16 = 0x840;
+
+// Ports of the software IOMMU
+pub const UHYVE_PORT_IOMMU_MAP: u16 = 0x880;
+pub const UHYVE_PORT_IOMMU_UNMAP: u16 = 0x8c0;
+
+// Structured log records of the kernel
+pub const UHYVE_PORT_KERNEL_LOG: u16 = 0x900;
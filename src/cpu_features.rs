@@ -0,0 +1,429 @@
+//! Adding and removing single CPUID features of the guest, see `--cpu-features`, and arbitrary
+//! bits of the CPUID leaves, see `--cpuid`.
+//!
+//! The features are named as in `/proc/cpuinfo` of Linux, their bits are taken from the Intel SDM
+//! Vol. 2A, CPUID, and the AMD APM Vol. 3, Appendix E.
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// A register of the result of `CPUID`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuidRegister {
+	Eax,
+	Ebx,
+	Ecx,
+	Edx,
+}
+
+impl CpuidRegister {
+	fn parse(register: &str) -> Option<Self> {
+		match register.to_ascii_lowercase().as_str() {
+			"eax" => Some(Self::Eax),
+			"ebx" => Some(Self::Ebx),
+			"ecx" => Some(Self::Ecx),
+			"edx" => Some(Self::Edx),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for CpuidRegister {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Eax => "eax",
+			Self::Ebx => "ebx",
+			Self::Ecx => "ecx",
+			Self::Edx => "edx",
+		})
+	}
+}
+
+/// A feature bit in the result of `CPUID`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CpuFeature {
+	pub name: &'static str,
+	pub leaf: u32,
+	pub subleaf: u32,
+	pub register: CpuidRegister,
+	pub bit: u8,
+}
+
+macro_rules! features {
+	($(($name:literal, $leaf:literal, $subleaf:literal, $register:ident, $bit:literal)),* $(,)?) => {
+		&[$(CpuFeature {
+			name: $name,
+			leaf: $leaf,
+			subleaf: $subleaf,
+			register: CpuidRegister::$register,
+			bit: $bit,
+		}),*]
+	};
+}
+
+/// The features, which can be changed.
+pub const CPU_FEATURES: &[CpuFeature] = features![
+	("fpu", 0x1, 0, Edx, 0),
+	("vme", 0x1, 0, Edx, 1),
+	("de", 0x1, 0, Edx, 2),
+	("pse", 0x1, 0, Edx, 3),
+	("tsc", 0x1, 0, Edx, 4),
+	("msr", 0x1, 0, Edx, 5),
+	("pae", 0x1, 0, Edx, 6),
+	("mce", 0x1, 0, Edx, 7),
+	("cx8", 0x1, 0, Edx, 8),
+	("apic", 0x1, 0, Edx, 9),
+	("sep", 0x1, 0, Edx, 11),
+	("mtrr", 0x1, 0, Edx, 12),
+	("pge", 0x1, 0, Edx, 13),
+	("mca", 0x1, 0, Edx, 14),
+	("cmov", 0x1, 0, Edx, 15),
+	("pat", 0x1, 0, Edx, 16),
+	("pse36", 0x1, 0, Edx, 17),
+	("clflush", 0x1, 0, Edx, 19),
+	("mmx", 0x1, 0, Edx, 23),
+	("fxsr", 0x1, 0, Edx, 24),
+	("sse", 0x1, 0, Edx, 25),
+	("sse2", 0x1, 0, Edx, 26),
+	("ss", 0x1, 0, Edx, 27),
+	("ht", 0x1, 0, Edx, 28),
+	("pni", 0x1, 0, Ecx, 0),
+	("pclmulqdq", 0x1, 0, Ecx, 1),
+	("monitor", 0x1, 0, Ecx, 3),
+	("vmx", 0x1, 0, Ecx, 5),
+	("ssse3", 0x1, 0, Ecx, 9),
+	("fma", 0x1, 0, Ecx, 12),
+	("cx16", 0x1, 0, Ecx, 13),
+	("pcid", 0x1, 0, Ecx, 17),
+	("sse4_1", 0x1, 0, Ecx, 19),
+	("sse4_2", 0x1, 0, Ecx, 20),
+	("x2apic", 0x1, 0, Ecx, 21),
+	("movbe", 0x1, 0, Ecx, 22),
+	("popcnt", 0x1, 0, Ecx, 23),
+	("tsc_deadline_timer", 0x1, 0, Ecx, 24),
+	("aes", 0x1, 0, Ecx, 25),
+	("xsave", 0x1, 0, Ecx, 26),
+	("avx", 0x1, 0, Ecx, 28),
+	("f16c", 0x1, 0, Ecx, 29),
+	("rdrand", 0x1, 0, Ecx, 30),
+	("hypervisor", 0x1, 0, Ecx, 31),
+	("fsgsbase", 0x7, 0, Ebx, 0),
+	("tsc_adjust", 0x7, 0, Ebx, 1),
+	("bmi1", 0x7, 0, Ebx, 3),
+	("hle", 0x7, 0, Ebx, 4),
+	("avx2", 0x7, 0, Ebx, 5),
+	("smep", 0x7, 0, Ebx, 7),
+	("bmi2", 0x7, 0, Ebx, 8),
+	("erms", 0x7, 0, Ebx, 9),
+	("invpcid", 0x7, 0, Ebx, 10),
+	("rtm", 0x7, 0, Ebx, 11),
+	("mpx", 0x7, 0, Ebx, 14),
+	("avx512f", 0x7, 0, Ebx, 16),
+	("avx512dq", 0x7, 0, Ebx, 17),
+	("rdseed", 0x7, 0, Ebx, 18),
+	("adx", 0x7, 0, Ebx, 19),
+	("smap", 0x7, 0, Ebx, 20),
+	("avx512ifma", 0x7, 0, Ebx, 21),
+	("clflushopt", 0x7, 0, Ebx, 23),
+	("clwb", 0x7, 0, Ebx, 24),
+	("avx512pf", 0x7, 0, Ebx, 26),
+	("avx512er", 0x7, 0, Ebx, 27),
+	("avx512cd", 0x7, 0, Ebx, 28),
+	("sha_ni", 0x7, 0, Ebx, 29),
+	("avx512bw", 0x7, 0, Ebx, 30),
+	("avx512vl", 0x7, 0, Ebx, 31),
+	("avx512vbmi", 0x7, 0, Ecx, 1),
+	("umip", 0x7, 0, Ecx, 2),
+	("pku", 0x7, 0, Ecx, 3),
+	("waitpkg", 0x7, 0, Ecx, 5),
+	("avx512_vbmi2", 0x7, 0, Ecx, 6),
+	("gfni", 0x7, 0, Ecx, 8),
+	("vaes", 0x7, 0, Ecx, 9),
+	("vpclmulqdq", 0x7, 0, Ecx, 10),
+	("avx512_vnni", 0x7, 0, Ecx, 11),
+	("avx512_bitalg", 0x7, 0, Ecx, 12),
+	("avx512_vpopcntdq", 0x7, 0, Ecx, 14),
+	("la57", 0x7, 0, Ecx, 16),
+	("rdpid", 0x7, 0, Ecx, 22),
+	("movdiri", 0x7, 0, Ecx, 27),
+	("movdir64b", 0x7, 0, Ecx, 28),
+	("avx512_4vnniw", 0x7, 0, Edx, 2),
+	("avx512_4fmaps", 0x7, 0, Edx, 3),
+	("fsrm", 0x7, 0, Edx, 4),
+	("md_clear", 0x7, 0, Edx, 10),
+	("serialize", 0x7, 0, Edx, 14),
+	("tsxldtrk", 0x7, 0, Edx, 16),
+	("amx_bf16", 0x7, 0, Edx, 22),
+	("avx512_fp16", 0x7, 0, Edx, 23),
+	("amx_tile", 0x7, 0, Edx, 24),
+	("amx_int8", 0x7, 0, Edx, 25),
+	("spec_ctrl", 0x7, 0, Edx, 26),
+	("intel_stibp", 0x7, 0, Edx, 27),
+	("arch_capabilities", 0x7, 0, Edx, 29),
+	("ssbd", 0x7, 0, Edx, 31),
+	("xsaveopt", 0xd, 1, Eax, 0),
+	("xsavec", 0xd, 1, Eax, 1),
+	("xgetbv1", 0xd, 1, Eax, 2),
+	("xsaves", 0xd, 1, Eax, 3),
+	("lahf_lm", 0x8000_0001, 0, Ecx, 0),
+	("abm", 0x8000_0001, 0, Ecx, 5),
+	("sse4a", 0x8000_0001, 0, Ecx, 6),
+	("3dnowprefetch", 0x8000_0001, 0, Ecx, 8),
+	("xop", 0x8000_0001, 0, Ecx, 11),
+	("fma4", 0x8000_0001, 0, Ecx, 16),
+	("tbm", 0x8000_0001, 0, Ecx, 21),
+	("syscall", 0x8000_0001, 0, Edx, 11),
+	("nx", 0x8000_0001, 0, Edx, 20),
+	("pdpe1gb", 0x8000_0001, 0, Edx, 26),
+	("rdtscp", 0x8000_0001, 0, Edx, 27),
+	("lm", 0x8000_0001, 0, Edx, 29),
+	("invtsc", 0x8000_0007, 0, Edx, 8),
+];
+
+/// Adds or removes a feature.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CpuFeatureChange {
+	pub feature: &'static CpuFeature,
+	pub enable: bool,
+}
+
+impl CpuFeatureChange {
+	/// Sets or clears the bit of the feature in the registers EAX, EBX, ECX and EDX of its leaf.
+	pub fn apply(&self, registers: &mut [u32; 4]) {
+		let register = &mut registers[self.feature.register as usize];
+		if self.enable {
+			*register |= 1 << self.feature.bit;
+		} else {
+			*register &= !(1 << self.feature.bit);
+		}
+	}
+}
+
+impl fmt::Display for CpuFeatureChange {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{}{}",
+			if self.enable { '+' } else { '-' },
+			self.feature.name
+		)
+	}
+}
+
+fn supported_features() -> String {
+	CPU_FEATURES
+		.iter()
+		.map(|feature| feature.name)
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseCpuFeaturesError {
+	#[error("{0:?} has to start with + or -")]
+	MissingSign(String),
+	#[error("unknown CPU feature {0:?}, supported are: {list}", list = supported_features())]
+	UnknownFeature(String),
+}
+
+/// Parses a comma-separated list of features like `+avx512f,-rtm,+vaes`.
+pub fn parse_cpu_features(features: &str) -> Result<Vec<CpuFeatureChange>, ParseCpuFeaturesError> {
+	features
+		.split(',')
+		.filter(|change| !change.is_empty())
+		.map(|change| {
+			let (enable, name) = if let Some(name) = change.strip_prefix('+') {
+				(true, name)
+			} else if let Some(name) = change.strip_prefix('-') {
+				(false, name)
+			} else {
+				return Err(ParseCpuFeaturesError::MissingSign(change.to_string()));
+			};
+			let feature = CPU_FEATURES
+				.iter()
+				.find(|feature| feature.name == name)
+				.ok_or_else(|| ParseCpuFeaturesError::UnknownFeature(name.to_string()))?;
+			Ok(CpuFeatureChange { feature, enable })
+		})
+		.collect()
+}
+
+/// Sets and clears bits of a register of a CPUID leaf.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CpuidOverride {
+	pub leaf: u32,
+	pub subleaf: u32,
+	pub register: CpuidRegister,
+	pub set: u32,
+	pub clear: u32,
+}
+
+impl CpuidOverride {
+	/// Clears and then sets the bits in the registers EAX, EBX, ECX and EDX of the leaf.
+	pub fn apply(&self, registers: &mut [u32; 4]) {
+		let register = &mut registers[self.register as usize];
+		*register = (*register & !self.clear) | self.set;
+	}
+}
+
+impl fmt::Display for CpuidOverride {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"0x{:x}:{}:{}:set=0x{:x}:clear=0x{:x}",
+			self.leaf, self.subleaf, self.register, self.set, self.clear
+		)
+	}
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseCpuidOverrideError {
+	#[error("expected LEAF:SUBLEAF:REGISTER:set=MASK:clear=MASK")]
+	InvalidFormat,
+	#[error("invalid number {0:?}")]
+	InvalidNumber(String),
+	#[error("unknown register {0:?}, supported are eax, ebx, ecx and edx")]
+	UnknownRegister(String),
+	#[error("the bits 0x{0:x} are both set and cleared")]
+	Conflict(u32),
+}
+
+/// Parses a decimal number or a hexadecimal one with the prefix `0x`.
+fn parse_number(number: &str) -> Result<u32, ParseCpuidOverrideError> {
+	match number.strip_prefix("0x") {
+		Some(hex) => u32::from_str_radix(hex, 16),
+		None => number.parse(),
+	}
+	.map_err(|_| ParseCpuidOverrideError::InvalidNumber(number.to_string()))
+}
+
+/// Parses an override like `0x7:0:ebx:set=0x20:clear=0x800`, of which `set` and `clear` may be
+/// omitted.
+pub fn parse_cpuid_override(spec: &str) -> Result<CpuidOverride, ParseCpuidOverrideError> {
+	let mut parts = spec.split(':');
+	let mut next = || parts.next().ok_or(ParseCpuidOverrideError::InvalidFormat);
+	let leaf = parse_number(next()?)?;
+	let subleaf = parse_number(next()?)?;
+	let register = next()?;
+	let register = CpuidRegister::parse(register)
+		.ok_or_else(|| ParseCpuidOverrideError::UnknownRegister(register.to_string()))?;
+
+	let (mut set, mut clear) = (0, 0);
+	for mask in parts {
+		if let Some(mask) = mask.strip_prefix("set=") {
+			set |= parse_number(mask)?;
+		} else if let Some(mask) = mask.strip_prefix("clear=") {
+			clear |= parse_number(mask)?;
+		} else {
+			return Err(ParseCpuidOverrideError::InvalidFormat);
+		}
+	}
+	if set & clear != 0 {
+		return Err(ParseCpuidOverrideError::Conflict(set & clear));
+	}
+
+	Ok(CpuidOverride {
+		leaf,
+		subleaf,
+		register,
+		set,
+		clear,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_cpu_features() {
+		let changes = parse_cpu_features("+avx512f,-rtm,+vaes").unwrap();
+		assert_eq!(
+			changes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+			["+avx512f", "-rtm", "+vaes"]
+		);
+		assert_eq!(
+			parse_cpu_features("avx"),
+			Err(ParseCpuFeaturesError::MissingSign("avx".to_string()))
+		);
+		let err = parse_cpu_features("+avx,-quantum").unwrap_err();
+		assert_eq!(
+			err,
+			ParseCpuFeaturesError::UnknownFeature("quantum".to_string())
+		);
+		assert!(err.to_string().contains("avx512f"));
+	}
+
+	#[test]
+	fn test_apply_cpu_features() {
+		let mut registers = [0, 1 << 11, 0, 0];
+		for change in parse_cpu_features("+avx2,-rtm,+avx512f").unwrap() {
+			change.apply(&mut registers);
+		}
+		assert_eq!(registers, [0, (1 << 5) | (1 << 16), 0, 0]);
+	}
+
+	#[test]
+	fn test_parse_cpuid_override() {
+		let cpuid_override = parse_cpuid_override("0x7:0:EBX:set=0x20:clear=2048").unwrap();
+		assert_eq!(
+			cpuid_override,
+			CpuidOverride {
+				leaf: 7,
+				subleaf: 0,
+				register: CpuidRegister::Ebx,
+				set: 1 << 5,
+				clear: 1 << 11,
+			}
+		);
+		assert_eq!(cpuid_override.to_string(), "0x7:0:ebx:set=0x20:clear=0x800");
+		assert_eq!(
+			parse_cpuid_override("0x80000001:0:edx:clear=0x8000000")
+				.unwrap()
+				.set,
+			0
+		);
+
+		assert_eq!(
+			parse_cpuid_override("0x7:0"),
+			Err(ParseCpuidOverrideError::InvalidFormat)
+		);
+		assert_eq!(
+			parse_cpuid_override("0x7:0:rax:set=1"),
+			Err(ParseCpuidOverrideError::UnknownRegister("rax".to_string()))
+		);
+		assert_eq!(
+			parse_cpuid_override("0x7:0:ebx:set=0xzz"),
+			Err(ParseCpuidOverrideError::InvalidNumber("0xzz".to_string()))
+		);
+		assert_eq!(
+			parse_cpuid_override("0x7:0:ebx:toggle=1"),
+			Err(ParseCpuidOverrideError::InvalidFormat)
+		);
+		assert_eq!(
+			parse_cpuid_override("1:0:ecx:set=0x3:clear=0x2"),
+			Err(ParseCpuidOverrideError::Conflict(2))
+		);
+	}
+
+	#[test]
+	fn test_apply_cpuid_override() {
+		let mut registers = [0, 0xff, 0, 0];
+		parse_cpuid_override("7:0:ebx:set=0x100:clear=0xf")
+			.unwrap()
+			.apply(&mut registers);
+		assert_eq!(registers, [0, 0x1f0, 0, 0]);
+	}
+
+	#[test]
+	fn test_cpu_feature_names_are_unique() {
+		for (index, feature) in CPU_FEATURES.iter().enumerate() {
+			assert!(CPU_FEATURES[..index]
+				.iter()
+				.all(|other| other.name != feature.name
+					&& (other.leaf, other.subleaf, other.register, other.bit)
+						!= (feature.leaf, feature.subleaf, feature.register, feature.bit)));
+		}
+	}
+}
@@ -0,0 +1,98 @@
+//! Names of the exit reasons of KVM, by which `--exit-filter` selects the logged exits.
+//!
+//! The numbers are the `KVM_EXIT_*` constants of `include/uapi/linux/kvm.h`.
+
+use thiserror::Error;
+
+/// The exit reasons, indexed by their number.
+pub const EXIT_REASONS: &[&str] = &[
+	"KVM_EXIT_UNKNOWN",
+	"KVM_EXIT_EXCEPTION",
+	"KVM_EXIT_IO",
+	"KVM_EXIT_HYPERCALL",
+	"KVM_EXIT_DEBUG",
+	"KVM_EXIT_HLT",
+	"KVM_EXIT_MMIO",
+	"KVM_EXIT_IRQ_WINDOW_OPEN",
+	"KVM_EXIT_SHUTDOWN",
+	"KVM_EXIT_FAIL_ENTRY",
+	"KVM_EXIT_INTR",
+	"KVM_EXIT_SET_TPR",
+	"KVM_EXIT_TPR_ACCESS",
+	"KVM_EXIT_S390_SIEIC",
+	"KVM_EXIT_S390_RESET",
+	"KVM_EXIT_DCR",
+	"KVM_EXIT_NMI",
+	"KVM_EXIT_INTERNAL_ERROR",
+	"KVM_EXIT_OSI",
+	"KVM_EXIT_PAPR_HCALL",
+	"KVM_EXIT_S390_UCONTROL",
+	"KVM_EXIT_WATCHDOG",
+	"KVM_EXIT_S390_TSCH",
+	"KVM_EXIT_EPR",
+	"KVM_EXIT_SYSTEM_EVENT",
+	"KVM_EXIT_S390_STSI",
+	"KVM_EXIT_IOAPIC_EOI",
+	"KVM_EXIT_HYPERV",
+	"KVM_EXIT_ARM_NISV",
+	"KVM_EXIT_X86_RDMSR",
+	"KVM_EXIT_X86_WRMSR",
+	"KVM_EXIT_DIRTY_RING_FULL",
+	"KVM_EXIT_AP_RESET_HOLD",
+	"KVM_EXIT_X86_BUS_LOCK",
+	"KVM_EXIT_XEN",
+	"KVM_EXIT_RISCV_SBI",
+	"KVM_EXIT_RISCV_CSR",
+	"KVM_EXIT_NOTIFY",
+];
+
+/// Returns the name of an exit reason or `None`, if it is newer than uhyve.
+pub fn exit_reason_name(reason: u32) -> Option<&'static str> {
+	EXIT_REASONS.get(reason as usize).copied()
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseExitFilterError {
+	#[error("unknown exit reason {0:?}, e.g. KVM_EXIT_IO or KVM_EXIT_MMIO are supported")]
+	UnknownReason(String),
+}
+
+/// Parses a comma-separated list of exit reasons like `KVM_EXIT_IO,KVM_EXIT_MMIO`.
+pub fn parse_exit_filter(filter: &str) -> Result<Vec<u32>, ParseExitFilterError> {
+	filter
+		.split(',')
+		.filter(|name| !name.is_empty())
+		.map(|name| {
+			EXIT_REASONS
+				.iter()
+				.position(|reason| reason.eq_ignore_ascii_case(name))
+				.map(|reason| reason as u32)
+				.ok_or_else(|| ParseExitFilterError::UnknownReason(name.to_string()))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_exit_filter() {
+		assert_eq!(
+			parse_exit_filter("KVM_EXIT_IO,KVM_EXIT_MMIO"),
+			Ok(vec![2, 6])
+		);
+		assert_eq!(parse_exit_filter("kvm_exit_hlt"), Ok(vec![5]));
+		assert_eq!(
+			parse_exit_filter("KVM_EXIT_IO,IO"),
+			Err(ParseExitFilterError::UnknownReason("IO".to_string()))
+		);
+	}
+
+	#[test]
+	fn test_exit_reason_name() {
+		assert_eq!(exit_reason_name(31), Some("KVM_EXIT_DIRTY_RING_FULL"));
+		assert_eq!(exit_reason_name(37), Some("KVM_EXIT_NOTIFY"));
+		assert_eq!(exit_reason_name(1000), None);
+	}
+}
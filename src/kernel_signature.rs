@@ -0,0 +1,129 @@
+//! Ed25519 signatures of kernel images.
+//!
+//! Kernels are signed with Ed25519ph, i.e., the signature covers the SHA-512 digest of the whole
+//! file. Keys and signatures are stored as raw bytes: 32 bytes for the public and the secret key
+//! and 64 bytes for a signature.
+
+use std::{
+	ffi::OsString,
+	fs::{self, File, OpenOptions},
+	io::{self, Read, Write},
+	os::unix::fs::OpenOptionsExt,
+	path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Digest, Keypair, PublicKey, SecretKey, Sha512, Signature, SECRET_KEY_LENGTH};
+
+/// Separates the kernel signatures from other uses of the same key.
+const CONTEXT: &[u8] = b"uhyve kernel";
+
+fn invalid_data<E>(_err: E) -> io::Error {
+	io::ErrorKind::InvalidData.into()
+}
+
+fn digest(kernel: &[u8]) -> Sha512 {
+	let mut digest = Sha512::new();
+	digest.update(kernel);
+	digest
+}
+
+/// Appends `extension` to `prefix`, which may already contain dots.
+fn with_suffix(prefix: &Path, extension: &str) -> PathBuf {
+	let mut path = OsString::from(prefix);
+	path.push(".");
+	path.push(extension);
+	path.into()
+}
+
+/// Returns the path of the signature, which is written for the kernel: `<kernel>.sig`.
+pub fn signature_path(kernel: &Path) -> PathBuf {
+	with_suffix(kernel, "sig")
+}
+
+/// The public key and the signature, which a kernel has to match.
+#[derive(Debug, Clone)]
+pub struct KernelVerifier {
+	public_key: PublicKey,
+	signature: Signature,
+}
+
+impl KernelVerifier {
+	/// Reads the signature and the public key from their files.
+	pub fn open(signature: &Path, public_key: &Path) -> io::Result<Self> {
+		Ok(Self {
+			public_key: PublicKey::from_bytes(&fs::read(public_key)?).map_err(invalid_data)?,
+			signature: Signature::try_from(&fs::read(signature)?[..]).map_err(invalid_data)?,
+		})
+	}
+
+	/// Checks, whether the signature is valid for the contents of the kernel file.
+	pub fn verify(&self, kernel: &[u8]) -> bool {
+		self.public_key
+			.verify_prehashed(digest(kernel), Some(CONTEXT), &self.signature)
+			.is_ok()
+	}
+}
+
+fn keypair(secret_key: &[u8]) -> io::Result<Keypair> {
+	let secret = SecretKey::from_bytes(secret_key).map_err(invalid_data)?;
+	let public = PublicKey::from(&secret);
+	Ok(Keypair { secret, public })
+}
+
+/// Signs the contents of a kernel file with a secret key.
+pub fn sign(kernel: &[u8], secret_key: &[u8]) -> io::Result<Signature> {
+	keypair(secret_key)?
+		.sign_prehashed(digest(kernel), Some(CONTEXT))
+		.map_err(invalid_data)
+}
+
+/// Generates a new key pair and writes it to `<prefix>.pub` and `<prefix>.sec`.
+///
+/// Existing files are not overwritten and the secret key is only readable by its owner.
+pub fn generate_key_pair(prefix: &Path) -> io::Result<()> {
+	let mut secret_key = [0u8; SECRET_KEY_LENGTH];
+	File::open("/dev/urandom")?.read_exact(&mut secret_key)?;
+	let keypair = keypair(&secret_key)?;
+
+	let create = |extension, mode| {
+		OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.mode(mode)
+			.open(with_suffix(prefix, extension))
+	};
+	create("sec", 0o600)?.write_all(keypair.secret.as_bytes())?;
+	create("pub", 0o644)?.write_all(keypair.public.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_kernel_signature() {
+		let secret_key = [7u8; SECRET_KEY_LENGTH];
+		let kernel = b"\x7fELF kernel".to_vec();
+
+		let verifier = KernelVerifier {
+			public_key: keypair(&secret_key).unwrap().public,
+			signature: sign(&kernel, &secret_key).unwrap(),
+		};
+		assert!(verifier.verify(&kernel));
+
+		let mut tampered = kernel.clone();
+		tampered[5] ^= 1;
+		assert!(!verifier.verify(&tampered));
+
+		let other = KernelVerifier {
+			public_key: keypair(&[8u8; SECRET_KEY_LENGTH]).unwrap().public,
+			..verifier
+		};
+		assert!(!other.verify(&kernel));
+
+		assert_eq!(
+			with_suffix(Path::new("keys/hermit.v1"), "pub"),
+			Path::new("keys/hermit.v1.pub")
+		);
+	}
+}
@@ -9,7 +9,11 @@ mod macros;
 extern crate log;
 
 mod arch;
+pub mod console_log;
 pub mod consts;
+pub mod cpu_features;
+pub mod exit_reasons;
+pub mod kernel_signature;
 #[cfg(target_os = "linux")]
 pub mod linux;
 #[cfg(target_os = "linux")]
@@ -19,8 +23,10 @@ pub mod macos;
 #[cfg(target_os = "macos")]
 pub use macos as os;
 pub mod paging;
+pub mod scratch_alloc;
 #[cfg(target_os = "linux")]
 pub mod shared_queue;
+pub mod stress_test;
 pub mod utils;
 pub mod vm;
 
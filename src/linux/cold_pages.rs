@@ -0,0 +1,128 @@
+//! Hinting the host about guest memory, which has not been written recently.
+//!
+//! Pages, which have not been dirtied since the last check, are marked with `MADV_COLD`. This
+//! does not free them, but makes them the first candidates for reclamation under memory pressure.
+
+use std::{
+	ops::Range,
+	sync::{
+		mpsc::{self, RecvTimeoutError, Sender},
+		Arc,
+	},
+	thread::{self, JoinHandle},
+	time::Duration,
+};
+
+use crate::{consts::PAGE_SIZE, linux::dirty_ring::DirtyTracker};
+
+/// Returns the page-aligned ranges of `regions`, which do not contain any of the sorted `dirty` pages.
+fn cold_ranges(regions: &[Range<u64>], dirty: &[u64]) -> Vec<Range<u64>> {
+	let mut cold = Vec::new();
+	let mut dirty = dirty.iter().copied().peekable();
+
+	for region in regions {
+		let mut start = region.start;
+		while let Some(&page) = dirty.peek() {
+			if page >= region.end {
+				break;
+			}
+			if page >= start {
+				if page > start {
+					cold.push(start..page);
+				}
+				start = page + PAGE_SIZE as u64;
+			}
+			dirty.next();
+		}
+		if start < region.end {
+			cold.push(start..region.end);
+		}
+	}
+
+	cold
+}
+
+/// Periodically marks the guest pages, which have not been written since the last period, as cold.
+#[derive(Debug)]
+pub struct ColdPageHinter {
+	stop: Sender<()>,
+	thread: JoinHandle<()>,
+}
+
+impl ColdPageHinter {
+	/// Starts hinting the guest physical `regions`, which are mapped at `host_address + gpa`.
+	pub fn start(
+		tracker: Arc<DirtyTracker>,
+		regions: Vec<Range<u64>>,
+		host_address: usize,
+		interval: Duration,
+	) -> Self {
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			// discard the pages dirtied while booting
+			let _ = tracker.collect_dirty_pages();
+
+			while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+				let dirty = match tracker.collect_dirty_pages() {
+					Ok(dirty) => dirty,
+					Err(err) => {
+						warn!("Unable to read the dirty pages: {}", err);
+						break;
+					}
+				};
+
+				let cold = cold_ranges(&regions, &dirty);
+				let cold_bytes = cold
+					.iter()
+					.map(|range| range.end - range.start)
+					.sum::<u64>();
+				for range in cold {
+					let ret = unsafe {
+						libc::madvise(
+							(host_address + range.start as usize) as *mut libc::c_void,
+							(range.end - range.start) as usize,
+							libc::MADV_COLD,
+						)
+					};
+					if ret < 0 {
+						debug!(
+							"MADV_COLD failed for {:#x?}: {}",
+							range,
+							std::io::Error::last_os_error()
+						);
+					}
+				}
+				debug!("Marked {} bytes of guest memory as cold", cold_bytes);
+			}
+		});
+
+		Self { stop, thread }
+	}
+
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		self.thread.join().unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cold_ranges() {
+		let page = PAGE_SIZE as u64;
+		let regions = [0..8 * page, 16 * page..20 * page];
+
+		assert_eq!(cold_ranges(&regions, &[]), regions.to_vec());
+		assert_eq!(
+			cold_ranges(&regions, &[0, 3 * page, 4 * page, 17 * page, 19 * page]),
+			vec![
+				page..3 * page,
+				5 * page..8 * page,
+				16 * page..17 * page,
+				18 * page..19 * page,
+			]
+		);
+	}
+}
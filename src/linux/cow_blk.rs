@@ -0,0 +1,285 @@
+//! Copy-on-write layering of a block device, see `--disk-overlay`.
+//!
+//! The base image is only read, the sectors, which the guest writes, are stored in an overlay file.
+//! Thus, several VMs may share a base image, each with its own overlay.
+//!
+//! # Format of the overlay
+//!
+//! All integers are little endian. The overlay starts with a header of [`HEADER_SIZE`] bytes:
+//!
+//! | Offset | Size | Content                                                    |
+//! |--------|------|------------------------------------------------------------|
+//! | 0      | 8    | magic `UHYVECOW`                                           |
+//! | 8      | 4    | version, currently 1                                       |
+//! | 12     | 4    | size of a cluster in bytes, a multiple of 512              |
+//! | 16     | 8    | capacity of the base image in 512 byte sectors             |
+//! | 24     | 8    | number of entries of the cluster map                       |
+//! | 32     | ...  | reserved, zero                                             |
+//!
+//! The cluster map follows the header. Its `n`th entry is a `u64` with the offset of the `n`th
+//! cluster of the disk in the overlay, or 0, if the cluster has not been written and is read from
+//! the base image. The data region starts at the first cluster boundary after the map. Clusters
+//! are appended to it, when they are written for the first time, and rewritten in place
+//! afterwards. A new cluster is copied from the base image, before the written sectors are merged
+//! into it, and its map entry is only updated after the data has been written.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io,
+	os::unix::fs::FileExt,
+	path::Path,
+	process,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::linux::virtio_blk::SECTOR_SIZE;
+
+const MAGIC: &[u8; 8] = b"UHYVECOW";
+const VERSION: u32 = 1;
+pub const HEADER_SIZE: u64 = 4096;
+/// Size of the clusters of new overlays
+pub const CLUSTER_SIZE: u64 = 64 * 1024;
+
+fn invalid_overlay(message: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+	(value + alignment - 1) / alignment * alignment
+}
+
+/// A read-only base image with an overlay, which receives all writes.
+#[derive(Debug)]
+pub struct CoWBlockDevice {
+	base: File,
+	overlay: File,
+	/// Size of the disk in bytes
+	len: u64,
+	cluster_size: u64,
+	/// Offsets of the clusters in the overlay, 0 for those of the base image
+	map: Vec<u64>,
+	/// Offset of the next cluster, which is appended to the overlay
+	next_cluster: u64,
+}
+
+impl CoWBlockDevice {
+	/// Layers the overlay at `path` over `base`, whose size is `capacity` sectors.
+	///
+	/// An empty or missing overlay is initialized, an existing one has to belong to an image of
+	/// the same size.
+	pub fn open(base: File, capacity: u64, path: &Path) -> io::Result<Self> {
+		let overlay = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)?;
+		Self::with_overlay(base, capacity, overlay)
+	}
+
+	/// Layers an anonymous overlay in the temporary directory over `base`, which is deleted, when
+	/// uhyve exits.
+	pub fn open_temporary(base: File, capacity: u64) -> io::Result<Self> {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!(
+			"uhyve-overlay-{}-{}",
+			process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		let overlay = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&path)?;
+		fs::remove_file(&path)?;
+		Self::with_overlay(base, capacity, overlay)
+	}
+
+	fn with_overlay(base: File, capacity: u64, overlay: File) -> io::Result<Self> {
+		let len = capacity * SECTOR_SIZE;
+		let mut header = [0u8; 32];
+		let (cluster_size, map) = if overlay.metadata()?.len() == 0 {
+			let cluster_size = CLUSTER_SIZE;
+			let entries = align_up(len, cluster_size) / cluster_size;
+			header[0..8].copy_from_slice(MAGIC);
+			header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+			header[12..16].copy_from_slice(&(cluster_size as u32).to_le_bytes());
+			header[16..24].copy_from_slice(&capacity.to_le_bytes());
+			header[24..32].copy_from_slice(&entries.to_le_bytes());
+			overlay.write_all_at(&header, 0)?;
+			overlay.set_len(HEADER_SIZE + entries * 8)?;
+			(cluster_size, vec![0; entries as usize])
+		} else {
+			overlay.read_exact_at(&mut header, 0)?;
+			let field = |range: std::ops::Range<usize>| {
+				let mut bytes = [0u8; 8];
+				bytes[..range.len()].copy_from_slice(&header[range]);
+				u64::from_le_bytes(bytes)
+			};
+			if &header[0..8] != MAGIC {
+				return Err(invalid_overlay("not an uhyve overlay".to_string()));
+			}
+			if field(8..12) != VERSION.into() {
+				return Err(invalid_overlay(format!(
+					"unsupported overlay version {}",
+					field(8..12)
+				)));
+			}
+			if field(16..24) != capacity {
+				return Err(invalid_overlay(format!(
+					"the overlay belongs to an image of {} sectors instead of {}",
+					field(16..24),
+					capacity
+				)));
+			}
+			let cluster_size = field(12..16);
+			let entries = field(24..32);
+			if cluster_size == 0
+				|| cluster_size % SECTOR_SIZE != 0
+				|| entries != align_up(len, cluster_size) / cluster_size
+			{
+				return Err(invalid_overlay("corrupted overlay header".to_string()));
+			}
+			let mut raw_map = vec![0u8; entries as usize * 8];
+			overlay.read_exact_at(&mut raw_map, HEADER_SIZE)?;
+			let map = raw_map
+				.chunks_exact(8)
+				.map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+				.collect();
+			(cluster_size, map)
+		};
+
+		let data_start = align_up(HEADER_SIZE + map.len() as u64 * 8, cluster_size);
+		let next_cluster = align_up(overlay.metadata()?.len(), cluster_size).max(data_start);
+		Ok(Self {
+			base,
+			overlay,
+			len,
+			cluster_size,
+			map,
+			next_cluster,
+		})
+	}
+
+	/// Splits the range of `len` bytes at `offset` at the cluster boundaries into the index of the
+	/// cluster, the offset in the cluster and the range in the buffer.
+	fn chunks(
+		cluster_size: u64,
+		offset: u64,
+		len: usize,
+	) -> impl Iterator<Item = (usize, u64, (usize, usize))> {
+		let mut done = 0;
+		std::iter::from_fn(move || {
+			if done == len {
+				return None;
+			}
+			let position = offset + done as u64;
+			let in_cluster = position % cluster_size;
+			let chunk = ((cluster_size - in_cluster) as usize).min(len - done);
+			let item = (
+				(position / cluster_size) as usize,
+				in_cluster,
+				(done, done + chunk),
+			);
+			done += chunk;
+			Some(item)
+		})
+	}
+
+	pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+		for (cluster, in_cluster, (start, end)) in
+			Self::chunks(self.cluster_size, offset, buf.len())
+		{
+			match self.map[cluster] {
+				0 => self
+					.base
+					.read_exact_at(&mut buf[start..end], offset + start as u64)?,
+				location => self
+					.overlay
+					.read_exact_at(&mut buf[start..end], location + in_cluster)?,
+			}
+		}
+		Ok(())
+	}
+
+	pub fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+		for (cluster, in_cluster, (start, end)) in
+			Self::chunks(self.cluster_size, offset, buf.len())
+		{
+			let location = match self.map[cluster] {
+				0 => self.copy_up(cluster)?,
+				location => location,
+			};
+			self.overlay
+				.write_all_at(&buf[start..end], location + in_cluster)?;
+		}
+		Ok(())
+	}
+
+	/// Copies a cluster of the base image into the overlay and returns its offset in the overlay.
+	fn copy_up(&mut self, cluster: usize) -> io::Result<u64> {
+		let start = cluster as u64 * self.cluster_size;
+		let mut data = vec![0u8; self.cluster_size as usize];
+		let in_base = (self.len - start).min(self.cluster_size) as usize;
+		self.base.read_exact_at(&mut data[..in_base], start)?;
+
+		let location = self.next_cluster;
+		self.overlay.write_all_at(&data, location)?;
+		self.overlay
+			.write_all_at(&location.to_le_bytes(), HEADER_SIZE + cluster as u64 * 8)?;
+		self.next_cluster += self.cluster_size;
+		self.map[cluster] = location;
+		Ok(location)
+	}
+
+	pub fn sync_data(&self) -> io::Result<()> {
+		self.overlay.sync_data()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_fs::TempDir;
+
+	#[test]
+	fn test_cow_block_device() {
+		let dir = TempDir::new().unwrap();
+		let base_path = dir.path().join("base.img");
+		let overlay_path = dir.path().join("overlay.img");
+		// the last cluster is only partially backed by the image
+		let capacity = 2 * CLUSTER_SIZE / SECTOR_SIZE + 1;
+		fs::write(&base_path, vec![1u8; (capacity * SECTOR_SIZE) as usize]).unwrap();
+
+		let open = || {
+			let base = File::open(&base_path).unwrap();
+			CoWBlockDevice::open(base, capacity, &overlay_path).unwrap()
+		};
+		let mut disk = open();
+		// crosses the boundary of the first two clusters
+		let offset = CLUSTER_SIZE - SECTOR_SIZE;
+		disk.write_all_at(&[2; 2 * SECTOR_SIZE as usize], offset)
+			.unwrap();
+		disk.write_all_at(&[3; SECTOR_SIZE as usize], 2 * CLUSTER_SIZE)
+			.unwrap();
+		drop(disk);
+
+		// the base image is untouched and the overlay is persistent
+		assert!(fs::read(&base_path).unwrap().iter().all(|&byte| byte == 1));
+		let disk = open();
+		let mut buf = vec![0u8; 4 * SECTOR_SIZE as usize];
+		disk.read_exact_at(&mut buf, offset - SECTOR_SIZE).unwrap();
+		let sector = SECTOR_SIZE as usize;
+		assert!(buf[..sector].iter().all(|&byte| byte == 1));
+		assert!(buf[sector..3 * sector].iter().all(|&byte| byte == 2));
+		assert!(buf[3 * sector..].iter().all(|&byte| byte == 1));
+		let mut last = vec![0u8; sector];
+		disk.read_exact_at(&mut last, 2 * CLUSTER_SIZE).unwrap();
+		assert!(last.iter().all(|&byte| byte == 3));
+		drop(disk);
+
+		// an overlay of another image is rejected
+		let base = File::open(&base_path).unwrap();
+		assert!(CoWBlockDevice::open(base, capacity - 1, &overlay_path).is_err());
+	}
+}
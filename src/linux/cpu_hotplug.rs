@@ -0,0 +1,128 @@
+//! Migration of the vCPU threads away from host CPUs, which are taken offline.
+//!
+//! The online CPUs are polled from sysfs, because hotplug events are not delivered through
+//! `inotify` on its pseudo files.
+
+use std::{
+	sync::{
+		mpsc::{self, RecvTimeoutError, Sender},
+		Arc,
+	},
+	thread::{self, JoinHandle},
+	time::Duration,
+};
+
+use core_affinity::CoreId;
+
+use crate::{
+	linux::{numa, online_cpus},
+	vm::Vm,
+	Uhyve,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the NUMA node of a host CPU.
+fn numa_node(cpu: usize) -> Option<usize> {
+	numa::host_node_of_cpu(cpu).ok()
+}
+
+/// Selects the online CPU, which replaces `cpu`, preferring one on the same NUMA node.
+///
+/// Among the candidates, the CPU with the least vCPUs according to `load` is chosen.
+fn replacement_cpu(
+	cpu: usize,
+	online: &[usize],
+	load: &[usize],
+	node: impl Fn(usize) -> Option<usize>,
+) -> Option<usize> {
+	let count = |candidate: usize| load.iter().filter(|&&cpu| cpu == candidate).count();
+	let least_loaded = |candidates: &mut dyn Iterator<Item = usize>| {
+		candidates.min_by_key(|&candidate| (count(candidate), candidate))
+	};
+
+	let same_node = node(cpu).and_then(|node_id| {
+		least_loaded(
+			&mut online
+				.iter()
+				.copied()
+				.filter(|&candidate| node(candidate) == Some(node_id)),
+		)
+	});
+	same_node.or_else(|| least_loaded(&mut online.iter().copied()))
+}
+
+/// Periodically checks for offline host CPUs and migrates their vCPUs to the online ones.
+#[derive(Debug)]
+pub struct CpuHotplugWatcher {
+	stop: Sender<()>,
+	thread: JoinHandle<()>,
+}
+
+impl CpuHotplugWatcher {
+	pub fn start(vm: Arc<Uhyve>) -> Self {
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(POLL_INTERVAL) {
+				let online = match online_cpus() {
+					Ok(online) if !online.is_empty() => online,
+					Ok(_) => continue,
+					Err(err) => {
+						warn!("Unable to determine the online CPUs: {}", err);
+						break;
+					}
+				};
+
+				let mut load = (0..vm.num_cpus())
+					.filter_map(|id| vm.vcpu_core(id))
+					.map(|core_id| core_id.id)
+					.collect::<Vec<_>>();
+				for id in 0..vm.num_cpus() {
+					let cpu = match vm.vcpu_core(id) {
+						Some(core_id) if !online.contains(&core_id.id) => core_id.id,
+						_ => continue,
+					};
+					let new_cpu = match replacement_cpu(cpu, &online, &load, numa_node) {
+						Some(new_cpu) => new_cpu,
+						None => continue,
+					};
+
+					warn!(
+						"CPU {} has been taken offline, migrating vCPU {} to CPU {}",
+						cpu, id, new_cpu
+					);
+					if let Err(err) = vm.migrate_vcpu(id, CoreId { id: new_cpu }) {
+						warn!("Unable to migrate vCPU {}: {}", id, err);
+					}
+					if let Some(slot) = load.iter_mut().find(|slot| **slot == cpu) {
+						*slot = new_cpu;
+					}
+				}
+			}
+		});
+
+		Self { stop, thread }
+	}
+
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		self.thread.join().unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_replacement_cpu() {
+		// CPUs 0-3 are on node 0, CPUs 4-7 on node 1
+		let node = |cpu: usize| Some(cpu / 4);
+
+		assert_eq!(replacement_cpu(1, &[0, 2, 3, 4], &[1, 0], node), Some(2));
+		assert_eq!(replacement_cpu(1, &[0, 4, 5], &[1, 0], node), Some(0));
+		assert_eq!(replacement_cpu(1, &[4, 5], &[1, 4], node), Some(5));
+		assert_eq!(replacement_cpu(1, &[4, 5], &[1], |_| None), Some(4));
+		assert_eq!(replacement_cpu(1, &[], &[1], node), None);
+	}
+}
@@ -0,0 +1,216 @@
+//! A dump of the registers of a vCPU, which `--registers-on-signal` prints on `SIGUSR2`.
+//!
+//! The layout follows QEMU's `info registers`, so that existing notes and scripts can be reused.
+
+use std::fmt;
+
+use kvm_bindings::{
+	kvm_fpu, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs, kvm_vcpu_events, kvm_xsave, Msrs,
+};
+use kvm_ioctls::VcpuFd;
+
+use crate::vm::HypervisorResult;
+
+const MSR_EFER: u32 = 0xc000_0080;
+const MSR_FS_BASE: u32 = 0xc000_0100;
+const MSR_GS_BASE: u32 = 0xc000_0101;
+/// Index of `XSTATE_BV` in `kvm_xsave::region`, i.e., byte 512 of the XSAVE area
+const XSTATE_BV: usize = 128;
+
+/// The registers of a vCPU, see [`UhyveCPU::dump_registers`](crate::linux::vcpu::UhyveCPU::dump_registers).
+#[derive(Clone, Copy)]
+pub struct CpuRegisters {
+	pub regs: kvm_regs,
+	pub sregs: kvm_sregs,
+	pub fpu: kvm_fpu,
+	pub xsave: kvm_xsave,
+	pub efer: u64,
+	pub fs_base: u64,
+	pub gs_base: u64,
+	/// The pending and injected exceptions, interrupts and NMIs
+	pub events: kvm_vcpu_events,
+}
+
+impl fmt::Debug for CpuRegisters {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("CpuRegisters")
+			.field("regs", &self.regs)
+			.field("sregs", &self.sregs)
+			.field("efer", &self.efer)
+			.field("fs_base", &self.fs_base)
+			.field("gs_base", &self.gs_base)
+			.finish_non_exhaustive()
+	}
+}
+
+impl CpuRegisters {
+	/// Reads the registers of `vcpu`, which must not be running.
+	pub fn read(vcpu: &VcpuFd) -> HypervisorResult<Self> {
+		let entries = [MSR_EFER, MSR_FS_BASE, MSR_GS_BASE].map(|index| kvm_msr_entry {
+			index,
+			..Default::default()
+		});
+		let mut msrs = Msrs::from_entries(&entries).unwrap();
+		if vcpu.get_msrs(&mut msrs)? != entries.len() {
+			return Err(kvm_ioctls::Error::new(libc::EIO));
+		}
+		let msr = |i: usize| msrs.as_slice()[i].data;
+
+		Ok(Self {
+			regs: vcpu.get_regs()?,
+			sregs: vcpu.get_sregs()?,
+			fpu: vcpu.get_fpu()?,
+			xsave: vcpu.get_xsave()?,
+			efer: msr(0),
+			fs_base: msr(1),
+			gs_base: msr(2),
+			events: vcpu.get_vcpu_events()?,
+		})
+	}
+
+	/// Returns the state components, which are not in their initial configuration.
+	pub fn xstate_bv(&self) -> u64 {
+		u64::from(self.xsave.region[XSTATE_BV])
+			| (u64::from(self.xsave.region[XSTATE_BV + 1]) << 32)
+	}
+}
+
+fn write_segment(f: &mut fmt::Formatter<'_>, name: &str, seg: &kvm_segment) -> fmt::Result {
+	writeln!(
+		f,
+		"{:<3}={:04x} {:016x} {:08x} type {:x} p {} dpl {} db {} s {} l {} g {} avl {}",
+		name,
+		seg.selector,
+		seg.base,
+		seg.limit,
+		seg.type_,
+		seg.present,
+		seg.dpl,
+		seg.db,
+		seg.s,
+		seg.l,
+		seg.g,
+		seg.avl
+	)
+}
+
+impl fmt::Display for CpuRegisters {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let regs = &self.regs;
+		writeln!(
+			f,
+			"RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+			regs.rax, regs.rbx, regs.rcx, regs.rdx
+		)?;
+		writeln!(
+			f,
+			"RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+			regs.rsi, regs.rdi, regs.rbp, regs.rsp
+		)?;
+		writeln!(
+			f,
+			"R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+			regs.r8, regs.r9, regs.r10, regs.r11
+		)?;
+		writeln!(
+			f,
+			"R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+			regs.r12, regs.r13, regs.r14, regs.r15
+		)?;
+		writeln!(f, "RIP={:016x} RFL={:08x}", regs.rip, regs.rflags)?;
+
+		let sregs = &self.sregs;
+		for (name, seg) in [
+			("ES", &sregs.es),
+			("CS", &sregs.cs),
+			("SS", &sregs.ss),
+			("DS", &sregs.ds),
+			("FS", &sregs.fs),
+			("GS", &sregs.gs),
+			("LDT", &sregs.ldt),
+			("TR", &sregs.tr),
+		] {
+			write_segment(f, name, seg)?;
+		}
+		writeln!(
+			f,
+			"GDT=     {:016x} {:08x}",
+			sregs.gdt.base, sregs.gdt.limit
+		)?;
+		writeln!(
+			f,
+			"IDT=     {:016x} {:08x}",
+			sregs.idt.base, sregs.idt.limit
+		)?;
+		writeln!(
+			f,
+			"CR0={:08x} CR2={:016x} CR3={:016x} CR4={:08x}",
+			sregs.cr0, sregs.cr2, sregs.cr3, sregs.cr4
+		)?;
+		writeln!(
+			f,
+			"EFER={:016x} FS_BASE={:016x} GS_BASE={:016x} APIC_BASE={:016x}",
+			self.efer, self.fs_base, self.gs_base, sregs.apic_base
+		)?;
+
+		writeln!(
+			f,
+			"FCW={:04x} FSW={:04x} MXCSR={:08x} XSTATE_BV={:016x}",
+			self.fpu.fcw,
+			self.fpu.fsw,
+			self.fpu.mxcsr,
+			self.xstate_bv()
+		)?;
+		for (i, xmm) in self.fpu.xmm.iter().enumerate() {
+			let separator = if i % 2 == 1 { "\n" } else { " " };
+			write!(
+				f,
+				"XMM{:02}={:032x}{}",
+				i,
+				u128::from_le_bytes(*xmm),
+				separator
+			)?;
+		}
+
+		let events = &self.events;
+		writeln!(
+			f,
+			"exception: injected {} pending {} nr {} error code {:x}",
+			events.exception.injected,
+			events.exception.pending,
+			events.exception.nr,
+			events.exception.error_code
+		)?;
+		writeln!(
+			f,
+			"interrupt: injected {} nr {} shadow {}",
+			events.interrupt.injected, events.interrupt.nr, events.interrupt.shadow
+		)?;
+		writeln!(
+			f,
+			"NMI: injected {} pending {} masked {}",
+			events.nmi.injected, events.nmi.pending, events.nmi.masked
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::linux::KVM;
+
+	#[test]
+	fn test_dump_reset_state() {
+		let vm = KVM.create_vm().unwrap();
+		let vcpu = vm.create_vcpu(0).unwrap();
+		let registers = CpuRegisters::read(&vcpu).unwrap();
+		assert_eq!(registers.efer, 0);
+
+		// a vCPU starts at the reset vector
+		let dump = registers.to_string();
+		assert!(dump.contains("RIP=000000000000fff0"));
+		assert!(dump.contains("CS =f000 00000000ffff0000 0000ffff"));
+		assert!(dump.contains("XMM15="));
+		assert_eq!(dump.lines().count(), 29);
+	}
+}
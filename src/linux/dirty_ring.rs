@@ -0,0 +1,176 @@
+//! Dirty page tracking with the dirty ring of KVM (`KVM_CAP_DIRTY_LOG_RING`, Linux 5.11+).
+//!
+//! Instead of maintaining a bitmap per memory slot, KVM appends the written guest
+//! frames to a ring buffer per vCPU, so that collecting the dirty pages only
+//! costs time proportional to the number of dirty pages.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+
+use kvm_bindings::kvm_enable_cap;
+use kvm_ioctls::{VcpuFd, VmFd};
+
+use crate::consts::PAGE_SIZE;
+use crate::vm::HypervisorResult;
+
+/// Not yet exported by `kvm-bindings`.
+const KVM_CAP_DIRTY_LOG_RING: u32 = 192;
+/// Offset of the dirty ring in the mapping of the vCPU fd in pages.
+const KVM_DIRTY_LOG_PAGE_OFFSET: i64 = 64;
+/// `_IO(KVMIO, 0xc7)`
+const KVM_RESET_DIRTY_RINGS: libc::c_ulong = 0xaec7;
+/// Exit reason, when the dirty ring of a vCPU is full.
+pub const KVM_EXIT_DIRTY_RING_FULL: u32 = 31;
+
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1 << 0;
+const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
+/// Number of entries of the dirty ring of each vCPU.
+pub const KVM_DIRTY_LOG_RING_SIZE: u32 = 4096;
+
+#[repr(C)]
+struct KvmDirtyGfn {
+	flags: u32,
+	slot: u32,
+	offset: u64,
+}
+
+/// The dirty ring of a single vCPU, which is shared with KVM.
+struct DirtyRing {
+	entries: *mut KvmDirtyGfn,
+	/// Index of the next entry to be harvested
+	next: u32,
+}
+
+unsafe impl Send for DirtyRing {}
+
+impl DirtyRing {
+	fn mmap_size() -> usize {
+		KVM_DIRTY_LOG_RING_SIZE as usize * std::mem::size_of::<KvmDirtyGfn>()
+	}
+}
+
+impl Drop for DirtyRing {
+	fn drop(&mut self) {
+		unsafe {
+			libc::munmap(self.entries as *mut libc::c_void, Self::mmap_size());
+		}
+	}
+}
+
+#[derive(Default)]
+struct DirtyTrackerInner {
+	rings: Vec<DirtyRing>,
+	dirty: BTreeSet<u64>,
+}
+
+/// Collects the guest physical addresses of the pages written by the guest.
+pub struct DirtyTracker {
+	vm_fd: RawFd,
+	/// Guest physical address of each memory slot
+	slot_bases: Vec<u64>,
+	inner: Mutex<DirtyTrackerInner>,
+}
+
+impl std::fmt::Debug for DirtyTracker {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("DirtyTracker")
+			.field("slot_bases", &self.slot_bases)
+			.finish()
+	}
+}
+
+impl DirtyTracker {
+	/// Enables the dirty ring for the VM.
+	///
+	/// This has to happen before the first vCPU is created. The memory slots
+	/// have to be registered with `KVM_MEM_LOG_DIRTY_PAGES`.
+	pub fn enable(vm: &VmFd, slot_bases: Vec<u64>) -> HypervisorResult<Self> {
+		let mut cap = kvm_enable_cap {
+			cap: KVM_CAP_DIRTY_LOG_RING,
+			..Default::default()
+		};
+		cap.args[0] = DirtyRing::mmap_size() as u64;
+		vm.enable_cap(&cap)?;
+
+		Ok(Self {
+			vm_fd: vm.as_raw_fd(),
+			slot_bases,
+			inner: Mutex::new(DirtyTrackerInner::default()),
+		})
+	}
+
+	/// Maps the dirty ring of a newly created vCPU.
+	pub fn register_vcpu(&self, vcpu: &VcpuFd) -> io::Result<()> {
+		let entries = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				DirtyRing::mmap_size(),
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED,
+				vcpu.as_raw_fd(),
+				KVM_DIRTY_LOG_PAGE_OFFSET * PAGE_SIZE as i64,
+			)
+		};
+		if entries == libc::MAP_FAILED {
+			return Err(io::Error::last_os_error());
+		}
+
+		self.inner.lock().unwrap().rings.push(DirtyRing {
+			entries: entries as *mut KvmDirtyGfn,
+			next: 0,
+		});
+		Ok(())
+	}
+
+	/// Moves the entries of all dirty rings into the set of dirty pages and
+	/// hands the entries back to KVM.
+	///
+	/// This has to be called, when a vCPU exits with [`KVM_EXIT_DIRTY_RING_FULL`].
+	pub fn harvest(&self) -> io::Result<()> {
+		let mut inner = self.inner.lock().unwrap();
+		let DirtyTrackerInner { rings, dirty } = &mut *inner;
+
+		for ring in rings.iter_mut() {
+			loop {
+				let entry = unsafe {
+					ring.entries
+						.add((ring.next % KVM_DIRTY_LOG_RING_SIZE) as usize)
+				};
+				let flags = unsafe { ptr::read_volatile(ptr::addr_of!((*entry).flags)) };
+				if flags & KVM_DIRTY_GFN_F_DIRTY == 0 {
+					break;
+				}
+
+				let (slot, offset) = unsafe { ((*entry).slot, (*entry).offset) };
+				// the upper 16 bits of the slot denote the address space
+				match self.slot_bases.get((slot & 0xffff) as usize) {
+					Some(base) => {
+						dirty.insert(base + offset * PAGE_SIZE as u64);
+					}
+					None => warn!("Dirty page in unknown memory slot {}", slot),
+				}
+
+				unsafe {
+					ptr::write_volatile(ptr::addr_of_mut!((*entry).flags), KVM_DIRTY_GFN_F_RESET)
+				};
+				ring.next = ring.next.wrapping_add(1);
+			}
+		}
+
+		if unsafe { libc::ioctl(self.vm_fd, KVM_RESET_DIRTY_RINGS) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(())
+	}
+
+	/// Returns the guest physical addresses of the pages written since the last call.
+	pub fn collect_dirty_pages(&self) -> io::Result<Vec<u64>> {
+		self.harvest()?;
+		let dirty = std::mem::take(&mut self.inner.lock().unwrap().dirty);
+		Ok(dirty.into_iter().collect())
+	}
+}
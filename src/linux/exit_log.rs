@@ -0,0 +1,95 @@
+//! Logging of every exit of the vCPUs with their registers, see `--verbose-exits`.
+//!
+//! The exits are logged at the debug level, so the registers are only read, if this level is
+//! enabled and the exit passes `--exit-filter`.
+
+use std::collections::HashSet;
+
+use kvm_bindings::KVM_EXIT_IO_IN;
+use kvm_ioctls::VcpuFd;
+use log::Level;
+
+use crate::exit_reasons::exit_reason_name;
+use crate::linux::vcpu::KvmRunAccess;
+
+/// Logs the exits of the reasons in the filter or all exits, if the filter is empty.
+#[derive(Debug, Default)]
+pub struct ExitLog {
+	filter: HashSet<u32>,
+}
+
+impl ExitLog {
+	pub fn new(filter: &[u32]) -> Self {
+		Self {
+			filter: filter.iter().copied().collect(),
+		}
+	}
+
+	fn logs(&self, reason: u32) -> bool {
+		self.filter.is_empty() || self.filter.contains(&reason)
+	}
+
+	/// Logs the exit of the vCPU `id`, before it is handled.
+	pub fn log(&self, id: u32, vcpu: &VcpuFd, run: &mut KvmRunAccess<'_>) {
+		if !log_enabled!(Level::Debug) {
+			return;
+		}
+		let reason = run.exit_reason();
+		if !self.logs(reason) {
+			return;
+		}
+
+		let name = exit_reason_name(reason).unwrap_or("KVM_EXIT_UNKNOWN");
+		let regs = match vcpu.get_regs() {
+			Ok(regs) => regs,
+			Err(err) => {
+				debug!("CPU {}: {} (registers unavailable: {})", id, name, err);
+				return;
+			}
+		};
+		let details = if let Some(io) = run.as_io().copied() {
+			let data = run.io_data_mut().map_or(&[][..], |data| &*data);
+			if u32::from(io.direction) == KVM_EXIT_IO_IN {
+				format!(", in port 0x{:x} size {}", io.port, data.len())
+			} else {
+				format!(
+					", out port 0x{:x} size {} data {:02x?}",
+					io.port,
+					data.len(),
+					data
+				)
+			}
+		} else if let Some(mmio) = run.as_mmio() {
+			let data = &mmio.data[..(mmio.len as usize).min(mmio.data.len())];
+			if mmio.is_write != 0 {
+				format!(
+					", write 0x{:x} size {} data {:02x?}",
+					mmio.phys_addr,
+					data.len(),
+					data
+				)
+			} else {
+				format!(", read 0x{:x} size {}", mmio.phys_addr, data.len())
+			}
+		} else {
+			String::new()
+		};
+		debug!(
+			"CPU {}: {} at RIP 0x{:x}, RSP 0x{:x}, RFLAGS 0x{:x}{}",
+			id, name, regs.rip, regs.rsp, regs.rflags, details
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_exit_log_filter() {
+		assert!(ExitLog::new(&[]).logs(5));
+		let exit_log = ExitLog::new(&[2, 6]);
+		assert!(exit_log.logs(2));
+		assert!(!exit_log.logs(5));
+	}
+}
@@ -0,0 +1,105 @@
+//! The x86-64 architecture of the GDB stub, whose registers contain the upper halves of the YMM
+//! registers, if the vCPUs support AVX.
+
+use gdbstub::arch::{Arch, Registers, SingleStepGdbBehavior};
+use gdbstub_arch::x86::reg::{id::X86_64CoreRegId, X86_64CoreRegs};
+
+/// Like `gdbstub_arch::x86::X86_64_SSE`, but with the upper halves of YMM0–YMM15, see
+/// [`super::target_xml`].
+pub enum X86_64Avx {}
+
+impl Arch for X86_64Avx {
+	type Usize = u64;
+	type Registers = X86_64Regs;
+	type RegId = X86_64CoreRegId;
+	type BreakpointKind = usize;
+
+	/// The description is served by `TargetDescriptionXmlOverride`, as it depends on the vCPUs.
+	fn target_description_xml() -> Option<&'static str> {
+		None
+	}
+
+	#[inline(always)]
+	fn single_step_gdb_behavior() -> SingleStepGdbBehavior {
+		SingleStepGdbBehavior::Optional
+	}
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct X86_64Regs {
+	pub core: X86_64CoreRegs,
+	/// The upper halves of YMM0–YMM15, which are only sent to GDB with AVX
+	pub ymmh: Option<[u128; 16]>,
+}
+
+/// Returns the number of bytes of the core and SSE registers in a `g` packet.
+fn core_len() -> usize {
+	let mut len = 0;
+	X86_64CoreRegs::default().gdb_serialize(|_| len += 1);
+	len
+}
+
+impl Registers for X86_64Regs {
+	type ProgramCounter = u64;
+
+	fn pc(&self) -> Self::ProgramCounter {
+		self.core.pc()
+	}
+
+	fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+		self.core.gdb_serialize(&mut write_byte);
+		for ymmh in self.ymmh.iter().flatten() {
+			ymmh.to_le_bytes()
+				.into_iter()
+				.for_each(|byte| write_byte(Some(byte)));
+		}
+	}
+
+	fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+		let (core, ymmh) = bytes.split_at(core_len().min(bytes.len()));
+		self.core.gdb_deserialize(core)?;
+		self.ymmh = match ymmh.len() {
+			0 => None,
+			len if len == 16 * 16 => {
+				let mut regs = [0; 16];
+				for (reg, bytes) in regs.iter_mut().zip(ymmh.chunks_exact(16)) {
+					*reg = u128::from_le_bytes(bytes.try_into().unwrap());
+				}
+				Some(regs)
+			}
+			_ => return Err(()),
+		};
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn serialize(regs: &X86_64Regs) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		regs.gdb_serialize(|byte| bytes.push(byte.unwrap_or(0)));
+		bytes
+	}
+
+	#[test]
+	fn test_registers_roundtrip() {
+		let mut regs = X86_64Regs::default();
+		regs.core.rip = 0x40_1000;
+		assert_eq!(serialize(&regs).len(), core_len());
+
+		let mut ymmh = [0; 16];
+		ymmh[15] = 0x0123_4567_89ab_cdef_0011_2233_4455_6677;
+		regs.ymmh = Some(ymmh);
+		let bytes = serialize(&regs);
+		assert_eq!(bytes.len(), core_len() + 16 * 16);
+
+		let mut deserialized = X86_64Regs::default();
+		deserialized.gdb_deserialize(&bytes).unwrap();
+		assert_eq!(deserialized, regs);
+		assert!(deserialized
+			.gdb_deserialize(&bytes[..bytes.len() - 1])
+			.is_err());
+	}
+}
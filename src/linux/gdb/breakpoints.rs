@@ -3,6 +3,7 @@ use std::collections::{hash_map::Entry, HashMap};
 use gdbstub::target::{self, ext::breakpoints::WatchKind, TargetResult};
 
 use crate::arch::x86_64::registers;
+use crate::vm::Vm;
 
 use super::GdbUhyve;
 
@@ -22,6 +23,54 @@ impl SwBreakpoint {
 
 pub type SwBreakpoints = HashMap<SwBreakpoint, Vec<u8>>;
 
+/// The single-use breakpoint at the entry point of the kernel, see `--debug-on-startup`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupBreakpoint {
+	/// Is inserted, when GDB resumes the boot processor the first time
+	Pending,
+	/// Has replaced the first byte of the entry point
+	Inserted(u8),
+	Done,
+}
+
+impl GdbUhyve {
+	/// Inserts the startup breakpoint before the boot processor enters the guest the first time.
+	pub(super) fn insert_startup_breakpoint(&mut self) {
+		if self.startup_breakpoint != StartupBreakpoint::Pending {
+			return;
+		}
+		let entry_point = self.vm.get_entry_point();
+		if self
+			.sw_breakpoints
+			.keys()
+			.any(|sw_breakpoint| sw_breakpoint.addr == entry_point)
+		{
+			// GDB stops there anyway
+			self.startup_breakpoint = StartupBreakpoint::Done;
+			return;
+		}
+
+		let mut cpu = self.vcpus[0].cpu();
+		let instruction = unsafe { cpu.memory(entry_point, 1) };
+		self.startup_breakpoint = StartupBreakpoint::Inserted(instruction[0]);
+		instruction[0] = SwBreakpoint::OPCODE;
+	}
+
+	/// Restores the entry point, if a vCPU has stopped at the startup breakpoint at `pc`.
+	pub(super) fn remove_startup_breakpoint(&mut self, pc: u64) {
+		let entry_point = self.vm.get_entry_point();
+		if let StartupBreakpoint::Inserted(byte) = self.startup_breakpoint {
+			if pc == entry_point {
+				let mut cpu = self.vcpus[0].cpu();
+				unsafe { cpu.memory(entry_point, 1) }
+				[0] = byte;
+				self.startup_breakpoint = StartupBreakpoint::Done;
+				info!("Stopped at the entry point {:#x}", entry_point);
+			}
+		}
+	}
+}
+
 impl target::ext::breakpoints::Breakpoints for GdbUhyve {
 	#[inline(always)]
 	fn support_sw_breakpoint(
@@ -50,7 +99,9 @@ impl target::ext::breakpoints::SwBreakpoint for GdbUhyve {
 		let sw_breakpoint = SwBreakpoint::new(addr, kind);
 
 		if let Entry::Vacant(entry) = self.sw_breakpoints.entry(sw_breakpoint) {
-			let instructions = unsafe { self.vcpu.memory(addr, kind) };
+			// the vCPUs share the page tables of the kernel
+			let mut cpu = self.vcpus[0].cpu();
+			let instructions = unsafe { cpu.memory(addr, kind) };
 			entry.insert(instructions.into());
 			instructions.fill(SwBreakpoint::OPCODE);
 			Ok(true)
@@ -63,7 +114,8 @@ impl target::ext::breakpoints::SwBreakpoint for GdbUhyve {
 		let sw_breakpoint = SwBreakpoint::new(addr, kind);
 
 		if let Entry::Occupied(entry) = self.sw_breakpoints.entry(sw_breakpoint) {
-			let instructions = unsafe { self.vcpu.memory(addr, kind) };
+			let mut cpu = self.vcpus[0].cpu();
+			let instructions = unsafe { cpu.memory(addr, kind) };
 			instructions.copy_from_slice(&entry.remove());
 			Ok(true)
 		} else {
@@ -72,6 +124,19 @@ impl target::ext::breakpoints::SwBreakpoint for GdbUhyve {
 	}
 }
 
+impl GdbUhyve {
+	/// Occupies a free debug register. GDB reports a failure to the user, if all four are in use.
+	fn insert_hw_breakpoint(&mut self, hw_breakpoint: registers::debug::HwBreakpoint) -> bool {
+		match self.hw_breakpoints.try_insert(hw_breakpoint) {
+			Ok(()) => true,
+			Err(_) => {
+				warn!("All four debug registers are in use");
+				false
+			}
+		}
+	}
+}
+
 impl target::ext::breakpoints::HwBreakpoint for GdbUhyve {
 	fn add_hw_breakpoint(&mut self, addr: u64, kind: usize) -> TargetResult<bool, Self> {
 		let hw_breakpoint = match registers::debug::HwBreakpoint::new_breakpoint(addr, kind) {
@@ -79,8 +144,7 @@ impl target::ext::breakpoints::HwBreakpoint for GdbUhyve {
 			Err(_) => return Ok(false),
 		};
 
-		let success = self.hw_breakpoints.try_insert(hw_breakpoint).is_ok();
-		Ok(success)
+		Ok(self.insert_hw_breakpoint(hw_breakpoint))
 	}
 
 	fn remove_hw_breakpoint(&mut self, addr: u64, kind: usize) -> TargetResult<bool, Self> {
@@ -106,8 +170,7 @@ impl target::ext::breakpoints::HwWatchpoint for GdbUhyve {
 			None => return Ok(false),
 		};
 
-		let success = self.hw_breakpoints.try_insert(hw_breakpoint).is_ok();
-		Ok(success)
+		Ok(self.insert_hw_breakpoint(hw_breakpoint))
 	}
 
 	fn remove_hw_watchpoint(
@@ -0,0 +1,105 @@
+//! The connection to GDB, which is accepted on a TCP port (`--gdb_port`) or a Unix socket
+//! (`--gdb-socket`).
+
+use std::{
+	io::{self, Read},
+	net::{TcpListener, TcpStream},
+	os::unix::net::{UnixListener, UnixStream},
+	path::Path,
+};
+
+use gdbstub::{Connection, ConnectionExt};
+
+#[derive(Debug)]
+pub enum GdbConnection {
+	Tcp(TcpStream),
+	Unix(UnixStream),
+}
+
+impl GdbConnection {
+	/// Blocks until GDB connects to the TCP port, i.e., runs `target remote localhost:<port>`.
+	pub fn accept_tcp(port: u16) -> io::Result<Self> {
+		let sockaddr = format!("localhost:{}", port);
+		eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
+		let sock = TcpListener::bind(sockaddr)?;
+		let (stream, addr) = sock.accept()?;
+		eprintln!("Debugger connected from {}", addr);
+		Ok(Self::Tcp(stream))
+	}
+
+	/// Blocks until GDB connects to the Unix socket, i.e., runs `target remote <path>`.
+	///
+	/// The socket file is left behind and removed, when the VM is dropped.
+	pub fn accept_unix(path: &Path) -> io::Result<Self> {
+		eprintln!("Waiting for a GDB connection on {}...", path.display());
+		let sock = UnixListener::bind(path)?;
+		let (stream, _) = sock.accept()?;
+		eprintln!("Debugger connected to {}", path.display());
+		Ok(Self::Unix(stream))
+	}
+
+	pub fn try_clone(&self) -> io::Result<Self> {
+		match self {
+			Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+			Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+		}
+	}
+}
+
+impl Read for GdbConnection {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Self::Tcp(stream) => Read::read(stream, buf),
+			Self::Unix(stream) => Read::read(stream, buf),
+		}
+	}
+}
+
+impl Connection for GdbConnection {
+	type Error = io::Error;
+
+	fn write(&mut self, byte: u8) -> io::Result<()> {
+		match self {
+			Self::Tcp(stream) => Connection::write(stream, byte),
+			Self::Unix(stream) => Connection::write(stream, byte),
+		}
+	}
+
+	fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+		match self {
+			Self::Tcp(stream) => Connection::write_all(stream, buf),
+			Self::Unix(stream) => Connection::write_all(stream, buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Self::Tcp(stream) => Connection::flush(stream),
+			Self::Unix(stream) => Connection::flush(stream),
+		}
+	}
+
+	fn on_session_start(&mut self) -> io::Result<()> {
+		match self {
+			Self::Tcp(stream) => Connection::on_session_start(stream),
+			Self::Unix(stream) => Connection::on_session_start(stream),
+		}
+	}
+}
+
+impl ConnectionExt for GdbConnection {
+	fn read(&mut self) -> io::Result<u8> {
+		match self {
+			Self::Tcp(stream) => ConnectionExt::read(stream),
+			Self::Unix(stream) => ConnectionExt::read(stream),
+		}
+	}
+
+	fn peek(&mut self) -> io::Result<Option<u8>> {
+		match self {
+			// `TcpStream` also has an inherent `peek`
+			Self::Tcp(stream) => ConnectionExt::peek(stream),
+			Self::Unix(stream) => ConnectionExt::peek(stream),
+		}
+	}
+}
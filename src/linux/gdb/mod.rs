@@ -1,58 +1,291 @@
+mod arch;
 mod breakpoints;
+mod connection;
 mod regs;
 mod section_offsets;
+mod target_xml;
 
+use core_affinity::CoreId;
 use gdbstub::{
-	common::Signal,
+	common::{Signal, Tid},
 	gdbstub_run_blocking,
 	target::{
 		self,
-		ext::base::{
-			multithread::ThreadStopReason,
-			singlethread::{SingleThreadOps, StopReason},
-		},
+		ext::base::multithread::{MultiThreadOps, ThreadStopReason},
 		Target, TargetError, TargetResult,
 	},
 	Connection, ConnectionExt,
 };
-use gdbstub_arch::x86::reg::X86_64CoreRegs;
 use kvm_bindings::{
 	kvm_guest_debug, kvm_guest_debug_arch, BP_VECTOR, DB_VECTOR, KVM_GUESTDBG_ENABLE,
-	KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+	KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP, KVM_MAX_CPUID_ENTRIES,
 };
 use libc::EINVAL;
-use nix::sys::pthread::pthread_self;
-use std::{io::Read, net::TcpStream, sync::Once, thread, time::Duration};
+use nix::sys::pthread::Pthread;
+use std::{
+	io::Read,
+	os::unix::thread::JoinHandleExt,
+	slice,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc::{self, Receiver, Sender},
+		Arc, Mutex, MutexGuard, Once,
+	},
+	thread,
+	time::Duration,
+};
 use x86_64::registers::debug::Dr6Flags;
 
-use crate::linux::{vcpu::UhyveCPU, KickSignal};
-use crate::vm::{VcpuStopReason, VirtualCPU};
+use crate::consts::PAGE_SIZE;
+use crate::linux::{immediate_exit::ExitRequest, vcpu::UhyveCPU, KickSignal};
+use crate::vm::{HypervisorResult, VcpuStopReason, VirtualCPU, Vm};
 use crate::{arch::x86_64::registers::debug::HwBreakpoints, Uhyve};
 
-use self::breakpoints::SwBreakpoints;
+use self::arch::{X86_64Avx, X86_64Regs};
+use self::breakpoints::{StartupBreakpoint, SwBreakpoints};
+pub use self::connection::GdbConnection;
 
 use super::HypervisorError;
 
+/// Time, for which an interrupt by the debugger waits for the vCPU to leave the guest.
+const EXIT_REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+/// Interval, in which a vCPU checks, whether the boot processor has started it
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// CPUID.01H:ECX.AVX
+const CPUID_AVX: u32 = 1 << 28;
+
+/// GDB numbers the threads from 1, so the vCPU `n` is the thread `n + 1`.
+fn tid(id: usize) -> Tid {
+	Tid::new(id + 1).unwrap()
+}
+
+/// Notifications of the vCPU threads and of the thread, which watches the connection to GDB
+enum GdbEvent {
+	/// GDB sent data, e.g., an interrupt
+	IncomingData,
+	/// A vCPU left the guest
+	Stopped(usize, HypervisorResult<VcpuStopReason>),
+}
+
+/// What a vCPU does, when GDB resumes the target the next time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResumeAction {
+	Stop,
+	Continue,
+	Step,
+}
+
+/// A vCPU, which runs in its own thread, whenever GDB resumes it.
+struct GdbVcpuState {
+	cpu: Arc<Mutex<UhyveCPU>>,
+	/// Lets the vCPU thread enter the guest once
+	resume: Sender<()>,
+	exit_request: Option<Arc<ExitRequest>>,
+	pthread: Pthread,
+	/// Stops a vCPU, which still waits for the boot processor to start it
+	kicked: Arc<AtomicBool>,
+	action: ResumeAction,
+	running: bool,
+	/// A stop, which has not been reported to GDB, because another vCPU stopped at the same time
+	pending: Option<ThreadStopReason<u64>>,
+}
+
+impl GdbVcpuState {
+	/// Locks the vCPU, which is only contended while the vCPU is running.
+	fn cpu(&self) -> MutexGuard<'_, UhyveCPU> {
+		self.cpu.lock().unwrap()
+	}
+
+	fn apply_guest_debug(&self, debugreg: [u64; 8], step: bool) -> Result<(), kvm_ioctls::Error> {
+		let mut control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP | KVM_GUESTDBG_USE_HW_BP;
+		if step {
+			control |= KVM_GUESTDBG_SINGLESTEP;
+		}
+		let debug_struct = kvm_guest_debug {
+			control,
+			pad: 0,
+			arch: kvm_guest_debug_arch { debugreg },
+		};
+		self.cpu().get_vcpu().set_guest_debug(&debug_struct)
+	}
+
+	fn resume(&mut self) {
+		self.kicked.store(false, Ordering::SeqCst);
+		self.running = true;
+		// the thread only stops after GDB has disconnected
+		let _ = self.resume.send(());
+	}
+
+	/// Kicks the vCPU out of the guest.
+	fn kick(&self) {
+		self.kicked.store(true, Ordering::SeqCst);
+		match &self.exit_request {
+			Some(exit_request) => {
+				if !exit_request.request_exit(EXIT_REQUEST_TIMEOUT) {
+					debug!("vCPU did not confirm the exit request");
+				}
+			}
+			None => {
+				if let Err(err) = KickSignal::pthread_kill(self.pthread) {
+					warn!("Unable to kick a vCPU: {}", err);
+				}
+			}
+		}
+	}
+}
+
+/// Runs a vCPU each time GDB resumes it and reports, why it stopped.
+fn run_vcpu_thread(
+	vm: &Uhyve,
+	id: usize,
+	cpu: &Mutex<UhyveCPU>,
+	kicked: &AtomicBool,
+	resumes: Receiver<()>,
+	events: Sender<GdbEvent>,
+) {
+	let mut started = id == 0;
+	for () in resumes {
+		// the boot processor starts the other ones one after another
+		while !started && !kicked.load(Ordering::SeqCst) {
+			started = id <= vm.cpu_online() as usize;
+			if !started {
+				thread::sleep(STARTUP_POLL_INTERVAL);
+			}
+		}
+		let result = if started {
+			cpu.lock().unwrap().r#continue()
+		} else {
+			Ok(VcpuStopReason::Kick)
+		};
+		if events.send(GdbEvent::Stopped(id, result)).is_err() {
+			break;
+		}
+	}
+}
+
 pub struct GdbUhyve {
-	vm: Uhyve,
-	vcpu: UhyveCPU,
+	vm: Arc<Uhyve>,
+	vcpus: Vec<GdbVcpuState>,
+	events: Receiver<GdbEvent>,
+	/// Handed to the thread, which watches the connection
+	events_sender: Sender<GdbEvent>,
 	hw_breakpoints: HwBreakpoints,
 	sw_breakpoints: SwBreakpoints,
+	startup_breakpoint: StartupBreakpoint,
+	/// Whether the registers include the upper halves of the YMM registers
+	avx: bool,
 }
 
 impl GdbUhyve {
-	pub fn new(vm: Uhyve, vcpu: UhyveCPU) -> Self {
+	/// Spawns a thread for each vCPU, which waits for GDB to resume it.
+	pub fn new(vm: Arc<Uhyve>, cpu_affinity: Option<&[CoreId]>) -> Self {
+		let (events_sender, events) = mpsc::channel();
+		let vcpus = (0..vm.num_cpus())
+			.map(|cpu_id| {
+				let core_id =
+					cpu_affinity.and_then(|core_ids| core_ids.get(cpu_id as usize).copied());
+				let (cpu_sender, cpu_receiver) = mpsc::channel();
+				let (resume, resumes) = mpsc::channel();
+				let kicked = Arc::new(AtomicBool::new(false));
+				let thread_vm = vm.clone();
+				let thread_kicked = kicked.clone();
+				let thread_events = events_sender.clone();
+				let thread = thread::spawn(move || {
+					let cpu = thread_vm.setup_vcpu(cpu_id, core_id);
+					cpu.warm_up_cache();
+					let cpu = Arc::new(Mutex::new(cpu));
+					cpu_sender.send(cpu.clone()).unwrap();
+					run_vcpu_thread(
+						&thread_vm,
+						cpu_id as usize,
+						&cpu,
+						&thread_kicked,
+						resumes,
+						thread_events,
+					);
+				});
+				let cpu: Arc<Mutex<UhyveCPU>> = cpu_receiver.recv().unwrap();
+				let exit_request = cpu.lock().unwrap().exit_request();
+				GdbVcpuState {
+					cpu,
+					resume,
+					exit_request,
+					pthread: thread.as_pthread_t(),
+					kicked,
+					action: ResumeAction::Stop,
+					running: false,
+					pending: None,
+				}
+			})
+			.collect::<Vec<_>>();
+		let avx = vcpus[0]
+			.cpu()
+			.get_vcpu()
+			.get_cpuid2(KVM_MAX_CPUID_ENTRIES)
+			.map_or(false, |cpuid| {
+				cpuid
+					.as_slice()
+					.iter()
+					.any(|entry| entry.function == 1 && entry.ecx & CPUID_AVX != 0)
+			});
+
+		let startup_breakpoint = if vm.debug_on_startup {
+			StartupBreakpoint::Pending
+		} else {
+			StartupBreakpoint::Done
+		};
+
 		Self {
 			vm,
-			vcpu,
+			vcpus,
+			events,
+			events_sender,
 			hw_breakpoints: HwBreakpoints::new(),
 			sw_breakpoints: SwBreakpoints::new(),
+			startup_breakpoint,
+			avx,
 		}
 	}
+
+	fn vcpu(&self, tid: Tid) -> TargetResult<&GdbVcpuState, Self> {
+		self.vcpus.get(tid.get() - 1).ok_or(TargetError::NonFatal)
+	}
+
+	fn vcpu_mut(&mut self, tid: Tid) -> Result<&mut GdbVcpuState, HypervisorError> {
+		self.vcpus
+			.get_mut(tid.get() - 1)
+			.ok_or_else(|| kvm_ioctls::Error::new(EINVAL))
+	}
+
+	/// Calls `f` with the offset and the host memory of each page of a guest virtual address range.
+	///
+	/// The pages are translated by the vCPU of `tid`, so that they do not need to be contiguous in
+	/// guest physical memory.
+	fn for_each_guest_page(
+		&self,
+		tid: Tid,
+		start_addr: u64,
+		len: usize,
+		mut f: impl FnMut(usize, &mut [u8]),
+	) -> TargetResult<(), Self> {
+		let cpu = self.vcpu(tid)?.cpu();
+		let mut offset = 0;
+		while offset < len {
+			let addr = start_addr + offset as u64;
+			let chunk_len = (PAGE_SIZE - addr as usize % PAGE_SIZE).min(len - offset);
+			let phys = cpu.translate_gva(addr).map_err(|_| TargetError::NonFatal)?;
+			let host = cpu.host_address(phys as usize) as *mut u8;
+			f(offset, unsafe {
+				slice::from_raw_parts_mut(host, chunk_len)
+			});
+			offset += chunk_len;
+		}
+		Ok(())
+	}
 }
 
 impl Target for GdbUhyve {
-	type Arch = gdbstub_arch::x86::X86_64_SSE;
+	type Arch = X86_64Avx;
 	type Error = HypervisorError;
 
 	// --------------- IMPORTANT NOTE ---------------
@@ -62,7 +295,7 @@ impl Target for GdbUhyve {
 
 	#[inline(always)]
 	fn base_ops(&mut self) -> target::ext::base::BaseOps<'_, Self::Arch, Self::Error> {
-		target::ext::base::BaseOps::SingleThread(self)
+		target::ext::base::BaseOps::MultiThread(self)
 	}
 
 	#[inline(always)]
@@ -72,6 +305,15 @@ impl Target for GdbUhyve {
 		Some(self)
 	}
 
+	#[inline(always)]
+	fn support_target_description_xml_override(
+		&mut self,
+	) -> Option<
+		target::ext::target_description_xml_override::TargetDescriptionXmlOverrideOps<'_, Self>,
+	> {
+		Some(self)
+	}
+
 	#[inline(always)]
 	fn support_section_offsets(
 		&mut self,
@@ -81,88 +323,180 @@ impl Target for GdbUhyve {
 }
 
 impl GdbUhyve {
-	fn apply_guest_debug(&mut self, step: bool) -> Result<(), kvm_ioctls::Error> {
-		let debugreg = self.hw_breakpoints.registers();
-		let mut control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP | KVM_GUESTDBG_USE_HW_BP;
-		if step {
-			control |= KVM_GUESTDBG_SINGLESTEP;
-		}
-		let debug_struct = kvm_guest_debug {
-			control,
-			pad: 0,
-			arch: kvm_guest_debug_arch { debugreg },
-		};
-		self.vcpu.get_vcpu().set_guest_debug(&debug_struct)
-	}
-
-	pub fn run(&mut self) -> Result<StopReason<u64>, kvm_ioctls::Error> {
-		let stop_reason = match self.vcpu.r#continue()? {
+	/// Translates the exit of the vCPU `id` for GDB.
+	fn stop_reason(&self, id: usize, reason: VcpuStopReason) -> ThreadStopReason<u64> {
+		match reason {
 			VcpuStopReason::Debug(debug) => match debug.exception {
 				DB_VECTOR => {
 					let dr6 = Dr6Flags::from_bits_truncate(debug.dr6);
-					self.hw_breakpoints.stop_reason(dr6)
+					self.hw_breakpoints.stop_reason(dr6, tid(id))
 				}
-				BP_VECTOR => StopReason::SwBreak,
+				BP_VECTOR => ThreadStopReason::SwBreak(tid(id)),
 				vector => unreachable!("unknown KVM exception vector: {}", vector),
 			},
 			VcpuStopReason::Exit(code) => {
 				let status = if code == 0 { 0 } else { 1 };
-				StopReason::Exited(status)
+				ThreadStopReason::Exited(status)
 			}
-			VcpuStopReason::Kick => StopReason::Signal(Signal::SIGINT),
-		};
-		Ok(stop_reason)
+			VcpuStopReason::Kick => ThreadStopReason::Signal(Signal::SIGINT),
+		}
+	}
+
+	/// Records the stop of a vCPU.
+	///
+	/// Returns the reason for GDB or `None`, if the vCPU has only been kicked.
+	fn handle_stop(
+		&mut self,
+		id: usize,
+		result: HypervisorResult<VcpuStopReason>,
+	) -> Result<Option<ThreadStopReason<u64>>, HypervisorError> {
+		self.vcpus[id].running = false;
+		let reason = result?;
+		if let VcpuStopReason::Debug(debug) = &reason {
+			if debug.exception == BP_VECTOR {
+				self.remove_startup_breakpoint(debug.pc);
+			}
+		}
+		match reason {
+			VcpuStopReason::Kick => Ok(None),
+			reason => Ok(Some(self.stop_reason(id, reason))),
+		}
+	}
+
+	/// Returns a stop, which has not been reported yet, of a vCPU, which GDB wants to resume.
+	fn take_pending_stop(&mut self) -> Option<ThreadStopReason<u64>> {
+		self.vcpus
+			.iter_mut()
+			.filter(|vcpu| vcpu.action != ResumeAction::Stop)
+			.find_map(|vcpu| vcpu.pending.take())
+	}
+
+	/// Kicks all running vCPUs out of the guest and waits, until they have stopped.
+	///
+	/// GDB sees all threads stopped, if one of them stops. The other stops are reported later.
+	/// This is the all-stop mode of GDB, as gdbstub does not implement the non-stop mode
+	/// (`QNonStop:1`). With `vCont;c:<tid>`, only the vCPUs of the given threads are running.
+	fn stop_all(&mut self) -> Result<(), HypervisorError> {
+		while self.vcpus.iter().any(|vcpu| vcpu.running) {
+			for vcpu in self.vcpus.iter().filter(|vcpu| vcpu.running) {
+				vcpu.kick();
+			}
+			while self.vcpus.iter().any(|vcpu| vcpu.running) {
+				match self.events.recv_timeout(EXIT_REQUEST_TIMEOUT) {
+					// the connection is read after the stop has been reported
+					Ok(GdbEvent::IncomingData) => {}
+					Ok(GdbEvent::Stopped(id, result)) => {
+						if let Some(reason) = self.handle_stop(id, result)? {
+							self.vcpus[id].pending = Some(reason);
+						}
+					}
+					// kick the remaining vCPUs again
+					Err(_) => break,
+				}
+			}
+		}
+		Ok(())
 	}
 }
 
-impl SingleThreadOps for GdbUhyve {
-	fn resume(&mut self, signal: Option<Signal>) -> Result<(), Self::Error> {
+impl MultiThreadOps for GdbUhyve {
+	fn resume(&mut self) -> Result<(), Self::Error> {
+		if self
+			.vcpus
+			.iter()
+			.any(|vcpu| vcpu.action != ResumeAction::Stop && vcpu.pending.is_some())
+		{
+			// report the pending stop first
+			return Ok(());
+		}
+
+		if self.vcpus[0].action != ResumeAction::Stop {
+			self.insert_startup_breakpoint();
+		}
+		let debugreg = self.hw_breakpoints.registers();
+		for vcpu in &mut self.vcpus {
+			if vcpu.action != ResumeAction::Stop {
+				vcpu.apply_guest_debug(debugreg, vcpu.action == ResumeAction::Step)?;
+				vcpu.resume();
+			}
+		}
+		Ok(())
+	}
+
+	fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
+		for vcpu in &mut self.vcpus {
+			vcpu.action = ResumeAction::Stop;
+		}
+		Ok(())
+	}
+
+	fn set_resume_action_continue(
+		&mut self,
+		tid: Tid,
+		signal: Option<Signal>,
+	) -> Result<(), Self::Error> {
 		if signal.is_some() {
 			// cannot resume with signal
 			return Err(kvm_ioctls::Error::new(EINVAL));
 		}
 
-		self.apply_guest_debug(false)
+		self.vcpu_mut(tid)?.action = ResumeAction::Continue;
+		Ok(())
 	}
 
 	#[inline(always)]
 	fn support_single_step(
 		&mut self,
-	) -> Option<target::ext::base::singlethread::SingleThreadSingleStepOps<'_, Self>> {
+	) -> Option<target::ext::base::multithread::MultiThreadSingleStepOps<'_, Self>> {
 		Some(self)
 	}
 
-	fn read_registers(&mut self, regs: &mut X86_64CoreRegs) -> TargetResult<(), Self> {
-		regs::read(self.vcpu.get_vcpu(), regs)
+	fn read_registers(&mut self, regs: &mut X86_64Regs, tid: Tid) -> TargetResult<(), Self> {
+		regs::read(self.vcpu(tid)?.cpu().get_vcpu(), regs, self.avx)
 			.map_err(|error| TargetError::Errno(error.errno().try_into().unwrap()))
 	}
 
-	fn write_registers(&mut self, regs: &X86_64CoreRegs) -> TargetResult<(), Self> {
-		regs::write(regs, self.vcpu.get_vcpu())
+	fn write_registers(&mut self, regs: &X86_64Regs, tid: Tid) -> TargetResult<(), Self> {
+		regs::write(regs, self.vcpu(tid)?.cpu().get_vcpu())
 			.map_err(|error| TargetError::Errno(error.errno().try_into().unwrap()))
 	}
 
-	fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<(), Self> {
-		let src = unsafe { self.vcpu.memory(start_addr, data.len()) };
-		data.copy_from_slice(src);
-		Ok(())
+	fn read_addrs(&mut self, start_addr: u64, data: &mut [u8], tid: Tid) -> TargetResult<(), Self> {
+		self.for_each_guest_page(tid, start_addr, data.len(), |offset, mem| {
+			data[offset..offset + mem.len()].copy_from_slice(mem);
+		})
+	}
+
+	fn write_addrs(&mut self, start_addr: u64, data: &[u8], tid: Tid) -> TargetResult<(), Self> {
+		self.for_each_guest_page(tid, start_addr, data.len(), |offset, mem| {
+			mem.copy_from_slice(&data[offset..offset + mem.len()]);
+		})
 	}
 
-	fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
-		let mem = unsafe { self.vcpu.memory(start_addr, data.len()) };
-		mem.copy_from_slice(data);
+	fn list_active_threads(
+		&mut self,
+		thread_is_active: &mut dyn FnMut(Tid),
+	) -> Result<(), Self::Error> {
+		for id in 0..self.vcpus.len() {
+			thread_is_active(tid(id));
+		}
 		Ok(())
 	}
 }
 
-impl target::ext::base::singlethread::SingleThreadSingleStep for GdbUhyve {
-	fn step(&mut self, signal: Option<Signal>) -> Result<(), Self::Error> {
+impl target::ext::base::multithread::MultiThreadSingleStep for GdbUhyve {
+	fn set_resume_action_step(
+		&mut self,
+		tid: Tid,
+		signal: Option<Signal>,
+	) -> Result<(), Self::Error> {
 		if signal.is_some() {
 			// cannot step with signal
 			return Err(kvm_ioctls::Error::new(EINVAL));
 		}
 
-		self.apply_guest_debug(true)
+		self.vcpu_mut(tid)?.action = ResumeAction::Step;
+		Ok(())
 	}
 }
 
@@ -170,7 +504,7 @@ pub enum UhyveGdbEventLoop {}
 
 impl gdbstub_run_blocking::BlockingEventLoop for UhyveGdbEventLoop {
 	type Target = GdbUhyve;
-	type Connection = TcpStream;
+	type Connection = GdbConnection;
 
 	#[allow(clippy::type_complexity)]
 	fn wait_for_stop_reason(
@@ -188,43 +522,64 @@ impl gdbstub_run_blocking::BlockingEventLoop for UhyveGdbEventLoop {
 		static SPAWN_THREAD: Once = Once::new();
 
 		SPAWN_THREAD.call_once(|| {
-			let parent_thread = pthread_self();
+			let events = target.events_sender.clone();
 			let mut conn_clone = conn.try_clone().unwrap();
 			thread::spawn(move || {
 				loop {
-					// Block on TCP stream without consuming any data.
+					// Block on the connection without consuming any data.
 					Read::read(&mut conn_clone, &mut []).unwrap();
 
-					// Kick VCPU out of KVM_RUN
-					KickSignal::pthread_kill(parent_thread).unwrap();
+					// Let the event loop kick the vCPUs out of KVM_RUN
+					if events.send(GdbEvent::IncomingData).is_err() {
+						break;
+					}
 
-					// Wait for all inputs to be processed and for VCPU to be running again
+					// Wait for all inputs to be processed and for the vCPUs to be running again
 					thread::sleep(Duration::from_millis(20));
 				}
 			});
 		});
 
-		let stop_reason = target.run().map_err(WaitForStopReasonError::Target)?;
-
-		let event = match stop_reason {
-			StopReason::Signal(Signal::SIGINT) => {
-				assert!(conn
-					.peek()
-					.map_err(WaitForStopReasonError::Connection)?
-					.is_some());
-				gdbstub_run_blocking::Event::IncomingData(
-					ConnectionExt::read(conn).map_err(WaitForStopReasonError::Connection)?,
-				)
-			}
-			stop_reason => gdbstub_run_blocking::Event::TargetStopped(stop_reason.into()),
-		};
+		if let Some(stop_reason) = target.take_pending_stop() {
+			return Ok(gdbstub_run_blocking::Event::TargetStopped(stop_reason));
+		}
 
-		Ok(event)
+		loop {
+			match target.events.recv().unwrap() {
+				// the data of a previous notification may have been read already
+				GdbEvent::IncomingData => {
+					if conn
+						.peek()
+						.map_err(WaitForStopReasonError::Connection)?
+						.is_some()
+					{
+						target.stop_all().map_err(WaitForStopReasonError::Target)?;
+						return Ok(gdbstub_run_blocking::Event::IncomingData(
+							ConnectionExt::read(conn)
+								.map_err(WaitForStopReasonError::Connection)?,
+						));
+					}
+				}
+				GdbEvent::Stopped(id, result) => {
+					match target
+						.handle_stop(id, result)
+						.map_err(WaitForStopReasonError::Target)?
+					{
+						Some(stop_reason) => {
+							target.stop_all().map_err(WaitForStopReasonError::Target)?;
+							return Ok(gdbstub_run_blocking::Event::TargetStopped(stop_reason));
+						}
+						// kicked by someone else than GDB
+						None => target.vcpus[id].resume(),
+					}
+				}
+			}
+		}
 	}
 
 	fn on_interrupt(
 		_target: &mut Self::Target,
 	) -> Result<Option<ThreadStopReason<u64>>, <Self::Target as Target>::Error> {
-		Ok(Some(StopReason::Signal(Signal::SIGINT).into()))
+		Ok(Some(ThreadStopReason::Signal(Signal::SIGINT)))
 	}
 }
@@ -1,7 +1,16 @@
 use gdbstub_arch::x86::reg::{X86SegmentRegs, X86_64CoreRegs, X87FpuInternalRegs, F80};
-use kvm_bindings::{kvm_fpu, kvm_regs, kvm_sregs};
+use kvm_bindings::{kvm_fpu, kvm_regs, kvm_sregs, kvm_xsave};
 use kvm_ioctls::VcpuFd;
 
+use super::arch::X86_64Regs;
+
+/// Index of `XSTATE_BV` in the XSAVE header, which follows the legacy region of 512 bytes
+const XSTATE_BV_INDEX: usize = 512 / 4;
+/// The state component of the upper halves of YMM0–YMM15
+const XSTATE_YMM: u64 = 1 << 2;
+/// Index of the YMM state in the standard format of the XSAVE area, CPUID.(EAX=0DH,ECX=2):EBX
+const XSAVE_YMM_INDEX: usize = 576 / 4;
+
 /// [`kvm_regs`]-related [`X86_64CoreRegs`] fields.
 struct Regs {
 	regs: [u64; 16],
@@ -178,7 +187,60 @@ impl From<Fpu> for kvm_fpu {
 	}
 }
 
-pub fn read(vcpu: &VcpuFd, regs: &mut X86_64CoreRegs) -> Result<(), kvm_ioctls::Error> {
+/// Returns the upper halves of YMM0–YMM15 of the XSAVE area of KVM.
+fn ymmh(xsave: &kvm_xsave) -> [u128; 16] {
+	let xstate_bv = u64::from(xsave.region[XSTATE_BV_INDEX])
+		| (u64::from(xsave.region[XSTATE_BV_INDEX + 1]) << 32);
+	let mut ymmh = [0; 16];
+	// the registers are zero in their initial configuration, whose area may be stale
+	if xstate_bv & XSTATE_YMM != 0 {
+		for (reg, words) in ymmh
+			.iter_mut()
+			.zip(xsave.region[XSAVE_YMM_INDEX..].chunks_exact(4))
+		{
+			*reg = words
+				.iter()
+				.rev()
+				.fold(0, |reg, &word| (reg << u32::BITS) | u128::from(word));
+		}
+	}
+	ymmh
+}
+
+fn set_ymmh(xsave: &mut kvm_xsave, ymmh: &[u128; 16]) {
+	for (reg, words) in ymmh
+		.iter()
+		.zip(xsave.region[XSAVE_YMM_INDEX..].chunks_exact_mut(4))
+	{
+		for (i, word) in words.iter_mut().enumerate() {
+			*word = (reg >> (i as u32 * u32::BITS)) as u32;
+		}
+	}
+	xsave.region[XSTATE_BV_INDEX] |= XSTATE_YMM as u32;
+}
+
+/// Reads the registers and, if `avx`, the upper halves of the YMM registers.
+pub fn read(vcpu: &VcpuFd, regs: &mut X86_64Regs, avx: bool) -> Result<(), kvm_ioctls::Error> {
+	read_core(vcpu, &mut regs.core)?;
+	regs.ymmh = if avx {
+		Some(ymmh(&vcpu.get_xsave()?))
+	} else {
+		None
+	};
+	Ok(())
+}
+
+pub fn write(regs: &X86_64Regs, vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
+	write_core(&regs.core, vcpu)?;
+	if let Some(ymmh) = &regs.ymmh {
+		let mut xsave = vcpu.get_xsave()?;
+		set_ymmh(&mut xsave, ymmh);
+		vcpu.set_xsave(&xsave)?;
+	}
+	Ok(())
+}
+
+fn read_core(vcpu: &VcpuFd, regs: &mut X86_64CoreRegs) -> Result<(), kvm_ioctls::Error> {
 	// TODO: Rewrite using destructuring assignment once stabilized
 
 	let Regs {
@@ -207,7 +269,7 @@ pub fn read(vcpu: &VcpuFd, regs: &mut X86_64CoreRegs) -> Result<(), kvm_ioctls::
 	Ok(())
 }
 
-pub fn write(regs: &X86_64CoreRegs, vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
+fn write_core(regs: &X86_64CoreRegs, vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
 	let X86_64CoreRegs {
 		regs,
 		eflags,
@@ -237,3 +299,23 @@ pub fn write(regs: &X86_64CoreRegs, vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Err
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_ymmh_roundtrip() {
+		let mut xsave = kvm_xsave { region: [0; 1024] };
+		let mut regs = [0; 16];
+		regs[1] = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+		set_ymmh(&mut xsave, &regs);
+		assert_eq!(xsave.region[XSAVE_YMM_INDEX + 4], 0x7654_3210);
+		assert_eq!(xsave.region[XSAVE_YMM_INDEX + 7], 0x0123_4567);
+		assert_eq!(ymmh(&xsave), regs);
+
+		// the area is ignored in the initial configuration
+		xsave.region[XSTATE_BV_INDEX] = 0;
+		assert_eq!(ymmh(&xsave), [0; 16]);
+	}
+}
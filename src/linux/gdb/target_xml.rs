@@ -0,0 +1,78 @@
+//! The target description, which GDB reads with `qXfer:features:read`.
+//!
+//! `target.xml` includes the features of GDB's `i386/64bit-*.xml` files, so that front-ends can
+//! display the registers symbolically. The AVX feature with the upper halves of YMM0–YMM15 is only
+//! included, if the vCPUs support AVX.
+
+use gdbstub::target::{self, TargetError, TargetResult};
+
+use super::GdbUhyve;
+
+const TARGET_XML: &str = include_str!("xml/target.xml");
+const TARGET_AVX_XML: &str = include_str!("xml/target-avx.xml");
+const CORE_XML: &str = include_str!("xml/64bit-core.xml");
+const SSE_XML: &str = include_str!("xml/64bit-sse.xml");
+const AVX_XML: &str = include_str!("xml/64bit-avx.xml");
+
+/// Returns the document `annex` or `None`, if it does not exist.
+fn document(annex: &[u8], avx: bool) -> Option<&'static str> {
+	match annex {
+		b"target.xml" if avx => Some(TARGET_AVX_XML),
+		b"target.xml" => Some(TARGET_XML),
+		b"64bit-core.xml" => Some(CORE_XML),
+		b"64bit-sse.xml" => Some(SSE_XML),
+		b"64bit-avx.xml" if avx => Some(AVX_XML),
+		_ => None,
+	}
+}
+
+/// Copies at most `length` bytes of `data` from `offset` into `buf` and returns their number.
+fn copy_range(data: &[u8], offset: u64, length: usize, buf: &mut [u8]) -> usize {
+	let start = usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+	let end = start.saturating_add(length.min(buf.len())).min(data.len());
+	buf[..end - start].copy_from_slice(&data[start..end]);
+	end - start
+}
+
+impl target::ext::target_description_xml_override::TargetDescriptionXmlOverride for GdbUhyve {
+	fn target_description_xml(
+		&self,
+		annex: &[u8],
+		offset: u64,
+		length: usize,
+		buf: &mut [u8],
+	) -> TargetResult<usize, Self> {
+		let xml = document(annex, self.avx).ok_or(TargetError::NonFatal)?;
+		Ok(copy_range(xml.as_bytes(), offset, length, buf))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_documents() {
+		assert!(!document(b"target.xml", false).unwrap().contains("avx"));
+		assert!(document(b"target.xml", true)
+			.unwrap()
+			.contains("64bit-avx.xml"));
+		assert!(document(b"64bit-avx.xml", false).is_none());
+		assert!(document(b"i386-64bit.xml", true).is_none());
+
+		// the registers of the description match X86_64CoreRegs and the YMM upper halves
+		let regs = |xml: &str| xml.matches("<reg ").count();
+		assert_eq!(regs(CORE_XML) + regs(SSE_XML), 57);
+		assert_eq!(regs(AVX_XML), 16);
+	}
+
+	#[test]
+	fn test_copy_range() {
+		let mut buf = [0; 4];
+		assert_eq!(copy_range(b"target", 2, 10, &mut buf), 4);
+		assert_eq!(&buf, b"rget");
+		assert_eq!(copy_range(b"target", 5, 10, &mut buf), 1);
+		assert_eq!(copy_range(b"target", 6, 10, &mut buf), 0);
+		assert_eq!(copy_range(b"target", 100, 10, &mut buf), 0);
+	}
+}
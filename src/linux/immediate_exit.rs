@@ -0,0 +1,146 @@
+//! Pausing vCPUs from other threads with `KVM_CAP_IMMEDIATE_EXIT`.
+//!
+//! The [`KickSignal`] only interrupts KVM_RUN, if the vCPU thread is in the guest when the
+//! signal arrives. If it handles an exit instead, the signal is lost and the guest keeps running
+//! until its next exit. Setting `kvm_run.immediate_exit` closes this gap, because every KVM_RUN
+//! returns `EINTR` right away while the flag is set.
+
+use std::{
+	os::unix::io::AsRawFd,
+	ptr,
+	sync::{
+		atomic::{AtomicU8, Ordering},
+		Condvar, Mutex,
+	},
+	time::Duration,
+};
+
+use kvm_bindings::kvm_run;
+use kvm_ioctls::{Cap, VcpuFd};
+use nix::sys::pthread::{pthread_self, Pthread};
+
+use crate::linux::{KickSignal, KVM};
+
+/// A handle, with which other threads kick a vCPU out of KVM_RUN and wait until it has left.
+#[derive(Debug)]
+pub struct ExitRequest {
+	/// The shared `kvm_run` of the vCPU
+	run: *mut kvm_run,
+	run_size: usize,
+	/// The thread, which currently runs the vCPU
+	thread: Mutex<Option<Pthread>>,
+	pending: Mutex<bool>,
+	exited: Condvar,
+}
+
+// SAFETY: Only `immediate_exit` of `kvm_run` is accessed, and only atomically.
+unsafe impl Send for ExitRequest {}
+unsafe impl Sync for ExitRequest {}
+
+impl ExitRequest {
+	/// Maps the `kvm_run` of the vCPU or returns `None`, if KVM lacks `KVM_CAP_IMMEDIATE_EXIT`.
+	pub(crate) fn new(vcpu: &VcpuFd) -> Option<Self> {
+		if !KVM.check_extension(Cap::ImmediateExit) {
+			debug!("KVM_CAP_IMMEDIATE_EXIT is not supported");
+			return None;
+		}
+
+		let run_size = KVM.get_vcpu_mmap_size().ok()?;
+		let run = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				run_size,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED,
+				vcpu.as_raw_fd(),
+				0,
+			)
+		};
+		if run == libc::MAP_FAILED {
+			warn!("Unable to map kvm_run: {}", std::io::Error::last_os_error());
+			return None;
+		}
+
+		Some(Self {
+			run: run as *mut kvm_run,
+			run_size,
+			thread: Mutex::new(None),
+			pending: Mutex::new(false),
+			exited: Condvar::new(),
+		})
+	}
+
+	fn immediate_exit(&self) -> &AtomicU8 {
+		// SAFETY: `kvm_run` stays mapped as long as `self` and `immediate_exit` is a plain `u8`.
+		unsafe { &*(ptr::addr_of_mut!((*self.run).immediate_exit) as *const AtomicU8) }
+	}
+
+	/// Registers the current thread as the one, which runs the vCPU.
+	pub(crate) fn enter(&self) {
+		*self.thread.lock().unwrap() = Some(pthread_self());
+	}
+
+	/// Confirms a pending request, after KVM_RUN has returned.
+	///
+	/// Returns `false`, if no exit has been requested.
+	pub(crate) fn acknowledge(&self) -> bool {
+		let mut pending = self.pending.lock().unwrap();
+		if !*pending {
+			return false;
+		}
+		self.immediate_exit().store(0, Ordering::SeqCst);
+		*pending = false;
+		self.exited.notify_all();
+		true
+	}
+
+	/// Calls `f` with `immediate_exit` set, e.g., to issue a KVM_RUN, which returns `EINTR` without
+	/// entering the guest.
+	///
+	/// Returns `None`, if an exit is pending, whose request must not be cleared.
+	pub(crate) fn with_immediate_exit<T>(&self, f: impl FnOnce() -> T) -> Option<T> {
+		let pending = self.pending.lock().unwrap();
+		if *pending {
+			return None;
+		}
+		self.immediate_exit().store(1, Ordering::SeqCst);
+		let result = f();
+		self.immediate_exit().store(0, Ordering::SeqCst);
+		drop(pending);
+		Some(result)
+	}
+
+	/// Kicks the vCPU out of KVM_RUN and waits until it has left, at most for `timeout`.
+	///
+	/// Returns `false`, if the vCPU did not confirm in time, e.g., because it is not running.
+	pub fn request_exit(&self, timeout: Duration) -> bool {
+		let mut pending = self.pending.lock().unwrap();
+		*pending = true;
+		self.immediate_exit().store(1, Ordering::SeqCst);
+		if let Some(thread) = *self.thread.lock().unwrap() {
+			if let Err(err) = KickSignal::pthread_kill(thread) {
+				warn!("Unable to kick the vCPU: {}", err);
+			}
+		}
+
+		let (mut pending, result) = self
+			.exited
+			.wait_timeout_while(pending, timeout, |pending| *pending)
+			.unwrap();
+		if result.timed_out() {
+			self.immediate_exit().store(0, Ordering::SeqCst);
+			*pending = false;
+			return false;
+		}
+		true
+	}
+}
+
+impl Drop for ExitRequest {
+	fn drop(&mut self) {
+		// SAFETY: `run` has been mapped with `run_size` in `new` and is unmapped only once.
+		unsafe {
+			libc::munmap(self.run as *mut libc::c_void, self.run_size);
+		}
+	}
+}
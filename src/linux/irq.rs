@@ -0,0 +1,174 @@
+//! The GSI routing of the in-kernel interrupt controllers.
+//!
+//! `KVM_CREATE_IRQCHIP` connects the GSIs 0–15 to the pins of the PICs and of the IOAPIC and the
+//! GSIs 16–23 to the remaining pins of the IOAPIC. `KVM_SET_GSI_ROUTING` replaces this topology
+//! as a whole, so [`GsiRoutingTable`] has to contain every interrupt, which uhyve or KVM raises.
+
+use std::{fmt, mem};
+
+use kvm_bindings::{
+	kvm_irq_routing, kvm_irq_routing_entry, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI,
+};
+use kvm_ioctls::VmFd;
+
+use crate::consts::{UHYVE_IRQ_BALLOON, UHYVE_IRQ_NET};
+use crate::vm::HypervisorResult;
+
+/// The GSI of the PIT, which KVM raises on its own
+pub const IRQ_PIT: u32 = 0;
+/// The GSI of the ACPI system control interrupt
+pub const IRQ_SCI: u32 = 9;
+/// The first GSI, which is not connected to a pin of the IOAPIC and is free for MSIs
+pub const MSI_GSI_BASE: u32 = 24;
+
+/// The interrupt controllers of `KVM_CREATE_IRQCHIP`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqChip {
+	PicMaster = 0,
+	PicSlave = 1,
+	Ioapic = 2,
+}
+
+/// The routing entries of the GSIs, which are applied with [`GsiRoutingTable::apply`].
+#[derive(Default)]
+pub struct GsiRoutingTable {
+	entries: Vec<kvm_irq_routing_entry>,
+}
+
+impl fmt::Debug for GsiRoutingTable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list()
+			.entries(self.entries.iter().map(|entry| entry.gsi))
+			.finish()
+	}
+}
+
+impl GsiRoutingTable {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The routing of uhyve's interrupts: the legacy interrupts of the PIT and the SCI are raised
+	/// on both PICs and the IOAPIC, the interrupts of the devices only on the IOAPIC.
+	pub fn uhyve() -> Self {
+		let mut table = Self::new();
+		table.add_legacy(IRQ_PIT);
+		table.add_legacy(IRQ_SCI);
+		table.add_irqchip(UHYVE_IRQ_BALLOON, IrqChip::Ioapic, UHYVE_IRQ_BALLOON);
+		table.add_irqchip(UHYVE_IRQ_NET, IrqChip::Ioapic, UHYVE_IRQ_NET);
+		table
+	}
+
+	/// Routes the legacy interrupt `irq` to its pin of the PICs and of the IOAPIC.
+	pub fn add_legacy(&mut self, irq: u32) {
+		assert!(irq < 16, "IRQ {} is not a legacy interrupt", irq);
+		if irq < 8 {
+			self.add_irqchip(irq, IrqChip::PicMaster, irq);
+		} else {
+			self.add_irqchip(irq, IrqChip::PicSlave, irq - 8);
+		}
+		self.add_irqchip(irq, IrqChip::Ioapic, irq);
+	}
+
+	/// Routes `gsi` to `pin` of `chip`.
+	pub fn add_irqchip(&mut self, gsi: u32, chip: IrqChip, pin: u32) {
+		let mut entry = kvm_irq_routing_entry {
+			gsi,
+			type_: KVM_IRQ_ROUTING_IRQCHIP,
+			..Default::default()
+		};
+		entry.u.irqchip.irqchip = chip as u32;
+		entry.u.irqchip.pin = pin;
+		self.entries.push(entry);
+	}
+
+	/// Routes `gsi` to the message signaled interrupt `data` at `address`, e.g., for a virtio
+	/// device with MSI-X, whose GSIs start at [`MSI_GSI_BASE`].
+	pub fn add_msi(&mut self, gsi: u32, address: u64, data: u32) {
+		let mut entry = kvm_irq_routing_entry {
+			gsi,
+			type_: KVM_IRQ_ROUTING_MSI,
+			..Default::default()
+		};
+		entry.u.msi.address_lo = address as u32;
+		entry.u.msi.address_hi = (address >> 32) as u32;
+		entry.u.msi.data = data;
+		self.entries.push(entry);
+	}
+
+	pub fn entries(&self) -> &[kvm_irq_routing_entry] {
+		&self.entries
+	}
+
+	/// Returns the `kvm_irq_routing` header followed by the entries. The header is repeated, so
+	/// that the buffer is aligned for the entries.
+	fn routing(&self) -> Vec<kvm_irq_routing> {
+		let header = mem::size_of::<kvm_irq_routing>();
+		let size = header + self.entries.len() * mem::size_of::<kvm_irq_routing_entry>();
+		let mut routing: Vec<kvm_irq_routing> = (0..(size + header - 1) / header)
+			.map(|_| kvm_irq_routing::default())
+			.collect();
+		routing[0].nr = self.entries.len() as u32;
+		unsafe {
+			routing[0]
+				.entries
+				.as_mut_slice(self.entries.len())
+				.copy_from_slice(&self.entries);
+		}
+		routing
+	}
+
+	/// Replaces the routing of `vm`, which has to be called after all devices are added, as the
+	/// interrupt of a device without an entry is never delivered.
+	pub fn apply(&self, vm: &VmFd) -> HypervisorResult<()> {
+		vm.set_gsi_routing(&self.routing()[0])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn irqchip(entry: &kvm_irq_routing_entry) -> (u32, u32, u32) {
+		assert_eq!(entry.type_, KVM_IRQ_ROUTING_IRQCHIP);
+		let irqchip = unsafe { entry.u.irqchip };
+		(entry.gsi, irqchip.irqchip, irqchip.pin)
+	}
+
+	#[test]
+	fn test_uhyve_routing() {
+		let table = GsiRoutingTable::uhyve();
+		let entries: Vec<_> = table.entries().iter().map(irqchip).collect();
+		assert_eq!(
+			entries,
+			[
+				(0, 0, 0),
+				(0, 2, 0),
+				(9, 1, 1),
+				(9, 2, 9),
+				(10, 2, 10),
+				(11, 2, 11)
+			]
+		);
+	}
+
+	#[test]
+	fn test_msi_routing() {
+		let mut table = GsiRoutingTable::new();
+		table.add_msi(MSI_GSI_BASE, 0x1_fee0_0000, 0x4021);
+		table.add_irqchip(UHYVE_IRQ_NET, IrqChip::Ioapic, UHYVE_IRQ_NET);
+
+		let entry = &table.entries()[0];
+		assert_eq!(entry.type_, KVM_IRQ_ROUTING_MSI);
+		let msi = unsafe { entry.u.msi };
+		assert_eq!(
+			(msi.address_hi, msi.address_lo, msi.data),
+			(1, 0xfee0_0000, 0x4021)
+		);
+
+		let routing = table.routing();
+		assert_eq!(routing[0].nr, 2);
+		let entries = unsafe { routing[0].entries.as_slice(2) };
+		assert_eq!(irqchip(&entries[1]), (11, 2, 11));
+	}
+}
@@ -0,0 +1,66 @@
+//! Level-triggered interrupts through irqfds with `KVM_IRQFD_FLAG_RESAMPLE`.
+//!
+//! A resampled irqfd keeps its GSI asserted until the guest acknowledges the interrupt. KVM then
+//! de-asserts the line and signals the resample fd, on which the device checks, whether its
+//! interrupt condition still holds, and re-asserts the line if so. The guest has to configure the
+//! GSI as level-triggered, otherwise the acknowledgement never reaches KVM.
+
+use std::{
+	io,
+	os::unix::io::AsRawFd,
+	thread::{self, JoinHandle},
+};
+
+use kvm_bindings::{kvm_irqfd, KVM_CAP_IRQFD_RESAMPLE, KVM_IRQFD_FLAG_RESAMPLE};
+use kvm_ioctls::VmFd;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::linux::KVM;
+
+/// `_IO(KVMIO, 0x03)`
+const KVM_CHECK_EXTENSION: libc::c_ulong = 0xae03;
+/// `_IOW(KVMIO, 0x76, struct kvm_irqfd)`
+const KVM_IRQFD: libc::c_ulong = 0x4020_ae76;
+
+/// Checks, whether KVM supports resampled irqfds.
+pub fn resample_supported() -> bool {
+	// kvm-ioctls does not know `KVM_CAP_IRQFD_RESAMPLE`.
+	unsafe { libc::ioctl(KVM.as_raw_fd(), KVM_CHECK_EXTENSION, KVM_CAP_IRQFD_RESAMPLE) > 0 }
+}
+
+/// Registers `trigger` as level-triggered irqfd of `gsi`, which is resampled through `resample`.
+pub fn register_irqfd_with_resample(
+	vm: &VmFd,
+	trigger: &EventFd,
+	resample: &EventFd,
+	gsi: u32,
+) -> io::Result<()> {
+	let irqfd = kvm_irqfd {
+		fd: trigger.as_raw_fd() as u32,
+		gsi,
+		flags: KVM_IRQFD_FLAG_RESAMPLE,
+		resamplefd: resample.as_raw_fd() as u32,
+		..Default::default()
+	};
+	if unsafe { libc::ioctl(vm.as_raw_fd(), KVM_IRQFD, &irqfd) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+/// Re-asserts the interrupt through `trigger` after every acknowledgement, while `pending` holds.
+pub fn start_resampler(
+	trigger: EventFd,
+	resample: EventFd,
+	pending: impl Fn() -> bool + Send + 'static,
+) -> JoinHandle<()> {
+	thread::spawn(move || {
+		while resample.read().is_ok() {
+			if pending() {
+				if let Err(err) = trigger.write(1) {
+					warn!("Unable to re-assert the interrupt: {}", err);
+				}
+			}
+		}
+	})
+}
@@ -0,0 +1,233 @@
+//! Structured log records of the kernel.
+//!
+//! The kernel writes the guest physical address of a [`KernelLogRecord`] to
+//! [`UHYVE_PORT_KERNEL_LOG`]. The vCPU copies the record and hands it to a logger thread, which
+//! emits it with the target `hermit` at the level of the record and, with `--kernel-log-file`,
+//! appends the message to a rotating log file.
+
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	ptr,
+	sync::{
+		mpsc::{self, Sender},
+		Mutex,
+	},
+	thread::{self, JoinHandle},
+};
+
+use log::Level;
+
+use crate::{
+	consts::UHYVE_PORT_KERNEL_LOG,
+	linux::vcpu::{hypercall_args, ExitAction, PortIoHandler, UhyveCPU},
+};
+
+const MESSAGE_LEN: usize = 256;
+/// Size of a log file, after which it is rotated
+const MAX_LOG_FILE_SIZE: u64 = 10 << 20;
+/// Number of rotated log files, which are kept besides the current one
+const LOG_FILE_ROTATIONS: usize = 5;
+
+/// A log record, as the kernel passes it to uhyve.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct KernelLogRecord {
+	/// Time stamp of the kernel
+	pub timestamp: u64,
+	/// Level as in the `log` crate: 1 (error) to 5 (trace)
+	pub level: u8,
+	/// The message, which ends at the first NUL byte
+	pub message: [u8; MESSAGE_LEN],
+}
+
+impl KernelLogRecord {
+	/// Returns the level of the record, unknown levels are treated as info.
+	pub fn level(&self) -> Level {
+		match self.level {
+			1 => Level::Error,
+			2 => Level::Warn,
+			4 => Level::Debug,
+			5 => Level::Trace,
+			_ => Level::Info,
+		}
+	}
+
+	pub fn message(&self) -> String {
+		let len = self
+			.message
+			.iter()
+			.position(|&byte| byte == 0)
+			.unwrap_or(MESSAGE_LEN);
+		String::from_utf8_lossy(&self.message[..len]).into_owned()
+	}
+}
+
+/// A log file, which is moved to `<path>.1` once it exceeds its maximum size.
+///
+/// Older files are shifted up to `<path>.<rotations>` and the oldest one is overwritten.
+#[derive(Debug)]
+pub struct RotatingLog {
+	path: PathBuf,
+	file: File,
+	size: u64,
+	max_size: u64,
+	rotations: usize,
+}
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+	OpenOptions::new()
+		.create(true)
+		.write(true)
+		.truncate(true)
+		.open(path)
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+	let mut rotated = path.as_os_str().to_owned();
+	rotated.push(format!(".{}", index));
+	rotated.into()
+}
+
+impl RotatingLog {
+	pub fn open(path: &Path, max_size: u64, rotations: usize) -> io::Result<Self> {
+		Ok(Self {
+			path: path.to_path_buf(),
+			file: open_log_file(path)?,
+			size: 0,
+			max_size,
+			rotations,
+		})
+	}
+
+	fn rotate(&mut self) -> io::Result<()> {
+		for index in (1..self.rotations).rev() {
+			match fs::rename(
+				rotated_path(&self.path, index),
+				rotated_path(&self.path, index + 1),
+			) {
+				Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+				_ => {}
+			}
+		}
+		if self.rotations > 0 {
+			fs::rename(&self.path, rotated_path(&self.path, 1))?;
+		}
+		self.file = open_log_file(&self.path)?;
+		self.size = 0;
+		Ok(())
+	}
+
+	/// Appends a line, rotating the file first, if the line does not fit anymore.
+	pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+		let len = line.len() as u64 + 1;
+		if self.size > 0 && self.size + len > self.max_size {
+			self.rotate()?;
+		}
+		writeln!(self.file, "{}", line)?;
+		self.size += len;
+		Ok(())
+	}
+}
+
+/// Forwards the log records of the kernel to the logger thread.
+pub struct KernelLog {
+	tx: Mutex<Option<Sender<KernelLogRecord>>>,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl KernelLog {
+	/// Starts the logger thread, which also writes the messages to `path`, if given.
+	pub fn start(path: Option<&Path>) -> io::Result<Self> {
+		let mut file = path
+			.map(|path| RotatingLog::open(path, MAX_LOG_FILE_SIZE, LOG_FILE_ROTATIONS))
+			.transpose()?;
+		let (tx, rx) = mpsc::channel::<KernelLogRecord>();
+		let thread = thread::spawn(move || {
+			for record in rx {
+				let message = record.message();
+				let timestamp = record.timestamp;
+				log!(target: "hermit", record.level(), "[{}] {}", timestamp, message);
+				if let Some(log_file) = &mut file {
+					if let Err(err) = log_file.write_line(&message) {
+						warn!("Unable to write the kernel log: {}", err);
+						file = None;
+					}
+				}
+			}
+		});
+
+		Ok(Self {
+			tx: Mutex::new(Some(tx)),
+			thread: Some(thread),
+		})
+	}
+}
+
+impl PortIoHandler for KernelLog {
+	fn handle_in(&self, _vcpu: &UhyveCPU, _port: u16, _data: &mut [u8]) -> ExitAction {
+		info!("Unhanded IO Exit");
+		ExitAction::Continue
+	}
+
+	fn handle_out(&self, vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction {
+		debug_assert_eq!(port, UHYVE_PORT_KERNEL_LOG);
+		let record =
+			unsafe { ptr::read_unaligned(hypercall_args(vcpu, data) as *const KernelLogRecord) };
+		if let Some(tx) = &*self.tx.lock().unwrap() {
+			let _ = tx.send(record);
+		}
+		ExitAction::Continue
+	}
+}
+
+impl Drop for KernelLog {
+	/// Waits until the logger thread has written all records.
+	fn drop(&mut self) {
+		self.tx.lock().unwrap().take();
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_fs::TempDir;
+
+	#[test]
+	fn test_kernel_log_record() {
+		let mut record = KernelLogRecord {
+			timestamp: 42,
+			level: 2,
+			message: [0; MESSAGE_LEN],
+		};
+		record.message[..5].copy_from_slice(b"hello");
+		assert_eq!(record.level(), Level::Warn);
+		assert_eq!(record.message(), "hello");
+
+		record.level = 0;
+		record.message = [b'x'; MESSAGE_LEN];
+		assert_eq!(record.level(), Level::Info);
+		assert_eq!(record.message().len(), MESSAGE_LEN);
+	}
+
+	#[test]
+	fn test_rotating_log() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("kernel.log");
+		let mut log = RotatingLog::open(&path, 8, 2).unwrap();
+
+		for line in ["one", "two", "three", "four"] {
+			log.write_line(line).unwrap();
+		}
+
+		let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+		assert_eq!(read(path.clone()), "four\n");
+		assert_eq!(read(rotated_path(&path, 1)), "three\n");
+		assert_eq!(read(rotated_path(&path, 2)), "one\ntwo\n");
+		assert!(!rotated_path(&path, 3).exists());
+	}
+}
@@ -0,0 +1,90 @@
+//! Reporting of the memory deduplicated by Kernel Samepage Merging (KSM).
+//!
+//! KSM only provides system-wide counters, which include other processes using `MADV_MERGEABLE`.
+
+use std::{
+	fs, io,
+	path::Path,
+	sync::mpsc::{self, RecvTimeoutError, Sender},
+	thread::{self, JoinHandle},
+	time::Duration,
+};
+
+const KSM_SYSFS: &str = "/sys/kernel/mm/ksm";
+
+/// A snapshot of the global KSM counters.
+#[derive(Debug, Clone, Copy)]
+pub struct KsmStats {
+	/// Number of unique pages, which are shared.
+	pub pages_shared: u64,
+	/// Number of pages, which share a page with at least one other page.
+	pub pages_sharing: u64,
+}
+
+impl KsmStats {
+	pub fn read() -> io::Result<Self> {
+		Ok(Self {
+			pages_shared: read_counter("pages_shared")?,
+			pages_sharing: read_counter("pages_sharing")?,
+		})
+	}
+
+	/// Returns the deduplication ratio or `None`, if no pages are shared.
+	pub fn ratio(&self) -> Option<f64> {
+		(self.pages_shared != 0).then(|| self.pages_sharing as f64 / self.pages_shared as f64)
+	}
+}
+
+fn read_counter(name: &str) -> io::Result<u64> {
+	fs::read_to_string(Path::new(KSM_SYSFS).join(name))?
+		.trim()
+		.parse()
+		.map_err(|_| io::ErrorKind::InvalidData.into())
+}
+
+/// Periodically prints the KSM statistics until it is stopped.
+#[derive(Debug)]
+pub struct KsmReporter {
+	stop: Sender<()>,
+	thread: JoinHandle<Option<f64>>,
+}
+
+impl KsmReporter {
+	pub fn start(interval: Duration) -> Self {
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			let mut peak: Option<f64> = None;
+			while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+				match KsmStats::read() {
+					Ok(stats) => {
+						println!(
+							"KSM: {} pages shared, {} pages sharing, deduplication ratio {:.2}",
+							stats.pages_shared,
+							stats.pages_sharing,
+							stats.ratio().unwrap_or(0.0)
+						);
+						if let Some(ratio) = stats.ratio() {
+							peak = Some(peak.map_or(ratio, |peak| peak.max(ratio)));
+						}
+					}
+					Err(err) => {
+						warn!("Unable to read KSM statistics: {}", err);
+						break;
+					}
+				}
+			}
+			peak
+		});
+
+		Self { stop, thread }
+	}
+
+	/// Stops reporting and prints the peak deduplication ratio.
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		match self.thread.join().unwrap() {
+			Some(peak) => println!("KSM: peak deduplication ratio {:.2}", peak),
+			None => println!("KSM: no pages have been merged"),
+		}
+	}
+}
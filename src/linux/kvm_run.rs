@@ -0,0 +1,84 @@
+//! `KVM_RUN` without the decoder of `kvm-ioctls`.
+//!
+//! `VcpuFd::run` of `kvm-ioctls` 0.10 panics on every exit reason, which it does not know, e.g.,
+//! `KVM_EXIT_DIRTY_RING_FULL`. uhyve therefore maps `kvm_run` of each vCPU itself, issues
+//! `KVM_RUN` and returns the exit reason. The details of the exit are read through
+//! [`KvmRunAccess`], which only returns the member of the exit union for this reason.
+
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use kvm_bindings::*;
+use kvm_ioctls::VcpuFd;
+
+use crate::linux::vcpu::KvmRunAccess;
+use crate::linux::KVM;
+use crate::vm::HypervisorResult;
+
+/// `_IO(KVMIO, 0x80)`
+const KVM_RUN: libc::c_ulong = 0xae80;
+
+/// The `kvm_run` structure of a vCPU, which is shared with KVM.
+pub struct KvmRun {
+	run: *mut kvm_run,
+	size: usize,
+}
+
+unsafe impl Send for KvmRun {}
+
+impl KvmRun {
+	pub fn new(vcpu: &VcpuFd) -> HypervisorResult<Self> {
+		let size = KVM.get_vcpu_mmap_size()?;
+		let run = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				size,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_SHARED,
+				vcpu.as_raw_fd(),
+				0,
+			)
+		};
+		if run == libc::MAP_FAILED {
+			return Err(kvm_ioctls::Error::last());
+		}
+		Ok(Self {
+			run: run as *mut kvm_run,
+			size,
+		})
+	}
+
+	/// Accesses the last exit in the mapping of `kvm_run`.
+	///
+	/// # Safety
+	///
+	/// The vCPU must not run and `kvm_run` must not be accessed otherwise, while the returned
+	/// access is used.
+	pub unsafe fn access(&self) -> KvmRunAccess<'_> {
+		KvmRunAccess::from_raw(self.run, self.size)
+	}
+
+	/// Reads the whole mapping, which faults in its pages and pulls it into the cache.
+	pub fn touch(&self, stride: usize) {
+		let run = self.run as *const u8;
+		for offset in (0..self.size).step_by(stride) {
+			unsafe { ptr::read_volatile(run.add(offset)) };
+		}
+	}
+
+	/// Runs `vcpu`, which `self` belongs to, until the next exit and returns its reason.
+	pub fn run(&self, vcpu: &VcpuFd) -> HypervisorResult<u32> {
+		if unsafe { libc::ioctl(vcpu.as_raw_fd(), KVM_RUN) } < 0 {
+			return Err(kvm_ioctls::Error::last());
+		}
+		Ok(unsafe { ptr::read_volatile(ptr::addr_of!((*self.run).exit_reason)) })
+	}
+}
+
+impl Drop for KvmRun {
+	fn drop(&mut self) {
+		unsafe {
+			libc::munmap(self.run as *mut libc::c_void, self.size);
+		}
+	}
+}
@@ -1,7 +1,41 @@
+pub mod cold_pages;
+pub mod cow_blk;
+pub mod cpu_hotplug;
+pub mod cpu_registers;
+pub mod dirty_ring;
+pub mod exit_log;
 pub mod gdb;
+pub mod immediate_exit;
+pub mod irq;
+pub mod irqfd;
+pub mod kernel_log;
+pub mod ksm;
+pub mod kvm_run;
+pub mod msr_filter;
+pub mod net_stats;
+pub mod netlink;
+pub mod numa;
+pub mod pcap;
+pub mod pci;
+pub mod port_forward;
+pub mod ptp;
+pub mod router;
+pub mod sched;
+pub mod shm_state;
+#[cfg(feature = "slirp")]
+pub mod slirp;
+pub mod snapshot;
+pub mod sw_iommu;
+pub mod tap;
+pub mod tsc;
+#[cfg(feature = "tui")]
+pub mod tui;
 pub mod uhyve;
 pub mod vcpu;
+pub mod vhost_net;
 pub mod virtio;
+pub mod virtio_balloon;
+pub mod virtio_blk;
 pub mod virtqueue;
 
 pub type HypervisorError = kvm_ioctls::Error;
@@ -9,10 +43,15 @@ pub type DebugExitInfo = kvm_bindings::kvm_debug_exit_arch;
 
 use std::{
 	hint, io, mem,
-	net::{TcpListener, TcpStream},
 	os::unix::prelude::JoinHandleExt,
-	sync::{Arc, Barrier},
-	thread,
+	path::{Path, PathBuf},
+	ptr,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Barrier, Condvar, Mutex,
+	},
+	thread::{self, JoinHandle},
+	time::Duration,
 };
 
 use core_affinity::CoreId;
@@ -20,14 +59,31 @@ use gdbstub::DisconnectReason;
 use kvm_ioctls::Kvm;
 use lazy_static::lazy_static;
 use libc::{SIGRTMAX, SIGRTMIN};
-use nix::sys::{
-	pthread::{pthread_kill, Pthread},
-	signal::{signal, SigHandler, Signal},
+use nix::{
+	sched::sched_getaffinity,
+	sys::{
+		pthread::{pthread_kill, Pthread},
+		signal::{signal, SigHandler, SigSet, Signal},
+	},
+	unistd::Pid,
 };
 
 use crate::{
-	linux::gdb::{GdbUhyve, UhyveGdbEventLoop},
-	vm::{VirtualCPU, Vm},
+	linux::{
+		cold_pages::ColdPageHinter,
+		cpu_hotplug::CpuHotplugWatcher,
+		cpu_registers::CpuRegisters,
+		gdb::{GdbConnection, GdbUhyve, UhyveGdbEventLoop},
+		ksm::KsmReporter,
+		net_stats::NetworkStatsReporter,
+		ptp::PtpClock,
+		sched::set_deadline_scheduling,
+		shm_state::NetworkStatsPublisher,
+		vcpu::UhyveCPU,
+		virtio_balloon::{BalloonControl, BalloonTarget},
+	},
+	utils::parse_ranges,
+	vm::{HypervisorResult, LoadKernelError, VirtualCPU, Vm},
 	Uhyve,
 };
 
@@ -35,6 +91,22 @@ lazy_static! {
 	static ref KVM: Kvm = Kvm::new().unwrap();
 }
 
+impl From<LoadKernelError> for HypervisorError {
+	fn from(err: LoadKernelError) -> Self {
+		let errno = match err {
+			LoadKernelError::Io(err) => err.raw_os_error().unwrap_or(libc::EIO),
+			LoadKernelError::Goblin(_) => libc::ENOEXEC,
+			LoadKernelError::InsufficientMemory => libc::ENOMEM,
+			LoadKernelError::SignatureVerificationFailed => libc::EKEYREJECTED,
+			LoadKernelError::KernelArgsFileTooLarge(_) => libc::E2BIG,
+		};
+		kvm_ioctls::Error::new(errno)
+	}
+}
+
+/// How long [`Uhyve::snapshot`] waits for the vCPUs to stop.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// The signal for kicking vCPUs out of KVM_RUN.
 ///
 /// It is used to stop a vCPU from another thread.
@@ -68,77 +140,695 @@ impl KickSignal {
 	}
 }
 
+/// A timer, which periodically kicks the current vCPU thread out of KVM_RUN.
+///
+/// KVM does not let userspace program the VMX preemption timer, because it uses the timer itself
+/// to emulate the local APIC timer. Instead, a POSIX timer on the CPU time of the thread sends the
+/// [`KickSignal`], so the guest is only interrupted after it actually ran for `interval`.
+struct PreemptionTimer {
+	timer: libc::timer_t,
+}
+
+impl PreemptionTimer {
+	fn start(interval: Duration) -> io::Result<Self> {
+		// SAFETY: `sigevent` is a plain C struct, for which all zeroes are valid.
+		let mut event: libc::sigevent = unsafe { mem::zeroed() };
+		event.sigev_notify = libc::SIGEV_THREAD_ID;
+		event.sigev_signo = KickSignal::get() as libc::c_int;
+		event.sigev_notify_thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as libc::c_int;
+
+		let mut timer = ptr::null_mut();
+		// SAFETY: `event` and `timer` are valid for reads and writes respectively.
+		if unsafe { libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut event, &mut timer) } < 0
+		{
+			return Err(io::Error::last_os_error());
+		}
+		let preemption_timer = Self { timer };
+
+		let period = libc::timespec {
+			tv_sec: interval.as_secs() as libc::time_t,
+			tv_nsec: interval.subsec_nanos() as libc::c_long,
+		};
+		let value = libc::itimerspec {
+			it_interval: period,
+			it_value: period,
+		};
+		// SAFETY: `timer` has been created above and `value` is valid for reads.
+		if unsafe { libc::timer_settime(preemption_timer.timer, 0, &value, ptr::null_mut()) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		Ok(preemption_timer)
+	}
+}
+
+impl Drop for PreemptionTimer {
+	fn drop(&mut self) {
+		// SAFETY: `timer` has been created by `timer_create` and is deleted only once.
+		unsafe {
+			libc::timer_delete(self.timer);
+		}
+	}
+}
+
+/// The threads, which accompany the vCPUs while the VM is running.
+struct BackgroundTasks {
+	ksm_reporter: Option<KsmReporter>,
+	network_stats_reporter: Option<NetworkStatsReporter>,
+	cold_page_hinter: Option<ColdPageHinter>,
+	network_stats_publisher: Option<NetworkStatsPublisher>,
+	ptp_clock: Option<PtpClock>,
+	balloon_control: Option<BalloonControl>,
+	balloon_target: Option<BalloonTarget>,
+}
+
+impl BackgroundTasks {
+	fn start(vm: &Uhyve) -> Self {
+		Self {
+			ksm_reporter: vm.ksm_report_interval.map(KsmReporter::start),
+			network_stats_reporter: vm.start_network_stats(),
+			cold_page_hinter: vm.start_cold_page_hints(),
+			network_stats_publisher: vm.start_network_stats_publisher(),
+			ptp_clock: vm.start_ptp_clock(),
+			balloon_control: vm.start_balloon_control(),
+			balloon_target: vm.start_balloon_target(),
+		}
+	}
+
+	fn stop(self) {
+		if let Some(ksm_reporter) = self.ksm_reporter {
+			ksm_reporter.stop();
+		}
+		if let Some(network_stats_reporter) = self.network_stats_reporter {
+			network_stats_reporter.stop();
+		}
+		if let Some(cold_page_hinter) = self.cold_page_hinter {
+			cold_page_hinter.stop();
+		}
+		if let Some(network_stats_publisher) = self.network_stats_publisher {
+			network_stats_publisher.stop();
+		}
+		if let Some(ptp_clock) = self.ptp_clock {
+			ptp_clock.stop();
+		}
+		if let Some(balloon_control) = self.balloon_control {
+			balloon_control.stop();
+		}
+		if let Some(balloon_target) = self.balloon_target {
+			balloon_target.stop();
+		}
+	}
+}
+
+/// Migrations of the vCPU threads to other host CPUs, see [`Uhyve::migrate_vcpu`].
+#[derive(Debug)]
+pub(crate) struct VcpuMigrations {
+	threads: Mutex<Vec<Pthread>>,
+	pending: Vec<Mutex<Option<CoreId>>>,
+	cores: Vec<Mutex<Option<CoreId>>>,
+	counts: Vec<AtomicUsize>,
+	stopping: AtomicBool,
+}
+
+impl VcpuMigrations {
+	pub(crate) fn new(num_cpus: u32) -> Self {
+		Self {
+			threads: Mutex::new(Vec::new()),
+			pending: (0..num_cpus).map(|_| Mutex::new(None)).collect(),
+			cores: (0..num_cpus).map(|_| Mutex::new(None)).collect(),
+			counts: (0..num_cpus).map(|_| AtomicUsize::new(0)).collect(),
+			stopping: AtomicBool::new(false),
+		}
+	}
+}
+
+/// Pausing and stopping all vCPUs from other threads, see [`Uhyve::pause_vcpus`].
+#[derive(Debug, Default)]
+pub(crate) struct VcpuControl {
+	paused: Mutex<bool>,
+	resumed: Condvar,
+	/// Set, until a vCPU has claimed returning the exit code of the shutdown
+	shutdown: AtomicBool,
+	/// The registers of the paused vCPUs, which [`Uhyve::snapshot`] collects
+	exported: Mutex<Option<Vec<Option<HypervisorResult<snapshot::VcpuState>>>>>,
+	all_exported: Condvar,
+	/// The registers of the paused vCPUs, which [`Uhyve::dump_registers`] collects
+	dumped: Mutex<Option<Vec<Option<HypervisorResult<CpuRegisters>>>>>,
+	all_dumped: Condvar,
+}
+
+impl VcpuControl {
+	/// Blocks the current vCPU thread while the vCPUs are paused.
+	///
+	/// Returns `false`, if the vCPUs have not been paused.
+	fn wait_while_paused(&self, id: u32, cpu: &UhyveCPU) -> bool {
+		let mut paused = self.paused.lock().unwrap();
+		if !*paused {
+			return false;
+		}
+		match cpu.kvmclock_ctrl() {
+			Ok(()) => {}
+			// the guest does not use kvmclock
+			Err(err) if err.errno() == libc::EINVAL => {}
+			Err(err) => warn!("Unable to notify the kvmclock of vCPU {}: {}", id, err),
+		}
+		while *paused {
+			self.export_state(id, cpu);
+			paused = self.resumed.wait(paused).unwrap();
+		}
+		true
+	}
+
+	/// Stores the registers of a paused vCPU, if a snapshot or a dump requests them.
+	fn export_state(&self, id: u32, cpu: &UhyveCPU) {
+		let mut exported = self.exported.lock().unwrap();
+		if let Some(state) = exported.as_mut().map(|states| &mut states[id as usize]) {
+			if state.is_none() {
+				*state = Some(cpu.export_state());
+				self.all_exported.notify_all();
+			}
+		}
+		drop(exported);
+
+		let mut dumped = self.dumped.lock().unwrap();
+		if let Some(registers) = dumped.as_mut().map(|dumps| &mut dumps[id as usize]) {
+			if registers.is_none() {
+				*registers = Some(cpu.dump_registers());
+				self.all_dumped.notify_all();
+			}
+		}
+	}
+
+	/// Returns `true` for exactly one vCPU after a shutdown has been requested.
+	fn claim_shutdown(&self) -> bool {
+		self.shutdown.swap(false, Ordering::SeqCst)
+	}
+}
+
 impl Uhyve {
+	/// Kicks all running vCPUs out of the guest.
+	fn kick_vcpus(&self) {
+		for pthread in self.migrations.threads.lock().unwrap().iter() {
+			if let Err(err) = KickSignal::pthread_kill(*pthread) {
+				warn!("Unable to kick a vCPU: {}", err);
+			}
+		}
+	}
+
+	/// Stops all vCPUs before they enter the guest again, until [`Uhyve::resume_vcpus`].
+	pub fn pause_vcpus(&self) {
+		*self.control.paused.lock().unwrap() = true;
+		self.kick_vcpus();
+	}
+
+	/// Lets the vCPUs continue, which have been stopped by [`Uhyve::pause_vcpus`].
+	pub fn resume_vcpus(&self) {
+		*self.control.paused.lock().unwrap() = false;
+		self.control.resumed.notify_all();
+	}
+
+	/// Returns `true` between [`Uhyve::pause_vcpus`] and [`Uhyve::resume_vcpus`].
+	pub fn vcpus_paused(&self) -> bool {
+		*self.control.paused.lock().unwrap()
+	}
+
+	/// Writes the guest memory and the registers of all vCPUs to `path`, see [`snapshot`].
+	///
+	/// The vCPUs are paused, until the snapshot has been written.
+	pub fn snapshot(&self, path: &Path) -> HypervisorResult<()> {
+		*self.control.exported.lock().unwrap() = Some(vec![None; self.num_cpus() as usize]);
+		let was_paused = mem::replace(&mut *self.control.paused.lock().unwrap(), true);
+		self.kick_vcpus();
+		// vCPUs, which have already been paused, wake up to export their registers
+		self.control.resumed.notify_all();
+
+		let exported = self.control.exported.lock().unwrap();
+		let (mut exported, wait) = self
+			.control
+			.all_exported
+			.wait_timeout_while(exported, SNAPSHOT_TIMEOUT, |states| {
+				states.as_ref().unwrap().iter().any(Option::is_none)
+			})
+			.unwrap();
+		let states = exported.take().unwrap();
+		drop(exported);
+
+		let result = if wait.timed_out() {
+			error!("Not all vCPUs have stopped for the snapshot");
+			Err(kvm_ioctls::Error::new(libc::EBUSY))
+		} else {
+			states
+				.into_iter()
+				.map(Option::unwrap)
+				.collect::<HypervisorResult<Vec<_>>>()
+				.and_then(|vcpus| self.write_snapshot(path, vcpus))
+		};
+		if !was_paused {
+			self.resume_vcpus();
+		}
+		result
+	}
+
+	/// Reads the registers of all vCPUs, which are paused for this.
+	pub fn dump_registers(&self) -> HypervisorResult<Vec<CpuRegisters>> {
+		*self.control.dumped.lock().unwrap() = Some((0..self.num_cpus()).map(|_| None).collect());
+		let was_paused = mem::replace(&mut *self.control.paused.lock().unwrap(), true);
+		self.kick_vcpus();
+		// vCPUs, which have already been paused, wake up to dump their registers
+		self.control.resumed.notify_all();
+
+		let dumped = self.control.dumped.lock().unwrap();
+		let (mut dumped, wait) = self
+			.control
+			.all_dumped
+			.wait_timeout_while(dumped, SNAPSHOT_TIMEOUT, |dumps| {
+				dumps.as_ref().unwrap().iter().any(Option::is_none)
+			})
+			.unwrap();
+		let dumps = dumped.take().unwrap();
+		drop(dumped);
+		if !was_paused {
+			self.resume_vcpus();
+		}
+
+		if wait.timed_out() {
+			error!("Not all vCPUs have stopped for the register dump");
+			return Err(kvm_ioctls::Error::new(libc::EBUSY));
+		}
+		dumps.into_iter().map(Option::unwrap).collect()
+	}
+
+	/// Stops the VM, as if the kernel exited with `0`.
+	pub fn shutdown(&self) {
+		self.control.shutdown.store(true, Ordering::SeqCst);
+		self.resume_vcpus();
+		self.kick_vcpus();
+	}
+
+	/// Migrates the thread of a vCPU to another host CPU.
+	///
+	/// The vCPU is kicked out of the guest and changes its affinity before it resumes execution.
+	/// This only requests the migration and returns before it happened.
+	pub fn migrate_vcpu(&self, id: u32, new_core: CoreId) -> HypervisorResult<()> {
+		let migrations = &self.migrations;
+		let pending = migrations
+			.pending
+			.get(id as usize)
+			.ok_or_else(|| kvm_ioctls::Error::new(libc::EINVAL))?;
+		let pthread = *migrations
+			.threads
+			.lock()
+			.unwrap()
+			.get(id as usize)
+			.ok_or_else(|| kvm_ioctls::Error::new(libc::ESRCH))?;
+
+		*pending.lock().unwrap() = Some(new_core);
+		KickSignal::pthread_kill(pthread).map_err(|err| kvm_ioctls::Error::new(err as i32))?;
+		Ok(())
+	}
+
+	/// Returns how often the thread of a vCPU has been migrated.
+	pub fn migration_count(&self, id: u32) -> usize {
+		self.migrations
+			.counts
+			.get(id as usize)
+			.map_or(0, |count| count.load(Ordering::Relaxed))
+	}
+
+	/// Returns the host CPU, to which the thread of a vCPU is pinned.
+	pub fn vcpu_core(&self, id: u32) -> Option<CoreId> {
+		self.migrations
+			.cores
+			.get(id as usize)
+			.and_then(|core_id| *core_id.lock().unwrap())
+	}
+
+	/// Distributes the vCPU threads round-robin across the online host CPUs.
+	pub fn rebalance_vcpus(&self) {
+		let online = match online_cpus() {
+			Ok(online) if !online.is_empty() => online,
+			Ok(_) => return,
+			Err(err) => {
+				warn!("Unable to determine the online CPUs: {}", err);
+				return;
+			}
+		};
+
+		for id in 0..self.num_cpus() {
+			let core_id = CoreId {
+				id: online[id as usize % online.len()],
+			};
+			if let Err(err) = self.migrate_vcpu(id, core_id) {
+				warn!("Unable to migrate vCPU {}: {}", id, err);
+			}
+		}
+	}
+
+	/// Applies a pending migration of the current vCPU thread.
+	///
+	/// Returns `false`, if the vCPU has been kicked for another reason.
+	fn apply_pending_migration(&self, id: u32) -> bool {
+		if self.migrations.stopping.load(Ordering::SeqCst) {
+			return false;
+		}
+		let core_id = match self.migrations.pending[id as usize].lock().unwrap().take() {
+			Some(core_id) => core_id,
+			None => return false,
+		};
+
+		debug!("Migrate vCPU {} to CPU {}", id, core_id.id);
+		core_affinity::set_for_current(core_id);
+		match sched_getaffinity(Pid::from_raw(0)) {
+			Ok(cpuset) if cpuset.is_set(core_id.id).unwrap_or(false) => {
+				self.migrations.counts[id as usize].fetch_add(1, Ordering::Relaxed);
+				*self.migrations.cores[id as usize].lock().unwrap() = Some(core_id);
+			}
+			Ok(_) => warn!("Unable to migrate vCPU {} to CPU {}", id, core_id.id),
+			Err(err) => warn!("Unable to verify the affinity of vCPU {}: {}", id, err),
+		}
+
+		true
+	}
+
+	/// Applies the requested scheduling policy to the current vCPU thread.
+	fn set_vcpu_scheduling(&self, id: u32) {
+		if let Some(deadline) = &self.deadline {
+			if let Err(err) = set_deadline_scheduling(deadline) {
+				warn!(
+					"Unable to set the scheduling policy of vCPU {}: {}",
+					id, err
+				);
+			}
+		}
+	}
+
 	/// Runs the VM.
 	///
 	/// Blocks until the VM has finished execution.
 	pub fn run(mut self, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
 		KickSignal::register_handler().unwrap();
 
-		unsafe {
-			self.load_kernel().expect("Unabled to load the kernel");
+		// a restored VM continues with the kernel of the snapshot
+		if !self.is_restored() {
+			unsafe {
+				self.load_kernel().expect("Unabled to load the kernel");
+			}
 		}
 
-		if self.gdb_port.is_none() {
+		let background_tasks = BackgroundTasks::start(&self);
+
+		let code = if !self.debugging() {
 			self.run_no_gdb(cpu_affinity)
 		} else {
 			self.run_gdb(cpu_affinity)
+		};
+
+		background_tasks.stop();
+
+		code
+	}
+
+	/// Runs the given kernels one after another in the same VM.
+	///
+	/// Each kernel is loaded by [`Uhyve::reload_kernel`] and `report` is called with its exit code.
+	/// Neither GDB nor the handling of `SIGUSR1` and `SIGUSR2` are available in this mode.
+	pub fn run_tests(
+		self,
+		kernels: impl IntoIterator<Item = PathBuf>,
+		cpu_affinity: Option<Vec<CoreId>>,
+		mut report: impl FnMut(&Path, HypervisorResult<i32>),
+	) {
+		assert!(
+			!self.debugging(),
+			"Debugging is not supported when running tests"
+		);
+		KickSignal::register_handler().unwrap();
+
+		let background_tasks = BackgroundTasks::start(&self);
+
+		let mut this = Arc::new(self);
+		for kernel in kernels {
+			let vm = Arc::get_mut(&mut this).expect("vCPU threads are still running");
+			let result = vm
+				.reload_kernel(kernel.clone())
+				.map(|()| this.run_vcpus(cpu_affinity.as_deref()));
+			report(&kernel, result);
 		}
+
+		background_tasks.stop();
 	}
 
-	fn run_no_gdb(self, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
-		// After spinning up all vCPU threads, the main thread waits for any vCPU to end execution.
-		let barrier = Arc::new(Barrier::new(2));
+	/// Runs the VM on the blocking thread pool of the current tokio runtime.
+	///
+	/// Each vCPU occupies a blocking thread until the kernel exits. `SIGUSR1` and `SIGUSR2` are
+	/// not handled in this mode. With GDB, the VM is run by [`Uhyve::run`] on a single blocking
+	/// thread instead.
+	#[cfg(feature = "tokio")]
+	pub async fn run_async(mut self, cpu_affinity: Option<Vec<CoreId>>) -> HypervisorResult<i32> {
+		if self.debugging() {
+			return tokio::task::spawn_blocking(move || self.run(cpu_affinity))
+				.await
+				.map_err(|_| kvm_ioctls::Error::new(libc::EIO));
+		}
+
+		KickSignal::register_handler().map_err(|err| kvm_ioctls::Error::new(err as i32))?;
+		if !self.is_restored() {
+			unsafe { self.load_kernel() }.map_err(|err| {
+				error!("Unable to load the kernel: {}", err);
+				HypervisorError::from(err)
+			})?;
+		}
+
+		let background_tasks = BackgroundTasks::start(&self);
 
 		let this = Arc::new(self);
-		let threads = (0..this.num_cpus())
-			.map(|cpu_id| {
-				let vm = this.clone();
-				let barrier = barrier.clone();
-				let local_cpu_affinity = cpu_affinity
-					.as_ref()
-					.map(|core_ids| core_ids.get(cpu_id as usize).copied())
-					.flatten();
+		this.migrations.stopping.store(false, Ordering::SeqCst);
+		let num_cpus = this.num_cpus();
+		let (pthread_tx, mut pthread_rx) = tokio::sync::mpsc::unbounded_channel();
+		let mut tasks = tokio::task::JoinSet::new();
+		for cpu_id in 0..num_cpus {
+			let vm = this.clone();
+			let pthread_tx = pthread_tx.clone();
+			let core_id = cpu_affinity
+				.as_ref()
+				.and_then(|core_ids| core_ids.get(cpu_id as usize).copied());
+			tasks.spawn_blocking(move || {
+				let _ = pthread_tx.send((cpu_id, nix::sys::pthread::pthread_self()));
+				(cpu_id, vm.run_vcpu(cpu_id, core_id))
+			});
+		}
 
-				thread::spawn(move || {
-					debug!("Create thread for CPU {}", cpu_id);
-					match local_cpu_affinity {
-						Some(core_id) => {
-							debug!("Trying to pin thread {} to CPU {}", cpu_id, core_id.id);
-							core_affinity::set_for_current(core_id); // This does not return an error if it fails :(
-						}
-						None => debug!("No affinity specified, not binding thread"),
+		let mut pthreads = Vec::with_capacity(num_cpus as usize);
+		for _ in 0..num_cpus {
+			match pthread_rx.recv().await {
+				Some(pthread) => pthreads.push(pthread),
+				None => break,
+			}
+		}
+		pthreads.sort_unstable_by_key(|(cpu_id, _)| *cpu_id);
+		*this.migrations.threads.lock().unwrap() =
+			pthreads.iter().map(|(_, pthread)| *pthread).collect();
+
+		let mut running = vec![true; num_cpus as usize];
+		let mut code = None;
+		while let Some(result) = tasks.join_next().await {
+			let (cpu_id, exit_code) = match result {
+				Ok(result) => result,
+				Err(err) => {
+					error!("vCPU task failed: {}", err);
+					continue;
+				}
+			};
+			running[cpu_id as usize] = false;
+
+			if let Some(exit_code) = exit_code {
+				assert!(
+					code.replace(exit_code).is_none(),
+					"more than one thread finished with an exit code"
+				);
+				// The blocking threads are pooled, so only kick those still running a vCPU.
+				this.migrations.stopping.store(true, Ordering::SeqCst);
+				this.resume_vcpus();
+				for (cpu_id, pthread) in &pthreads {
+					if running[*cpu_id as usize] {
+						KickSignal::pthread_kill(*pthread).unwrap();
 					}
+				}
+			}
+		}
+
+		background_tasks.stop();
+		code.ok_or_else(|| kvm_ioctls::Error::new(libc::EIO))
+	}
 
-					let mut cpu = vm.create_cpu(cpu_id).unwrap();
-					cpu.init(vm.get_entry_point()).unwrap();
+	fn run_no_gdb(self, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
+		let this = Arc::new(self);
 
-					// only one core is able to enter startup code
-					// => the wait for the predecessor core
-					while cpu_id != vm.cpu_online() {
-						hint::spin_loop();
+		let mut sigset = SigSet::empty();
+		if this.nmi_on_signal || this.registers_on_signal {
+			sigset.add(Signal::SIGUSR2);
+		}
+		if this.pause_on_signal || cpu_affinity.is_some() {
+			sigset.add(Signal::SIGUSR1);
+		}
+		if this.snapshot_path.is_some() {
+			sigset.add(Signal::SIGTERM);
+		}
+		let signal_thread = if this.nmi_on_signal
+			|| this.registers_on_signal
+			|| this.pause_on_signal
+			|| cpu_affinity.is_some()
+			|| this.snapshot_path.is_some()
+		{
+			// Block the signals before spawning the vCPU threads, which inherit the mask.
+			Some(SignalThread::spawn(this.clone(), sigset).expect("Unable to handle signals"))
+		} else {
+			None
+		};
+
+		// Only pinned vCPUs have to be moved away from offline CPUs.
+		let hotplug_watcher = (this.auto_migrate && cpu_affinity.is_some())
+			.then(|| CpuHotplugWatcher::start(this.clone()));
+
+		#[cfg(feature = "tui")]
+		let dashboard = this
+			.dashboard_receiver
+			.lock()
+			.unwrap()
+			.take()
+			.map(|events| tui::Dashboard::start(this.clone(), events));
+
+		let code = this.run_vcpus(cpu_affinity.as_deref());
+
+		#[cfg(feature = "tui")]
+		if let Some(dashboard) = dashboard {
+			dashboard.stop();
+		}
+		if let Some(hotplug_watcher) = hotplug_watcher {
+			hotplug_watcher.stop();
+		}
+		if let Some(signal_thread) = signal_thread {
+			signal_thread.stop();
+		}
+
+		code
+	}
+
+	/// Pins the current thread to `core_id` and creates the vCPU `cpu_id` in it.
+	fn setup_vcpu(&self, cpu_id: u32, core_id: Option<CoreId>) -> UhyveCPU {
+		debug!("Create thread for CPU {}", cpu_id);
+		match core_id {
+			Some(core_id) => {
+				debug!("Trying to pin thread {} to CPU {}", cpu_id, core_id.id);
+				core_affinity::set_for_current(core_id); // This does not return an error if it fails :(
+			}
+			None => debug!("No affinity specified, not binding thread"),
+		}
+		*self.migrations.cores[cpu_id as usize].lock().unwrap() = core_id;
+
+		self.set_vcpu_scheduling(cpu_id);
+
+		let mut cpu = self.create_cpu(cpu_id).unwrap();
+		cpu.init(self.get_entry_point()).unwrap();
+		cpu
+	}
+
+	/// Executes a vCPU in the current thread.
+	///
+	/// Returns the exit code of the kernel or `None`, if the vCPU has been kicked or crashed.
+	fn run_vcpu(&self, cpu_id: u32, core_id: Option<CoreId>) -> Option<i32> {
+		let mut cpu = self.setup_vcpu(cpu_id, core_id);
+		let restored_state = self.take_restored_state(cpu_id);
+		if let Some(state) = &restored_state {
+			cpu.import_state(state).unwrap();
+		}
+		cpu.warm_up_cache();
+
+		// only one core is able to enter startup code
+		// => the wait for the predecessor core
+		// restored vCPUs have already been started
+		while restored_state.is_none() && cpu_id != self.cpu_online() {
+			hint::spin_loop();
+		}
+
+		let preemption_timer = self.preemption_timer.and_then(|interval| {
+			PreemptionTimer::start(interval)
+				.map_err(|err| {
+					warn!(
+						"Unable to start the preemption timer of vCPU {}: {}",
+						cpu_id, err
+					)
+				})
+				.ok()
+		});
+
+		// jump into the VM and execute code of the guest
+		let result = loop {
+			match cpu.run() {
+				Ok(None) if self.apply_pending_migration(cpu_id) => continue,
+				Ok(None) if self.control.claim_shutdown() => break Ok(Some(0)),
+				Ok(None) if self.control.wait_while_paused(cpu_id, &cpu) => {
+					// the vCPUs are resumed on a shutdown and when the VM stops
+					if self.control.claim_shutdown() {
+						break Ok(Some(0));
+					}
+					if self.migrations.stopping.load(Ordering::SeqCst) {
+						break Ok(None);
 					}
+					continue;
+				}
+				Ok(None)
+					if preemption_timer.is_some()
+						&& !self.migrations.stopping.load(Ordering::SeqCst) =>
+				{
+					thread::yield_now();
+					continue;
+				}
+				result => break result,
+			}
+		};
+		drop(preemption_timer);
+		self.release_cpu(cpu_id, cpu);
+		match result {
+			Ok(code) => code,
+			Err(err) => {
+				error!("CPU {} crashed with {:?}", cpu_id, err);
+				None
+			}
+		}
+	}
 
-					// jump into the VM and execute code of the guest
-					match cpu.run() {
-						Ok(code) => {
-							if code.is_some() {
-								// Let the main thread continue with kicking the other vCPUs
-								barrier.wait();
-							}
-							code
-						}
-						Err(err) => {
-							error!("CPU {} crashed with {:?}", cpu_id, err);
-							None
-						}
+	/// Spawns a thread for each vCPU and waits, until the kernel exits.
+	fn run_vcpus(self: &Arc<Self>, cpu_affinity: Option<&[CoreId]>) -> i32 {
+		// After spinning up all vCPU threads, the main thread waits for any vCPU to end execution.
+		let barrier = Arc::new(Barrier::new(2));
+		self.migrations.stopping.store(false, Ordering::SeqCst);
+
+		let threads = (0..self.num_cpus())
+			.map(|cpu_id| {
+				let vm = self.clone();
+				let barrier = barrier.clone();
+				let local_cpu_affinity =
+					cpu_affinity.and_then(|core_ids| core_ids.get(cpu_id as usize).copied());
+
+				thread::spawn(move || {
+					let code = vm.run_vcpu(cpu_id, local_cpu_affinity);
+					if code.is_some() {
+						// Let the main thread continue with kicking the other vCPUs
+						barrier.wait();
 					}
+					code
 				})
 			})
 			.collect::<Vec<_>>();
 
+		*self.migrations.threads.lock().unwrap() =
+			threads.iter().map(|thread| thread.as_pthread_t()).collect();
+
 		// Wait for one vCPU to return with an exit code.
 		barrier.wait();
+		self.migrations.stopping.store(true, Ordering::SeqCst);
+		self.resume_vcpus();
 		for thread in &threads {
 			KickSignal::pthread_kill(thread.as_pthread_t()).unwrap();
 		}
@@ -155,31 +845,22 @@ impl Uhyve {
 		code[0]
 	}
 
+	/// Runs each vCPU in its own thread, while GDB controls them from the current one.
 	fn run_gdb(self, cpu_affinity: Option<Vec<CoreId>>) -> i32 {
-		let cpu_id = 0;
+		let gdb_port = self.gdb_port;
+		let gdb_socket = self.gdb_socket.clone();
+		let mut debuggable_vm = GdbUhyve::new(Arc::new(self), cpu_affinity.as_deref());
 
-		let local_cpu_affinity = cpu_affinity
-			.as_ref()
-			.map(|core_ids| core_ids.get(cpu_id as usize).copied())
-			.flatten();
-
-		match local_cpu_affinity {
-			Some(core_id) => {
-				debug!("Trying to pin thread {} to CPU {}", cpu_id, core_id.id);
-				core_affinity::set_for_current(core_id); // This does not return an error if it fails :(
-			}
-			None => debug!("No affinity specified, not binding thread"),
+		let connection = match (gdb_port, gdb_socket) {
+			(_, Some(path)) => GdbConnection::accept_unix(&path),
+			(Some(port), None) => GdbConnection::accept_tcp(port),
+			(None, None) => unreachable!("GDB is not enabled"),
 		}
-
-		let mut cpu = self.create_cpu(cpu_id).unwrap();
-		cpu.init(self.get_entry_point()).unwrap();
-
-		let connection = wait_for_gdb_connection(self.gdb_port.unwrap()).unwrap();
+		.unwrap();
 		let debugger = gdbstub::GdbStub::new(connection);
-		let mut debuggable_vcpu = GdbUhyve::new(self, cpu);
 
 		match debugger
-			.run_blocking::<UhyveGdbEventLoop>(&mut debuggable_vcpu)
+			.run_blocking::<UhyveGdbEventLoop>(&mut debuggable_vm)
 			.unwrap()
 		{
 			DisconnectReason::TargetExited(code) => code.into(),
@@ -196,15 +877,107 @@ impl Uhyve {
 	}
 }
 
-fn wait_for_gdb_connection(port: u16) -> io::Result<TcpStream> {
-	let sockaddr = format!("localhost:{}", port);
-	eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
-	let sock = TcpListener::bind(sockaddr)?;
-	let (stream, addr) = sock.accept()?;
+/// The thread, which handles `SIGUSR1` (pausing and resuming or rebalancing of the vCPU threads),
+/// `SIGUSR2` (NMI injection or register dump) and `SIGTERM` (snapshot before stopping the VM).
+struct SignalThread {
+	thread: JoinHandle<()>,
+	stopping: Arc<AtomicBool>,
+	/// A signal of the set, which wakes the thread up to stop it
+	wake_signal: Signal,
+}
+
+impl SignalThread {
+	/// Blocks the signals of `sigset` in the current thread and handles them in a new one.
+	fn spawn(vm: Arc<Uhyve>, sigset: SigSet) -> nix::Result<Self> {
+		sigset.thread_block()?;
+
+		let wake_signal = if sigset.contains(Signal::SIGUSR2) {
+			Signal::SIGUSR2
+		} else if sigset.contains(Signal::SIGUSR1) {
+			Signal::SIGUSR1
+		} else {
+			Signal::SIGTERM
+		};
+		let stopping = Arc::new(AtomicBool::new(false));
+		let thread_stopping = stopping.clone();
+		let thread = thread::spawn(move || loop {
+			let signal = sigset.wait();
+			if thread_stopping.load(Ordering::SeqCst) {
+				break;
+			}
+			match signal {
+				Ok(Signal::SIGUSR1) if vm.pause_on_signal => {
+					let result = if vm.vcpus_paused() {
+						info!("Received SIGUSR1, resuming vCPUs");
+						vm.resume()
+					} else {
+						info!("Received SIGUSR1, pausing vCPUs");
+						vm.pause()
+					};
+					if let Err(err) = result {
+						error!("Unable to pause or resume the vCPUs: {}", err);
+					}
+				}
+				Ok(Signal::SIGUSR1) => {
+					info!("Received SIGUSR1, rebalancing vCPUs");
+					vm.rebalance_vcpus();
+				}
+				Ok(Signal::SIGUSR2) if vm.registers_on_signal => {
+					info!("Received SIGUSR2, dumping the registers of the vCPUs");
+					match vm.dump_registers() {
+						Ok(dumps) => {
+							for (id, registers) in dumps.iter().enumerate() {
+								eprintln!("CPU {}:\n{}", id, registers);
+							}
+						}
+						Err(err) => error!("Unable to dump the registers: {}", err),
+					}
+				}
+				Ok(Signal::SIGUSR2) => {
+					info!("Received SIGUSR2, injecting NMI into vCPU 0");
+					if let Err(err) = vm.inject_nmi(0) {
+						error!("Unable to inject NMI: {}", err);
+					}
+				}
+				Ok(Signal::SIGTERM) => {
+					if let Some(path) = &vm.snapshot_path {
+						info!("Received SIGTERM, saving the VM to {}", path.display());
+						if let Err(err) = vm.snapshot(path) {
+							error!("Unable to save the VM: {}", err);
+						}
+					}
+					vm.shutdown();
+				}
+				Ok(signal) => debug!("Ignoring {}", signal),
+				Err(err) => {
+					error!("Waiting for signals failed: {}", err);
+					break;
+				}
+			}
+		});
+
+		Ok(Self {
+			thread,
+			stopping,
+			wake_signal,
+		})
+	}
 
-	// Blocks until a GDB client connects via TCP.
-	// i.e: Running `target remote localhost:<port>` from the GDB prompt.
+	/// Stops the thread, so that it releases its reference to the VM.
+	fn stop(self) {
+		self.stopping.store(true, Ordering::SeqCst);
+		if let Err(err) = pthread_kill(self.thread.as_pthread_t(), self.wake_signal) {
+			warn!("Unable to stop the signal thread: {}", err);
+			return;
+		}
+		self.thread.join().unwrap();
+	}
+}
 
-	eprintln!("Debugger connected from {}", addr);
-	Ok(stream) // `TcpStream` implements `gdbstub::Connection`
+/// Returns the host CPUs, which are currently online.
+fn online_cpus() -> io::Result<Vec<usize>> {
+	let online = std::fs::read_to_string("/sys/devices/system/cpu/online")?;
+	parse_ranges(online.trim().split(','))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|_| io::ErrorKind::InvalidData.into())
 }
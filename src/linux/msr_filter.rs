@@ -0,0 +1,217 @@
+//! Forwarding of MSRs to the host, see `--allow-msr`.
+//!
+//! KVM injects a `#GP`, if the guest accesses an MSR, which KVM does not emulate, e.g.,
+//! `IA32_ENERGY_PERF_BIAS`. With `KVM_CAP_X86_USER_SPACE_MSR`, these accesses exit to uhyve,
+//! which reads or writes the MSR of the host CPU through `/dev/cpu/N/msr` (kernel module `msr`).
+//! If the kernel supports `KVM_X86_SET_MSR_FILTER` (Linux 5.10+), the allowed MSRs are filtered,
+//! so that they also exit, if KVM emulates them, e.g., `MSR_PLATFORM_INFO`. Otherwise, only the
+//! allowed MSRs, which KVM does not know, reach the host.
+
+use std::{
+	fs::{File, OpenOptions},
+	io,
+	os::unix::{fs::FileExt, io::AsRawFd},
+	path::Path,
+};
+
+use kvm_bindings::kvm_enable_cap;
+use kvm_ioctls::VmFd;
+
+use crate::vm::HypervisorResult;
+
+/// Not yet exported by `kvm-bindings`.
+const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+/// Accesses to MSRs, which KVM does not know, exit to user space
+const KVM_MSR_EXIT_REASON_UNKNOWN: u64 = 1 << 1;
+/// Accesses to MSRs, which are denied by the filter, exit to user space
+const KVM_MSR_EXIT_REASON_FILTER: u64 = 1 << 2;
+/// `_IOW(KVMIO, 0xc6, struct kvm_msr_filter)`
+const KVM_X86_SET_MSR_FILTER: libc::c_ulong = 0x4188_aec6;
+pub const KVM_EXIT_X86_RDMSR: u32 = 29;
+pub const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+const KVM_MSR_FILTER_READ: u32 = 1 << 0;
+const KVM_MSR_FILTER_WRITE: u32 = 1 << 1;
+const KVM_MSR_FILTER_DEFAULT_ALLOW: u32 = 0;
+const KVM_MSR_FILTER_MAX_RANGES: usize = 16;
+/// The maximal number of MSRs of a range
+const KVM_MSR_FILTER_MAX_RANGE_MSRS: u32 = 0x600 * 8;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct KvmMsrFilterRange {
+	flags: u32,
+	nmsrs: u32,
+	base: u32,
+	/// Address of a bitmap, whose set bits let KVM handle the MSR and whose cleared bits let the
+	/// access exit to user space
+	bitmap: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct KvmMsrFilter {
+	flags: u32,
+	ranges: [KvmMsrFilterRange; KVM_MSR_FILTER_MAX_RANGES],
+}
+
+/// The `msr` member of the exit union of `kvm_run`, which KVM reads again on the next `KVM_RUN`.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct KvmRunMsr {
+	/// Injects a `#GP`, if set
+	pub error: u8,
+	pad: [u8; 7],
+	pub reason: u32,
+	pub index: u32,
+	pub data: u64,
+}
+
+/// Splits the sorted `msrs` into the bitmaps of filter ranges, in which only `msrs` are cleared.
+fn filter_ranges(msrs: &[u32]) -> Vec<(u32, u32, Vec<u8>)> {
+	let mut ranges: Vec<(u32, u32, Vec<u8>)> = Vec::new();
+	for &msr in msrs {
+		match ranges.last_mut() {
+			Some((base, nmsrs, bitmap)) if msr - *base < KVM_MSR_FILTER_MAX_RANGE_MSRS => {
+				*nmsrs = msr - *base + 1;
+				bitmap.resize((*nmsrs as usize + 7) / 8, 0xff);
+			}
+			_ => ranges.push((msr, 1, vec![0xff])),
+		}
+		let (base, _, bitmap) = ranges.last_mut().unwrap();
+		let bit = (msr - *base) as usize;
+		bitmap[bit / 8] &= !(1 << (bit % 8));
+	}
+	ranges
+}
+
+/// Lets the accesses of the guest to `msrs` exit to user space, where they are forwarded by
+/// [`handle_exit`].
+///
+/// Returns `false`, if the kernel has no MSR filter and only unknown MSRs exit. All other exits
+/// of unknown MSRs inject a `#GP` like KVM.
+pub fn enable(vm: &VmFd, msrs: &[u32]) -> HypervisorResult<bool> {
+	if !Path::new("/dev/cpu/0/msr").exists() {
+		warn!(
+			"/dev/cpu/0/msr does not exist, the kernel module msr has to be loaded for --allow-msr"
+		);
+	}
+
+	let mut msrs = msrs.to_vec();
+	msrs.sort_unstable();
+	msrs.dedup();
+	let ranges = filter_ranges(&msrs);
+	if ranges.len() > KVM_MSR_FILTER_MAX_RANGES {
+		error!(
+			"The MSRs of --allow-msr need more than {} filter ranges",
+			KVM_MSR_FILTER_MAX_RANGES
+		);
+		return Err(kvm_ioctls::Error::new(libc::EINVAL));
+	}
+
+	// the allowed MSRs, which KVM does not know, exit also without the filter
+	let mut cap = kvm_enable_cap {
+		cap: KVM_CAP_X86_USER_SPACE_MSR,
+		..Default::default()
+	};
+	cap.args[0] = KVM_MSR_EXIT_REASON_UNKNOWN | KVM_MSR_EXIT_REASON_FILTER;
+	vm.enable_cap(&cap)?;
+
+	let mut filter = KvmMsrFilter {
+		flags: KVM_MSR_FILTER_DEFAULT_ALLOW,
+		..Default::default()
+	};
+	for (range, (base, nmsrs, bitmap)) in filter.ranges.iter_mut().zip(&ranges) {
+		*range = KvmMsrFilterRange {
+			flags: KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE,
+			nmsrs: *nmsrs,
+			base: *base,
+			bitmap: bitmap.as_ptr() as u64,
+		};
+	}
+	// KVM copies the bitmaps
+	let filtered = unsafe {
+		libc::ioctl(
+			vm.as_raw_fd(),
+			KVM_X86_SET_MSR_FILTER,
+			&filter as *const KvmMsrFilter,
+		)
+	} == 0;
+	Ok(filtered)
+}
+
+/// Opens the MSRs of the host CPU, on which the calling thread runs.
+fn host_msrs(write: bool) -> io::Result<File> {
+	let cpu = unsafe { libc::sched_getcpu() };
+	if cpu < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	OpenOptions::new()
+		.read(!write)
+		.write(write)
+		.open(format!("/dev/cpu/{}/msr", cpu))
+}
+
+/// Forwards the access of a `KVM_EXIT_X86_RDMSR` or `KVM_EXIT_X86_WRMSR` to the host, if `allowed`
+/// contains the MSR, and injects a `#GP` otherwise.
+pub fn handle_exit(msr: &mut KvmRunMsr, write: bool, allowed: &[u32]) {
+	if !allowed.contains(&msr.index) {
+		debug!("Inject #GP for an access to the MSR {:#x}", msr.index);
+		msr.error = 1;
+		return;
+	}
+
+	let result = if write {
+		host_msrs(true)
+			.and_then(|file| file.write_all_at(&msr.data.to_le_bytes(), msr.index.into()))
+	} else {
+		let mut data = [0; 8];
+		host_msrs(false)
+			.and_then(|file| file.read_exact_at(&mut data, msr.index.into()))
+			.map(|()| msr.data = u64::from_le_bytes(data))
+	};
+	msr.error = match result {
+		Ok(()) => 0,
+		Err(err) => {
+			warn!(
+				"Unable to access the MSR {:#x} of the host: {}",
+				msr.index, err
+			);
+			1
+		}
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_filter_layout() {
+		assert_eq!(std::mem::size_of::<KvmMsrFilter>(), 0x188);
+		assert_eq!(std::mem::size_of::<KvmRunMsr>(), 24);
+	}
+
+	#[test]
+	fn test_filter_ranges() {
+		let ranges = filter_ranges(&[0xce, 0xcf, 0x1b0, 0xc001_0015]);
+		assert_eq!(ranges.len(), 2);
+		let (base, nmsrs, bitmap) = &ranges[0];
+		assert_eq!((*base, *nmsrs), (0xce, 0x1b0 - 0xce + 1));
+		assert_eq!(bitmap.len(), (0x1b0 - 0xce) / 8 + 1);
+		assert_eq!(bitmap[0], 0xfc);
+		assert!(bitmap[1..bitmap.len() - 1].iter().all(|&byte| byte == 0xff));
+		assert_eq!(bitmap[bitmap.len() - 1] & (1 << ((0x1b0 - 0xce) % 8)), 0);
+		assert_eq!(ranges[1], (0xc001_0015, 1, vec![0xfe]));
+	}
+
+	#[test]
+	fn test_deny_unknown_msr() {
+		let mut msr = KvmRunMsr {
+			index: 0x1b0,
+			..Default::default()
+		};
+		handle_exit(&mut msr, false, &[0xce]);
+		assert_eq!(msr.error, 1);
+	}
+}
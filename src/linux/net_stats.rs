@@ -0,0 +1,375 @@
+//! Statistics of the traffic of the uhyve network interface.
+//!
+//! Besides the counters, the sizes of the packets are counted in buckets, which tell, whether a
+//! workload is dominated by small or by large packets. The histograms can be written in the text
+//! format of Prometheus, e.g., for the textfile collector of the node exporter.
+
+use std::{
+	fmt::Write as _,
+	fs::{self, File},
+	io::{self, Write},
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		mpsc::{self, RecvTimeoutError, Sender},
+		Arc,
+	},
+	thread::{self, JoinHandle},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const CSV_HEADER: &str =
+	"timestamp,bytes_sent,bytes_received,packets_sent,packets_received,send_errors,receive_errors";
+
+/// Exclusive upper bounds of the buckets of the packet sizes, the last bucket is unbounded.
+pub const PACKET_SIZE_BUCKETS: [u64; 4] = [64, 512, 1500, 9000];
+
+/// Numbers of packets per size bucket, see [`PACKET_SIZE_BUCKETS`].
+#[derive(Debug, Default)]
+struct PacketSizeHistogram {
+	buckets: [AtomicU64; PACKET_SIZE_BUCKETS.len() + 1],
+}
+
+impl PacketSizeHistogram {
+	fn record(&self, len: usize) {
+		let bucket = PACKET_SIZE_BUCKETS
+			.iter()
+			.position(|&bound| (len as u64) < bound)
+			.unwrap_or(PACKET_SIZE_BUCKETS.len());
+		self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn snapshot(&self) -> PacketSizes {
+		let mut sizes = PacketSizes::default();
+		for (packets, bucket) in sizes.0.iter_mut().zip(&self.buckets) {
+			*packets = bucket.load(Ordering::Relaxed);
+		}
+		sizes
+	}
+}
+
+/// The numbers of packets per size bucket at one point in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PacketSizes(pub [u64; PACKET_SIZE_BUCKETS.len() + 1]);
+
+impl PacketSizes {
+	/// Returns the bucket, which contains the `percentile`th packet size, as range of sizes.
+	pub fn percentile(&self, percentile: u64) -> Option<(u64, Option<u64>)> {
+		let count: u64 = self.0.iter().sum();
+		if count == 0 {
+			return None;
+		}
+		// the rank of the percentile, rounded up
+		let rank = (count * percentile + 99) / 100;
+		let mut seen = 0;
+		let bucket = self
+			.0
+			.iter()
+			.position(|&packets| {
+				seen += packets;
+				seen >= rank.max(1)
+			})
+			.unwrap();
+		let start = bucket.checked_sub(1).map_or(0, |i| PACKET_SIZE_BUCKETS[i]);
+		Some((start, PACKET_SIZE_BUCKETS.get(bucket).copied()))
+	}
+
+	/// Formats the 50th, 95th and 99th percentile, e.g. `p50 [64, 512)`.
+	fn percentiles(&self) -> String {
+		[50, 95, 99]
+			.into_iter()
+			.map(|percentile| match self.percentile(percentile) {
+				Some((start, Some(end))) => format!("p{} [{}, {})", percentile, start, end),
+				Some((start, None)) => format!("p{} [{}, ∞)", percentile, start),
+				None => format!("p{} -", percentile),
+			})
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+
+	/// Formats the buckets as Prometheus histogram `name` with the total `sum` of the sizes.
+	fn prometheus(&self, name: &str, help: &str, sum: u64) -> String {
+		let mut text = format!(
+			"# HELP {name} {help}\n# TYPE {name} histogram\n",
+			name = name,
+			help = help
+		);
+		let mut cumulative = 0;
+		for (i, packets) in self.0.iter().enumerate() {
+			cumulative += packets;
+			// the sizes are integers, so a bucket of sizes below `bound` ends at `bound - 1`
+			let le = PACKET_SIZE_BUCKETS
+				.get(i)
+				.map_or_else(|| "+Inf".to_string(), |bound| (bound - 1).to_string());
+			writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative).unwrap();
+		}
+		writeln!(text, "{}_sum {}", name, sum).unwrap();
+		writeln!(text, "{}_count {}", name, cumulative).unwrap();
+		text
+	}
+}
+
+/// Counters of the network interface, which are updated by its reader and writer threads.
+#[derive(Debug, Default)]
+pub struct NetworkStats {
+	bytes_sent: AtomicU64,
+	bytes_received: AtomicU64,
+	packets_sent: AtomicU64,
+	packets_received: AtomicU64,
+	send_errors: AtomicU64,
+	receive_errors: AtomicU64,
+	sent_sizes: PacketSizeHistogram,
+	received_sizes: PacketSizeHistogram,
+}
+
+impl NetworkStats {
+	/// Records a packet sent to the TAP device.
+	pub fn record_send(&self, result: &io::Result<usize>) {
+		match result {
+			Ok(len) => {
+				self.bytes_sent.fetch_add(*len as u64, Ordering::Relaxed);
+				self.packets_sent.fetch_add(1, Ordering::Relaxed);
+				self.sent_sizes.record(*len);
+			}
+			Err(_) => {
+				self.send_errors.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+
+	/// Records a packet received from the TAP device.
+	pub fn record_receive(&self, result: &io::Result<usize>) {
+		match result {
+			Ok(len) => {
+				self.bytes_received
+					.fetch_add(*len as u64, Ordering::Relaxed);
+				self.packets_received.fetch_add(1, Ordering::Relaxed);
+				self.received_sizes.record(*len);
+			}
+			Err(_) => {
+				self.receive_errors.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+	}
+
+	pub fn snapshot(&self) -> NetworkStatsSnapshot {
+		NetworkStatsSnapshot {
+			bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+			bytes_received: self.bytes_received.load(Ordering::Relaxed),
+			packets_sent: self.packets_sent.load(Ordering::Relaxed),
+			packets_received: self.packets_received.load(Ordering::Relaxed),
+			send_errors: self.send_errors.load(Ordering::Relaxed),
+			receive_errors: self.receive_errors.load(Ordering::Relaxed),
+			sent_sizes: self.sent_sizes.snapshot(),
+			received_sizes: self.received_sizes.snapshot(),
+		}
+	}
+}
+
+/// The values of [`NetworkStats`] at one point in time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkStatsSnapshot {
+	pub bytes_sent: u64,
+	pub bytes_received: u64,
+	pub packets_sent: u64,
+	pub packets_received: u64,
+	pub send_errors: u64,
+	pub receive_errors: u64,
+	pub sent_sizes: PacketSizes,
+	pub received_sizes: PacketSizes,
+}
+
+impl NetworkStatsSnapshot {
+	fn counters(&self) -> [(&'static str, u64); 6] {
+		[
+			("bytes sent", self.bytes_sent),
+			("bytes received", self.bytes_received),
+			("packets sent", self.packets_sent),
+			("packets received", self.packets_received),
+			("send errors", self.send_errors),
+			("receive errors", self.receive_errors),
+		]
+	}
+
+	/// Formats the counters as a table with the change since `previous` and the total.
+	pub fn table(&self, previous: &Self) -> String {
+		let mut table = format!("{:<18}{:>16}{:>16}\n", "Network", "delta", "total");
+		for ((name, total), (_, old)) in self.counters().into_iter().zip(previous.counters()) {
+			writeln!(table, "{:<18}{:>16}{:>16}", name, total - old, total).unwrap();
+		}
+		table
+	}
+
+	/// Formats the counters as a line of CSV, see [`CSV_HEADER`].
+	pub fn csv_record(&self, timestamp: Duration) -> String {
+		let mut record = format!("{}.{:03}", timestamp.as_secs(), timestamp.subsec_millis());
+		for (_, value) in self.counters() {
+			write!(record, ",{}", value).unwrap();
+		}
+		record
+	}
+
+	/// Formats the size histograms in the text format of Prometheus.
+	pub fn prometheus(&self) -> String {
+		self.sent_sizes.prometheus(
+			"uhyve_network_tx_packet_size_bytes",
+			"Sizes of the packets sent to the TUN/TAP device",
+			self.bytes_sent,
+		) + &self.received_sizes.prometheus(
+			"uhyve_network_rx_packet_size_bytes",
+			"Sizes of the packets received from the TUN/TAP device",
+			self.bytes_received,
+		)
+	}
+}
+
+/// Replaces the metrics file at once, so that a collector never reads a partial file.
+fn write_metrics(path: &Path, metrics: &str) -> io::Result<()> {
+	let mut tmp = path.as_os_str().to_owned();
+	tmp.push(".tmp");
+	fs::write(&tmp, metrics)?;
+	fs::rename(&tmp, path)
+}
+
+/// Periodically prints and logs the network statistics until it is stopped.
+#[derive(Debug)]
+pub struct NetworkStatsReporter {
+	stop: Sender<()>,
+	thread: JoinHandle<()>,
+	stats: Arc<NetworkStats>,
+}
+
+impl NetworkStatsReporter {
+	/// Prints the statistics every `interval`, if `verbose` is set, and appends them to `log`.
+	/// The size histograms are written to `metrics`.
+	pub fn start(
+		stats: Arc<NetworkStats>,
+		interval: Duration,
+		verbose: bool,
+		mut log: Option<File>,
+		metrics: Option<PathBuf>,
+	) -> Self {
+		let (stop, rx) = mpsc::channel();
+		let reporter_stats = stats.clone();
+		let thread = thread::spawn(move || {
+			if let Some(file) = &mut log {
+				if let Err(err) = writeln!(file, "{}", CSV_HEADER) {
+					warn!("Unable to write the network statistics log: {}", err);
+					log = None;
+				}
+			}
+
+			let mut previous = NetworkStatsSnapshot::default();
+			while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+				let current = reporter_stats.snapshot();
+				if verbose {
+					print!("{}", current.table(&previous));
+				}
+				if let Some(file) = &mut log {
+					let timestamp = SystemTime::now()
+						.duration_since(UNIX_EPOCH)
+						.unwrap_or_default();
+					if let Err(err) = writeln!(file, "{}", current.csv_record(timestamp)) {
+						warn!("Unable to write the network statistics log: {}", err);
+						log = None;
+					}
+				}
+				if let Some(path) = &metrics {
+					if let Err(err) = write_metrics(path, &current.prometheus()) {
+						warn!("Unable to write the network metrics: {}", err);
+					}
+				}
+				previous = current;
+			}
+		});
+
+		Self {
+			stop,
+			thread,
+			stats,
+		}
+	}
+
+	/// Stops reporting and prints the final statistics.
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		self.thread.join().unwrap();
+		let total = self.stats.snapshot();
+		print!("{}", total.table(&NetworkStatsSnapshot::default()));
+		println!("{:<18}{}", "sent sizes", total.sent_sizes.percentiles());
+		println!(
+			"{:<18}{}",
+			"received sizes",
+			total.received_sizes.percentiles()
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_network_stats() {
+		let stats = NetworkStats::default();
+		stats.record_send(&Ok(1500));
+		stats.record_send(&Ok(60));
+		stats.record_receive(&Ok(90));
+		stats.record_receive(&Err(io::ErrorKind::Other.into()));
+
+		let snapshot = stats.snapshot();
+		assert_eq!(
+			snapshot,
+			NetworkStatsSnapshot {
+				bytes_sent: 1560,
+				bytes_received: 90,
+				packets_sent: 2,
+				packets_received: 1,
+				send_errors: 0,
+				receive_errors: 1,
+				sent_sizes: PacketSizes([1, 0, 0, 1, 0]),
+				received_sizes: PacketSizes([0, 1, 0, 0, 0]),
+			}
+		);
+		assert_eq!(
+			snapshot.csv_record(Duration::from_millis(1_500)),
+			"1.500,1560,90,2,1,0,1"
+		);
+		assert!(snapshot
+			.table(&NetworkStatsSnapshot {
+				bytes_sent: 1500,
+				..Default::default()
+			})
+			.contains(&format!("{:<18}{:>16}{:>16}", "bytes sent", 60, 1560)));
+	}
+
+	#[test]
+	fn test_packet_size_percentiles() {
+		assert_eq!(PacketSizes::default().percentile(50), None);
+
+		let sizes = PacketSizes([50, 40, 0, 9, 1]);
+		assert_eq!(sizes.percentile(50), Some((0, Some(64))));
+		assert_eq!(sizes.percentile(95), Some((1500, Some(9000))));
+		assert_eq!(sizes.percentile(99), Some((1500, Some(9000))));
+		assert_eq!(sizes.percentile(100), Some((9000, None)));
+		assert_eq!(
+			sizes.percentiles(),
+			"p50 [0, 64), p95 [1500, 9000), p99 [1500, 9000)"
+		);
+	}
+
+	#[test]
+	fn test_prometheus_histogram() {
+		let stats = NetworkStats::default();
+		for len in [60, 64, 1500, 9000] {
+			stats.record_send(&Ok(len));
+		}
+		let text = stats.snapshot().prometheus();
+		assert!(text.contains("# TYPE uhyve_network_tx_packet_size_bytes histogram\n"));
+		assert!(text.contains("uhyve_network_tx_packet_size_bytes_bucket{le=\"63\"} 1\n"));
+		assert!(text.contains("uhyve_network_tx_packet_size_bytes_bucket{le=\"1499\"} 2\n"));
+		assert!(text.contains("uhyve_network_tx_packet_size_bytes_bucket{le=\"+Inf\"} 4\n"));
+		assert!(text.contains("uhyve_network_tx_packet_size_bytes_sum 10624\n"));
+		assert!(text.contains("uhyve_network_rx_packet_size_bytes_count 0\n"));
+	}
+}
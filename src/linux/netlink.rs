@@ -0,0 +1,285 @@
+//! Minimal rtnetlink client for setting up the bridge and the TUN devices of the network
+//! interfaces.
+//!
+//! Only the few link and address operations, which uhyve needs, are implemented. Each request is
+//! sent on its own `NETLINK_ROUTE` socket and waits for the acknowledgement of the kernel.
+
+use std::{
+	ffi::CString,
+	io, mem,
+	net::Ipv4Addr,
+	os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+/// Not exported by `libc` on all versions.
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+const NLMSG_HDR_LEN: usize = mem::size_of::<libc::nlmsghdr>();
+const IFINFOMSG_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+const RTA_HDR_LEN: usize = 4;
+
+fn nla_align(len: usize) -> usize {
+	(len + 3) & !3
+}
+
+/// A `RTM_NEWLINK`, `RTM_DELLINK` or `RTM_NEWADDR` request.
+struct LinkRequest {
+	buf: Vec<u8>,
+}
+
+impl LinkRequest {
+	fn with_body(msg_type: u16, flags: u16, body_len: usize) -> Self {
+		let mut buf = vec![0; NLMSG_HDR_LEN + body_len];
+		buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+		buf[6..8].copy_from_slice(
+			&(libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16 | flags).to_ne_bytes(),
+		);
+		buf[8..12].copy_from_slice(&1u32.to_ne_bytes());
+		Self { buf }
+	}
+
+	fn new(msg_type: u16, flags: u16, index: i32) -> Self {
+		let mut request = Self::with_body(msg_type, flags, IFINFOMSG_LEN);
+		// struct ifinfomsg: ifi_family = AF_UNSPEC, ifi_type = 0
+		request.buf[NLMSG_HDR_LEN + 4..NLMSG_HDR_LEN + 8].copy_from_slice(&index.to_ne_bytes());
+		request
+	}
+
+	/// Starts a request for an IPv4 address of the interface `index`.
+	fn new_address(msg_type: u16, flags: u16, index: i32, prefix_len: u8) -> Self {
+		let mut request = Self::with_body(msg_type, flags, IFADDRMSG_LEN);
+		// struct ifaddrmsg: ifa_family, ifa_prefixlen, ifa_flags = 0, ifa_scope = RT_SCOPE_UNIVERSE
+		request.buf[NLMSG_HDR_LEN] = libc::AF_INET as u8;
+		request.buf[NLMSG_HDR_LEN + 1] = prefix_len;
+		request.buf[NLMSG_HDR_LEN + 4..NLMSG_HDR_LEN + 8].copy_from_slice(&index.to_ne_bytes());
+		request
+	}
+
+	fn link_flags(mut self, flags: u32, change: u32) -> Self {
+		let offset = NLMSG_HDR_LEN + 8;
+		self.buf[offset..offset + 4].copy_from_slice(&flags.to_ne_bytes());
+		self.buf[offset + 4..offset + 8].copy_from_slice(&change.to_ne_bytes());
+		self
+	}
+
+	fn attr(mut self, attr_type: u16, data: &[u8]) -> Self {
+		self.push_attr(attr_type, data);
+		self
+	}
+
+	fn push_attr(&mut self, attr_type: u16, data: &[u8]) {
+		let len = RTA_HDR_LEN + data.len();
+		self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+		self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+		self.buf.extend_from_slice(data);
+		self.buf.resize(nla_align(self.buf.len()), 0);
+	}
+
+	fn nested_attr(mut self, attr_type: u16, f: impl FnOnce(&mut Self)) -> Self {
+		let start = self.buf.len();
+		self.push_attr(attr_type, &[]);
+		f(&mut self);
+		let len = (self.buf.len() - start) as u16;
+		self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+		self
+	}
+
+	fn finish(mut self) -> Vec<u8> {
+		let len = self.buf.len() as u32;
+		self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+		self.buf
+	}
+
+	/// Sends the request and returns the error of the acknowledgement.
+	fn send(self) -> io::Result<()> {
+		let msg = self.finish();
+
+		let fd = unsafe {
+			libc::socket(
+				libc::AF_NETLINK,
+				libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+				libc::NETLINK_ROUTE,
+			)
+		};
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		// SAFETY: `fd` has just been created and is not owned by anything else.
+		let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+		let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+		addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+		let sent = unsafe {
+			libc::sendto(
+				socket.as_raw_fd(),
+				msg.as_ptr() as *const libc::c_void,
+				msg.len(),
+				0,
+				&addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+				mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+			)
+		};
+		if sent < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		let mut reply = [0u8; 4096];
+		let received = unsafe {
+			libc::recv(
+				socket.as_raw_fd(),
+				reply.as_mut_ptr() as *mut libc::c_void,
+				reply.len(),
+				0,
+			)
+		};
+		if received < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		parse_ack(&reply[..received as usize])
+	}
+}
+
+/// Parses the `NLMSG_ERROR` message, which acknowledges a request.
+fn parse_ack(reply: &[u8]) -> io::Result<()> {
+	if reply.len() < NLMSG_HDR_LEN + mem::size_of::<i32>() {
+		return Err(io::ErrorKind::InvalidData.into());
+	}
+	let msg_type = u16::from_ne_bytes([reply[4], reply[5]]);
+	if msg_type != libc::NLMSG_ERROR as u16 {
+		return Err(io::ErrorKind::InvalidData.into());
+	}
+	let error = i32::from_ne_bytes(reply[NLMSG_HDR_LEN..NLMSG_HDR_LEN + 4].try_into().unwrap());
+	match error {
+		0 => Ok(()),
+		error => Err(io::Error::from_raw_os_error(-error)),
+	}
+}
+
+fn c_name(name: &str) -> io::Result<CString> {
+	CString::new(name).map_err(|_| io::ErrorKind::InvalidInput.into())
+}
+
+/// Returns the index of a network interface or `None`, if it does not exist.
+pub fn link_index(name: &str) -> io::Result<Option<i32>> {
+	let name = c_name(name)?;
+	let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+	Ok((index != 0).then(|| index as i32))
+}
+
+fn existing_link_index(name: &str) -> io::Result<i32> {
+	link_index(name)?.ok_or_else(|| io::Error::from_raw_os_error(libc::ENODEV))
+}
+
+/// Creates a new bridge, like `ip link add <name> type bridge`.
+pub fn create_bridge(name: &str) -> io::Result<()> {
+	let name = c_name(name)?;
+	LinkRequest::new(
+		libc::RTM_NEWLINK,
+		(libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+		0,
+	)
+	.attr(IFLA_IFNAME, name.as_bytes_with_nul())
+	.nested_attr(IFLA_LINKINFO, |request| {
+		request.push_attr(IFLA_INFO_KIND, b"bridge")
+	})
+	.send()
+}
+
+/// Deletes a network interface, like `ip link del <name>`.
+pub fn delete_link(name: &str) -> io::Result<()> {
+	LinkRequest::new(libc::RTM_DELLINK, 0, existing_link_index(name)?).send()
+}
+
+/// Sets the master of a network interface, like `ip link set <name> master <master>`.
+///
+/// Without `master`, the interface is detached, like `ip link set <name> nomaster`.
+pub fn set_master(name: &str, master: Option<&str>) -> io::Result<()> {
+	let master_index = master.map(existing_link_index).transpose()?.unwrap_or(0);
+	LinkRequest::new(libc::RTM_NEWLINK, 0, existing_link_index(name)?)
+		.attr(IFLA_MASTER, &master_index.to_ne_bytes())
+		.send()
+}
+
+/// Brings a network interface up, like `ip link set <name> up`.
+pub fn set_up(name: &str) -> io::Result<()> {
+	let up = libc::IFF_UP as u32;
+	LinkRequest::new(libc::RTM_NEWLINK, 0, existing_link_index(name)?)
+		.link_flags(up, up)
+		.send()
+}
+
+/// Adds an IPv4 address to a network interface, like `ip addr add <addr>/<prefix_len> dev <name>`.
+pub fn add_address(name: &str, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+	LinkRequest::new_address(
+		libc::RTM_NEWADDR,
+		(libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+		existing_link_index(name)?,
+		prefix_len,
+	)
+	.attr(IFA_LOCAL, &addr.octets())
+	.attr(IFA_ADDRESS, &addr.octets())
+	.send()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_link_request() {
+		let msg = LinkRequest::new(libc::RTM_NEWLINK, 0, 7)
+			.attr(IFLA_IFNAME, b"br0\0")
+			.nested_attr(IFLA_LINKINFO, |request| {
+				request.push_attr(IFLA_INFO_KIND, b"bridge")
+			})
+			.finish();
+
+		// header, ifinfomsg, "br0\0" (8 bytes), nested "bridge" (4 + 12 bytes)
+		assert_eq!(msg.len(), 16 + 16 + 8 + 16);
+		assert_eq!(u32::from_ne_bytes(msg[0..4].try_into().unwrap()), 56);
+		assert_eq!(i32::from_ne_bytes(msg[20..24].try_into().unwrap()), 7);
+		assert_eq!(u16::from_ne_bytes(msg[40..42].try_into().unwrap()), 16);
+		assert_eq!(
+			u16::from_ne_bytes(msg[42..44].try_into().unwrap()),
+			IFLA_LINKINFO
+		);
+		assert_eq!(&msg[48..54], b"bridge");
+	}
+
+	#[test]
+	fn test_address_request() {
+		let msg = LinkRequest::new_address(libc::RTM_NEWADDR, 0, 3, 30)
+			.attr(IFA_LOCAL, &[10, 0, 0, 1])
+			.finish();
+
+		// header, ifaddrmsg, IFA_LOCAL (4 + 4 bytes)
+		assert_eq!(msg.len(), 16 + 8 + 8);
+		assert_eq!(msg[16], libc::AF_INET as u8);
+		assert_eq!(msg[17], 30);
+		assert_eq!(i32::from_ne_bytes(msg[20..24].try_into().unwrap()), 3);
+		assert_eq!(
+			u16::from_ne_bytes(msg[26..28].try_into().unwrap()),
+			IFA_LOCAL
+		);
+		assert_eq!(&msg[28..32], &[10, 0, 0, 1]);
+	}
+
+	#[test]
+	fn test_parse_ack() {
+		let mut reply = vec![0u8; NLMSG_HDR_LEN + 4];
+		reply[4..6].copy_from_slice(&(libc::NLMSG_ERROR as u16).to_ne_bytes());
+		assert!(parse_ack(&reply).is_ok());
+
+		reply[NLMSG_HDR_LEN..].copy_from_slice(&(-libc::EPERM).to_ne_bytes());
+		assert_eq!(
+			parse_ack(&reply).unwrap_err().raw_os_error(),
+			Some(libc::EPERM)
+		);
+	}
+}
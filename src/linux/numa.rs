@@ -0,0 +1,96 @@
+//! Placement of the guest memory on the NUMA nodes of the host, see `--numa`.
+//!
+//! The memory range of every guest node is bound with `mbind` to the host nodes of its CPUs, so
+//! that vCPUs, which are pinned to these CPUs, access local memory. `mbind` is not wrapped by
+//! `libc`, so it is invoked as raw syscall.
+
+use std::{fs, io, path::Path};
+
+/// `MPOL_BIND` of `include/uapi/linux/mempolicy.h`
+const MPOL_BIND: libc::c_ulong = 2;
+/// Moves pages, which have already been faulted in, to the nodes of the policy
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+const SYSFS_CPU: &str = "/sys/devices/system/cpu";
+
+/// Returns the NUMA node of a host CPU, which sysfs links as `nodeN` into the directory of the CPU.
+pub fn host_node_of_cpu(cpu: usize) -> io::Result<usize> {
+	node_in_dir(&Path::new(SYSFS_CPU).join(format!("cpu{}", cpu)))
+}
+
+fn node_in_dir(dir: &Path) -> io::Result<usize> {
+	for entry in fs::read_dir(dir)? {
+		let name = entry?.file_name();
+		if let Some(node) = name
+			.to_str()
+			.and_then(|name| name.strip_prefix("node"))
+			.and_then(|node| node.parse().ok())
+		{
+			return Ok(node);
+		}
+	}
+	Err(io::Error::new(
+		io::ErrorKind::NotFound,
+		format!("{} belongs to no NUMA node", dir.display()),
+	))
+}
+
+/// Builds the bitmask of `nodes` for `mbind`.
+fn node_mask(nodes: &[usize]) -> Vec<libc::c_ulong> {
+	let bits = libc::c_ulong::BITS as usize;
+	let len = nodes.iter().max().map_or(0, |max| max / bits + 1);
+	let mut mask = vec![0; len];
+	for node in nodes {
+		mask[node / bits] |= 1 << (node % bits);
+	}
+	mask
+}
+
+/// Restricts the pages of `len` bytes at `addr` to the host NUMA nodes `nodes`.
+///
+/// # Safety
+///
+/// `addr` has to be page aligned and the range has to be mapped.
+pub unsafe fn bind(addr: usize, len: usize, nodes: &[usize]) -> io::Result<()> {
+	let mask = node_mask(nodes);
+	let ret = libc::syscall(
+		libc::SYS_mbind,
+		addr,
+		len,
+		MPOL_BIND,
+		mask.as_ptr(),
+		// the kernel ignores the last bit of `maxnode`
+		mask.len() * libc::c_ulong::BITS as usize + 1,
+		MPOL_MF_MOVE,
+	);
+	if ret < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_fs::TempDir;
+
+	#[test]
+	fn test_node_mask() {
+		assert!(node_mask(&[]).is_empty());
+		assert_eq!(node_mask(&[0, 2]), [0b101]);
+		let bits = libc::c_ulong::BITS as usize;
+		assert_eq!(node_mask(&[1, bits + 3]), [0b10, 0b1000]);
+	}
+
+	#[test]
+	fn test_node_in_dir() {
+		let dir = TempDir::new().unwrap();
+		fs::create_dir(dir.path().join("cache")).unwrap();
+		assert_eq!(
+			node_in_dir(dir.path()).unwrap_err().kind(),
+			io::ErrorKind::NotFound
+		);
+		fs::create_dir(dir.path().join("node3")).unwrap();
+		assert_eq!(node_in_dir(dir.path()).unwrap(), 3);
+	}
+}
@@ -0,0 +1,172 @@
+//! Capturing of the guest's network traffic in a libpcap file, see `--pcap`.
+//!
+//! The network threads hand copies of the packets to a capture thread, so that writing the file
+//! does not slow them down. Packets, which do not fit into the channel, are dropped from the
+//! capture.
+
+use std::{
+	fs::File,
+	io::{self, BufWriter, Write},
+	path::Path,
+	sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+	thread::{self, JoinHandle},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+/// Longest packet, which is captured completely
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+/// IP packets without a link layer header
+const LINKTYPE_RAW: u32 = 101;
+/// Number of packets, which may wait for the capture thread
+const CAPTURE_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+enum Message {
+	Packet(SystemTime, Vec<u8>),
+	Close,
+}
+
+/// Hands packets to the capture thread.
+#[derive(Debug, Clone)]
+pub struct PcapSender {
+	tx: SyncSender<Message>,
+}
+
+impl PcapSender {
+	/// Captures a copy of `packet`, unless the capture thread is behind.
+	pub fn capture(&self, packet: &[u8]) {
+		match self
+			.tx
+			.try_send(Message::Packet(SystemTime::now(), packet.to_vec()))
+		{
+			Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+			Err(TrySendError::Full(_)) => debug!("pcap: capture queue is full, dropping a packet"),
+		}
+	}
+}
+
+/// A capture file and the thread, which writes it.
+///
+/// Dropping the capture flushes and closes the file, even though the network threads keep their
+/// senders.
+#[derive(Debug)]
+pub struct PcapCapture {
+	sender: PcapSender,
+	thread: Option<JoinHandle<()>>,
+}
+
+fn write_header(file: &mut impl Write, link_type: u32) -> io::Result<()> {
+	file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+	file.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+	file.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+	// time zone and accuracy of the time stamps
+	file.write_all(&0i32.to_le_bytes())?;
+	file.write_all(&0u32.to_le_bytes())?;
+	file.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+	file.write_all(&link_type.to_le_bytes())
+}
+
+fn write_packet(file: &mut impl Write, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+	let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let captured = &packet[..packet.len().min(PCAP_SNAPLEN as usize)];
+	file.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+	file.write_all(&time.subsec_micros().to_le_bytes())?;
+	file.write_all(&(captured.len() as u32).to_le_bytes())?;
+	file.write_all(&(packet.len() as u32).to_le_bytes())?;
+	file.write_all(captured)
+}
+
+fn run_capture(mut file: BufWriter<File>, rx: Receiver<Message>) {
+	for message in rx {
+		match message {
+			Message::Packet(time, packet) => {
+				if let Err(err) = write_packet(&mut file, time, &packet) {
+					warn!("Unable to write the packet capture: {}", err);
+					return;
+				}
+			}
+			Message::Close => break,
+		}
+	}
+	if let Err(err) = file.flush() {
+		warn!("Unable to write the packet capture: {}", err);
+	}
+}
+
+impl PcapCapture {
+	/// Creates the capture file and starts the capture thread.
+	///
+	/// The packets are Ethernet frames or, with `raw_ip`, IP packets of TUN devices.
+	pub fn create(path: &Path, raw_ip: bool) -> io::Result<Self> {
+		let mut file = BufWriter::new(File::create(path)?);
+		let link_type = if raw_ip {
+			LINKTYPE_RAW
+		} else {
+			LINKTYPE_ETHERNET
+		};
+		write_header(&mut file, link_type)?;
+
+		let (tx, rx) = sync_channel(CAPTURE_QUEUE_SIZE);
+		let thread = thread::spawn(move || run_capture(file, rx));
+		Ok(Self {
+			sender: PcapSender { tx },
+			thread: Some(thread),
+		})
+	}
+
+	pub fn sender(&self) -> PcapSender {
+		self.sender.clone()
+	}
+}
+
+impl Drop for PcapCapture {
+	/// Writes the remaining packets and closes the file.
+	fn drop(&mut self) {
+		// the capture thread drains the channel, so this does not block for long
+		let _ = self.sender.tx.send(Message::Close);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_fs::TempDir;
+	use std::fs;
+
+	#[test]
+	fn test_pcap_capture() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("guest.pcap");
+
+		let capture = PcapCapture::create(&path, false).unwrap();
+		let sender = capture.sender();
+		sender.capture(&[1, 2, 3]);
+		sender.capture(&[4; 60]);
+		drop(capture);
+		// a sender, which outlives the capture, is ignored
+		sender.capture(&[5]);
+
+		let file = fs::read(&path).unwrap();
+		let u32_at =
+			|offset: usize| u32::from_le_bytes(file[offset..offset + 4].try_into().unwrap());
+		assert_eq!(u32_at(0), PCAP_MAGIC);
+		assert_eq!(u32_at(16), PCAP_SNAPLEN);
+		assert_eq!(u32_at(20), LINKTYPE_ETHERNET);
+
+		let first = 24;
+		assert_eq!(u32_at(first + 8), 3);
+		assert_eq!(u32_at(first + 12), 3);
+		assert_eq!(&file[first + 16..first + 19], &[1, 2, 3]);
+
+		let second = first + 16 + 3;
+		assert_eq!(u32_at(second + 8), 60);
+		assert_eq!(file.len(), second + 16 + 60);
+	}
+}
@@ -0,0 +1,228 @@
+//! A minimal PCI subsystem, which dispatches accesses to the configuration space
+//! (I/O ports `0xCF8` and `0xCFC`) to the registered devices.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+pub const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+pub const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+
+/// Number of device slots on a PCI bus.
+const PCI_MAX_SLOTS: usize = 32;
+/// Value returned when reading the configuration space of a non-existent device.
+const PCI_NO_DEVICE: u32 = 0xffffffff;
+
+/// A base address register of a PCI device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciBar {
+	/// The device is accessed via the I/O ports starting at the given address.
+	Io(u16),
+	/// The device is accessed via memory-mapped I/O at the given address.
+	Memory(u64),
+}
+
+pub trait PciDevice {
+	fn vendor_id(&self) -> u16;
+	fn device_id(&self) -> u16;
+	/// Returns the 24 bit class code (class, subclass and programming interface).
+	fn class_code(&self) -> u32;
+	fn bar(&self, n: u8) -> Option<PciBar>;
+	fn irq_line(&self) -> u8;
+	/// Reads `size` bytes at `offset` of the configuration space.
+	fn config_read(&self, offset: u32, size: u8) -> u32;
+	/// Writes the lower `size` bytes of `value` at `offset` of the configuration space.
+	fn config_write(&mut self, offset: u32, size: u8, value: u32);
+}
+
+/// Devices are usually shared with the vCPUs, which handle the accesses to their BARs.
+impl<T: PciDevice> PciDevice for Arc<Mutex<T>> {
+	fn vendor_id(&self) -> u16 {
+		self.lock().unwrap().vendor_id()
+	}
+
+	fn device_id(&self) -> u16 {
+		self.lock().unwrap().device_id()
+	}
+
+	fn class_code(&self) -> u32 {
+		self.lock().unwrap().class_code()
+	}
+
+	fn bar(&self, n: u8) -> Option<PciBar> {
+		self.lock().unwrap().bar(n)
+	}
+
+	fn irq_line(&self) -> u8 {
+		self.lock().unwrap().irq_line()
+	}
+
+	fn config_read(&self, offset: u32, size: u8) -> u32 {
+		self.lock().unwrap().config_read(offset, size)
+	}
+
+	fn config_write(&mut self, offset: u32, size: u8, value: u32) {
+		self.lock().unwrap().config_write(offset, size, value)
+	}
+}
+
+/// A value written to [`PCI_CONFIG_ADDRESS_PORT`].
+#[derive(Clone, Copy, Debug)]
+struct PciConfigAddress(u32);
+
+impl PciConfigAddress {
+	fn bus(self) -> u8 {
+		(self.0 >> 16) as u8
+	}
+
+	fn slot(self) -> u8 {
+		((self.0 >> 11) & 0x1f) as u8
+	}
+
+	fn function(self) -> u8 {
+		((self.0 >> 8) & 0x7) as u8
+	}
+
+	fn register(self) -> u32 {
+		self.0 & 0xfc
+	}
+}
+
+/// PCI bus 0, the only bus uhyve provides.
+pub struct PciBus {
+	devices: Vec<Option<Box<dyn PciDevice>>>,
+}
+
+impl PciBus {
+	pub fn new() -> Self {
+		Self {
+			devices: (0..PCI_MAX_SLOTS).map(|_| None).collect(),
+		}
+	}
+
+	/// Attaches a device to the given slot.
+	pub fn register(&mut self, slot: u8, device: Box<dyn PciDevice>) {
+		let entry = &mut self.devices[usize::from(slot)];
+		assert!(entry.is_none(), "PCI slot {} is already in use", slot);
+		debug!(
+			"Register PCI device {:04x}:{:04x} at slot {}",
+			device.vendor_id(),
+			device.device_id(),
+			slot
+		);
+		*entry = Some(device);
+	}
+
+	fn device(&self, address: PciConfigAddress) -> Option<&dyn PciDevice> {
+		if address.bus() != 0 || address.function() != 0 {
+			return None;
+		}
+		self.devices[usize::from(address.slot())].as_deref()
+	}
+
+	fn device_mut(&mut self, address: PciConfigAddress) -> Option<&mut Box<dyn PciDevice>> {
+		if address.bus() != 0 || address.function() != 0 {
+			return None;
+		}
+		self.devices[usize::from(address.slot())].as_mut()
+	}
+
+	/// Handles a read from [`PCI_CONFIG_DATA_PORT`] with the previously selected `address`.
+	pub fn config_read(&self, address: u32, size: u8) -> u32 {
+		let address = PciConfigAddress(address);
+		self.device(address).map_or(PCI_NO_DEVICE, |device| {
+			device.config_read(address.register(), size)
+		})
+	}
+
+	/// Handles a write to [`PCI_CONFIG_DATA_PORT`] with the previously selected `address`.
+	pub fn config_write(&mut self, address: u32, size: u8, value: u32) {
+		let address = PciConfigAddress(address);
+		if let Some(device) = self.device_mut(address) {
+			device.config_write(address.register(), size, value);
+		}
+	}
+}
+
+impl Default for PciBus {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl fmt::Debug for PciBus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list()
+			.entries(
+				self.devices
+					.iter()
+					.enumerate()
+					.filter_map(|(slot, device)| {
+						device
+							.as_ref()
+							.map(|device| (slot, device.vendor_id(), device.device_id()))
+					}),
+			)
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct DummyDevice([u8; 0x40]);
+
+	impl PciDevice for DummyDevice {
+		fn vendor_id(&self) -> u16 {
+			0x1234
+		}
+
+		fn device_id(&self) -> u16 {
+			0x5678
+		}
+
+		fn class_code(&self) -> u32 {
+			0
+		}
+
+		fn bar(&self, _n: u8) -> Option<PciBar> {
+			None
+		}
+
+		fn irq_line(&self) -> u8 {
+			0
+		}
+
+		fn config_read(&self, offset: u32, size: u8) -> u32 {
+			let mut bytes = [0; 4];
+			bytes[..usize::from(size)]
+				.copy_from_slice(&self.0[offset as usize..][..usize::from(size)]);
+			u32::from_le_bytes(bytes)
+		}
+
+		fn config_write(&mut self, offset: u32, size: u8, value: u32) {
+			self.0[offset as usize..][..usize::from(size)]
+				.copy_from_slice(&value.to_le_bytes()[..usize::from(size)]);
+		}
+	}
+
+	#[test]
+	fn test_pci_bus_dispatch() {
+		let mut bus = PciBus::new();
+		bus.register(3, Box::new(DummyDevice([0; 0x40])));
+
+		// enable bit, bus 0, slot 3, function 0, register 0x10
+		let address = 0x8000_0000 | (3 << 11) | 0x10;
+		bus.config_write(address, 4, 0xdeadbeef);
+		assert_eq!(bus.config_read(address, 4), 0xdeadbeef);
+		assert_eq!(bus.config_read(address, 2), 0xbeef);
+
+		// empty slot
+		assert_eq!(bus.config_read(0x8000_0000 | (4 << 11), 4), PCI_NO_DEVICE);
+		// other function of an existing device
+		assert_eq!(
+			bus.config_read(0x8000_0000 | (3 << 11) | (1 << 8), 4),
+			PCI_NO_DEVICE
+		);
+	}
+}
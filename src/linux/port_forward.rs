@@ -0,0 +1,111 @@
+//! Forwarding of host ports to a guest behind a TAP device through iptables, see `--port-forward`.
+//!
+//! The DNAT rules are added to the `nat` table, when the VM starts, and deleted again on drop. They
+//! carry the comment `uhyve`, so that rules left behind by a killed uhyve are easy to find.
+//! Connections to the loopback address of the host are only forwarded with
+//! `net.ipv4.conf.all.route_localnet` enabled.
+
+use std::{io, net::Ipv4Addr, process::Command};
+
+use crate::vm::PortForward;
+
+/// The iptables rules of the forwarded ports, which are removed on drop.
+#[derive(Debug)]
+pub struct PortForwardRules {
+	rules: Vec<Vec<String>>,
+}
+
+impl PortForwardRules {
+	/// Forwards the ports of the host to the guest at `guest`.
+	pub fn install(guest: Ipv4Addr, forwards: &[PortForward]) -> io::Result<Self> {
+		// the rules, which have been added before a failure, are removed on return
+		let mut installed = Self { rules: Vec::new() };
+		for forward in forwards {
+			// PREROUTING sees the connections from other hosts, OUTPUT those from the host itself
+			for chain in ["PREROUTING", "OUTPUT"] {
+				let rule = dnat_rule(chain, guest, forward);
+				iptables("-A", &rule)?;
+				installed.rules.push(rule);
+			}
+			info!(
+				"Forward {} port {} to {}:{}",
+				forward.protocol.as_str(),
+				forward.host_port,
+				guest,
+				forward.guest_port
+			);
+		}
+		Ok(installed)
+	}
+}
+
+impl Drop for PortForwardRules {
+	fn drop(&mut self) {
+		for rule in self.rules.iter().rev() {
+			if let Err(err) = iptables("-D", rule) {
+				warn!("Unable to remove the port forwarding: {}", err);
+			}
+		}
+	}
+}
+
+fn dnat_rule(chain: &str, guest: Ipv4Addr, forward: &PortForward) -> Vec<String> {
+	let host_port = forward.host_port.to_string();
+	let destination = format!("{}:{}", guest, forward.guest_port);
+	let mut rule = vec![
+		chain,
+		"-p",
+		forward.protocol.as_str(),
+		"--dport",
+		host_port.as_str(),
+	];
+	if chain == "OUTPUT" {
+		rule.extend(["-m", "addrtype", "--dst-type", "LOCAL"]);
+	}
+	rule.extend(["-m", "comment", "--comment", "uhyve"]);
+	rule.extend(["-j", "DNAT", "--to-destination", destination.as_str()]);
+	rule.into_iter().map(str::to_string).collect()
+}
+
+fn iptables(action: &str, rule: &[String]) -> io::Result<()> {
+	let output = Command::new("iptables")
+		.args(["-t", "nat", action])
+		.args(rule)
+		.output()?;
+	if !output.status.success() {
+		return Err(io::Error::new(
+			io::ErrorKind::Other,
+			format!(
+				"iptables {} {}: {}",
+				action,
+				rule.join(" "),
+				String::from_utf8_lossy(&output.stderr).trim()
+			),
+		));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vm::Protocol;
+
+	#[test]
+	fn test_dnat_rule() {
+		let forward = PortForward {
+			host_port: 8080,
+			guest_port: 80,
+			protocol: Protocol::Tcp,
+		};
+		let guest = Ipv4Addr::new(10, 0, 5, 3);
+		assert_eq!(
+			dnat_rule("PREROUTING", guest, &forward).join(" "),
+			"PREROUTING -p tcp --dport 8080 -m comment --comment uhyve -j DNAT --to-destination 10.0.5.3:80"
+		);
+		assert_eq!(
+			dnat_rule("OUTPUT", guest, &forward).join(" "),
+			"OUTPUT -p tcp --dport 8080 -m addrtype --dst-type LOCAL -m comment --comment uhyve -j DNAT --to-destination 10.0.5.3:80"
+		);
+	}
+}
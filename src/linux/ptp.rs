@@ -0,0 +1,260 @@
+//! Cross timestamps of the guest's kvmclock and the host's realtime clock, see `--ptp`.
+//!
+//! `KVM_GET_CLOCK` returns the kvmclock together with the host's `CLOCK_REALTIME` and TSC, which
+//! have been read at the same instant. The updater publishes these cross timestamps in a page of
+//! the guest memory, from which a PTP clock driver of the guest synchronizes its time, similar to
+//! `ptp_kvm_getcrosststamp()` of Linux guests. The page is protected by a sequence counter, which
+//! is odd while the page is updated.
+
+use std::{
+	fs::File,
+	io,
+	os::unix::io::{AsRawFd, FromRawFd, RawFd},
+	ptr,
+	sync::{
+		atomic::{fence, Ordering},
+		mpsc::{self, RecvTimeoutError, Sender},
+	},
+	thread::{self, JoinHandle},
+	time::Duration,
+};
+
+use crate::linux::KVM;
+
+/// `_IO(KVMIO, 0x03)`
+const KVM_CHECK_EXTENSION: libc::c_ulong = 0xae03;
+/// `_IOR(KVMIO, 0x7c, struct kvm_clock_data)`
+const KVM_GET_CLOCK: libc::c_ulong = 0x8030_ae7c;
+const KVM_CAP_ADJUST_CLOCK: libc::c_ulong = 39;
+/// The realtime clock of the host is valid
+const KVM_CLOCK_REALTIME: u32 = 1 << 2;
+/// The TSC of the host is valid
+const KVM_CLOCK_HOST_TSC: u32 = 1 << 3;
+
+/// Interval, in which the cross timestamps are updated
+pub const PTP_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `struct kvm_clock_data` of Linux 5.16, kvm-bindings lacks `realtime` and `host_tsc`.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct KvmClockData {
+	clock: u64,
+	flags: u32,
+	pad0: u32,
+	realtime: u64,
+	host_tsc: u64,
+	pad: [u32; 4],
+}
+
+/// `struct ptp_clock_time` of Linux
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PtpClockTime {
+	pub sec: i64,
+	pub nsec: u32,
+	pub reserved: u32,
+}
+
+impl PtpClockTime {
+	fn from_nanos(nanos: u64) -> Self {
+		Self {
+			sec: (nanos / 1_000_000_000) as i64,
+			nsec: (nanos % 1_000_000_000) as u32,
+			reserved: 0,
+		}
+	}
+}
+
+/// The page of the guest memory with the latest cross timestamp.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct PtpClockPage {
+	/// Incremented before and after each update
+	pub sequence: u32,
+	pub flags: u32,
+	/// The guest's kvmclock
+	pub device_time: PtpClockTime,
+	/// The host's `CLOCK_REALTIME`
+	pub system_time: PtpClockTime,
+	pub host_tsc: u64,
+}
+
+/// Checks, whether `KVM_GET_CLOCK` returns cross timestamps with the host's realtime clock.
+pub fn cross_timestamps_supported() -> bool {
+	let flags = unsafe { libc::ioctl(KVM.as_raw_fd(), KVM_CHECK_EXTENSION, KVM_CAP_ADJUST_CLOCK) };
+	flags > 0 && flags as u32 & KVM_CLOCK_REALTIME != 0
+}
+
+fn get_clock(vm: &File) -> io::Result<KvmClockData> {
+	let mut data = KvmClockData::default();
+	if unsafe { libc::ioctl(vm.as_raw_fd(), KVM_GET_CLOCK, &mut data) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(data)
+}
+
+/// Running mean and variance of the offset between the host's realtime clock and the kvmclock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OffsetStats {
+	samples: u64,
+	mean: f64,
+	m2: f64,
+}
+
+impl OffsetStats {
+	fn record(&mut self, offset: f64) {
+		self.samples += 1;
+		let delta = offset - self.mean;
+		self.mean += delta / self.samples as f64;
+		self.m2 += delta * (offset - self.mean);
+	}
+
+	/// Returns the standard deviation of the offsets in nanoseconds, which is the jitter of the
+	/// synchronization.
+	pub fn std_dev(&self) -> Option<f64> {
+		(self.samples > 1).then(|| (self.m2 / (self.samples - 1) as f64).sqrt())
+	}
+}
+
+/// Updates the cross timestamps in the guest memory until it is stopped.
+#[derive(Debug)]
+pub struct PtpClock {
+	stop: Sender<()>,
+	thread: JoinHandle<OffsetStats>,
+}
+
+impl PtpClock {
+	/// Starts publishing the cross timestamps of the VM `vm_fd` in the page at `page`.
+	///
+	/// # Safety
+	///
+	/// `page` has to point to a page of the guest memory, which stays mapped, until the updater is
+	/// stopped.
+	pub unsafe fn start(vm_fd: RawFd, page: usize) -> io::Result<Self> {
+		let fd = libc::dup(vm_fd);
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let vm = File::from_raw_fd(fd);
+
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			let page = page as *mut PtpClockPage;
+			let mut stats = OffsetStats::default();
+			loop {
+				match get_clock(&vm) {
+					Ok(clock) => {
+						publish(page, &clock);
+						if clock.flags & KVM_CLOCK_REALTIME != 0 {
+							stats.record(clock.realtime as f64 - clock.clock as f64);
+						}
+					}
+					Err(err) => {
+						warn!("Unable to read the guest clock: {}", err);
+						break;
+					}
+				}
+				if !matches!(
+					rx.recv_timeout(PTP_UPDATE_INTERVAL),
+					Err(RecvTimeoutError::Timeout)
+				) {
+					break;
+				}
+			}
+			stats
+		});
+
+		Ok(Self { stop, thread })
+	}
+
+	/// Stops updating and prints the achieved accuracy.
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		let stats = self.thread.join().unwrap();
+		match stats.std_dev() {
+			Some(std_dev) => println!(
+				"PTP: {} cross timestamps, offset standard deviation {:.1} ns",
+				stats.samples, std_dev
+			),
+			None => println!("PTP: not enough cross timestamps to determine the accuracy"),
+		}
+	}
+}
+
+/// Writes a cross timestamp into the page.
+unsafe fn publish(page: *mut PtpClockPage, clock: &KvmClockData) {
+	let sequence = ptr::read_volatile(ptr::addr_of!((*page).sequence));
+	ptr::write_volatile(
+		ptr::addr_of_mut!((*page).sequence),
+		sequence.wrapping_add(1),
+	);
+	fence(Ordering::Release);
+
+	ptr::write_volatile(
+		ptr::addr_of_mut!((*page).flags),
+		clock.flags & (KVM_CLOCK_REALTIME | KVM_CLOCK_HOST_TSC),
+	);
+	ptr::write_volatile(
+		ptr::addr_of_mut!((*page).device_time),
+		PtpClockTime::from_nanos(clock.clock),
+	);
+	ptr::write_volatile(
+		ptr::addr_of_mut!((*page).system_time),
+		PtpClockTime::from_nanos(clock.realtime),
+	);
+	ptr::write_volatile(ptr::addr_of_mut!((*page).host_tsc), clock.host_tsc);
+
+	fence(Ordering::Release);
+	ptr::write_volatile(
+		ptr::addr_of_mut!((*page).sequence),
+		sequence.wrapping_add(2),
+	);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem;
+
+	#[test]
+	fn test_ptp_abi() {
+		// The size is encoded in the ioctl number.
+		assert_eq!(mem::size_of::<KvmClockData>(), 48);
+		assert_eq!(mem::size_of::<PtpClockTime>(), 16);
+		assert_eq!(mem::size_of::<PtpClockPage>(), 48);
+	}
+
+	#[test]
+	fn test_publish() {
+		let mut page = PtpClockPage::default();
+		let clock = KvmClockData {
+			clock: 5_000_000_123,
+			flags: KVM_CLOCK_REALTIME | 2,
+			realtime: 1_600_000_000_000_000_456,
+			..Default::default()
+		};
+		unsafe { publish(&mut page, &clock) };
+		assert_eq!(page.sequence, 2);
+		assert_eq!(page.flags, KVM_CLOCK_REALTIME);
+		assert_eq!(
+			page.device_time,
+			PtpClockTime {
+				sec: 5,
+				nsec: 123,
+				reserved: 0
+			}
+		);
+		assert_eq!(page.system_time.sec, 1_600_000_000);
+		assert_eq!(page.system_time.nsec, 456);
+	}
+
+	#[test]
+	fn test_offset_stats() {
+		let mut stats = OffsetStats::default();
+		assert_eq!(stats.std_dev(), None);
+		for offset in [10.0, 12.0, 14.0] {
+			stats.record(offset);
+		}
+		assert_eq!(stats.std_dev(), Some(2.0));
+	}
+}
@@ -0,0 +1,193 @@
+//! Routing between the TUN devices of several network interfaces, see `--guest-ip-forward`.
+//!
+//! A guest with two network interfaces, e.g., a request router, would otherwise receive the
+//! packets for the other network and send them again. Instead, uhyve looks up the destination of
+//! every packet, which a TUN device receives, and writes packets of another interface directly to
+//! its TUN device, like a host with `ip_forward` enabled. The TTL or hop limit is decremented on
+//! the way.
+
+use std::{
+	net::{IpAddr, Ipv4Addr, Ipv6Addr},
+	sync::{Arc, RwLock},
+};
+
+use crate::linux::tap::Tap;
+use crate::vm::{GuestRoute, IpNetwork};
+
+/// Returns the destination address of an IPv4 or IPv6 packet.
+fn destination(packet: &[u8]) -> Option<IpAddr> {
+	match packet.first()? >> 4 {
+		4 if packet.len() >= 20 => {
+			Some(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]).into())
+		}
+		6 if packet.len() >= 40 => {
+			let address: [u8; 16] = packet[24..40].try_into().unwrap();
+			Some(Ipv6Addr::from(address).into())
+		}
+		_ => None,
+	}
+}
+
+/// Decrements the TTL or the hop limit of a packet, whose destination has been parsed.
+///
+/// Returns `false`, if the packet has expired and must be dropped.
+fn decrement_ttl(packet: &mut [u8]) -> bool {
+	match packet[0] >> 4 {
+		4 => {
+			if packet[8] <= 1 {
+				return false;
+			}
+			packet[8] -= 1;
+			// RFC 1624: the TTL is the high byte of the 16-bit word at offset 8
+			let checksum = u16::from_be_bytes([packet[10], packet[11]]);
+			let sum = u32::from(checksum) + 0x0100;
+			let checksum = (sum + (sum >> 16)) as u16;
+			packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+			true
+		}
+		_ => {
+			if packet[7] <= 1 {
+				return false;
+			}
+			packet[7] -= 1;
+			true
+		}
+	}
+}
+
+/// What happens to a packet, which a TUN device received.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RouteDecision {
+	/// The packet is for the network interface of the TUN device.
+	Deliver,
+	/// The packet has been forwarded to or dropped for another network interface.
+	Forwarded,
+}
+
+/// A software IP router between the TUN devices of the network interfaces.
+#[derive(Debug)]
+pub struct Router {
+	routes: Vec<(IpNetwork, usize)>,
+	/// The TUN devices by the index of their network interface
+	ifaces: RwLock<Vec<Option<Arc<Tap>>>>,
+}
+
+impl Router {
+	pub fn new(routes: &[GuestRoute], num_nics: usize) -> Self {
+		Self {
+			routes: routes
+				.iter()
+				.map(|route| (route.network, route.nic))
+				.collect(),
+			ifaces: RwLock::new(vec![None; num_nics]),
+		}
+	}
+
+	/// Adds the TUN device of the network interface `nic`, to which packets are forwarded.
+	pub fn attach(&self, nic: usize, iface: Arc<Tap>) {
+		self.ifaces.write().unwrap()[nic] = Some(iface);
+	}
+
+	/// Returns the network interface of the longest route, which contains `address`.
+	fn lookup(&self, address: IpAddr) -> Option<usize> {
+		self.routes
+			.iter()
+			.filter(|(network, _)| network.contains(address))
+			.max_by_key(|(network, _)| network.prefix_len)
+			.map(|(_, nic)| *nic)
+	}
+
+	/// Forwards `packet`, which the TUN device of `nic` received, if it is routed to another one.
+	pub fn route(&self, nic: usize, packet: &mut [u8]) -> RouteDecision {
+		let to = match destination(packet).and_then(|address| self.lookup(address)) {
+			Some(to) if to != nic => to,
+			_ => return RouteDecision::Deliver,
+		};
+		if !decrement_ttl(packet) {
+			trace!("Drop an expired packet from interface {} to {}", nic, to);
+			return RouteDecision::Forwarded;
+		}
+
+		match &self.ifaces.read().unwrap()[to] {
+			Some(iface) => {
+				if let Err(err) = iface.send(packet) {
+					warn!("Unable to forward a packet to interface {}: {}", to, err);
+				}
+			}
+			None => trace!("Drop a packet for interface {}, which is not up yet", to),
+		}
+		RouteDecision::Forwarded
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::parse_guest_route;
+
+	/// An IPv4 header from 10.0.0.2 to `destination` with TTL 64 and a valid checksum.
+	fn ipv4_packet(destination: [u8; 4]) -> Vec<u8> {
+		let mut packet = vec![
+			0x45, 0, 0, 20, 0, 0, 0x40, 0, 64, 17, 0, 0, 10, 0, 0, 2, 0, 0, 0, 0,
+		];
+		packet[16..20].copy_from_slice(&destination);
+		let checksum = internet_checksum(&packet);
+		packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+		packet
+	}
+
+	fn internet_checksum(header: &[u8]) -> u16 {
+		let mut sum = header
+			.chunks(2)
+			.map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+			.sum::<u32>();
+		while sum > 0xffff {
+			sum = (sum & 0xffff) + (sum >> 16);
+		}
+		!(sum as u16)
+	}
+
+	#[test]
+	fn test_lookup() {
+		let routes = ["10.0.0.0/8:0", "10.0.1.0/24:1", "fd00::/16:1"]
+			.iter()
+			.map(|route| parse_guest_route(route).unwrap())
+			.collect::<Vec<_>>();
+		let router = Router::new(&routes, 2);
+		assert_eq!(router.lookup("10.0.1.5".parse().unwrap()), Some(1));
+		assert_eq!(router.lookup("10.0.2.5".parse().unwrap()), Some(0));
+		assert_eq!(router.lookup("fd00::5".parse().unwrap()), Some(1));
+		assert_eq!(router.lookup("192.0.2.1".parse().unwrap()), None);
+
+		// packets for the own interface and unknown networks reach the guest
+		let mut packet = ipv4_packet([10, 0, 2, 5]);
+		assert_eq!(router.route(0, &mut packet), RouteDecision::Deliver);
+		let mut packet = ipv4_packet([192, 0, 2, 1]);
+		assert_eq!(router.route(1, &mut packet), RouteDecision::Deliver);
+		assert_eq!(router.route(0, &mut []), RouteDecision::Deliver);
+		// the other interface has no TUN device, so the packet is dropped
+		let mut packet = ipv4_packet([10, 0, 1, 5]);
+		assert_eq!(router.route(0, &mut packet), RouteDecision::Forwarded);
+	}
+
+	#[test]
+	fn test_destination_and_ttl() {
+		let mut packet = ipv4_packet([10, 0, 1, 5]);
+		assert_eq!(destination(&packet), Some("10.0.1.5".parse().unwrap()));
+		assert!(decrement_ttl(&mut packet));
+		assert_eq!(packet[8], 63);
+		assert_eq!(internet_checksum(&packet), 0);
+
+		packet[8] = 1;
+		assert!(!decrement_ttl(&mut packet));
+
+		let mut packet = vec![0; 40];
+		packet[0] = 0x60;
+		packet[7] = 2;
+		packet[39] = 1;
+		assert_eq!(destination(&packet), Some("::1".parse().unwrap()));
+		assert!(decrement_ttl(&mut packet));
+		assert_eq!(packet[7], 1);
+		assert_eq!(destination(&packet[..39]), None);
+	}
+}
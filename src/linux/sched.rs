@@ -0,0 +1,72 @@
+//! Real-time scheduling of the vCPU threads with `SCHED_DEADLINE`.
+//!
+//! `sched_setattr` is neither wrapped by `libc` nor by `nix`, so it is invoked as raw syscall.
+//! Setting the policy requires `CAP_SYS_NICE`. Moreover, the host kernel has to be built without
+//! `CONFIG_RT_GROUP_SCHED`, because deadline tasks are rejected in real-time cgroups otherwise.
+
+use std::{io, mem, ptr};
+
+use crate::vm::DeadlineParameters;
+
+/// Not yet exported by `libc`.
+const SCHED_DEADLINE: u32 = 6;
+
+/// `struct sched_attr` of `include/uapi/linux/sched/types.h` (`SCHED_ATTR_SIZE_VER0`)
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SchedAttr {
+	size: u32,
+	sched_policy: u32,
+	sched_flags: u64,
+	sched_nice: i32,
+	sched_priority: u32,
+	sched_runtime: u64,
+	sched_deadline: u64,
+	sched_period: u64,
+}
+
+fn sched_setattr(attr: &SchedAttr) -> io::Result<()> {
+	// SAFETY: `attr` is valid for reads and its `size` matches the struct.
+	let ret = unsafe { libc::syscall(libc::SYS_sched_setattr, 0, attr as *const SchedAttr, 0) };
+	if ret < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+fn set_fifo_scheduling() -> io::Result<()> {
+	let param = libc::sched_param {
+		sched_priority: unsafe { libc::sched_get_priority_min(libc::SCHED_FIFO) },
+	};
+	// SAFETY: `param` is valid for reads.
+	if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, ptr::addr_of!(param)) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+/// Schedules the calling thread with `SCHED_DEADLINE`.
+///
+/// If the thread is not permitted to do so, `SCHED_FIFO` with the lowest priority is tried instead.
+/// Note that the kernel rejects deadline tasks, whose affinity does not span their root domain.
+pub fn set_deadline_scheduling(params: &DeadlineParameters) -> io::Result<()> {
+	let attr = SchedAttr {
+		size: mem::size_of::<SchedAttr>() as u32,
+		sched_policy: SCHED_DEADLINE,
+		sched_runtime: params.runtime,
+		sched_deadline: params.deadline,
+		sched_period: params.period,
+		..Default::default()
+	};
+
+	match sched_setattr(&attr) {
+		Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+			warn!(
+				"Not permitted to use SCHED_DEADLINE ({}), falling back to SCHED_FIFO",
+				err
+			);
+			set_fifo_scheduling()
+		}
+		result => result,
+	}
+}
@@ -0,0 +1,253 @@
+//! A read-only view of the state of the VM in POSIX shared memory.
+//!
+//! With `--shm-id <KEY>`, uhyve creates `/dev/shm/uhyve-<KEY>`, which host tools can map to
+//! read the state without a round trip to uhyve. The region starts with a [`VmSharedState`],
+//! which is followed by one [`VcpuSharedState`] per vCPU.
+//!
+//! The layout is a stable ABI: fields are only appended and the record sizes in the header tell
+//! readers, where the records start. Incompatible changes increment [`VERSION`]. All values are
+//! updated with relaxed atomics, so every field is read consistently, but not the fields together.
+
+use std::{
+	io, mem,
+	ptr::{self, NonNull},
+	sync::{
+		atomic::{AtomicU64, AtomicU8, Ordering},
+		mpsc::{self, RecvTimeoutError, Sender},
+		Arc,
+	},
+	thread::{self, JoinHandle},
+	time::Duration,
+};
+
+use nix::{
+	fcntl::OFlag,
+	sys::{
+		mman::{mmap, munmap, shm_open, shm_unlink, MapFlags, ProtFlags},
+		stat::Mode,
+	},
+	unistd::{close, ftruncate},
+};
+
+use crate::linux::net_stats::{NetworkStats, NetworkStatsSnapshot};
+
+/// `UHYVESHM` in little endian, which is written last, once the header is valid
+pub const MAGIC: u64 = u64::from_le_bytes(*b"UHYVESHM");
+pub const VERSION: u32 = 1;
+
+/// Interval, in which the network counters are copied into the shared memory.
+const NETWORK_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of a vCPU in [`VcpuSharedState::state`].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuState {
+	/// The vCPU has not entered the guest yet.
+	Created = 0,
+	/// The vCPU executes the guest.
+	Running = 1,
+	/// The vCPU handles an exit in uhyve.
+	Exited = 2,
+	/// The vCPU has stopped, because the kernel exited or the vCPU crashed.
+	Stopped = 3,
+}
+
+/// The header of the shared memory.
+#[repr(C)]
+#[derive(Debug)]
+pub struct VmSharedState {
+	/// [`MAGIC`], once the header has been initialized
+	pub magic: AtomicU64,
+	/// [`VERSION`]
+	pub version: u32,
+	pub num_cpus: u32,
+	/// Size of this header, at which the first [`VcpuSharedState`] starts
+	pub header_size: u32,
+	/// Size of a [`VcpuSharedState`]
+	pub vcpu_state_size: u32,
+	pub network: SharedNetworkStats,
+}
+
+/// The counters of the network interface, see [`NetworkStats`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct SharedNetworkStats {
+	pub bytes_sent: AtomicU64,
+	pub bytes_received: AtomicU64,
+	pub packets_sent: AtomicU64,
+	pub packets_received: AtomicU64,
+	pub send_errors: AtomicU64,
+	pub receive_errors: AtomicU64,
+}
+
+/// The state of a single vCPU.
+#[repr(C)]
+#[derive(Debug)]
+pub struct VcpuSharedState {
+	/// Instruction pointer at the last exit
+	pub rip: AtomicU64,
+	pub exit_count: AtomicU64,
+	/// A [`VcpuState`]
+	pub state: AtomicU8,
+	_padding: [u8; 7],
+}
+
+impl VcpuSharedState {
+	pub fn set_state(&self, state: VcpuState) {
+		self.state.store(state as u8, Ordering::Relaxed);
+	}
+
+	pub fn record_exit(&self, rip: u64) {
+		self.rip.store(rip, Ordering::Relaxed);
+		self.exit_count.fetch_add(1, Ordering::Relaxed);
+		self.set_state(VcpuState::Exited);
+	}
+}
+
+/// The shared memory `/uhyve-<KEY>`, which is removed on drop.
+#[derive(Debug)]
+pub struct SharedState {
+	name: String,
+	header: NonNull<VmSharedState>,
+	size: usize,
+}
+
+// SAFETY: The mapping is only accessed through atomics after its initialization.
+unsafe impl Send for SharedState {}
+unsafe impl Sync for SharedState {}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+	io::Error::from_raw_os_error(err as i32)
+}
+
+impl SharedState {
+	/// Creates the shared memory for `num_cpus` vCPUs, which must not exist yet.
+	pub fn create(key: &str, num_cpus: u32) -> io::Result<Self> {
+		if key.is_empty() || key.contains('/') {
+			return Err(io::ErrorKind::InvalidInput.into());
+		}
+		let name = format!("/uhyve-{}", key);
+		let size =
+			mem::size_of::<VmSharedState>() + num_cpus as usize * mem::size_of::<VcpuSharedState>();
+
+		let fd = shm_open(
+			name.as_str(),
+			OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR,
+			Mode::from_bits_truncate(0o644),
+		)
+		.map_err(nix_to_io)?;
+		let mapping = ftruncate(fd, size as libc::off_t).and_then(|_| unsafe {
+			mmap(
+				ptr::null_mut(),
+				size,
+				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+				MapFlags::MAP_SHARED,
+				fd,
+				0,
+			)
+		});
+		let _ = close(fd);
+		let header = match mapping {
+			Ok(mapping) => mapping as *mut VmSharedState,
+			Err(err) => {
+				let _ = shm_unlink(name.as_str());
+				return Err(nix_to_io(err));
+			}
+		};
+
+		// SAFETY: The new mapping is zeroed and large enough for the header.
+		unsafe {
+			ptr::addr_of_mut!((*header).version).write(VERSION);
+			ptr::addr_of_mut!((*header).num_cpus).write(num_cpus);
+			ptr::addr_of_mut!((*header).header_size).write(mem::size_of::<VmSharedState>() as u32);
+			ptr::addr_of_mut!((*header).vcpu_state_size)
+				.write(mem::size_of::<VcpuSharedState>() as u32);
+		}
+		let shared_state = Self {
+			name,
+			header: NonNull::new(header).unwrap(),
+			size,
+		};
+		shared_state.header().magic.store(MAGIC, Ordering::Release);
+		Ok(shared_state)
+	}
+
+	pub fn header(&self) -> &VmSharedState {
+		// SAFETY: The header is initialized in `create` and only modified atomically afterwards.
+		unsafe { self.header.as_ref() }
+	}
+
+	/// Returns the state of a vCPU.
+	///
+	/// # Panics
+	///
+	/// Panics if `id` is not a vCPU of the VM.
+	pub fn vcpu(&self, id: u32) -> &VcpuSharedState {
+		assert!(id < self.header().num_cpus, "vCPU {} does not exist", id);
+		// SAFETY: The records of all vCPUs follow the header within the mapping.
+		unsafe { &*(self.header.as_ptr().add(1) as *const VcpuSharedState).add(id as usize) }
+	}
+
+	pub fn update_network(&self, snapshot: &NetworkStatsSnapshot) {
+		let network = &self.header().network;
+		for (counter, value) in [
+			(&network.bytes_sent, snapshot.bytes_sent),
+			(&network.bytes_received, snapshot.bytes_received),
+			(&network.packets_sent, snapshot.packets_sent),
+			(&network.packets_received, snapshot.packets_received),
+			(&network.send_errors, snapshot.send_errors),
+			(&network.receive_errors, snapshot.receive_errors),
+		] {
+			counter.store(value, Ordering::Relaxed);
+		}
+	}
+}
+
+impl Drop for SharedState {
+	fn drop(&mut self) {
+		unsafe {
+			let _ = munmap(self.header.as_ptr() as *mut libc::c_void, self.size);
+		}
+		if let Err(err) = shm_unlink(self.name.as_str()) {
+			warn!("Unable to remove the shared memory {}: {}", self.name, err);
+		}
+	}
+}
+
+/// Periodically copies the network counters into the shared memory.
+#[derive(Debug)]
+pub struct NetworkStatsPublisher {
+	stop: Sender<()>,
+	thread: JoinHandle<()>,
+}
+
+impl NetworkStatsPublisher {
+	pub fn start(shared_state: Arc<SharedState>, stats: Arc<NetworkStats>) -> Self {
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(NETWORK_UPDATE_INTERVAL) {
+				shared_state.update_network(&stats.snapshot());
+			}
+			shared_state.update_network(&stats.snapshot());
+		});
+
+		Self { stop, thread }
+	}
+
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		self.thread.join().unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_shared_state_layout() {
+		assert_eq!(mem::size_of::<VmSharedState>(), 72);
+		assert_eq!(mem::size_of::<VcpuSharedState>(), 24);
+		assert_eq!(mem::align_of::<VcpuSharedState>(), 8);
+	}
+}
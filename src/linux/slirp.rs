@@ -0,0 +1,445 @@
+//! User-mode networking through libslirp, see `--nic slirp`.
+//!
+//! libslirp emulates a virtual network with a NAT gateway, a DHCP and a DNS server in user space,
+//! so the guest reaches the host's network without a TAP device or any privileges. libslirp is
+//! not thread-safe, so a single thread polls its sockets and timers and exchanges the packets with
+//! the shared queues of the interface.
+
+use std::{
+	ffi::CStr,
+	io, mem,
+	net::Ipv4Addr,
+	os::raw::{c_char, c_int, c_void},
+	os::unix::io::AsRawFd,
+	ptr,
+	sync::{mpsc::sync_channel, Arc},
+	thread::{self, JoinHandle},
+	time::Instant,
+};
+
+use libslirp_sys::{
+	in_addr, slirp_add_hostfwd, slirp_input, slirp_new, slirp_pollfds_fill, slirp_pollfds_poll,
+	SlirpCb, SlirpConfig, SlirpTimerCb,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::consts::UHYVE_NET_MTU;
+use crate::linux::net_stats::NetworkStats;
+use crate::linux::pcap::PcapSender;
+use crate::shared_queue::SharedQueue;
+use crate::vm::{NicSpec, PortForward, Protocol};
+
+/// Address of the guest in the default network, the same as in QEMU
+const DEFAULT_GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+/// Address of the gateway in the default network, behind which the host's network is
+const DEFAULT_GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+const DEFAULT_MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// Longest time between two polls of the sockets, if libslirp has no earlier timeout
+const POLL_TIMEOUT_MS: u32 = 1000;
+
+// from `libslirp.h`
+const SLIRP_POLL_IN: c_int = 1 << 0;
+const SLIRP_POLL_OUT: c_int = 1 << 1;
+const SLIRP_POLL_PRI: c_int = 1 << 2;
+const SLIRP_POLL_ERR: c_int = 1 << 3;
+const SLIRP_POLL_HUP: c_int = 1 << 4;
+
+const POLL_EVENTS: [(c_int, libc::c_short); 5] = [
+	(SLIRP_POLL_IN, libc::POLLIN),
+	(SLIRP_POLL_OUT, libc::POLLOUT),
+	(SLIRP_POLL_PRI, libc::POLLPRI),
+	(SLIRP_POLL_ERR, libc::POLLERR),
+	(SLIRP_POLL_HUP, libc::POLLHUP),
+];
+
+/// The virtual network of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlirpNetwork {
+	pub guest: Ipv4Addr,
+	/// Address of the host in the virtual network, which routes the guest's packets
+	pub gateway: Ipv4Addr,
+	pub mask: Ipv4Addr,
+	/// Address of the DNS forwarder, which answers with the host's resolver
+	pub dns: Ipv4Addr,
+}
+
+impl SlirpNetwork {
+	/// Takes the addresses of `nic` and defaults to QEMU's network `10.0.2.0/24` for the others.
+	///
+	/// The DNS forwarder is the third host of the network, unless `dns` is given.
+	pub fn new(nic: &NicSpec<'_>, dns: Option<Ipv4Addr>) -> Self {
+		let guest = nic.ip.unwrap_or(DEFAULT_GUEST_ADDR);
+		let mask = nic.mask.unwrap_or(DEFAULT_MASK);
+		let network = u32::from(guest) & u32::from(mask);
+		let gateway = nic.gateway.unwrap_or_else(|| {
+			if nic.ip.is_some() {
+				Ipv4Addr::from(network | 2)
+			} else {
+				DEFAULT_GATEWAY_ADDR
+			}
+		});
+		Self {
+			guest,
+			gateway,
+			mask,
+			dns: dns.unwrap_or_else(|| Ipv4Addr::from(network | 3)),
+		}
+	}
+
+	fn network(&self) -> Ipv4Addr {
+		Ipv4Addr::from(u32::from(self.guest) & u32::from(self.mask))
+	}
+}
+
+fn to_in_addr(addr: Ipv4Addr) -> in_addr {
+	in_addr {
+		s_addr: u32::from(addr).to_be(),
+	}
+}
+
+/// A timer of libslirp, which is fired by the polling thread.
+struct Timer {
+	callback: SlirpTimerCb,
+	opaque: *mut c_void,
+	/// Expiry in milliseconds since the start of the thread
+	expire_ms: Option<i64>,
+}
+
+/// The state of the polling thread, which the callbacks of libslirp receive as `opaque`.
+struct Context {
+	rx_queue: &'static mut SharedQueue,
+	evtfd: EventFd,
+	stats: Arc<NetworkStats>,
+	pcap: Option<PcapSender>,
+	start: Instant,
+	pollfds: Vec<libc::pollfd>,
+	timers: Vec<*mut Timer>,
+}
+
+impl Context {
+	fn now_ms(&self) -> i64 {
+		self.start.elapsed().as_millis() as i64
+	}
+
+	/// Returns the time until the next timer expires.
+	fn next_timeout(&self, timeout: u32) -> u32 {
+		let now = self.now_ms();
+		self.timers
+			.iter()
+			.filter_map(|timer| unsafe { (**timer).expire_ms })
+			.map(|expire| (expire - now).clamp(0, timeout.into()) as u32)
+			.fold(timeout, u32::min)
+	}
+
+	/// Fires the expired timers.
+	///
+	/// The callbacks may modify or free any timer, so every timer is checked right before it fires.
+	unsafe fn fire_timers(context: *mut Context) {
+		let now = (*context).now_ms();
+		let due = (*context).timers.clone();
+		for timer in due {
+			if !(*context).timers.contains(&timer) {
+				continue;
+			}
+			if matches!((*timer).expire_ms, Some(expire) if expire <= now) {
+				(*timer).expire_ms = None;
+				if let Some(callback) = (*timer).callback {
+					callback((*timer).opaque);
+				}
+			}
+		}
+	}
+}
+
+unsafe fn context<'a>(opaque: *mut c_void) -> &'a mut Context {
+	&mut *(opaque as *mut Context)
+}
+
+/// Copies a packet of libslirp into the receive queue of the guest.
+unsafe extern "C" fn send_packet(buf: *const c_void, len: usize, opaque: *mut c_void) -> isize {
+	let context = context(opaque);
+	let packet = std::slice::from_raw_parts(buf as *const u8, len);
+	let queue = &mut *context.rx_queue;
+
+	let written = ptr::read_volatile(&queue.written);
+	let read = ptr::read_volatile(&queue.read);
+	let idx = written % queue.depth();
+	if written - read >= queue.depth() || len > queue.inner[idx].data.len() {
+		// libslirp retransmits TCP, like a congested link would
+		debug!("slirp: receive queue is full, dropping a packet");
+		context
+			.stats
+			.record_receive(&Err(io::ErrorKind::WouldBlock.into()));
+		return len as isize;
+	}
+
+	queue.inner[idx].data[..len].copy_from_slice(packet);
+	ptr::write_volatile(&mut queue.inner[idx].len, len as u16);
+	ptr::write_volatile(&mut queue.written, written + 1);
+	context.stats.record_receive(&Ok(len));
+	if let Some(pcap) = &context.pcap {
+		pcap.capture(packet);
+	}
+	if let Err(err) = context.evtfd.write(1) {
+		warn!("Unable to trigger interrupt: {}", err);
+	}
+	len as isize
+}
+
+unsafe extern "C" fn guest_error(msg: *const c_char, _opaque: *mut c_void) {
+	warn!("slirp: {}", CStr::from_ptr(msg).to_string_lossy());
+}
+
+unsafe extern "C" fn clock_get_ns(opaque: *mut c_void) -> i64 {
+	context(opaque).start.elapsed().as_nanos() as i64
+}
+
+unsafe extern "C" fn timer_new(
+	callback: SlirpTimerCb,
+	cb_opaque: *mut c_void,
+	opaque: *mut c_void,
+) -> *mut c_void {
+	let timer = Box::into_raw(Box::new(Timer {
+		callback,
+		opaque: cb_opaque,
+		expire_ms: None,
+	}));
+	context(opaque).timers.push(timer);
+	timer as *mut c_void
+}
+
+unsafe extern "C" fn timer_free(timer: *mut c_void, opaque: *mut c_void) {
+	let timer = timer as *mut Timer;
+	context(opaque).timers.retain(|other| *other != timer);
+	drop(Box::from_raw(timer));
+}
+
+unsafe extern "C" fn timer_mod(timer: *mut c_void, expire_time: i64, _opaque: *mut c_void) {
+	(*(timer as *mut Timer)).expire_ms = Some(expire_time);
+}
+
+// The sockets are registered on every poll, so there is nothing to track.
+unsafe extern "C" fn register_poll_fd(_fd: c_int, _opaque: *mut c_void) {}
+
+unsafe extern "C" fn unregister_poll_fd(_fd: c_int, _opaque: *mut c_void) {}
+
+// libslirp is only called from the polling thread, which polls again anyway.
+unsafe extern "C" fn notify(_opaque: *mut c_void) {}
+
+unsafe extern "C" fn add_poll(fd: c_int, events: c_int, opaque: *mut c_void) -> c_int {
+	let pollfds = &mut context(opaque).pollfds;
+	let events = POLL_EVENTS
+		.iter()
+		.filter(|(slirp, _)| events & slirp != 0)
+		.fold(0, |acc, (_, poll)| acc | poll);
+	pollfds.push(libc::pollfd {
+		fd,
+		events,
+		revents: 0,
+	});
+	(pollfds.len() - 1) as c_int
+}
+
+unsafe extern "C" fn get_revents(idx: c_int, opaque: *mut c_void) -> c_int {
+	let revents = context(opaque).pollfds[idx as usize].revents;
+	POLL_EVENTS
+		.iter()
+		.filter(|(_, poll)| revents & poll != 0)
+		.fold(0, |acc, (slirp, _)| acc | slirp)
+}
+
+/// Hands the packets in the transmit queue of the guest to libslirp.
+unsafe fn send_available_packets(
+	slirp: *mut libslirp_sys::Slirp,
+	tx_queue: &mut SharedQueue,
+	context: *mut Context,
+) {
+	loop {
+		let written = ptr::read_volatile(&tx_queue.written);
+		let read = ptr::read_volatile(&tx_queue.read);
+		if written == read {
+			return;
+		}
+
+		let idx = read % tx_queue.depth();
+		let len = ptr::read_volatile(&tx_queue.inner[idx].len) as usize;
+		let packet = &tx_queue.inner[idx].data[..len];
+		if let Some(pcap) = &(*context).pcap {
+			pcap.capture(packet);
+		}
+		slirp_input(slirp, packet.as_ptr(), len as c_int);
+		(*context).stats.record_send(&Ok(len));
+		ptr::write_volatile(&mut tx_queue.read, read + 1);
+	}
+}
+
+/// Forwards the ports of all addresses of the host to the guest.
+unsafe fn add_port_forwards(
+	slirp: *mut libslirp_sys::Slirp,
+	network: &SlirpNetwork,
+	forwards: &[PortForward],
+) {
+	for forward in forwards {
+		let ret = slirp_add_hostfwd(
+			slirp,
+			(forward.protocol == Protocol::Udp) as c_int,
+			to_in_addr(Ipv4Addr::UNSPECIFIED),
+			forward.host_port.into(),
+			to_in_addr(network.guest),
+			forward.guest_port.into(),
+		);
+		if ret < 0 {
+			warn!(
+				"slirp: unable to forward {} port {} of the host",
+				forward.protocol.as_str(),
+				forward.host_port
+			);
+		} else {
+			info!(
+				"slirp: forward {} port {} to {}:{}",
+				forward.protocol.as_str(),
+				forward.host_port,
+				network.guest,
+				forward.guest_port
+			);
+		}
+	}
+}
+
+/// Starts the thread, which connects the shared queues at `start` to a virtual network.
+///
+/// The guest signals new packets in its transmit queue through `kick`, and new packets in the
+/// receive queue are signaled through `evtfd`.
+#[allow(clippy::too_many_arguments)]
+pub fn start(
+	network: SlirpNetwork,
+	port_forwards: Vec<PortForward>,
+	start: usize,
+	queue_depth: usize,
+	evtfd: EventFd,
+	kick: EventFd,
+	stats: Arc<NetworkStats>,
+	pcap: Option<PcapSender>,
+) -> io::Result<JoinHandle<()>> {
+	let (result_tx, result_rx) = sync_channel(1);
+
+	let thread = thread::spawn(move || {
+		let rx_queue = unsafe { SharedQueue::from_raw(start, queue_depth) };
+		rx_queue.init();
+		let tx_queue =
+			unsafe { SharedQueue::from_raw(start + SharedQueue::size(queue_depth), queue_depth) };
+		tx_queue.init();
+
+		let context = Box::into_raw(Box::new(Context {
+			rx_queue,
+			evtfd,
+			stats,
+			pcap,
+			start: Instant::now(),
+			pollfds: Vec::new(),
+			timers: Vec::new(),
+		}));
+		let callbacks = SlirpCb {
+			send_packet: Some(send_packet),
+			guest_error: Some(guest_error),
+			clock_get_ns: Some(clock_get_ns),
+			timer_new: Some(timer_new),
+			timer_free: Some(timer_free),
+			timer_mod: Some(timer_mod),
+			register_poll_fd: Some(register_poll_fd),
+			unregister_poll_fd: Some(unregister_poll_fd),
+			notify: Some(notify),
+			..unsafe { mem::zeroed() }
+		};
+
+		let mut config: SlirpConfig = unsafe { mem::zeroed() };
+		config.version = 1;
+		config.in_enabled = true;
+		config.vnetwork = to_in_addr(network.network());
+		config.vnetmask = to_in_addr(network.mask);
+		config.vhost = to_in_addr(network.gateway);
+		config.vdhcp_start = to_in_addr(network.guest);
+		config.vnameserver = to_in_addr(network.dns);
+		config.if_mtu = UHYVE_NET_MTU;
+		config.if_mru = UHYVE_NET_MTU;
+
+		let slirp = unsafe { slirp_new(&config, &callbacks, context as *mut c_void) };
+		if slirp.is_null() {
+			let _ = result_tx.send(Err(io::Error::new(
+				io::ErrorKind::Other,
+				"libslirp rejected the network configuration",
+			)));
+			return;
+		}
+		unsafe { add_port_forwards(slirp, &network, &port_forwards) };
+		let _ = result_tx.send(Ok(()));
+
+		// The shared queues live as long as the VM, so libslirp is never cleaned up.
+		loop {
+			unsafe {
+				(*context).pollfds.clear();
+				(*context).pollfds.push(libc::pollfd {
+					fd: kick.as_raw_fd(),
+					events: libc::POLLIN,
+					revents: 0,
+				});
+				let mut timeout = POLL_TIMEOUT_MS;
+				slirp_pollfds_fill(slirp, &mut timeout, Some(add_poll), context as *mut c_void);
+				let timeout = (*context).next_timeout(timeout);
+
+				let pollfds = &mut (*context).pollfds;
+				let ret = libc::poll(
+					pollfds.as_mut_ptr(),
+					pollfds.len() as libc::nfds_t,
+					timeout as c_int,
+				);
+				if ret < 0 {
+					let err = io::Error::last_os_error();
+					if err.kind() != io::ErrorKind::Interrupted {
+						warn!("slirp: poll failed: {}", err);
+					}
+				}
+				if (*context).pollfds[0].revents & libc::POLLIN != 0 {
+					let _ = kick.read();
+				}
+
+				slirp_pollfds_poll(
+					slirp,
+					(ret < 0) as c_int,
+					Some(get_revents),
+					context as *mut c_void,
+				);
+				Context::fire_timers(context);
+				send_available_packets(slirp, tx_queue, context);
+			}
+		}
+	});
+
+	result_rx
+		.recv()
+		.unwrap_or_else(|_| Err(io::ErrorKind::BrokenPipe.into()))?;
+	Ok(thread)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_slirp_network() {
+		let qemu = SlirpNetwork::new(&NicSpec::default(), None);
+		assert_eq!(qemu.guest, Ipv4Addr::new(10, 0, 2, 15));
+		assert_eq!(qemu.gateway, Ipv4Addr::new(10, 0, 2, 2));
+		assert_eq!(qemu.dns, Ipv4Addr::new(10, 0, 2, 3));
+		assert_eq!(qemu.network(), Ipv4Addr::new(10, 0, 2, 0));
+
+		let nic = NicSpec {
+			ip: Some(Ipv4Addr::new(192, 168, 7, 10)),
+			..Default::default()
+		};
+		let custom = SlirpNetwork::new(&nic, Some(Ipv4Addr::new(192, 168, 7, 53)));
+		assert_eq!(custom.gateway, Ipv4Addr::new(192, 168, 7, 2));
+		assert_eq!(custom.mask, DEFAULT_MASK);
+		assert_eq!(custom.dns, Ipv4Addr::new(192, 168, 7, 53));
+	}
+}
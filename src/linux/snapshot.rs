@@ -0,0 +1,452 @@
+//! Snapshots of a VM, see `--snapshot` and `--restore`.
+//!
+//! A snapshot holds the guest memory, the registers of all vCPUs and the [`BootInfo`], so that a
+//! restored kernel continues where it has been stopped, without booting again. The state of the
+//! devices is not part of the snapshot, they are reset to the state after booting.
+//!
+//! # Format
+//!
+//! All integers are little endian. The file starts with a header:
+//!
+//! | Offset | Size | Content                                                    |
+//! |--------|------|------------------------------------------------------------|
+//! | 0      | 8    | magic `UHYVESNP`                                           |
+//! | 8      | 4    | version, currently 1                                       |
+//! | 12     | 4    | number of vCPUs                                            |
+//! | 16     | 8    | size of the guest memory in bytes                          |
+//! | 24     | 8    | guest physical address of the kernel                       |
+//! | 32     | 8    | entry point of the kernel                                  |
+//! | 40     | 4    | size of the [`BootInfo`]                                   |
+//! | 44     | 4    | size of the state of a vCPU                                |
+//! | 48     | 4    | length of the path of the kernel                           |
+//! | 52     | ...  | path of the kernel                                         |
+//!
+//! The [`BootInfo`], the [`VcpuState`] of each vCPU and the guest memory follow the header. The
+//! structures are stored as they are laid out in memory, the sizes in the header reject snapshots
+//! of other builds.
+
+use std::{
+	fmt,
+	fs::File,
+	io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+	mem::{self, MaybeUninit},
+	os::unix::ffi::OsStrExt,
+	path::{Path, PathBuf},
+	slice,
+};
+
+use kvm_bindings::{kvm_fpu, kvm_lapic_state, kvm_regs, kvm_sregs, kvm_xsave};
+use kvm_ioctls::VcpuFd;
+
+use crate::vm::{BootInfo, HypervisorResult};
+
+const MAGIC: &[u8; 8] = b"UHYVESNP";
+const VERSION: u32 = 1;
+
+fn invalid_snapshot(message: String) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+	unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Reads a `T` as it is laid out in memory.
+///
+/// # Safety
+///
+/// Every bit pattern has to be a valid `T`.
+unsafe fn read_raw<T: Copy>(reader: &mut impl Read) -> io::Result<T> {
+	let mut value = MaybeUninit::<T>::zeroed();
+	reader.read_exact(slice::from_raw_parts_mut(
+		value.as_mut_ptr() as *mut u8,
+		mem::size_of::<T>(),
+	))?;
+	Ok(value.assume_init())
+}
+
+/// The registers of a vCPU, see [`UhyveCPU::export_state`](crate::linux::vcpu::UhyveCPU::export_state).
+#[derive(Clone, Copy)]
+pub struct VcpuState {
+	pub regs: kvm_regs,
+	pub sregs: kvm_sregs,
+	pub fpu: kvm_fpu,
+	/// The 4096 bytes of the extended state in the standard format of `XSAVE`
+	///
+	/// Besides the SSE and AVX registers, this holds the opmask registers, the upper halves of
+	/// ZMM0-ZMM15 and ZMM16-ZMM31 of AVX-512, which end at byte 2688. `kvm_fpu` only covers the
+	/// legacy region, so a guest using AVX-512 would be corrupted without this area.
+	pub xsave: kvm_xsave,
+	/// The local APIC, whose timer keeps the scheduler of the kernel running
+	pub lapic: kvm_lapic_state,
+}
+
+impl fmt::Debug for VcpuState {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("VcpuState")
+			.field("regs", &self.regs)
+			.field("sregs", &self.sregs)
+			.finish_non_exhaustive()
+	}
+}
+
+impl VcpuState {
+	const SIZE: usize = mem::size_of::<kvm_regs>()
+		+ mem::size_of::<kvm_sregs>()
+		+ mem::size_of::<kvm_fpu>()
+		+ mem::size_of::<kvm_xsave>()
+		+ mem::size_of::<kvm_lapic_state>();
+
+	/// Reads the registers of `vcpu`.
+	pub fn export(vcpu: &VcpuFd) -> HypervisorResult<Self> {
+		Ok(Self {
+			regs: vcpu.get_regs()?,
+			sregs: vcpu.get_sregs()?,
+			fpu: vcpu.get_fpu()?,
+			xsave: vcpu.get_xsave()?,
+			lapic: vcpu.get_lapic()?,
+		})
+	}
+
+	/// Loads the registers into `vcpu`, whose CPUID has to permit the same extended state.
+	///
+	/// The special registers are set first, as they determine the mode of the vCPU, in which the
+	/// other registers are interpreted.
+	pub fn import(&self, vcpu: &VcpuFd) -> HypervisorResult<()> {
+		vcpu.set_sregs(&self.sregs)?;
+		vcpu.set_regs(&self.regs)?;
+		vcpu.set_fpu(&self.fpu)?;
+		vcpu.set_xsave(&self.xsave)?;
+		vcpu.set_lapic(&self.lapic)?;
+		Ok(())
+	}
+
+	fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+		writer.write_all(as_bytes(&self.regs))?;
+		writer.write_all(as_bytes(&self.sregs))?;
+		writer.write_all(as_bytes(&self.fpu))?;
+		writer.write_all(as_bytes(&self.xsave))?;
+		writer.write_all(as_bytes(&self.lapic))
+	}
+
+	fn read(reader: &mut impl Read) -> io::Result<Self> {
+		// the KVM structures consist of integers only
+		unsafe {
+			Ok(Self {
+				regs: read_raw(reader)?,
+				sregs: read_raw(reader)?,
+				fpu: read_raw(reader)?,
+				xsave: read_raw(reader)?,
+				lapic: read_raw(reader)?,
+			})
+		}
+	}
+}
+
+/// The state of a VM except its memory.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+	pub kernel_path: PathBuf,
+	pub memory_size: u64,
+	pub offset: u64,
+	pub entry_point: u64,
+	pub boot_info: BootInfo,
+	pub vcpus: Vec<VcpuState>,
+}
+
+impl Snapshot {
+	/// Writes the snapshot followed by `memory` to `path`.
+	pub fn write(&self, path: &Path, memory: &[u8]) -> io::Result<()> {
+		assert_eq!(memory.len() as u64, self.memory_size);
+		let kernel_path = self.kernel_path.as_os_str().as_bytes();
+		let mut file = BufWriter::new(File::create(path)?);
+		file.write_all(MAGIC)?;
+		file.write_all(&VERSION.to_le_bytes())?;
+		file.write_all(&(self.vcpus.len() as u32).to_le_bytes())?;
+		file.write_all(&self.memory_size.to_le_bytes())?;
+		file.write_all(&self.offset.to_le_bytes())?;
+		file.write_all(&self.entry_point.to_le_bytes())?;
+		file.write_all(&(mem::size_of::<BootInfo>() as u32).to_le_bytes())?;
+		file.write_all(&(VcpuState::SIZE as u32).to_le_bytes())?;
+		file.write_all(&(kernel_path.len() as u32).to_le_bytes())?;
+		file.write_all(kernel_path)?;
+
+		file.write_all(as_bytes(&self.boot_info))?;
+		for vcpu in &self.vcpus {
+			vcpu.write(&mut file)?;
+		}
+		file.write_all(memory)?;
+		file.into_inner()?.sync_all()
+	}
+
+	/// Reads the snapshot at `path` and returns the file positioned at the guest memory.
+	pub fn open(path: &Path) -> io::Result<(Self, File)> {
+		let mut file = BufReader::new(File::open(path)?);
+		let mut header = [0u8; 52];
+		file.read_exact(&mut header)?;
+		let field = |range: std::ops::Range<usize>| {
+			let mut bytes = [0u8; 8];
+			bytes[..range.len()].copy_from_slice(&header[range]);
+			u64::from_le_bytes(bytes)
+		};
+		if &header[0..8] != MAGIC {
+			return Err(invalid_snapshot("not an uhyve snapshot".to_string()));
+		}
+		if field(8..12) != VERSION.into() {
+			return Err(invalid_snapshot(format!(
+				"unsupported snapshot version {}",
+				field(8..12)
+			)));
+		}
+		if field(40..44) != mem::size_of::<BootInfo>() as u64
+			|| field(44..48) != VcpuState::SIZE as u64
+		{
+			return Err(invalid_snapshot(
+				"the snapshot has been written by another version of uhyve".to_string(),
+			));
+		}
+		let mut kernel_path = vec![0u8; field(48..52) as usize];
+		file.read_exact(&mut kernel_path)?;
+
+		// `BootInfo` consists of integers only
+		let boot_info = unsafe { read_raw(&mut file)? };
+		let vcpus = (0..field(12..16))
+			.map(|_| VcpuState::read(&mut file))
+			.collect::<io::Result<_>>()?;
+		let snapshot = Self {
+			kernel_path: PathBuf::from(std::ffi::OsStr::from_bytes(&kernel_path)),
+			memory_size: field(16..24),
+			offset: field(24..32),
+			entry_point: field(32..40),
+			boot_info,
+			vcpus,
+		};
+
+		let position = file.stream_position()?;
+		let mut file = file.into_inner();
+		file.seek(SeekFrom::Start(position))?;
+		if file.metadata()?.len() != position + snapshot.memory_size {
+			return Err(invalid_snapshot(
+				"the guest memory is truncated".to_string(),
+			));
+		}
+		Ok((snapshot, file))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::linux::KVM;
+	use assert_fs::TempDir;
+	use kvm_bindings::{kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES};
+	use kvm_ioctls::{VcpuExit, VmFd};
+	use std::arch::x86_64::__cpuid_count;
+
+	const GUEST_MEM_SIZE: usize = 2 << 20;
+	const CODE_ADDR: usize = 0x4000;
+
+	/// Creates a VM with identity mapped memory and a vCPU in long mode, which runs `code`.
+	fn long_mode_vm(code: &[u8]) -> (VmFd, VcpuFd, *mut u8) {
+		let vm = KVM.create_vm().unwrap();
+		let mem = unsafe {
+			libc::mmap(
+				std::ptr::null_mut(),
+				GUEST_MEM_SIZE,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+				-1,
+				0,
+			)
+		} as *mut u8;
+		assert_ne!(mem as *mut libc::c_void, libc::MAP_FAILED);
+		let region = kvm_userspace_memory_region {
+			slot: 0,
+			flags: 0,
+			guest_phys_addr: 0,
+			memory_size: GUEST_MEM_SIZE as u64,
+			userspace_addr: mem as u64,
+		};
+		unsafe { vm.set_user_memory_region(region) }.unwrap();
+
+		unsafe {
+			// PML4 at 0x1000, PDPT at 0x2000 and a PD at 0x3000 with a 2 MiB page
+			let write = |addr: usize, entry: u64| (mem.add(addr) as *mut u64).write(entry);
+			write(0x1000, 0x2003);
+			write(0x2000, 0x3003);
+			write(0x3000, 0x83);
+			std::ptr::copy_nonoverlapping(code.as_ptr(), mem.add(CODE_ADDR), code.len());
+		}
+
+		let vcpu = vm.create_vcpu(0).unwrap();
+		vcpu.set_cpuid2(&KVM.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap())
+			.unwrap();
+		let mut sregs = vcpu.get_sregs().unwrap();
+		let mut segment = sregs.cs;
+		segment.base = 0;
+		segment.limit = 0xffff_ffff;
+		segment.present = 1;
+		segment.dpl = 0;
+		segment.s = 1;
+		segment.g = 1;
+		segment.selector = 0x10;
+		segment.type_ = 3;
+		segment.db = 1;
+		segment.l = 0;
+		sregs.ds = segment;
+		sregs.es = segment;
+		sregs.ss = segment;
+		segment.selector = 0x8;
+		segment.type_ = 11;
+		segment.db = 0;
+		segment.l = 1;
+		sregs.cs = segment;
+		sregs.cr3 = 0x1000;
+		// PAE, OSFXSR, OSXMMEXCPT and OSXSAVE
+		sregs.cr4 = (1 << 5) | (1 << 9) | (1 << 10) | (1 << 18);
+		// PE, MP, ET, NE and PG
+		sregs.cr0 = 0x8000_0033;
+		// LME and LMA
+		sregs.efer = (1 << 8) | (1 << 10);
+		vcpu.set_sregs(&sregs).unwrap();
+		let regs = kvm_regs {
+			rip: CODE_ADDR as u64,
+			rflags: 2,
+			..Default::default()
+		};
+		vcpu.set_regs(&regs).unwrap();
+		(vm, vcpu, mem)
+	}
+
+	fn zmm_pattern(index: usize) -> u32 {
+		0xa5a5_0000 | index as u32
+	}
+
+	/// Checks, that ZMM16-ZMM31 hold the pattern of [`zmm_pattern`] in the `XSAVE` area.
+	fn assert_hi16_zmm(xsave: &kvm_xsave) {
+		// the offset of the Hi16_ZMM component in the standard format
+		let offset = unsafe { __cpuid_count(0xd, 7) }.ebx as usize / 4;
+		for index in 0..16 {
+			let zmm = &xsave.region[offset + index * 16..offset + (index + 1) * 16];
+			assert!(
+				zmm.iter().all(|&lane| lane == zmm_pattern(index)),
+				"ZMM{} is {:x?}",
+				16 + index,
+				zmm
+			);
+		}
+	}
+
+	#[test]
+	fn test_avx512_state() {
+		// KVM may hide AVX-512 from its guests, e.g., in a nested VM
+		let avx512f = KVM
+			.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+			.unwrap()
+			.as_slice()
+			.iter()
+			.any(|entry| entry.function == 7 && entry.index == 0 && entry.ebx & (1 << 16) != 0);
+		if !avx512f {
+			eprintln!("Skipping the test, as KVM lacks AVX-512");
+			return;
+		}
+
+		// enable x87, SSE, AVX, the opmasks and all ZMM registers in XCR0
+		// xor ecx, ecx; mov eax, 0xe7; xor edx, edx; xsetbv
+		let mut code = vec![
+			0x31, 0xc9, 0xb8, 0xe7, 0x00, 0x00, 0x00, 0x31, 0xd2, 0x0f, 0x01, 0xd1,
+		];
+		for index in 0..16 {
+			// mov eax, pattern
+			code.push(0xb8);
+			code.extend_from_slice(&zmm_pattern(index).to_le_bytes());
+			// vpbroadcastd zmm(16 + index), eax with the inverted bit 3 of the register in EVEX.R
+			let evex_p0 = if index < 8 { 0xe2 } else { 0x62 };
+			let modrm = 0xc0 | ((index as u8 & 7) << 3);
+			code.extend_from_slice(&[0x62, evex_p0, 0x7d, 0x48, 0x7c, modrm]);
+		}
+		// hlt
+		code.push(0xf4);
+
+		let (vm, vcpu, mem) = long_mode_vm(&code);
+		assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+		let state = VcpuState::export(&vcpu).unwrap();
+		assert_hi16_zmm(&state.xsave);
+
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("vm.snapshot");
+		let memory = unsafe { slice::from_raw_parts(mem, GUEST_MEM_SIZE) };
+		let snapshot = Snapshot {
+			kernel_path: PathBuf::from("/path/to/kernel"),
+			memory_size: GUEST_MEM_SIZE as u64,
+			offset: 0,
+			entry_point: CODE_ADDR as u64,
+			boot_info: BootInfo::new(),
+			vcpus: vec![state],
+		};
+		snapshot.write(&path, memory).unwrap();
+
+		// the registers survive the file and the import into another VM
+		let (restored, _) = Snapshot::open(&path).unwrap();
+		let (restored_vm, restored_vcpu, restored_mem) = long_mode_vm(&[0xf4]);
+		restored.vcpus[0].import(&restored_vcpu).unwrap();
+		assert_hi16_zmm(&restored_vcpu.get_xsave().unwrap());
+		assert_eq!(
+			restored_vcpu.get_regs().unwrap().rip,
+			vcpu.get_regs().unwrap().rip
+		);
+
+		drop((vcpu, vm, restored_vcpu, restored_vm));
+		unsafe {
+			libc::munmap(mem as *mut libc::c_void, GUEST_MEM_SIZE);
+			libc::munmap(restored_mem as *mut libc::c_void, GUEST_MEM_SIZE);
+		}
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("vm.snapshot");
+		let mut vcpu: VcpuState = unsafe { MaybeUninit::zeroed().assume_init() };
+		vcpu.regs.rip = 0x800123;
+		vcpu.xsave.region[42] = 0xdead_beef;
+		let mut boot_info = BootInfo::new();
+		boot_info.cpu_online = 2;
+		let snapshot = Snapshot {
+			kernel_path: PathBuf::from("/path/to/kernel"),
+			memory_size: 0x4000,
+			offset: 0x800000,
+			entry_point: 0x800100,
+			boot_info,
+			vcpus: vec![vcpu; 2],
+		};
+		let memory = (0..0x4000).map(|byte| byte as u8).collect::<Vec<_>>();
+		snapshot.write(&path, &memory).unwrap();
+
+		let (restored, mut file) = Snapshot::open(&path).unwrap();
+		assert_eq!(restored.kernel_path, snapshot.kernel_path);
+		assert_eq!(restored.memory_size, 0x4000);
+		assert_eq!(restored.offset, 0x800000);
+		assert_eq!(restored.entry_point, 0x800100);
+		assert_eq!(restored.boot_info.cpu_online, 2);
+		assert_eq!(restored.vcpus.len(), 2);
+		assert_eq!(restored.vcpus[1].regs.rip, 0x800123);
+		assert_eq!(restored.vcpus[1].xsave.region[42], 0xdead_beef);
+		let mut restored_memory = Vec::new();
+		file.read_to_end(&mut restored_memory).unwrap();
+		assert_eq!(restored_memory, memory);
+
+		// a truncated memory is rejected
+		let len = std::fs::metadata(&path).unwrap().len();
+		std::fs::OpenOptions::new()
+			.write(true)
+			.open(&path)
+			.unwrap()
+			.set_len(len - 1)
+			.unwrap();
+		assert_eq!(
+			Snapshot::open(&path).unwrap_err().kind(),
+			io::ErrorKind::InvalidData
+		);
+	}
+}
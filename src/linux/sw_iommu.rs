@@ -0,0 +1,159 @@
+//! A paravirtualized software IOMMU for the DMA of the virtio devices.
+//!
+//! The guest maps I/O virtual addresses (IOVAs) to guest physical addresses with
+//! hypercalls. Before a device accesses guest memory, the address is translated
+//! and checked against these mappings.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+/// The device may read from the mapping.
+pub const IOMMU_PERM_READ: u8 = 1 << 0;
+/// The device may write to the mapping.
+pub const IOMMU_PERM_WRITE: u8 = 1 << 1;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum IommuError {
+	#[error("IOVA 0x{iova:x} of device {devid} is not mapped")]
+	NotMapped { devid: u32, iova: u64 },
+	#[error("Access to IOVA 0x{iova:x} of device {devid} exceeds its mapping")]
+	OutOfBounds { devid: u32, iova: u64 },
+	#[error("Device {devid} lacks permissions 0b{perm:b} for IOVA 0x{iova:x}")]
+	PermissionDenied { devid: u32, iova: u64, perm: u8 },
+	#[error("Mapping at IOVA 0x{iova:x} of device {devid} overlaps an existing mapping")]
+	Overlap { devid: u32, iova: u64 },
+}
+
+/// Arguments of the hypercalls on [`UHYVE_PORT_IOMMU_MAP`](crate::consts::UHYVE_PORT_IOMMU_MAP)
+/// and [`UHYVE_PORT_IOMMU_UNMAP`](crate::consts::UHYVE_PORT_IOMMU_UNMAP).
+#[repr(C, packed)]
+pub struct SysIommuMap {
+	pub devid: u32,
+	pub perm: u8,
+	pub iova: u64,
+	pub gpa: u64,
+	pub size: usize,
+	pub ret: i32,
+}
+
+/// The IOMMU mappings of all devices.
+#[derive(Debug, Default)]
+pub struct SoftIommu {
+	/// IOVA → (GPA, size, permissions) per device
+	domains: HashMap<u32, HashMap<u64, (u64, usize, u8)>>,
+}
+
+impl SoftIommu {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn map(
+		&mut self,
+		devid: u32,
+		iova: u64,
+		gpa: u64,
+		size: usize,
+		perm: u8,
+	) -> Result<(), IommuError> {
+		let domain = self.domains.entry(devid).or_default();
+		let end = iova + size as u64;
+		if size == 0
+			|| domain
+				.iter()
+				.any(|(&start, &(_, len, _))| iova < start + len as u64 && start < end)
+		{
+			return Err(IommuError::Overlap { devid, iova });
+		}
+
+		domain.insert(iova, (gpa, size, perm));
+		Ok(())
+	}
+
+	pub fn unmap(&mut self, devid: u32, iova: u64) -> Result<(), IommuError> {
+		self.domains
+			.get_mut(&devid)
+			.and_then(|domain| domain.remove(&iova))
+			.map(|_| ())
+			.ok_or(IommuError::NotMapped { devid, iova })
+	}
+
+	/// Translates an access of `len` bytes at `iova` into a guest physical address.
+	pub fn translate(
+		&self,
+		devid: u32,
+		iova: u64,
+		len: usize,
+		perm: u8,
+	) -> Result<u64, IommuError> {
+		let (start, &(gpa, size, mapped_perm)) = self
+			.domains
+			.get(&devid)
+			.and_then(|domain| {
+				domain
+					.iter()
+					.find(|(&start, &(_, size, _))| start <= iova && iova < start + size as u64)
+			})
+			.ok_or(IommuError::NotMapped { devid, iova })?;
+
+		if iova + len as u64 > start + size as u64 {
+			return Err(IommuError::OutOfBounds { devid, iova });
+		}
+		if mapped_perm & perm != perm {
+			return Err(IommuError::PermissionDenied { devid, iova, perm });
+		}
+
+		Ok(gpa + (iova - start))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_sw_iommu() {
+		let mut iommu = SoftIommu::new();
+		iommu
+			.map(0, 0x1000, 0x20_0000, 0x2000, IOMMU_PERM_READ)
+			.unwrap();
+
+		assert_eq!(
+			iommu.translate(0, 0x1800, 0x100, IOMMU_PERM_READ),
+			Ok(0x20_0800)
+		);
+		assert_eq!(
+			iommu.translate(0, 0x2f00, 0x200, IOMMU_PERM_READ),
+			Err(IommuError::OutOfBounds {
+				devid: 0,
+				iova: 0x2f00
+			})
+		);
+		assert_eq!(
+			iommu.translate(0, 0x1000, 1, IOMMU_PERM_WRITE),
+			Err(IommuError::PermissionDenied {
+				devid: 0,
+				iova: 0x1000,
+				perm: IOMMU_PERM_WRITE
+			})
+		);
+		assert_eq!(
+			iommu.translate(1, 0x1000, 1, IOMMU_PERM_READ),
+			Err(IommuError::NotMapped {
+				devid: 1,
+				iova: 0x1000
+			})
+		);
+		assert_eq!(
+			iommu.map(0, 0x2000, 0, 0x1000, IOMMU_PERM_READ),
+			Err(IommuError::Overlap {
+				devid: 0,
+				iova: 0x2000
+			})
+		);
+
+		iommu.unmap(0, 0x1000).unwrap();
+		assert!(iommu.translate(0, 0x1000, 1, IOMMU_PERM_READ).is_err());
+	}
+}
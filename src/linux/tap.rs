@@ -0,0 +1,161 @@
+//! TUN/TAP devices, which optionally prefix every packet with a `virtio_net_hdr`, see
+//! `--net-vnet-hdr` and `--nic-mode`.
+//!
+//! tun-tap opens its devices without `IFF_VNET_HDR` and the flags of a TUN/TAP device cannot be
+//! changed after `TUNSETIFF`, so the device is opened with its own ioctl. uhyve does not enable
+//! any offloads with `TUNSETOFFLOAD`, so the kernel never passes GSO packets to uhyve, and
+//! the header of every sent packet has `gso_type = VIRTIO_NET_HDR_GSO_NONE`.
+
+use std::{
+	ffi::CStr,
+	fs::{File, OpenOptions},
+	io,
+	os::unix::io::{AsRawFd, RawFd},
+};
+
+use crate::vm::NicMode;
+
+/// `_IOW('T', 202, int)`
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+/// `_IOW('T', 216, int)`
+const TUNSETVNETHDRSZ: libc::c_ulong = 0x400454d8;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+
+/// Size of `struct virtio_net_hdr_v1`, which includes `num_buffers`
+pub const VNET_HDR_SIZE: usize = 12;
+
+/// `struct ifreq` with the flags of `TUNSETIFF`
+#[repr(C)]
+struct IfReqFlags {
+	name: [libc::c_char; libc::IFNAMSIZ],
+	flags: libc::c_short,
+	_padding: [u8; 22],
+}
+
+/// A TUN or TAP device without packet information.
+#[derive(Debug)]
+pub struct Tap {
+	file: File,
+	name: String,
+	vnet_hdr: bool,
+}
+
+impl Tap {
+	/// Opens the TAP or, in [`NicMode::Tun`], the TUN device `name`, which is created if necessary.
+	/// The kernel chooses the name, if `name` is empty.
+	pub fn open(name: &str, mode: NicMode, vnet_hdr: bool) -> io::Result<Self> {
+		if name.len() >= libc::IFNAMSIZ {
+			return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+		}
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open("/dev/net/tun")?;
+
+		let mut request = IfReqFlags {
+			name: [0; libc::IFNAMSIZ],
+			flags: IFF_NO_PI,
+			_padding: [0; 22],
+		};
+		request.flags |= match mode {
+			NicMode::Tap => IFF_TAP,
+			NicMode::Tun => IFF_TUN,
+		};
+		if vnet_hdr {
+			request.flags |= IFF_VNET_HDR;
+		}
+		for (dst, &src) in request.name.iter_mut().zip(name.as_bytes()) {
+			*dst = src as libc::c_char;
+		}
+		if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut request) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		if vnet_hdr {
+			let size = VNET_HDR_SIZE as libc::c_int;
+			if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETVNETHDRSZ, &size) } < 0 {
+				return Err(io::Error::last_os_error());
+			}
+		}
+
+		let name = unsafe { CStr::from_ptr(request.name.as_ptr()) }
+			.to_string_lossy()
+			.into_owned();
+		Ok(Self {
+			file,
+			name,
+			vnet_hdr,
+		})
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Sends an Ethernet frame or IP packet, which is prefixed with an empty header, if the device has one.
+	pub fn send(&self, frame: &[u8]) -> io::Result<usize> {
+		let header = [0u8; VNET_HDR_SIZE];
+		let header_len = if self.vnet_hdr { VNET_HDR_SIZE } else { 0 };
+		let iov = [
+			libc::iovec {
+				iov_base: header.as_ptr() as *mut libc::c_void,
+				iov_len: header_len,
+			},
+			libc::iovec {
+				iov_base: frame.as_ptr() as *mut libc::c_void,
+				iov_len: frame.len(),
+			},
+		];
+		let written = unsafe { libc::writev(self.file.as_raw_fd(), iov.as_ptr(), 2) };
+		if written < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok((written as usize).saturating_sub(header_len))
+	}
+
+	/// Receives an Ethernet frame or IP packet into `frame` and strips the header, if the device has one.
+	pub fn recv(&self, frame: &mut [u8]) -> io::Result<usize> {
+		let mut header = [0u8; VNET_HDR_SIZE];
+		let header_len = if self.vnet_hdr { VNET_HDR_SIZE } else { 0 };
+		let iov = [
+			libc::iovec {
+				iov_base: header.as_mut_ptr() as *mut libc::c_void,
+				iov_len: header_len,
+			},
+			libc::iovec {
+				iov_base: frame.as_mut_ptr() as *mut libc::c_void,
+				iov_len: frame.len(),
+			},
+		];
+		let read = unsafe { libc::readv(self.file.as_raw_fd(), iov.as_ptr(), 2) };
+		if read < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok((read as usize).saturating_sub(header_len))
+	}
+}
+
+impl AsRawFd for Tap {
+	fn as_raw_fd(&self) -> RawFd {
+		self.file.as_raw_fd()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem;
+
+	#[test]
+	fn test_ifreq_abi() {
+		assert_eq!(mem::size_of::<IfReqFlags>(), 40);
+	}
+
+	#[test]
+	fn test_name_too_long() {
+		let err = Tap::open("a-name-longer-than-ifnamsiz", NicMode::Tap, true).unwrap_err();
+		assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+	}
+}
@@ -0,0 +1,144 @@
+//! The TSC of the vCPUs, see `--tsc-khz` and `--tsc-offset`.
+//!
+//! KVM derives the TSC of a vCPU from the host's TSC, which is scaled to the frequency of the vCPU
+//! and shifted by its offset. A guest, which uses the TSC as clock source, sees no jumps, if all
+//! vCPUs have the same frequency and offset, even when they are migrated between host cores.
+
+use std::{fs, os::unix::io::AsRawFd};
+
+use kvm_bindings::kvm_device_attr;
+use kvm_ioctls::VcpuFd;
+
+use crate::vm::HypervisorResult;
+
+/// `_IO(KVMIO, 0xa2)`
+const KVM_SET_TSC_KHZ: libc::c_ulong = 0xaea2;
+/// `_IO(KVMIO, 0xa3)`
+const KVM_GET_TSC_KHZ: libc::c_ulong = 0xaea3;
+/// `_IOW(KVMIO, 0xe1, struct kvm_device_attr)`
+const KVM_SET_DEVICE_ATTR: libc::c_ulong = 0x4018_aee1;
+/// Not yet exported by `kvm-bindings` (Linux 5.16+).
+const KVM_VCPU_TSC_CTRL: u32 = 0;
+const KVM_VCPU_TSC_OFFSET: u64 = 0;
+
+/// Scales the TSC of `vcpu` to `khz`, which requires TSC scaling of the host CPU, if it differs
+/// from the host's frequency.
+pub fn set_tsc_khz(vcpu: &VcpuFd, khz: u32) -> HypervisorResult<()> {
+	if unsafe { libc::ioctl(vcpu.as_raw_fd(), KVM_SET_TSC_KHZ, libc::c_ulong::from(khz)) } < 0 {
+		return Err(kvm_ioctls::Error::last());
+	}
+	Ok(())
+}
+
+/// Returns the TSC frequency of `vcpu` in kHz.
+pub fn get_tsc_khz(vcpu: &VcpuFd) -> HypervisorResult<u32> {
+	let khz = unsafe { libc::ioctl(vcpu.as_raw_fd(), KVM_GET_TSC_KHZ) };
+	if khz < 0 {
+		return Err(kvm_ioctls::Error::last());
+	}
+	Ok(khz as u32)
+}
+
+/// Sets the offset of the TSC of `vcpu` to the scaled TSC of the host.
+pub fn set_tsc_offset(vcpu: &VcpuFd, offset: i64) -> HypervisorResult<()> {
+	let attr = kvm_device_attr {
+		group: KVM_VCPU_TSC_CTRL,
+		attr: KVM_VCPU_TSC_OFFSET,
+		addr: &offset as *const i64 as u64,
+		..Default::default()
+	};
+	let attr = &attr as *const kvm_device_attr;
+	if unsafe { libc::ioctl(vcpu.as_raw_fd(), KVM_SET_DEVICE_ATTR, attr) } < 0 {
+		return Err(kvm_ioctls::Error::last());
+	}
+	Ok(())
+}
+
+/// Returns the TSC frequency of the host in kHz, which a new `vcpu` runs with, or the maximal
+/// frequency of the first host CPU, if KVM does not report it.
+pub fn host_tsc_khz(vcpu: &VcpuFd) -> Option<u32> {
+	get_tsc_khz(vcpu).ok().filter(|&khz| khz > 0).or_else(|| {
+		fs::read_to_string("/sys/bus/cpu/devices/cpu0/cpufreq/cpuinfo_max_freq")
+			.ok()?
+			.trim()
+			.parse()
+			.ok()
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::linux::KVM;
+	use core::arch::x86_64::_rdtsc as rdtsc;
+	use kvm_bindings::{kvm_regs, kvm_userspace_memory_region};
+	use std::ptr;
+
+	#[test]
+	fn test_tsc_offset() {
+		let vm = KVM.create_vm().unwrap();
+		let size = 0x2000;
+		let mem = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				size,
+				libc::PROT_READ | libc::PROT_WRITE,
+				libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+				-1,
+				0,
+			)
+		};
+		assert_ne!(mem, libc::MAP_FAILED);
+		let region = kvm_userspace_memory_region {
+			slot: 0,
+			flags: 0,
+			guest_phys_addr: 0,
+			memory_size: size as u64,
+			userspace_addr: mem as u64,
+		};
+		unsafe { vm.set_user_memory_region(region) }.unwrap();
+
+		// cpuid; rdtsc; hlt
+		let code = [0x0f, 0xa2, 0x0f, 0x31, 0xf4];
+		unsafe {
+			ptr::copy_nonoverlapping(code.as_ptr(), (mem as *mut u8).add(0x1000), code.len());
+		}
+
+		// the guest's TSC starts at zero
+		let offset = -(unsafe { rdtsc() } as i64);
+		for id in 0..2 {
+			let vcpu = vm.create_vcpu(id).unwrap();
+			set_tsc_offset(&vcpu, offset).unwrap();
+			assert!(host_tsc_khz(&vcpu).unwrap() > 0);
+
+			let mut sregs = vcpu.get_sregs().unwrap();
+			sregs.cs.base = 0;
+			sregs.cs.selector = 0;
+			vcpu.set_sregs(&sregs).unwrap();
+			let regs = kvm_regs {
+				rip: 0x1000,
+				rflags: 2,
+				..Default::default()
+			};
+			vcpu.set_regs(&regs).unwrap();
+
+			let before = unsafe { rdtsc() };
+			assert!(matches!(vcpu.run().unwrap(), kvm_ioctls::VcpuExit::Hlt));
+			let after = unsafe { rdtsc() };
+
+			let regs = vcpu.get_regs().unwrap();
+			let guest = (regs.rdx << 32) | (regs.rax & 0xffff_ffff);
+			let host = guest.wrapping_sub(offset as u64);
+			assert!(
+				before <= host && host <= after,
+				"vCPU {} read the TSC {:#x} outside of {:#x}..={:#x}",
+				id,
+				host,
+				before,
+				after
+			);
+		}
+
+		unsafe { libc::munmap(mem, size) };
+	}
+}
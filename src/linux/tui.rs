@@ -0,0 +1,423 @@
+//! A dashboard of the running VM in the terminal (`--tui`).
+//!
+//! The vCPU threads report their exits every [`EVENT_INTERVAL`] and the dashboard keeps the exits
+//! per second of the last minute. Besides, it shows the network throughput, the memory used by
+//! uhyve and the last log messages, which [`init_logger`] captures instead of printing them.
+//!
+//! Keys: `q` stops the VM, `p` pauses and `r` resumes all vCPUs.
+
+use std::{
+	collections::VecDeque,
+	fs, io,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		mpsc::{Receiver, Sender},
+		Arc, Mutex,
+	},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant},
+};
+
+use crossterm::{
+	event::{self, Event, KeyCode},
+	execute,
+	terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record};
+use ratatui::{
+	backend::{Backend, CrosstermBackend},
+	layout::{Constraint, Direction, Layout, Rect},
+	style::{Color, Style},
+	widgets::{Block, Borders, Gauge, Paragraph, Sparkline},
+	Frame, Terminal,
+};
+
+use crate::{consts::PAGE_SIZE, vm::Vm, Uhyve};
+
+/// Interval, in which the vCPUs report their exits and the dashboard is redrawn.
+pub const EVENT_INTERVAL: Duration = Duration::from_millis(100);
+/// Length of the history in seconds
+const HISTORY_SECS: usize = 60;
+const LOG_TAIL_LINES: usize = 100;
+
+lazy_static! {
+	static ref LOG_TAIL: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+}
+
+/// Keeps the last log messages for the dashboard.
+struct TailLogger {
+	level: LevelFilter,
+}
+
+impl Log for TailLogger {
+	fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+		metadata.level() <= self.level
+	}
+
+	fn log(&self, record: &Record<'_>) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		let mut tail = LOG_TAIL.lock().unwrap();
+		if tail.len() == LOG_TAIL_LINES {
+			tail.pop_front();
+		}
+		tail.push_back(format!("[{}] {}", record.level(), record.args()));
+	}
+
+	fn flush(&self) {}
+}
+
+/// Captures the log messages for the dashboard instead of printing them.
+///
+/// The level is taken from `RUST_LOG`, if it is a plain level, and defaults to `info`.
+pub fn init_logger() {
+	let level = std::env::var("RUST_LOG")
+		.ok()
+		.and_then(|level| level.parse().ok())
+		.unwrap_or(LevelFilter::Info);
+	log::set_logger(Box::leak(Box::new(TailLogger { level }))).expect("Unable to set the logger");
+	log::set_max_level(level);
+}
+
+/// The exits of a vCPU since its last event.
+#[derive(Debug, Clone, Copy)]
+pub struct VcpuEvent {
+	pub id: u32,
+	pub exits: u64,
+}
+
+/// Counts the exits of a vCPU and reports them to the dashboard every [`EVENT_INTERVAL`].
+#[derive(Debug)]
+pub struct VcpuEventSender {
+	id: u32,
+	tx: Sender<VcpuEvent>,
+	exits: u64,
+	last_sent: Instant,
+}
+
+impl VcpuEventSender {
+	pub fn new(id: u32, tx: Sender<VcpuEvent>) -> Self {
+		Self {
+			id,
+			tx,
+			exits: 0,
+			last_sent: Instant::now(),
+		}
+	}
+
+	pub fn record_exit(&mut self) {
+		self.exits += 1;
+		if self.last_sent.elapsed() >= EVENT_INTERVAL {
+			// The dashboard may already be gone while the VM shuts down.
+			let _ = self.tx.send(VcpuEvent {
+				id: self.id,
+				exits: self.exits,
+			});
+			self.exits = 0;
+			self.last_sent = Instant::now();
+		}
+	}
+}
+
+/// Counts per second of the last [`HISTORY_SECS`] seconds.
+#[derive(Debug, Default)]
+struct History {
+	seconds: VecDeque<u64>,
+	current: u64,
+}
+
+impl History {
+	fn add(&mut self, count: u64) {
+		self.current += count;
+	}
+
+	/// Completes the current second.
+	fn tick(&mut self) {
+		if self.seconds.len() == HISTORY_SECS {
+			self.seconds.pop_front();
+		}
+		self.seconds.push_back(self.current);
+		self.current = 0;
+	}
+
+	/// Returns the count of the last complete second.
+	fn last(&self) -> u64 {
+		self.seconds.back().copied().unwrap_or(0)
+	}
+
+	/// Returns at most the last `len` seconds, the oldest first.
+	fn tail(&self, len: usize) -> Vec<u64> {
+		let skip = self.seconds.len().saturating_sub(len);
+		self.seconds.iter().skip(skip).copied().collect()
+	}
+}
+
+/// Returns the resident memory of uhyve in bytes, which includes the touched guest memory.
+fn resident_memory() -> Option<usize> {
+	let statm = fs::read_to_string("/proc/self/statm").ok()?;
+	let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+	Some(pages * PAGE_SIZE)
+}
+
+fn format_bytes(bytes: u64) -> String {
+	const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+	let mut value = bytes as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", value, UNITS[unit])
+}
+
+struct DashboardState {
+	vcpus: Vec<History>,
+	network: History,
+	network_bytes: u64,
+	mem_size: usize,
+	rss: usize,
+	paused: bool,
+}
+
+impl DashboardState {
+	fn new(vm: &Uhyve) -> Self {
+		Self {
+			vcpus: (0..vm.num_cpus()).map(|_| History::default()).collect(),
+			network: History::default(),
+			network_bytes: vm.network_bytes().unwrap_or(0),
+			mem_size: vm.guest_mem().1,
+			rss: resident_memory().unwrap_or(0),
+			paused: false,
+		}
+	}
+
+	fn record(&mut self, event: VcpuEvent) {
+		if let Some(history) = self.vcpus.get_mut(event.id as usize) {
+			history.add(event.exits);
+		}
+	}
+
+	fn tick(&mut self, vm: &Uhyve) {
+		for history in &mut self.vcpus {
+			history.tick();
+		}
+		if let Some(bytes) = vm.network_bytes() {
+			self.network.add(bytes.saturating_sub(self.network_bytes));
+			self.network_bytes = bytes;
+		}
+		self.network.tick();
+		self.rss = resident_memory().unwrap_or(self.rss);
+	}
+}
+
+fn draw_vcpus<B: Backend>(frame: &mut Frame<'_, B>, area: Rect, state: &DashboardState) {
+	let block = Block::default().borders(Borders::ALL).title("vCPU exits/s");
+	let inner = block.inner(area);
+	frame.render_widget(block, area);
+
+	let rows = inner.height as usize;
+	let shown = if state.vcpus.len() > rows {
+		rows.saturating_sub(1)
+	} else {
+		state.vcpus.len()
+	};
+	for (id, history) in state.vcpus.iter().enumerate().take(shown) {
+		let row = Rect {
+			y: inner.y + id as u16,
+			height: 1,
+			..inner
+		};
+		let columns = Layout::default()
+			.direction(Direction::Horizontal)
+			.constraints([Constraint::Length(18), Constraint::Min(0)].as_ref())
+			.split(row);
+		let label = Paragraph::new(format!("vCPU {:<3}{:>9}", id, history.last()));
+		frame.render_widget(label, columns[0]);
+		let data = history.tail(columns[1].width as usize);
+		let sparkline = Sparkline::default()
+			.data(&data)
+			.style(Style::default().fg(Color::Green));
+		frame.render_widget(sparkline, columns[1]);
+	}
+	if shown < state.vcpus.len() {
+		let row = Rect {
+			y: inner.y + shown as u16,
+			height: 1,
+			..inner
+		};
+		let more = Paragraph::new(format!("... and {} more", state.vcpus.len() - shown));
+		frame.render_widget(more, row);
+	}
+}
+
+fn draw<B: Backend>(frame: &mut Frame<'_, B>, state: &DashboardState) {
+	let rows = Layout::default()
+		.direction(Direction::Vertical)
+		.constraints(
+			[
+				Constraint::Min(4),
+				Constraint::Length(3),
+				Constraint::Length(3),
+				Constraint::Length(8),
+				Constraint::Length(1),
+			]
+			.as_ref(),
+		)
+		.split(frame.size());
+
+	draw_vcpus(frame, rows[0], state);
+
+	let data = state.network.tail(rows[1].width.saturating_sub(2) as usize);
+	let network = Sparkline::default()
+		.block(
+			Block::default()
+				.borders(Borders::ALL)
+				.title(format!("Network {}/s", format_bytes(state.network.last()))),
+		)
+		.data(&data)
+		.style(Style::default().fg(Color::Cyan));
+	frame.render_widget(network, rows[1]);
+
+	let ratio = if state.mem_size == 0 {
+		0.0
+	} else {
+		(state.rss as f64 / state.mem_size as f64).min(1.0)
+	};
+	let memory = Gauge::default()
+		.block(Block::default().borders(Borders::ALL).title("Memory"))
+		.gauge_style(Style::default().fg(Color::Yellow))
+		.ratio(ratio)
+		.label(format!(
+			"{} of {}",
+			format_bytes(state.rss as u64),
+			format_bytes(state.mem_size as u64)
+		));
+	frame.render_widget(memory, rows[2]);
+
+	let lines = rows[3].height.saturating_sub(2) as usize;
+	let log = {
+		let tail = LOG_TAIL.lock().unwrap();
+		let skip = tail.len().saturating_sub(lines);
+		tail.iter()
+			.skip(skip)
+			.cloned()
+			.collect::<Vec<_>>()
+			.join("\n")
+	};
+	let log = Paragraph::new(log).block(Block::default().borders(Borders::ALL).title("Log"));
+	frame.render_widget(log, rows[3]);
+
+	let help = if state.paused {
+		"q: quit  p: pause  r: resume  [paused]"
+	} else {
+		"q: quit  p: pause  r: resume"
+	};
+	frame.render_widget(Paragraph::new(help), rows[4]);
+}
+
+fn run<B: Backend>(
+	terminal: &mut Terminal<B>,
+	vm: &Uhyve,
+	events: &Receiver<VcpuEvent>,
+	stop: &AtomicBool,
+) -> io::Result<()> {
+	let mut state = DashboardState::new(vm);
+	let mut next_tick = Instant::now() + Duration::from_secs(1);
+
+	while !stop.load(Ordering::SeqCst) {
+		for event in events.try_iter() {
+			state.record(event);
+		}
+		if Instant::now() >= next_tick {
+			state.tick(vm);
+			next_tick += Duration::from_secs(1);
+		}
+
+		terminal.draw(|frame| draw(frame, &state))?;
+
+		if event::poll(EVENT_INTERVAL)? {
+			if let Event::Key(key) = event::read()? {
+				match key.code {
+					KeyCode::Char('q') => vm.shutdown(),
+					KeyCode::Char('p') => {
+						vm.pause_vcpus();
+						state.paused = true;
+					}
+					KeyCode::Char('r') => {
+						vm.resume_vcpus();
+						state.paused = false;
+					}
+					_ => {}
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Draws the dashboard on the terminal, until it is stopped.
+#[derive(Debug)]
+pub struct Dashboard {
+	stop: Arc<AtomicBool>,
+	thread: JoinHandle<io::Result<()>>,
+}
+
+impl Dashboard {
+	pub fn start(vm: Arc<Uhyve>, events: Receiver<VcpuEvent>) -> Self {
+		let stop = Arc::new(AtomicBool::new(false));
+		let thread_stop = stop.clone();
+		let thread = thread::spawn(move || {
+			enable_raw_mode()?;
+			let mut stdout = io::stdout();
+			execute!(stdout, EnterAlternateScreen)?;
+			let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+			let result = run(&mut terminal, &vm, &events, &thread_stop);
+
+			disable_raw_mode()?;
+			execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+			terminal.show_cursor()?;
+			result
+		});
+
+		Self { stop, thread }
+	}
+
+	/// Restores the terminal and prints the log messages, which are still kept.
+	pub fn stop(self) {
+		self.stop.store(true, Ordering::SeqCst);
+		let result = self.thread.join().unwrap();
+		for line in LOG_TAIL.lock().unwrap().drain(..) {
+			eprintln!("{}", line);
+		}
+		if let Err(err) = result {
+			eprintln!("The dashboard failed: {}", err);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_history() {
+		let mut history = History::default();
+		assert_eq!(history.last(), 0);
+
+		history.add(3);
+		history.add(4);
+		history.tick();
+		assert_eq!(history.last(), 7);
+
+		for second in 0..HISTORY_SECS as u64 {
+			history.add(second);
+			history.tick();
+		}
+		assert_eq!(history.seconds.len(), HISTORY_SECS);
+		assert_eq!(history.tail(3), [57, 58, 59]);
+		assert_eq!(history.tail(2 * HISTORY_SECS).len(), HISTORY_SECS);
+	}
+}
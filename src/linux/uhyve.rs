@@ -1,61 +1,452 @@
 //! This file contains the entry point to the Hypervisor. The Uhyve utilizes KVM to
 //! create a Virtual Machine and load the kernel.
 
+use crate::console_log::{ConsoleLog, DebugPortLog};
 use crate::consts::*;
+use crate::cpu_features::{CpuFeatureChange, CpuidOverride};
+use crate::kernel_signature::KernelVerifier;
+use crate::linux::cold_pages::ColdPageHinter;
+use crate::linux::dirty_ring::DirtyTracker;
+use crate::linux::exit_log::ExitLog;
+use crate::linux::irq::GsiRoutingTable;
+use crate::linux::irqfd;
+use crate::linux::kernel_log::KernelLog;
+use crate::linux::msr_filter;
+use crate::linux::net_stats::{NetworkStats, NetworkStatsReporter};
+use crate::linux::netlink;
+use crate::linux::numa;
+use crate::linux::pcap::PcapCapture;
+use crate::linux::pci::{PciBus, PciDevice};
+use crate::linux::port_forward::PortForwardRules;
+use crate::linux::ptp::{self, PtpClock};
+use crate::linux::router::{RouteDecision, Router};
+use crate::linux::shm_state::{NetworkStatsPublisher, SharedState, VcpuState};
+#[cfg(feature = "slirp")]
+use crate::linux::slirp::{self, SlirpNetwork};
+use crate::linux::snapshot::{self, Snapshot};
+use crate::linux::sw_iommu::SoftIommu;
+use crate::linux::tap::Tap;
+use crate::linux::tsc;
+#[cfg(feature = "tui")]
+use crate::linux::tui::{VcpuEvent, VcpuEventSender};
 use crate::linux::vcpu::*;
+use crate::linux::vhost_net::{GuestRegion, VhostNet};
 use crate::linux::virtio::*;
+use crate::linux::virtio_balloon::{
+	self, BalloonControl, BalloonTarget, VirtioBalloonDevice, VirtioBalloonHandler,
+	BALLOON_PAGE_SIZE,
+};
+use crate::linux::virtio_blk::{self, BlockImage, VirtioBlkDevice, VirtioBlkHandler};
 use crate::linux::KVM;
+use crate::linux::{VcpuControl, VcpuMigrations};
 use crate::shared_queue::*;
+use crate::utils::{format_mac, parse_mem_available, stable_mac};
 use crate::vm::HypervisorResult;
-use crate::vm::{BootInfo, Parameter, Vm};
+use crate::vm::{
+	apply_clock_offset, canonicalize_allowed_paths, collect_entropy, collect_kernel_args,
+	detect_cpu_frequency, BootInfo, DeadlineParameters, EntropySource, GuestMemoryRegion,
+	MemoryBackend, NetworkBackend, NicInfo, NicMode, NicSpec, NumaNode, Parameter, PortForward, Vm,
+	ENTROPY_SIZE,
+};
 use kvm_bindings::*;
-use kvm_ioctls::VmFd;
+use kvm_ioctls::{VcpuFd, VmFd};
 use log::debug;
+use nix::errno::Errno;
 use nix::sys::mman::*;
 use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::hint;
-use std::mem;
-use std::net::Ipv4Addr;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::os::raw::c_void;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::ptr;
 use std::ptr::{read_volatile, write_volatile};
-use std::str::FromStr;
 use std::sync::mpsc::sync_channel;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use tun_tap::{Iface, Mode};
+use std::time::{Duration, Instant};
 use vmm_sys_util::eventfd::EventFd;
 
 const KVM_32BIT_MAX_MEM_SIZE: usize = 1 << 32;
 const KVM_32BIT_GAP_SIZE: usize = 768 << 20;
 const KVM_32BIT_GAP_START: usize = KVM_32BIT_MAX_MEM_SIZE - KVM_32BIT_GAP_SIZE;
 
+/// The virtio network device is also identified by its slot in the software IOMMU.
+const VIRTIO_NET_PCI_SLOT: u8 = 0;
+const VIRTIO_BLK_PCI_SLOT: u8 = 1;
+const VIRTIO_BALLOON_PCI_SLOT: u8 = 2;
+
+const MSI_ADDRESS_BASE: u32 = 0xfee00000;
+const MSI_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+/// Interval, after which pages without writes are marked as cold.
+const COLD_PAGE_HINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Not yet exported by `kvm-bindings`.
+const KVM_CAP_X86_APIC_BUS_CYCLES_NS: u32 = 237;
+/// Not yet exported by `kvm-bindings`.
+const KVM_CAP_X86_NOTIFY_VMEXIT: u32 = 219;
+const KVM_X86_NOTIFY_VMEXIT_ENABLED: u64 = 1 << 0;
+/// Exit to user space with `KVM_EXIT_NOTIFY` instead of only resuming the vCPU
+const KVM_X86_NOTIFY_VMEXIT_USER: u64 = 1 << 1;
+/// KVM's APIC bus frequency, if the bus cycle is not configured.
+const KVM_APIC_BUS_FREQUENCY: u64 = 1_000_000_000;
+
+/// `_IOR('T', 211, int)`
+const TUNGETSNDBUF: libc::c_ulong = 0x800454d3;
+/// `_IOW('T', 212, int)`
+const TUNSETSNDBUF: libc::c_ulong = 0x400454d4;
+/// Interval, in which the packets dropped by the TAP device are checked.
+const TAP_DROP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Host side of a TUN device, whose interface has no gateway, see `--nic-mode`
+const TUN_HOST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+/// A TUN device is a point-to-point link between the host and the guest.
+const TUN_PREFIX_LEN: u8 = 30;
+const TUN_MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 252);
+
+/// The kind of a network interface given by `--nic`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NetworkIfaceType {
+	/// A Linux bridge, to which a new TAP device is attached, and which is created if necessary
+	Bridge(String),
+	/// A TAP device, which is used directly
+	Tap(String),
+	/// A TUN device, which exchanges IP packets, and the address of its host side
+	Tun(String, Ipv4Addr),
+	/// A virtual network of libslirp, which forwards these ports itself
+	#[cfg(feature = "slirp")]
+	Slirp(SlirpNetwork, Vec<PortForward>),
+}
+
+impl NetworkIfaceType {
+	/// Returns the interface of `nic` in the shared queues, if it has one.
+	///
+	/// The addresses of a virtual network or a TUN device are filled into `nic`, so that the kernel
+	/// is configured accordingly.
+	fn from_spec(nic: &mut NicSpec<'_>, mode: NicMode) -> Option<Self> {
+		match nic.backend {
+			NetworkBackend::Tap(name) if mode == NicMode::Tun => {
+				let host = *nic.gateway.get_or_insert(TUN_HOST_ADDR);
+				let guest = tun_peer(host).unwrap_or_else(|| {
+					panic!(
+						"The gateway {} of a TUN device has to be a host of a /30 subnet",
+						host
+					)
+				});
+				nic.ip.get_or_insert(guest);
+				nic.mask = Some(TUN_MASK);
+				Some(Self::Tun(name.to_string(), host))
+			}
+			NetworkBackend::Tap(name) => Some(Self::detect(name)),
+			NetworkBackend::VirtioNet => None,
+			#[cfg(feature = "slirp")]
+			NetworkBackend::Slirp { dns } => {
+				let network = SlirpNetwork::new(nic, dns);
+				nic.ip = Some(network.guest);
+				nic.gateway = Some(network.gateway);
+				nic.mask = Some(network.mask);
+				Some(Self::Slirp(network, Vec::new()))
+			}
+		}
+	}
+
+	/// Lets a virtual network forward `port_forwards` itself and returns those, which are left to
+	/// iptables.
+	fn take_port_forwards<'a>(&mut self, port_forwards: &'a [PortForward]) -> &'a [PortForward] {
+		#[cfg(feature = "slirp")]
+		if let Self::Slirp(_, forwards) = self {
+			*forwards = port_forwards.to_vec();
+			return &[];
+		}
+		port_forwards
+	}
+
+	fn detect(name: &str) -> Self {
+		if Path::new("/sys/class/net")
+			.join(name)
+			.join("bridge")
+			.is_dir()
+		{
+			Self::Bridge(name.to_string())
+		} else {
+			Self::Tap(name.to_string())
+		}
+	}
+}
+
+/// Returns the other host of the /30 subnet of `host`, which must be neither the network nor the
+/// broadcast address.
+fn tun_peer(host: Ipv4Addr) -> Option<Ipv4Addr> {
+	let host = u32::from(host);
+	match host & 0b11 {
+		1 => Some(Ipv4Addr::from(host + 1)),
+		2 => Some(Ipv4Addr::from(host - 1)),
+		_ => None,
+	}
+}
+
+/// Attaches the TAP device to a bridge and detaches it again on drop.
+#[derive(Debug)]
+struct NetworkBridgeGuard {
+	bridge: String,
+	tap_name: String,
+	/// Whether the bridge has been created by uhyve and should be removed on drop
+	remove_bridge: bool,
+}
+
+impl NetworkBridgeGuard {
+	/// Creates the bridge, if it does not exist yet, and attaches the TAP device to it.
+	fn attach(bridge: String, tap_name: String, remove_bridge_on_exit: bool) -> io::Result<Self> {
+		let created = if netlink::link_index(&bridge)?.is_none() {
+			info!("Create bridge {}", bridge);
+			netlink::create_bridge(&bridge)?;
+			netlink::set_up(&bridge)?;
+			true
+		} else {
+			false
+		};
+
+		debug!("Attach {} to bridge {}", tap_name, bridge);
+		netlink::set_master(&tap_name, Some(&bridge))?;
+		netlink::set_up(&tap_name)?;
+
+		Ok(Self {
+			bridge,
+			tap_name,
+			remove_bridge: created && remove_bridge_on_exit,
+		})
+	}
+}
+
+impl Drop for NetworkBridgeGuard {
+	fn drop(&mut self) {
+		debug!("Detach {} from bridge {}", self.tap_name, self.bridge);
+		if let Err(err) = netlink::set_master(&self.tap_name, None) {
+			warn!("Unable to detach {}: {}", self.tap_name, err);
+		}
+		if self.remove_bridge {
+			info!("Remove bridge {}", self.bridge);
+			if let Err(err) = netlink::delete_link(&self.bridge) {
+				warn!("Unable to remove bridge {}: {}", self.bridge, err);
+			}
+		}
+	}
+}
+
+/// Sets the size of the send buffer of a TUN/TAP device and returns the size, which the kernel uses.
+///
+/// The device is not a socket, so its buffer is configured with `TUNSETSNDBUF` instead of
+/// `SO_SNDBUF`. Writes block while the buffer is full, which throttles the guest's transmissions.
+fn set_tun_send_buffer(iface: &impl AsRawFd, size: u32) -> io::Result<i32> {
+	let size = i32::try_from(size).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+	if unsafe { libc::ioctl(iface.as_raw_fd(), TUNSETSNDBUF, &size) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let mut actual: i32 = 0;
+	if unsafe { libc::ioctl(iface.as_raw_fd(), TUNGETSNDBUF, &mut actual) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(actual)
+}
+
+/// `struct ifreq` with the hardware address of `SIOCSIFHWADDR`
+#[repr(C)]
+struct IfReqHwAddr {
+	name: [libc::c_char; libc::IFNAMSIZ],
+	hwaddr: libc::sockaddr,
+	_padding: [u8; 8],
+}
+
+/// Sets the MAC address of a TAP device.
+///
+/// The ioctl is issued on the device itself, which ignores the interface name of the request.
+pub(crate) fn set_tap_mac_address(iface: &impl AsRawFd, mac: [u8; 6]) -> io::Result<()> {
+	let mut request = IfReqHwAddr {
+		name: [0; libc::IFNAMSIZ],
+		hwaddr: libc::sockaddr {
+			sa_family: libc::ARPHRD_ETHER,
+			sa_data: [0; 14],
+		},
+		_padding: [0; 8],
+	};
+	for (dest, byte) in request.hwaddr.sa_data.iter_mut().zip(mac) {
+		*dest = byte as libc::c_char;
+	}
+	if unsafe { libc::ioctl(iface.as_raw_fd(), libc::SIOCSIFHWADDR, &request) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+/// Reports the packets, which the kernel has dropped after they have been written to the TAP device.
+///
+/// `TIOCOUTQ` is not implemented for TUN/TAP devices, so the drops are read from the receive
+/// statistics of the interface, which counts the packets of the guest from the host's view.
+#[derive(Debug)]
+struct TapDropMonitor {
+	tap_name: String,
+	dropped: Option<u64>,
+	last_check: Instant,
+}
+
+impl TapDropMonitor {
+	fn new(tap_name: String) -> Self {
+		let mut monitor = Self {
+			tap_name,
+			dropped: None,
+			last_check: Instant::now(),
+		};
+		monitor.dropped = monitor.read_dropped();
+		monitor
+	}
+
+	fn read_dropped(&self) -> Option<u64> {
+		let path = format!("/sys/class/net/{}/statistics/rx_dropped", self.tap_name);
+		std::fs::read_to_string(path).ok()?.trim().parse().ok()
+	}
+
+	/// Warns about new drops, but reads the statistics at most once per check interval.
+	fn check(&mut self) {
+		if self.dropped.is_none() || self.last_check.elapsed() < TAP_DROP_CHECK_INTERVAL {
+			return;
+		}
+		self.last_check = Instant::now();
+
+		let dropped = self.read_dropped();
+		if let (Some(old), Some(new)) = (self.dropped, dropped) {
+			if new > old {
+				warn!(
+					"{} dropped {} packets of the guest, consider increasing --tun-tx-queue-size",
+					self.tap_name,
+					new - old
+				);
+			}
+		}
+		self.dropped = dropped;
+	}
+}
+
+/// The settings, which all network interfaces share, see [`UhyveNetwork::new`].
+struct NetworkConfig {
+	/// Number of entries of each shared queue
+	queue_depth: usize,
+	remove_bridge_on_exit: bool,
+	tx_queue_size: Option<u32>,
+	vnet_hdr: bool,
+	stats: Arc<NetworkStats>,
+	pcap: Option<Arc<PcapCapture>>,
+	/// Forwards the packets between the interfaces, see `--guest-ip-forward`
+	router: Option<Arc<Router>>,
+}
+
 #[derive(Debug)]
 struct UhyveNetwork {
 	#[allow(dead_code)]
 	reader: std::thread::JoinHandle<()>,
 	#[allow(dead_code)]
 	writer: std::thread::JoinHandle<()>,
+	#[allow(dead_code)]
+	resampler: Option<std::thread::JoinHandle<()>>,
 	tx: std::sync::mpsc::SyncSender<usize>,
+	/// The bridge, to which the TAP device is attached
+	#[allow(dead_code)]
+	bridge: Option<NetworkBridgeGuard>,
+	tap_name: String,
+	stats: Arc<NetworkStats>,
+	/// Closes the capture file, once all interfaces are dropped
+	#[allow(dead_code)]
+	pcap: Option<Arc<PcapCapture>>,
 }
 
 impl UhyveNetwork {
-	pub fn new(evtfd: EventFd, name: String, start: usize) -> Self {
+	/// Connects the shared queues at `start` to the `nic`th network interface.
+	pub fn new(
+		evtfd: EventFd,
+		resample_fd: Option<EventFd>,
+		iface_type: NetworkIfaceType,
+		start: usize,
+		mac: [u8; 6],
+		nic: usize,
+		config: &NetworkConfig,
+	) -> Self {
+		let queue_depth = config.queue_depth;
+		let stats = config.stats.clone();
+		let pcap = config.pcap.clone();
+		let router = config.router.clone().map(|router| (router, nic));
+		// for bridges, the kernel chooses the name of the TAP device
+		let (bridge, tap_name, tun_host) = match iface_type {
+			NetworkIfaceType::Bridge(bridge) => (Some(bridge), String::new(), None),
+			NetworkIfaceType::Tap(name) => (None, name, None),
+			NetworkIfaceType::Tun(name, host) => (None, name, Some(host)),
+			#[cfg(feature = "slirp")]
+			NetworkIfaceType::Slirp(network, port_forwards) => {
+				return Self::new_slirp(network, port_forwards, evtfd, resample_fd, start, config)
+			}
+		};
+		let mode = if tun_host.is_some() {
+			NicMode::Tun
+		} else {
+			NicMode::Tap
+		};
 		let iface = Arc::new(
-			Iface::without_packet_info(&name, Mode::Tap).expect("Unable to creat TUN/TAP device"),
+			Tap::open(&tap_name, mode, config.vnet_hdr).expect("Unable to creat TUN/TAP device"),
 		);
+		let tap_name = iface.name().to_string();
+
+		// TUN devices have no hardware address
+		if mode == NicMode::Tap {
+			match set_tap_mac_address(&*iface, mac) {
+				Ok(()) => info!("MAC address of {} is {}", tap_name, format_mac(&mac)),
+				Err(err) => warn!("Unable to set the MAC address of {}: {}", tap_name, err),
+			}
+		}
+
+		if let Some(size) = config.tx_queue_size {
+			let actual = set_tun_send_buffer(&*iface, size)
+				.expect("Unable to set the send buffer of the TUN/TAP device");
+			debug!("Send buffer of {} is {} bytes", tap_name, actual);
+		}
+
+		if let Some(host) = tun_host {
+			match netlink::add_address(&tap_name, host, TUN_PREFIX_LEN) {
+				Ok(()) => info!("Address of {} is {}/{}", tap_name, host, TUN_PREFIX_LEN),
+				// a persistent TUN device keeps its address
+				Err(err) if err.raw_os_error() == Some(libc::EEXIST) => {}
+				Err(err) => warn!("Unable to set the address of {}: {}", tap_name, err),
+			}
+			if let Err(err) = netlink::set_up(&tap_name) {
+				warn!("Unable to bring {} up: {}", tap_name, err);
+			}
+		}
+
+		let bridge = bridge.map(|bridge| {
+			NetworkBridgeGuard::attach(bridge, tap_name.clone(), config.remove_bridge_on_exit)
+				.expect("Unable to attach the TAP device to the bridge")
+		});
+
+		if let Some((router, nic)) = &router {
+			router.attach(*nic, iface.clone());
+		}
 
 		let iface_writer = Arc::clone(&iface);
 		let iface_reader = Arc::clone(&iface);
+		let writer_stats = stats.clone();
+		let reader_stats = stats.clone();
 		let (tx, rx) = sync_channel(1);
+		let mut drop_monitor = TapDropMonitor::new(tap_name.clone());
+		let writer_pcap = pcap.as_ref().map(|pcap| pcap.sender());
+		let reader_pcap = pcap.as_ref().map(|pcap| pcap.sender());
+
+		let resampler = Self::start_resampler(&evtfd, resample_fd, start);
 
 		let writer = thread::spawn(move || {
 			let tx_queue = unsafe {
-				#[allow(clippy::cast_ptr_alignment)]
-				&mut *((start + align_up!(mem::size_of::<SharedQueue>(), 64)) as *mut u8
-					as *mut SharedQueue)
+				SharedQueue::from_raw(start + SharedQueue::size(queue_depth), queue_depth)
 			};
 			tx_queue.init();
 
@@ -67,11 +458,18 @@ impl UhyveNetwork {
 				let distance = written - read;
 
 				if distance > 0 {
-					let idx = read % UHYVE_QUEUE_SIZE;
+					let idx = read % tx_queue.depth();
 					let len = unsafe { read_volatile(&tx_queue.inner[idx].len) } as usize;
-					let _ = iface_writer
-						.send(&tx_queue.inner[idx].data[0..len])
-						.expect("Send on TUN/TAP device failed!");
+					let packet = &tx_queue.inner[idx].data[0..len];
+					if let Some(pcap) = &writer_pcap {
+						pcap.capture(packet);
+					}
+					let result = iface_writer.send(packet);
+					writer_stats.record_send(&result);
+					if let Err(err) = result {
+						warn!("Send on TUN/TAP device failed: {}", err);
+					}
+					drop_monitor.check();
 
 					unsafe { write_volatile(&mut tx_queue.read, read + 1) };
 				}
@@ -79,10 +477,7 @@ impl UhyveNetwork {
 		});
 
 		let reader = thread::spawn(move || {
-			let rx_queue = unsafe {
-				#[allow(clippy::cast_ptr_alignment)]
-				&mut *(start as *mut u8 as *mut SharedQueue)
-			};
+			let rx_queue = unsafe { SharedQueue::from_raw(start, queue_depth) };
 			rx_queue.init();
 
 			loop {
@@ -90,17 +485,28 @@ impl UhyveNetwork {
 				let read = unsafe { read_volatile(&rx_queue.read) };
 				let distance = written - read;
 
-				if distance < UHYVE_QUEUE_SIZE {
-					let idx = written % UHYVE_QUEUE_SIZE;
+				if distance < rx_queue.depth() {
+					let idx = written % rx_queue.depth();
+					let result = iface_reader.recv(&mut rx_queue.inner[idx].data);
+					reader_stats.record_receive(&result);
+					let len = match result {
+						Ok(len) => len,
+						Err(err) => {
+							warn!("Receive on TUN/TAP device failed: {}", err);
+							continue;
+						}
+					};
+					if let Some(pcap) = &reader_pcap {
+						pcap.capture(&rx_queue.inner[idx].data[..len]);
+					}
+					if let Some((router, nic)) = &router {
+						let packet = &mut rx_queue.inner[idx].data[..len];
+						if router.route(*nic, packet) == RouteDecision::Forwarded {
+							continue;
+						}
+					}
 					unsafe {
-						write_volatile(
-							&mut rx_queue.inner[idx].len,
-							iface_reader
-								.recv(&mut rx_queue.inner[idx].data)
-								.expect("Receive on TUN/TAP device failed!")
-								.try_into()
-								.unwrap(),
-						);
+						write_volatile(&mut rx_queue.inner[idx].len, len.try_into().unwrap());
 						write_volatile(&mut rx_queue.written, written + 1);
 					}
 
@@ -111,13 +517,90 @@ impl UhyveNetwork {
 			}
 		});
 
-		UhyveNetwork { reader, writer, tx }
+		UhyveNetwork {
+			reader,
+			writer,
+			resampler,
+			tx,
+			bridge,
+			tap_name,
+			stats,
+			pcap,
+		}
+	}
+
+	/// Connects the shared queues at `start` to a virtual network of libslirp.
+	#[cfg(feature = "slirp")]
+	fn new_slirp(
+		network: SlirpNetwork,
+		port_forwards: Vec<PortForward>,
+		evtfd: EventFd,
+		resample_fd: Option<EventFd>,
+		start: usize,
+		config: &NetworkConfig,
+	) -> Self {
+		let stats = config.stats.clone();
+		let pcap = config.pcap.clone();
+		let resampler = Self::start_resampler(&evtfd, resample_fd, start);
+
+		// The vCPUs signal transmissions through the channel, which the slirp thread cannot poll.
+		let kick = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+		let writer_kick = kick.try_clone().expect("Unable to duplicate an eventfd");
+		let (tx, rx) = sync_channel::<usize>(1);
+		let writer = thread::spawn(move || {
+			while rx.recv().is_ok() {
+				writer_kick
+					.write(1)
+					.expect("Unable to wake up the slirp thread");
+			}
+		});
+
+		let reader = slirp::start(
+			network,
+			port_forwards,
+			start,
+			config.queue_depth,
+			evtfd,
+			kick,
+			stats.clone(),
+			pcap.as_ref().map(|pcap| pcap.sender()),
+		)
+		.expect("Unable to start the user-mode network");
+		info!(
+			"slirp: guest {} with mask {}, gateway {}, DNS {}",
+			network.guest, network.mask, network.gateway, network.dns
+		);
+
+		UhyveNetwork {
+			reader,
+			writer,
+			resampler,
+			tx,
+			bridge: None,
+			tap_name: "slirp".to_string(),
+			stats,
+			pcap,
+		}
+	}
+
+	/// Keeps the interrupt pending, until the guest has read all packets.
+	fn start_resampler(
+		evtfd: &EventFd,
+		resample_fd: Option<EventFd>,
+		start: usize,
+	) -> Option<std::thread::JoinHandle<()>> {
+		resample_fd.map(|resample_fd| {
+			let trigger = evtfd.try_clone().expect("Unable to duplicate the irqfd");
+			irqfd::start_resampler(trigger, resample_fd, move || unsafe {
+				SharedQueue::has_unread(start)
+			})
+		})
 	}
 }
 
 impl Drop for UhyveNetwork {
 	fn drop(&mut self) {
-		debug!("Dropping network interface!");
+		debug!("Dropping network interface {}!", self.tap_name);
 	}
 }
 
@@ -133,9 +616,128 @@ pub struct Uhyve {
 	ip: Option<Ipv4Addr>,
 	gateway: Option<Ipv4Addr>,
 	mask: Option<Ipv4Addr>,
-	uhyve_device: Option<UhyveNetwork>,
+	ip6: Option<Ipv6Addr>,
+	prefix_len6: Option<u8>,
+	/// The network interfaces, whose shared queues follow each other from `SHAREDQUEUE_START`
+	uhyve_devices: Vec<UhyveNetwork>,
+	nics: Vec<NicInfo>,
+	/// Removes the iptables rules of the forwarded ports, when the VM is dropped
+	#[allow(dead_code)]
+	port_forwards: Option<PortForwardRules>,
 	virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
+	pci_bus: Arc<Mutex<PciBus>>,
 	pub(super) gdb_port: Option<u16>,
+	/// Unix socket of the GDB stub, which is removed, when the VM is dropped
+	pub(super) gdb_socket: Option<PathBuf>,
+	/// Stops the boot processor at the entry point, when GDB resumes it the first time
+	pub(super) debug_on_startup: bool,
+	print_memory_layout: bool,
+	pub(super) ksm_report_interval: Option<Duration>,
+	pub(super) nmi_on_signal: bool,
+	pub(super) registers_on_signal: bool,
+	pub(super) pause_on_signal: bool,
+	pub(super) deadline: Option<DeadlineParameters>,
+	pub(super) preemption_timer: Option<Duration>,
+	pub(super) auto_migrate: bool,
+	stats_interval: Duration,
+	stats_log: Option<File>,
+	net_metrics_file: Option<PathBuf>,
+	memory_pressure_hints: bool,
+	/// Whether the cross timestamps are published in `PTP_CLOCK_PAGE`
+	ptp: bool,
+	balloon_device: Option<Arc<Mutex<VirtioBalloonDevice>>>,
+	/// The file, from which the size of the balloon is read
+	balloon_control: Option<PathBuf>,
+	/// The initial size of the balloon in pages, see [`BalloonTarget`]
+	balloon_target: Option<u32>,
+	console_log: Option<Arc<ConsoleLog>>,
+	debug_port_log: Option<Arc<DebugPortLog>>,
+	iommu: Option<Arc<Mutex<SoftIommu>>>,
+	pub(super) migrations: VcpuMigrations,
+	pub(super) control: VcpuControl,
+	dirty_tracker: Option<Arc<DirtyTracker>>,
+	exit_handlers: Arc<ExitHandlers>,
+	host_allowed_paths: Option<Arc<[PathBuf]>>,
+	/// The arguments of the kernel, see [`collect_kernel_args`]
+	kernel_args: Arc<[String]>,
+	/// Changes of the CPUID features of the vCPUs
+	cpu_features: Arc<[CpuFeatureChange]>,
+	cpuid_overrides: Arc<[CpuidOverride]>,
+	/// The MSRs, which are forwarded to the host, see `--allow-msr`
+	msr_whitelist: Arc<[u32]>,
+	/// The seed of the kernel's CSPRNG, see [`collect_entropy`]
+	entropy: [u8; ENTROPY_SIZE],
+	kernel_verifier: Option<KernelVerifier>,
+	/// Offset of the guest's clock in nanoseconds, see [`Vm::clock_offset`]
+	clock_offset: i64,
+	/// TSC frequency of the vCPUs in kHz, see [`Vm::tsc_khz`]
+	tsc_khz: Option<u32>,
+	/// Offset of the TSC of all vCPUs to the host's TSC
+	tsc_offset: Option<i64>,
+	/// Number of entries of each shared network queue
+	net_queue_depth: usize,
+	fault_tolerant: bool,
+	exit_log: Option<Arc<ExitLog>>,
+	/// The state of the VM, which is published for host tools
+	shared_state: Option<Arc<SharedState>>,
+	/// The vCPUs of previous runs, see [`Uhyve::reload_kernel`]
+	vcpu_fds: Mutex<Vec<Option<VcpuFd>>>,
+	/// The file, to which the VM is saved on `SIGTERM`, see [`Uhyve::snapshot`]
+	pub(super) snapshot_path: Option<PathBuf>,
+	/// The registers, with which the vCPUs of a restored VM start, see [`Uhyve::restore`]
+	restored_vcpus: Mutex<Vec<Option<snapshot::VcpuState>>>,
+	/// The vCPUs report their exits to the dashboard through this channel
+	#[cfg(feature = "tui")]
+	dashboard_events: Option<Mutex<std::sync::mpsc::Sender<VcpuEvent>>>,
+	#[cfg(feature = "tui")]
+	pub(super) dashboard_receiver: Mutex<Option<std::sync::mpsc::Receiver<VcpuEvent>>>,
+}
+
+/// Logs the available host memory and rejects a guest memory, which does not fit, if `strict` is set.
+///
+/// The guest memory is mapped with `MAP_NORESERVE`, so it is otherwise only limited by the address space.
+fn check_host_memory(mem_size: usize, strict: bool) -> HypervisorResult<()> {
+	let available = std::fs::read_to_string("/proc/meminfo")
+		.ok()
+		.as_deref()
+		.and_then(parse_mem_available);
+	match available {
+		Some(available) => info!("Available host memory: {} MiB", available >> 20),
+		None => warn!("Unable to determine the available host memory"),
+	}
+	if !strict {
+		return Ok(());
+	}
+
+	// leave 10% of the available memory to the host
+	if let Some(available) = available {
+		if mem_size > available / 10 * 9 {
+			error!(
+				"The guest memory of {} MiB exceeds 90% of the available host memory of {} MiB",
+				mem_size >> 20,
+				available >> 20
+			);
+			return Err(kvm_ioctls::Error::new(libc::ENOMEM));
+		}
+	}
+
+	let mut limit = libc::rlimit {
+		rlim_cur: 0,
+		rlim_max: 0,
+	};
+	if unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut limit) } == 0
+		&& limit.rlim_cur != libc::RLIM_INFINITY
+		&& mem_size as u64 > limit.rlim_cur
+	{
+		error!(
+			"The guest memory of {} MiB exceeds the virtual address space limit of {} MiB",
+			mem_size >> 20,
+			limit.rlim_cur >> 20
+		);
+		return Err(kvm_ioctls::Error::new(libc::ENOMEM));
+	}
+
+	Ok(())
 }
 
 impl fmt::Debug for Uhyve {
@@ -150,35 +752,153 @@ impl fmt::Debug for Uhyve {
 			.field("ip", &self.ip)
 			.field("gateway", &self.gateway)
 			.field("mask", &self.mask)
-			.field("uhyve_device", &self.uhyve_device)
+			.field("ip6", &self.ip6)
+			.field("prefix_len6", &self.prefix_len6)
+			.field("uhyve_devices", &self.uhyve_devices)
 			.field("virtio_device", &self.virtio_device)
+			.field("pci_bus", &self.pci_bus)
 			.finish()
 	}
 }
 
 impl Uhyve {
 	pub fn new(kernel_path: PathBuf, specs: &Parameter<'_>) -> HypervisorResult<Uhyve> {
-		// parse string to get IP address
-		let ip_addr = specs
-			.ip
-			.as_ref()
-			.map(|addr_str| Ipv4Addr::from_str(addr_str).expect("Unable to parse ip address"));
-
-		// parse string to get gateway address
-		let gw_addr = specs
-			.gateway
-			.as_ref()
-			.map(|addr_str| Ipv4Addr::from_str(addr_str).expect("Unable to parse gateway address"));
-
-		// parse string to get gateway address
-		let mask = specs
-			.mask
-			.as_ref()
-			.map(|addr_str| Ipv4Addr::from_str(addr_str).expect("Unable to parse network parse"));
+		let kernel_args = collect_kernel_args(specs.kernel_args_file).map_err(|err| {
+			error!("Invalid kernel arguments: {}", err);
+			err
+		})?;
+		let entropy = collect_entropy(specs.entropy).map_err(|err| {
+			error!("Unable to read the entropy of the kernel: {}", err);
+			err
+		})?;
+		if matches!(specs.entropy, EntropySource::Seed(_)) && !specs.nics.is_empty() {
+			warn!("The guest uses a fixed entropy seed, its network connections are not secure");
+		}
+
+		if !specs.cpu_features.is_empty() {
+			let supported = KVM.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+			for change in specs.cpu_features.iter().filter(|change| change.enable) {
+				let feature = change.feature;
+				let available = supported.as_slice().iter().any(|entry| {
+					let registers = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+					entry.function == feature.leaf
+						&& entry.index == feature.subleaf
+						&& registers[feature.register as usize] & (1 << feature.bit) != 0
+				});
+				if !available {
+					warn!("KVM does not support the CPU feature {}", feature.name);
+				}
+			}
+		}
+		if !specs.cpuid_overrides.is_empty() {
+			let supported = KVM.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+			for cpuid_override in specs.cpuid_overrides {
+				match supported.as_slice().iter().find(|entry| {
+					entry.function == cpuid_override.leaf && entry.index == cpuid_override.subleaf
+				}) {
+					Some(entry) => {
+						let registers = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+						let missing =
+							cpuid_override.set & !registers[cpuid_override.register as usize];
+						if missing != 0 {
+							warn!(
+								"KVM does not support the bits 0x{:x} of --cpuid {}",
+								missing, cpuid_override
+							);
+						}
+					}
+					None => warn!(
+						"KVM does not support the CPUID leaf of --cpuid {}, which is ignored",
+						cpuid_override
+					),
+				}
+			}
+		}
+
+		let max_vcpus = KVM.get_max_vcpus();
+		if specs.num_cpus as usize > max_vcpus {
+			error!(
+				"This system supports up to {} vCPUs, but {} were requested.",
+				max_vcpus, specs.num_cpus
+			);
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		let recommended_vcpus = KVM.get_nr_vcpus();
+		if specs.num_cpus as usize > recommended_vcpus {
+			warn!(
+				"KVM recommends at most {} vCPUs, but {} were requested.",
+				recommended_vcpus, specs.num_cpus
+			);
+		}
+
+		check_host_memory(specs.mem_size, specs.strict_memory_model)?;
 
 		let vm = KVM.create_vm()?;
 
-		let mem = MmapMemory::new(0, specs.mem_size, 0, specs.hugepage, specs.mergeable);
+		if specs.clock_offset != 0 {
+			// shifts the kvmclock of guests, which use it instead of the boot time and the TSC
+			let mut clock = vm.get_clock()?;
+			clock.clock = apply_clock_offset(clock.clock, specs.clock_offset);
+			vm.set_clock(&clock)?;
+			debug!("Shift the guest clock by {} ns", specs.clock_offset);
+		}
+
+		// the dirty ring has to be enabled before creating any vCPU
+		let dirty_tracker = if specs.dirty_tracking || specs.memory_pressure_hints {
+			let slot_bases = vec![0, (KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE) as u64];
+			match DirtyTracker::enable(&vm, slot_bases) {
+				Ok(dirty_tracker) => Some(Arc::new(dirty_tracker)),
+				Err(err) => {
+					warn!("Unable to enable the dirty ring: {}", err);
+					None
+				}
+			}
+		} else {
+			None
+		};
+		// the dirty ring also requires logging, but replaces the bitmap of `KVM_GET_DIRTY_LOG`
+		if specs.dirty_log && dirty_tracker.is_some() {
+			warn!("The dirty ring replaces the dirty bitmap, which is not available");
+		}
+
+		let mem = MmapMemory::new(
+			specs.dirty_log || dirty_tracker.is_some(),
+			specs.mem_size,
+			0,
+			specs.hugepage,
+			specs.mergeable,
+			specs.memory_backend,
+		)
+		.map_err(|err| {
+			error!("Unable to map the guest memory: {}", err);
+			kvm_ioctls::Error::new(err as i32)
+		})?;
+		if !specs.numa_nodes.is_empty() {
+			let numa_memory = specs
+				.numa_nodes
+				.iter()
+				.map(|node| node.memory.len())
+				.sum::<usize>();
+			if numa_memory != specs.mem_size {
+				error!(
+					"The memory of the NUMA nodes adds up to {} bytes instead of the guest memory of {} bytes",
+					numa_memory, specs.mem_size
+				);
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+			for node in specs.numa_nodes {
+				mem.bind(node).unwrap_or_else(|err| {
+					warn!(
+						"Unable to bind the memory of the NUMA node with CPUs {:?}: {}",
+						node.cpus, err
+					)
+				});
+			}
+		}
+		// the pages are faulted in on the bound nodes
+		if specs.prealloc {
+			mem.pretouch(specs.pretouch_workers);
+		}
 
 		let sz = if specs.mem_size < KVM_32BIT_GAP_START {
 			specs.mem_size
@@ -187,29 +907,81 @@ impl Uhyve {
 		};
 
 		// create virtio interface
-		let virtio_device = Arc::new(Mutex::new(VirtioNetPciDevice::new()));
-
-		let kvm_mem = kvm_userspace_memory_region {
-			slot: 0,
-			flags: mem.flags,
-			memory_size: sz as u64,
-			guest_phys_addr: mem.guest_address as u64,
-			userspace_addr: mem.host_address as u64,
+		let iommu = specs
+			.soft_iommu
+			.then(|| Arc::new(Mutex::new(SoftIommu::new())));
+		// `--mac` sets the address of the first interface, which virtio-net shares
+		let nic_mac = |index: usize, nic: &NicSpec<'_>| {
+			nic.mac
+				.or(if index == 0 { specs.mac } else { None })
+				.unwrap_or_else(|| stable_mac(&kernel_path, index))
 		};
+		let mac = nic_mac(0, specs.nics.first().unwrap_or(&NicSpec::default()));
+		let mut virtio_device = VirtioNetPciDevice::new();
+		virtio_device.set_mac_addr(mac);
+		virtio_device.set_guest_mem((mem.host_address as *mut u8, mem.memory_size));
+		if let Some(iommu) = &iommu {
+			virtio_device.set_iommu(VIRTIO_NET_PCI_SLOT.into(), iommu.clone());
+		}
+		let virtio_device = Arc::new(Mutex::new(virtio_device));
+		let mut pci_bus = PciBus::new();
+		pci_bus.register(VIRTIO_NET_PCI_SLOT, Box::new(virtio_device.clone()));
+		let block_device = specs.block.map(|path| {
+			// a read-only image still accepts writes, which go to a temporary overlay
+			let image = if specs.block_readonly || specs.block_overlay.is_some() {
+				BlockImage::with_overlay(path, specs.block_overlay)
+			} else {
+				BlockImage::open(path, false)
+			}
+			.unwrap_or_else(|err| panic!("Unable to open the disk {}: {}", path.display(), err));
+			info!(
+				"Attach {} with {} sectors as virtio-blk device",
+				path.display(),
+				image.capacity()
+			);
+			Arc::new(Mutex::new(VirtioBlkDevice::new(
+				image,
+				(mem.host_address as *mut u8, mem.memory_size),
+			)))
+		});
+		if let Some(block_device) = &block_device {
+			pci_bus.register(VIRTIO_BLK_PCI_SLOT, Box::new(block_device.clone()));
+		}
+		let balloon_target = match specs.balloon_target {
+			Some(_) if !specs.balloon => {
+				error!("The initial size of the balloon requires the balloon");
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+			Some(size) if size > specs.mem_size => {
+				error!(
+					"The initial size of the balloon ({} bytes) exceeds the guest memory ({} bytes)",
+					size, specs.mem_size
+				);
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+			// `num_pages` of the device configuration has 32 bits
+			Some(size) => Some((size / BALLOON_PAGE_SIZE).min(u32::MAX as usize) as u32),
+			None => None,
+		};
+		// the irqfd is registered after the interrupt controller has been created
+		let balloon_irq = specs.balloon.then(|| EventFd::new(0).unwrap());
+		let balloon_device = balloon_irq.as_ref().map(|irq| {
+			Arc::new(Mutex::new(VirtioBalloonDevice::new(
+				(mem.host_address as *mut u8, mem.memory_size),
+				irq.try_clone().unwrap(),
+				UHYVE_IRQ_BALLOON as u8,
+			)))
+		});
+		if let Some(balloon_device) = &balloon_device {
+			pci_bus.register(VIRTIO_BALLOON_PCI_SLOT, Box::new(balloon_device.clone()));
+		}
 
-		unsafe { vm.set_user_memory_region(kvm_mem) }?;
-
-		if specs.mem_size > KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE {
-			let kvm_mem = kvm_userspace_memory_region {
-				slot: 1,
-				flags: mem.flags,
-				memory_size: (specs.mem_size - KVM_32BIT_GAP_START - KVM_32BIT_GAP_SIZE) as u64,
-				guest_phys_addr: (mem.guest_address + KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE)
-					as u64,
-				userspace_addr: (mem.host_address + KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE)
-					as u64,
-			};
-
+		for kvm_mem in memory_slots(
+			specs.mem_size,
+			mem.guest_address,
+			mem.host_address,
+			mem.flags(),
+		) {
 			unsafe { vm.set_user_memory_region(kvm_mem) }?;
 		}
 
@@ -217,6 +989,38 @@ impl Uhyve {
 
 		// create basic interrupt controller
 		vm.create_irq_chip()?;
+		if let Some(balloon_irq) = &balloon_irq {
+			vm.register_irqfd(balloon_irq, UHYVE_IRQ_BALLOON)?;
+		}
+
+		// The APIC timer is driven by the APIC bus, whose cycle length has to be
+		// configured before any vCPU is created. The TSC deadline mode is not affected.
+		if let Some(hz) = specs.apic_timer_hz {
+			if hz == 0 || u64::from(hz) > KVM_APIC_BUS_FREQUENCY {
+				error!(
+					"APIC timer frequency has to be between 1 Hz and 1 GHz, but is {} Hz",
+					hz
+				);
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+			let bus_cycle_ns = KVM_APIC_BUS_FREQUENCY / u64::from(hz);
+			if KVM_APIC_BUS_FREQUENCY % u64::from(hz) != 0 {
+				warn!(
+					"APIC timer frequency rounded to {} Hz",
+					KVM_APIC_BUS_FREQUENCY / bus_cycle_ns
+				);
+			}
+
+			let mut cap: kvm_enable_cap = kvm_bindings::kvm_enable_cap {
+				cap: KVM_CAP_X86_APIC_BUS_CYCLES_NS,
+				..Default::default()
+			};
+			cap.args[0] = bus_cycle_ns;
+			match vm.enable_cap(&cap) {
+				Ok(()) => debug!("Set APIC bus cycle to {} ns", bus_cycle_ns),
+				Err(err) => warn!("Unable to configure the APIC timer frequency: {}", err),
+			}
+		}
 
 		// enable x2APIC support
 		let mut cap: kvm_enable_cap = kvm_bindings::kvm_enable_cap {
@@ -257,25 +1061,316 @@ impl Uhyve {
 		vm.enable_cap(&cap)
 			.expect("Unable to disable exists due pause instructions");
 
-		let evtfd = EventFd::new(0).unwrap();
-		vm.register_irqfd(&evtfd, UHYVE_IRQ_NET)?;
-		// create TUN/TAP device
-		let uhyve_device = match &specs.nic {
-			Some(nic) => {
-				debug!("Initialize network interface");
-				Some(UhyveNetwork::new(
-					evtfd,
-					nic.to_string(),
-					mem.host_address + SHAREDQUEUE_START,
-				))
+		// The notify window has to be set before any vCPU is created. It is a VMX feature of
+		// recent Intel CPUs (Sapphire Rapids+), so it is only logged, if KVM lacks it.
+		if specs.notify_vmexit_window > 0 {
+			let mut cap: kvm_enable_cap = kvm_bindings::kvm_enable_cap {
+				cap: KVM_CAP_X86_NOTIFY_VMEXIT,
+				..Default::default()
+			};
+			cap.args[0] = (u64::from(specs.notify_vmexit_window) << 32)
+				| KVM_X86_NOTIFY_VMEXIT_ENABLED
+				| KVM_X86_NOTIFY_VMEXIT_USER;
+			match vm.enable_cap(&cap) {
+				Ok(()) => debug!(
+					"Set the notify VM exit window to {} cycles",
+					specs.notify_vmexit_window
+				),
+				Err(err) => info!("KVM_CAP_X86_NOTIFY_VMEXIT is not available: {}", err),
+			}
+		}
+
+		// The MSR filter is only applied, before a vCPU runs. Without it, only the MSRs, which
+		// KVM does not emulate, exit to uhyve.
+		if !specs.msr_whitelist.is_empty() {
+			match msr_filter::enable(&vm, specs.msr_whitelist) {
+				Ok(true) => debug!("Forward the MSRs {:x?} to the host", specs.msr_whitelist),
+				Ok(false) => warn!(
+					"KVM has no MSR filter, only the unknown MSRs of --allow-msr reach the host"
+				),
+				Err(err) => warn!(
+					"Unable to forward MSRs to the host, --allow-msr is ignored: {}",
+					err
+				),
+			}
+		}
+
+		let net_queue_depth = specs.net_queue_depth.unwrap_or(UHYVE_QUEUE_SIZE);
+		if !net_queue_depth.is_power_of_two() || net_queue_depth > UHYVE_MAX_QUEUE_SIZE {
+			error!(
+				"The network queue depth has to be a power of two between 1 and {}, but is {}",
+				UHYVE_MAX_QUEUE_SIZE, net_queue_depth
+			);
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		// create TUN/TAP devices and virtual networks, interfaces with only virtio-net are skipped
+		if specs.network_bridge.is_some() && specs.nic_mode != NicMode::Tap {
+			error!("TUN devices cannot be attached to a bridge");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		let (mut ifaces, first_nic) = match specs.network_bridge {
+			Some(bridge) => (
+				vec![(
+					0,
+					NetworkIfaceType::Bridge(bridge.to_string()),
+					NicSpec::default(),
+				)],
+				NicSpec::default(),
+			),
+			None => {
+				let mut nics = specs.nics.to_vec();
+				let ifaces = nics
+					.iter_mut()
+					.enumerate()
+					.filter_map(|(index, nic)| {
+						Some((
+							index,
+							NetworkIfaceType::from_spec(nic, specs.nic_mode)?,
+							*nic,
+						))
+					})
+					.collect::<Vec<_>>();
+				(ifaces, nics.first().copied().unwrap_or_default())
 			}
-			_ => None,
 		};
+		if ifaces.len() > UHYVE_MAX_NICS {
+			error!(
+				"uhyve supports at most {} network interfaces, but {} were requested",
+				UHYVE_MAX_NICS,
+				ifaces.len()
+			);
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		let net_queues_size = 2 * SharedQueue::size(net_queue_depth);
+		if SHAREDQUEUE_START + ifaces.len() * net_queues_size > SHAREDQUEUE_END {
+			error!(
+				"The shared queues of {} network interfaces with a depth of {} overlap the kernel",
+				ifaces.len(),
+				net_queue_depth
+			);
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		if specs.net_irq_resample && !irqfd::resample_supported() {
+			error!("KVM does not support resampled irqfds");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
 
-		assert!(
-			specs.gdbport.is_none() || specs.num_cpus == 1,
-			"gdbstub is only supported with one CPU"
-		);
+		let (ip_addr, gw_addr, mask) = (first_nic.ip, first_nic.gateway, first_nic.mask);
+		// the ports are forwarded to the first interface of the guest
+		let iptables_forwards = match ifaces.first_mut() {
+			Some((0, iface_type, _)) => iface_type.take_port_forwards(specs.port_forwards),
+			_ => specs.port_forwards,
+		};
+		let ptp = specs.ptp && {
+			let supported = ptp::cross_timestamps_supported();
+			if !supported {
+				warn!("KVM does not provide cross timestamps of the host's realtime clock, --ptp is ignored");
+			}
+			supported
+		};
+		let port_forwards = (!iptables_forwards.is_empty()).then(|| {
+			let guest = ip_addr.expect(
+				"Forwarding ports to a TAP device requires the address of the guest (--nic NAME,ip=IP)",
+			);
+			PortForwardRules::install(guest, iptables_forwards)
+				.expect("Unable to forward the ports")
+		});
+		// all interfaces share the interrupt line, the statistics and the packet capture
+		let network_stats = Arc::new(NetworkStats::default());
+		let pcap = specs.pcap.map(|path| {
+			Arc::new(
+				PcapCapture::create(path, specs.nic_mode == NicMode::Tun)
+					.expect("Unable to create the packet capture file"),
+			)
+		});
+		if specs.guest_ip_forward {
+			if specs.nic_mode != NicMode::Tun || specs.vhost_net {
+				error!(
+					"IP forwarding between the guest's interfaces requires TUN devices without vhost-net"
+				);
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+			if let Some(route) = specs
+				.guest_routes
+				.iter()
+				.find(|route| route.nic >= specs.nics.len())
+			{
+				error!(
+					"The route to {}/{} refers to a missing network interface {}",
+					route.network.address, route.network.prefix_len, route.nic
+				);
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+		}
+		let network_config = NetworkConfig {
+			queue_depth: net_queue_depth,
+			remove_bridge_on_exit: specs.remove_bridge_on_exit,
+			tx_queue_size: specs.tun_tx_queue_size,
+			vnet_hdr: specs.net_vnet_hdr,
+			stats: network_stats,
+			pcap,
+			router: specs
+				.guest_ip_forward
+				.then(|| Arc::new(Router::new(specs.guest_routes, specs.nics.len()))),
+		};
+		let mut uhyve_devices = Vec::with_capacity(ifaces.len());
+		let mut nics = Vec::with_capacity(ifaces.len());
+		for (index, (nic_index, iface_type, nic)) in ifaces.into_iter().enumerate() {
+			let evtfd = EventFd::new(0).unwrap();
+			let resample_fd = if specs.net_irq_resample {
+				let resample_fd = EventFd::new(0).unwrap();
+				irqfd::register_irqfd_with_resample(&vm, &evtfd, &resample_fd, UHYVE_IRQ_NET)
+					.map_err(|err| {
+						kvm_ioctls::Error::new(err.raw_os_error().unwrap_or(libc::EIO))
+					})?;
+				Some(resample_fd)
+			} else {
+				vm.register_irqfd(&evtfd, UHYVE_IRQ_NET)?;
+				None
+			};
+
+			debug!("Initialize network interface {}", index);
+			let mac = nic_mac(nic_index, &nic);
+			uhyve_devices.push(UhyveNetwork::new(
+				evtfd,
+				resample_fd,
+				iface_type,
+				mem.host_address + SHAREDQUEUE_START + index * net_queues_size,
+				mac,
+				nic_index,
+				&network_config,
+			));
+			nics.push(NicInfo::new(&nic, mac));
+		}
+
+		if specs.vhost_net {
+			let mut regions = vec![GuestRegion {
+				guest_address: mem.guest_address,
+				host_address: mem.host_address,
+				size: sz,
+			}];
+			if specs.mem_size > KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE {
+				let offset = KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE;
+				regions.push(GuestRegion {
+					guest_address: mem.guest_address + offset,
+					host_address: mem.host_address + offset,
+					size: specs.mem_size - offset,
+				});
+			}
+			match VhostNet::open(&vm, &regions, UHYVE_IRQ_NET) {
+				Ok(vhost) => virtio_device
+					.lock()
+					.unwrap()
+					.set_vhost(vhost, UHYVE_IRQ_NET as u8),
+				Err(err) => warn!(
+					"vhost-net is not available, virtio-net falls back to uhyve: {}",
+					err
+				),
+			}
+		}
+
+		// all devices are added, so that each of their interrupts has a route
+		GsiRoutingTable::uhyve().apply(&vm)?;
+
+		let console_log = specs.console_log_file.map(|path| {
+			Arc::new(
+				ConsoleLog::open(path, specs.console_log_append)
+					.expect("Unable to open the console log file"),
+			)
+		});
+
+		let debug_port_log = specs.debug_port_file.map(|path| {
+			Arc::new(
+				DebugPortLog::open(path, specs.debug_port_prefix, specs.verbose)
+					.expect("Unable to open the debug port file"),
+			)
+		});
+
+		let stats_log = specs
+			.stats_log
+			.map(|path| File::create(path).expect("Unable to create the network statistics log"));
+		if specs.stats_interval == 0 {
+			error!("Statistics interval has to be at least one second");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		if specs.debug_on_startup && specs.gdbport.is_none() && specs.gdb_socket.is_none() {
+			error!("--debug-on-startup requires --gdb_port or --gdb-socket");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		if specs.tsc_khz == Some(0) {
+			error!("TSC frequency has to be at least 1 kHz");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		if specs.ksm_report_interval == 0 {
+			error!("KSM report interval has to be at least one second");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		if let Some(deadline) = specs.deadline {
+			if deadline.runtime > deadline.deadline || deadline.deadline > deadline.period {
+				error!("SCHED_DEADLINE requires runtime <= deadline <= period");
+				return Err(kvm_ioctls::Error::new(libc::EINVAL));
+			}
+		}
+
+		if specs.preemption_timer == Some(0) {
+			error!("Preemption timer has to be at least one cycle");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		let preemption_timer =
+			specs
+				.preemption_timer
+				.and_then(|cycles| match detect_cpu_frequency() {
+					0 => {
+						warn!(
+							"Unable to determine the TSC frequency, disabling the preemption timer"
+						);
+						None
+					}
+					mhz => Some(Duration::from_nanos(
+						(cycles.saturating_mul(1000) / mhz as u64).max(1),
+					)),
+				});
+
+		if specs.tui && !cfg!(feature = "tui") {
+			error!("uhyve has been built without the `tui` feature");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		#[cfg(feature = "tui")]
+		let (dashboard_events, dashboard_receiver) = if specs.tui {
+			let (tx, rx) = std::sync::mpsc::channel();
+			(Some(Mutex::new(tx)), Mutex::new(Some(rx)))
+		} else {
+			(None, Mutex::new(None))
+		};
+
+		let mut exit_handlers = ExitHandlers::builtin();
+		let kernel_log =
+			KernelLog::start(specs.kernel_log_file).expect("Unable to open the kernel log file");
+		exit_handlers.register_ports([UHYVE_PORT_KERNEL_LOG], Arc::new(kernel_log));
+		if let Some(block_device) = block_device {
+			exit_handlers.register_ports(
+				virtio_blk::ports(),
+				Arc::new(VirtioBlkHandler(block_device)),
+			);
+		}
+		if let Some(balloon_device) = &balloon_device {
+			exit_handlers.register_ports(
+				virtio_balloon::ports(),
+				Arc::new(VirtioBalloonHandler(balloon_device.clone())),
+			);
+		}
+
+		let shared_state = specs.shm_id.map(|key| {
+			Arc::new(
+				SharedState::create(key, specs.num_cpus)
+					.expect("Unable to create the shared memory of the VM state"),
+			)
+		});
 
 		let hyve = Uhyve {
 			vm,
@@ -286,18 +1381,349 @@ impl Uhyve {
 			path: kernel_path,
 			boot_info: ptr::null(),
 			verbose: specs.verbose,
+			print_memory_layout: specs.print_memory_layout,
+			ksm_report_interval: (specs.mergeable && specs.verbose)
+				.then(|| Duration::from_secs(specs.ksm_report_interval)),
+			nmi_on_signal: specs.nmi_on_signal,
+			registers_on_signal: specs.registers_on_signal,
+			pause_on_signal: specs.pause_on_signal,
+			deadline: specs.deadline,
+			preemption_timer,
+			auto_migrate: specs.auto_migrate,
+			stats_interval: Duration::from_secs(specs.stats_interval),
+			stats_log,
+			net_metrics_file: specs.net_metrics_file.map(Path::to_path_buf),
+			memory_pressure_hints: specs.memory_pressure_hints,
+			ptp,
+			balloon_device,
+			balloon_control: specs.balloon_control.map(Path::to_path_buf),
+			balloon_target,
+			console_log,
+			debug_port_log,
 			ip: ip_addr,
 			gateway: gw_addr,
 			mask,
-			uhyve_device,
+			ip6: specs.ip6,
+			prefix_len6: specs.prefix_len6,
+			uhyve_devices,
+			nics,
+			port_forwards,
 			virtio_device,
+			pci_bus: Arc::new(Mutex::new(pci_bus)),
 			gdb_port: specs.gdbport,
+			gdb_socket: specs.gdb_socket.map(Path::to_path_buf),
+			debug_on_startup: specs.debug_on_startup,
+			iommu,
+			migrations: VcpuMigrations::new(specs.num_cpus),
+			control: VcpuControl::default(),
+			dirty_tracker,
+			exit_handlers: Arc::new(exit_handlers),
+			host_allowed_paths: specs.host_allowed_paths.map(canonicalize_allowed_paths),
+			kernel_args,
+			cpu_features: specs.cpu_features.into(),
+			cpuid_overrides: specs.cpuid_overrides.into(),
+			msr_whitelist: specs.msr_whitelist.into(),
+			entropy,
+			kernel_verifier: specs.kernel_signature.map(|signature| signature.verifier()),
+			clock_offset: specs.clock_offset,
+			tsc_khz: specs.tsc_khz,
+			tsc_offset: specs.tsc_offset,
+			net_queue_depth,
+			fault_tolerant: specs.fault_tolerant,
+			exit_log: specs
+				.verbose_exits
+				.then(|| Arc::new(ExitLog::new(specs.exit_filter))),
+			shared_state,
+			vcpu_fds: Mutex::new((0..specs.num_cpus).map(|_| None).collect()),
+			snapshot_path: specs.snapshot.map(Path::to_path_buf),
+			restored_vcpus: Mutex::new(Vec::new()),
+			#[cfg(feature = "tui")]
+			dashboard_events,
+			#[cfg(feature = "tui")]
+			dashboard_receiver,
 		};
 
 		hyve.init_guest_mem();
 
 		Ok(hyve)
 	}
+
+	/// Attaches an additional device to the given slot of the PCI bus.
+	pub fn register_pci_device(&mut self, slot: u8, device: Box<dyn PciDevice>) {
+		self.pci_bus.lock().unwrap().register(slot, device);
+	}
+
+	/// Lets an additional handler handle the exits of `reason`.
+	///
+	/// # Panics
+	///
+	/// Panics if a vCPU has already been created or the reason is already handled.
+	pub fn register_exit_handler(&mut self, reason: u32, handler: Arc<dyn ExitHandler>) {
+		Arc::get_mut(&mut self.exit_handlers)
+			.expect("exit handlers are in use by the vCPUs")
+			.register(reason, handler);
+	}
+
+	/// Lets an additional device emulate the given I/O ports.
+	///
+	/// # Panics
+	///
+	/// Panics if a vCPU has already been created or a port is already handled.
+	pub fn register_port_handler(
+		&mut self,
+		ports: impl IntoIterator<Item = u16>,
+		handler: Arc<dyn PortIoHandler>,
+	) {
+		Arc::get_mut(&mut self.exit_handlers)
+			.expect("exit handlers are in use by the vCPUs")
+			.register_ports(ports, handler);
+	}
+
+	/// Replaces the kernel of the VM without creating a new VM.
+	///
+	/// As this requires exclusive access, no vCPU is running. The guest memory is zeroed
+	/// and the kernel is loaded from `path`. The memory allocation and the vCPUs of the previous
+	/// run are reused, the state of the vCPUs is reset by [`VirtualCPU::init`](crate::vm::VirtualCPU::init) once they are started
+	/// again.
+	pub fn reload_kernel(&mut self, path: PathBuf) -> HypervisorResult<()> {
+		debug!("Reload kernel from {}", path.display());
+
+		self.mem.zero().map_err(|err| {
+			error!("Unable to discard the guest memory: {}", err);
+			kvm_ioctls::Error::new(err as i32)
+		})?;
+		self.path = path;
+		self.offset = 0;
+		self.entry_point = 0;
+		self.boot_info = ptr::null();
+
+		self.init_guest_mem();
+		unsafe { self.load_kernel() }.map_err(|err| {
+			error!("Unable to load the kernel: {}", err);
+			err.into()
+		})
+	}
+
+	/// Creates a VM from a snapshot of [`Uhyve::snapshot`], whose kernel continues where it has
+	/// been stopped.
+	///
+	/// `specs` have to request as many vCPUs and as much memory as the VM of the snapshot had.
+	pub fn restore(path: &Path, specs: &Parameter<'_>) -> HypervisorResult<Uhyve> {
+		if specs.gdbport.is_some() || specs.gdb_socket.is_some() {
+			error!("Debugging a restored VM is not supported");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		let io_error =
+			|err: io::Error| kvm_ioctls::Error::new(err.raw_os_error().unwrap_or(libc::EINVAL));
+		let (snapshot, mut memory) = Snapshot::open(path).map_err(|err| {
+			error!("Unable to read the snapshot {}: {}", path.display(), err);
+			io_error(err)
+		})?;
+		if snapshot.vcpus.len() != specs.num_cpus as usize
+			|| snapshot.memory_size != specs.mem_size as u64
+		{
+			error!(
+				"The snapshot {} requires {} vCPUs and {} bytes of memory",
+				path.display(),
+				snapshot.vcpus.len(),
+				snapshot.memory_size
+			);
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		let mut vm = Uhyve::new(snapshot.kernel_path.clone(), specs)?;
+		memory.read_exact(vm.mem.as_slice_mut()).map_err(io_error)?;
+
+		// the guest memory is mapped at another host address than before
+		let mut boot_info = snapshot.boot_info;
+		boot_info.host_logical_addr = vm.mem.host_address as u64;
+		let boot_info_ptr = (vm.mem.host_address + BOOT_INFO_ADDR as usize) as *mut BootInfo;
+		unsafe { ptr::write(boot_info_ptr, boot_info) };
+		vm.boot_info = boot_info_ptr;
+		vm.offset = snapshot.offset;
+		vm.entry_point = snapshot.entry_point;
+		*vm.restored_vcpus.get_mut().unwrap() = snapshot.vcpus.into_iter().map(Some).collect();
+
+		info!(
+			"Restored {} from {}",
+			snapshot.kernel_path.display(),
+			path.display()
+		);
+		Ok(vm)
+	}
+
+	/// Writes the memory and the given registers of the vCPUs to `path`.
+	pub(super) fn write_snapshot(
+		&self,
+		path: &Path,
+		vcpus: Vec<snapshot::VcpuState>,
+	) -> HypervisorResult<()> {
+		if self.boot_info.is_null() {
+			error!("The kernel has not been loaded yet");
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+		let snapshot = Snapshot {
+			kernel_path: self.path.clone(),
+			memory_size: self.mem.memory_size as u64,
+			offset: self.offset,
+			entry_point: self.entry_point,
+			boot_info: unsafe { ptr::read_volatile(self.boot_info) },
+			vcpus,
+		};
+		snapshot.write(path, self.mem.as_slice()).map_err(|err| {
+			error!("Unable to write the snapshot {}: {}", path.display(), err);
+			kvm_ioctls::Error::new(err.raw_os_error().unwrap_or(libc::EIO))
+		})?;
+		info!("Saved the VM to {}", path.display());
+		Ok(())
+	}
+
+	/// Returns whether the VM waits for GDB on a TCP port or a Unix socket.
+	pub(super) fn debugging(&self) -> bool {
+		self.gdb_port.is_some() || self.gdb_socket.is_some()
+	}
+
+	/// Returns whether the VM has been created by [`Uhyve::restore`].
+	pub(super) fn is_restored(&self) -> bool {
+		!self.restored_vcpus.lock().unwrap().is_empty()
+	}
+
+	/// Returns the registers of a vCPU of a restored VM, once.
+	pub(super) fn take_restored_state(&self, id: u32) -> Option<snapshot::VcpuState> {
+		self.restored_vcpus
+			.lock()
+			.unwrap()
+			.get_mut(id as usize)
+			.and_then(Option::take)
+	}
+
+	/// Keeps KVM's vCPU of a finished [`UhyveCPU`] for the next run.
+	pub(super) fn release_cpu(&self, id: u32, cpu: UhyveCPU) {
+		if let Some(shared_state) = &self.shared_state {
+			shared_state.vcpu(id).set_state(VcpuState::Stopped);
+		}
+		self.vcpu_fds.lock().unwrap()[id as usize] = Some(cpu.into_vcpu_fd());
+	}
+
+	/// Starts reporting the traffic of the network interfaces, if there are any.
+	pub(super) fn start_network_stats(&self) -> Option<NetworkStatsReporter> {
+		let device = self.uhyve_devices.first()?;
+		let log = self.stats_log.as_ref().map(|file| {
+			file.try_clone()
+				.expect("Unable to access the network statistics log")
+		});
+		Some(NetworkStatsReporter::start(
+			device.stats.clone(),
+			self.stats_interval,
+			self.verbose,
+			log,
+			self.net_metrics_file.clone(),
+		))
+	}
+
+	/// Returns the bytes, which have been sent and received, if there are network interfaces.
+	#[cfg(feature = "tui")]
+	pub(super) fn network_bytes(&self) -> Option<u64> {
+		let stats = self.uhyve_devices.first()?.stats.snapshot();
+		Some(stats.bytes_sent + stats.bytes_received)
+	}
+
+	/// Starts publishing the traffic of the network interfaces in the shared memory, if both exist.
+	pub(super) fn start_network_stats_publisher(&self) -> Option<NetworkStatsPublisher> {
+		let device = self.uhyve_devices.first()?;
+		let shared_state = self.shared_state.clone()?;
+		Some(NetworkStatsPublisher::start(
+			shared_state,
+			device.stats.clone(),
+		))
+	}
+
+	/// Starts publishing the cross timestamps for the guest, if requested.
+	pub(super) fn start_ptp_clock(&self) -> Option<PtpClock> {
+		if !self.ptp {
+			return None;
+		}
+		let page = self.mem.host_address + PTP_CLOCK_PAGE as usize;
+		match unsafe { PtpClock::start(self.vm.as_raw_fd(), page) } {
+			Ok(ptp_clock) => Some(ptp_clock),
+			Err(err) => {
+				warn!("Unable to publish the cross timestamps: {}", err);
+				None
+			}
+		}
+	}
+
+	/// Starts adjusting the balloon to its control file, if requested.
+	pub(super) fn start_balloon_control(&self) -> Option<BalloonControl> {
+		match (&self.balloon_device, &self.balloon_control) {
+			(Some(device), Some(path)) => Some(BalloonControl::start(device.clone(), path)),
+			_ => None,
+		}
+	}
+
+	/// Starts inflating the balloon to its initial size, if requested.
+	pub(super) fn start_balloon_target(&self) -> Option<BalloonTarget> {
+		match (&self.balloon_device, self.balloon_target) {
+			(Some(device), Some(num_pages)) => {
+				Some(BalloonTarget::start(device.clone(), num_pages))
+			}
+			_ => None,
+		}
+	}
+
+	/// Starts marking the guest memory, which is not written, as cold, if requested.
+	pub(super) fn start_cold_page_hints(&self) -> Option<ColdPageHinter> {
+		if !self.memory_pressure_hints {
+			return None;
+		}
+		let dirty_tracker = match &self.dirty_tracker {
+			Some(dirty_tracker) => dirty_tracker.clone(),
+			None => {
+				warn!("Memory pressure hints require the dirty ring");
+				return None;
+			}
+		};
+
+		let mem_size = self.mem.memory_size as u64;
+		let gap_end = (KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE) as u64;
+		let mut regions = vec![0..mem_size.min(KVM_32BIT_GAP_START as u64)];
+		if mem_size > gap_end {
+			regions.push(gap_end..mem_size);
+		}
+
+		Some(ColdPageHinter::start(
+			dirty_tracker,
+			regions,
+			self.mem.host_address,
+			COLD_PAGE_HINT_INTERVAL,
+		))
+	}
+
+	/// Returns the tracker of the pages written by the guest, if `dirty_tracking` has been requested.
+	pub fn dirty_tracker(&self) -> Option<&DirtyTracker> {
+		self.dirty_tracker.as_deref()
+	}
+
+	/// Injects a non-maskable interrupt into the given vCPU.
+	///
+	/// The NMI is delivered as MSI, so that it can be sent from any thread.
+	pub fn inject_nmi(&self, vcpu_id: u32) -> HypervisorResult<()> {
+		if vcpu_id >= self.num_cpus {
+			return Err(kvm_ioctls::Error::new(libc::EINVAL));
+		}
+
+		let msi = kvm_msi {
+			address_lo: MSI_ADDRESS_BASE | ((vcpu_id & 0xff) << 12),
+			// with KVM_X2APIC_API_USE_32BIT_IDS, the upper bits of the destination are taken from address_hi
+			address_hi: vcpu_id & !0xff,
+			data: MSI_DELIVERY_MODE_NMI,
+			..Default::default()
+		};
+		self.vm.signal_msi(msi)?;
+		debug!("Injected NMI into vCPU {}", vcpu_id);
+
+		Ok(())
+	}
 }
 
 impl Vm for Uhyve {
@@ -305,6 +1731,22 @@ impl Vm for Uhyve {
 		self.verbose
 	}
 
+	fn print_memory_layout(&self) -> bool {
+		self.print_memory_layout
+	}
+
+	fn clock_offset(&self) -> i64 {
+		self.clock_offset
+	}
+
+	fn tsc_khz(&self) -> Option<u32> {
+		self.tsc_khz
+	}
+
+	fn net_queue_depth(&self) -> u32 {
+		self.net_queue_depth as u32
+	}
+
 	fn set_offset(&mut self, offset: u64) {
 		self.offset = offset;
 	}
@@ -333,6 +1775,40 @@ impl Vm for Uhyve {
 		self.mask
 	}
 
+	fn get_nics(&self) -> Vec<NicInfo> {
+		self.nics.clone()
+	}
+
+	fn ptp_clock_page(&self) -> Option<u64> {
+		self.ptp.then_some(PTP_CLOCK_PAGE)
+	}
+
+	fn entropy(&self) -> [u8; ENTROPY_SIZE] {
+		self.entropy
+	}
+
+	fn get_dirty_log(&self, slot: u32) -> HypervisorResult<Vec<u64>> {
+		self.mem.get_dirty_log(&self.vm, slot)
+	}
+
+	fn pause(&self) -> HypervisorResult<()> {
+		self.pause_vcpus();
+		Ok(())
+	}
+
+	fn resume(&self) -> HypervisorResult<()> {
+		self.resume_vcpus();
+		Ok(())
+	}
+
+	fn get_ip6(&self) -> Option<Ipv6Addr> {
+		self.ip6
+	}
+
+	fn get_prefix_len6(&self) -> Option<u8> {
+		self.prefix_len6
+	}
+
 	fn num_cpus(&self) -> u32 {
 		self.num_cpus
 	}
@@ -345,18 +1821,138 @@ impl Vm for Uhyve {
 		self.path.as_path()
 	}
 
+	fn kernel_verifier(&self) -> Option<&KernelVerifier> {
+		self.kernel_verifier.as_ref()
+	}
+
+	fn platform_memory_regions(&self) -> Vec<GuestMemoryRegion> {
+		let mut regions = Vec::new();
+
+		let mem_size = self.mem.memory_size;
+		regions.push(GuestMemoryRegion::new(
+			0,
+			mem_size.min(KVM_32BIT_GAP_START) as u64,
+			"Guest RAM",
+		));
+		if mem_size > KVM_32BIT_GAP_START {
+			regions.push(GuestMemoryRegion::new(
+				KVM_32BIT_GAP_START as u64,
+				KVM_32BIT_GAP_SIZE as u64,
+				"32 bit MMIO gap (not backed by RAM)",
+			));
+		}
+		if mem_size > KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE {
+			regions.push(GuestMemoryRegion::new(
+				(KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE) as u64,
+				(mem_size - KVM_32BIT_GAP_START - KVM_32BIT_GAP_SIZE) as u64,
+				"Guest RAM",
+			));
+		}
+
+		if self.ptp {
+			regions.push(GuestMemoryRegion::new(
+				PTP_CLOCK_PAGE,
+				PAGE_SIZE as u64,
+				"PTP clock page",
+			));
+		}
+
+		let queue_size = SharedQueue::size(self.net_queue_depth);
+		for index in 0..self.uhyve_devices.len() {
+			let start = SHAREDQUEUE_START + index * 2 * queue_size;
+			regions.push(GuestMemoryRegion::new(
+				start as u64,
+				queue_size as u64,
+				"Shared queue (host to guest)",
+			));
+			regions.push(GuestMemoryRegion::new(
+				(start + queue_size) as u64,
+				queue_size as u64,
+				"Shared queue (guest to host)",
+			));
+		}
+
+		regions
+	}
+
 	fn create_cpu(&self, id: u32) -> HypervisorResult<UhyveCPU> {
 		let vm_start = self.mem.host_address as usize;
-		let tx = self.uhyve_device.as_ref().map(|dev| dev.tx.clone());
+		let tx = self
+			.uhyve_devices
+			.iter()
+			.map(|dev| dev.tx.clone())
+			.collect();
+
+		let released_vcpu = self.vcpu_fds.lock().unwrap()[id as usize].take();
+		let vcpu = match released_vcpu {
+			Some(vcpu) => vcpu,
+			None => {
+				let vcpu = self.vm.create_vcpu(id.try_into().unwrap())?;
+				if let Some(dirty_tracker) = &self.dirty_tracker {
+					dirty_tracker.register_vcpu(&vcpu).map_err(|err| {
+						kvm_ioctls::Error::new(err.raw_os_error().unwrap_or(libc::EIO))
+					})?;
+				}
+				vcpu
+			}
+		};
 
-		Ok(UhyveCPU::new(
-			id,
-			self.path.clone(),
-			self.vm.create_vcpu(id.try_into().unwrap())?,
+		if let Some(khz) = self.tsc_khz {
+			tsc::set_tsc_khz(&vcpu, khz).map_err(|err| {
+				error!(
+					"Unable to set the TSC frequency of vCPU {} to {} kHz: {}",
+					id, khz, err
+				);
+				err
+			})?;
+		} else if id == 0 {
+			match tsc::host_tsc_khz(&vcpu) {
+				Some(khz) => info!(
+					"The vCPUs run with the TSC frequency of the host, {} kHz",
+					khz
+				),
+				None => warn!("Unable to determine the TSC frequency of the host"),
+			}
+		}
+		if let Some(offset) = self.tsc_offset {
+			tsc::set_tsc_offset(&vcpu, offset).map_err(|err| {
+				error!(
+					"Unable to set the TSC offset of vCPU {}, which requires Linux 5.16: {}",
+					id, err
+				);
+				err
+			})?;
+		}
+
+		let config = VcpuConfig {
+			kernel_path: self.path.clone(),
 			vm_start,
 			tx,
-			self.virtio_device.clone(),
-		))
+			virtio_device: self.virtio_device.clone(),
+			pci_bus: self.pci_bus.clone(),
+			console_log: self.console_log.clone(),
+			debug_port_log: self.debug_port_log.clone(),
+			iommu: self.iommu.clone(),
+			dirty_tracker: self.dirty_tracker.clone(),
+			exit_handlers: self.exit_handlers.clone(),
+			host_allowed_paths: self.host_allowed_paths.clone(),
+			kernel_args: self.kernel_args.clone(),
+			cpu_features: self.cpu_features.clone(),
+			cpuid_overrides: self.cpuid_overrides.clone(),
+			msr_whitelist: self.msr_whitelist.clone(),
+			shared_state: self.shared_state.clone(),
+			net_queue_depth: self.net_queue_depth,
+			fault_tolerant: self.fault_tolerant,
+			exit_log: self.exit_log.clone(),
+		};
+		#[allow(unused_mut)]
+		let mut cpu = UhyveCPU::new(id, vcpu, config);
+		#[cfg(feature = "tui")]
+		if let Some(dashboard_events) = &self.dashboard_events {
+			let tx = dashboard_events.lock().unwrap().clone();
+			cpu.report_exits(VcpuEventSender::new(id, tx));
+		}
+		Ok(cpu)
 	}
 
 	fn set_boot_info(&mut self, header: *const BootInfo) {
@@ -373,8 +1969,24 @@ impl Vm for Uhyve {
 }
 
 impl Drop for Uhyve {
+	/// The vCPU threads and all threads accompanying them hold an `Arc<Uhyve>` and are joined
+	/// when the VM stops running, so none of them can access the guest memory or the vCPUs anymore.
 	fn drop(&mut self) {
 		debug!("Drop virtual machine");
+
+		if let Some(path) = &self.gdb_socket {
+			let is_socket = fs::symlink_metadata(path)
+				.map_or(false, |metadata| metadata.file_type().is_socket());
+			if is_socket {
+				if let Err(err) = fs::remove_file(path) {
+					warn!(
+						"Unable to remove the GDB socket {}: {}",
+						path.display(),
+						err
+					);
+				}
+			}
+		}
 	}
 }
 
@@ -386,55 +1998,279 @@ unsafe impl Sync for Uhyve {}
 
 #[derive(Debug)]
 struct MmapMemory {
-	flags: u32,
+	/// Whether KVM logs the pages written by the guest, see [`MmapMemory::get_dirty_log`]
+	dirty_log: bool,
+	memory_size: usize,
+	guest_address: usize,
+	host_address: usize,
+	/// Whether the memory is a shared mapping of a file
+	file_backed: bool,
+}
+
+/// Opens the file backing the guest memory and sets it to `memory_size` bytes.
+fn open_memory_file(path: &Path, memory_size: usize) -> nix::Result<File> {
+	let io_error = |what: &str, err: io::Error| {
+		error!(
+			"Unable to {} the memory backend file {}: {}",
+			what,
+			path.display(),
+			err
+		);
+		Errno::from_i32(err.raw_os_error().unwrap_or(libc::EIO))
+	};
+	let file = OpenOptions::new()
+		.read(true)
+		.write(true)
+		.create(true)
+		// the guest memory of the last run is preserved
+		.truncate(false)
+		.open(path)
+		.map_err(|err| io_error("open", err))?;
+	let file_size = file
+		.metadata()
+		.map_err(|err| io_error("query the size of", err))?
+		.len() as usize;
+
+	if file_size == 0 {
+		debug!("Create memory backend file {}", path.display());
+	} else if file_size > memory_size {
+		warn!(
+			"Truncating memory backend file {} from {} to {} bytes",
+			path.display(),
+			file_size,
+			memory_size
+		);
+	} else if file_size < memory_size {
+		error!(
+			"Memory backend file {} has {} bytes, but the guest memory requires {} bytes",
+			path.display(),
+			file_size,
+			memory_size
+		);
+		return Err(Errno::EINVAL);
+	}
+
+	if file_size != memory_size {
+		file.set_len(memory_size as u64)
+			.map_err(|err| io_error("resize", err))?;
+	}
+	Ok(file)
+}
+
+/// Returns the KVM memory slots of `memory_size` bytes of guest memory.
+///
+/// Slot 0 ends at the 32-bit gap and slot 1 holds the memory above the gap, so that the gap is
+/// free for MMIO. The memory in the gap remains mapped in the host, but is never used.
+fn memory_slots(
 	memory_size: usize,
 	guest_address: usize,
 	host_address: usize,
+	flags: u32,
+) -> Vec<kvm_userspace_memory_region> {
+	let mut slots = vec![kvm_userspace_memory_region {
+		slot: 0,
+		flags,
+		memory_size: memory_size.min(KVM_32BIT_GAP_START) as u64,
+		guest_phys_addr: guest_address as u64,
+		userspace_addr: host_address as u64,
+	}];
+	let gap_end = KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE;
+	if memory_size > gap_end {
+		slots.push(kvm_userspace_memory_region {
+			slot: 1,
+			flags,
+			memory_size: (memory_size - gap_end) as u64,
+			guest_phys_addr: (guest_address + gap_end) as u64,
+			userspace_addr: (host_address + gap_end) as u64,
+		});
+	}
+	slots
 }
 
 impl MmapMemory {
+	/// Maps the guest memory.
+	///
+	/// Failures of the hints `mergeable` and `huge_pages` only cause warnings, as the kernel may
+	/// lack KSM or transparent huge pages.
 	pub fn new(
-		flags: u32,
+		dirty_log: bool,
 		memory_size: usize,
 		guest_address: u64,
 		huge_pages: bool,
 		mergeable: bool,
-	) -> MmapMemory {
-		let host_address = unsafe {
-			mmap(
-				std::ptr::null_mut(),
-				memory_size,
-				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-				MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
-				-1,
-				0,
-			)
-			.expect("mmap failed")
+		backend: MemoryBackend<'_>,
+	) -> nix::Result<MmapMemory> {
+		let host_address = match backend {
+			MemoryBackend::Anonymous => unsafe {
+				mmap(
+					std::ptr::null_mut(),
+					memory_size,
+					ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+					MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
+					-1,
+					0,
+				)?
+			},
+			// file-backed pages have to be allocated in the file, so MAP_NORESERVE is not used
+			MemoryBackend::File(path) => {
+				let file = open_memory_file(path, memory_size)?;
+				unsafe {
+					mmap(
+						std::ptr::null_mut(),
+						memory_size,
+						ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+						MapFlags::MAP_SHARED,
+						file.as_raw_fd(),
+						0,
+					)?
+				}
+			}
 		};
+		let file_backed = matches!(backend, MemoryBackend::File(_));
+
+		if mergeable && file_backed {
+			warn!("KSM is not able to merge pages of a memory backend file");
+		}
 
 		if mergeable {
 			debug!("Enable kernel feature to merge same pages");
-			unsafe {
-				madvise(host_address, memory_size, MmapAdvise::MADV_MERGEABLE).unwrap();
+			if let Err(err) =
+				unsafe { madvise(host_address, memory_size, MmapAdvise::MADV_MERGEABLE) }
+			{
+				warn!("Unable to mark the guest memory as mergeable: {}", err);
 			}
 		}
 
 		if huge_pages {
 			debug!("Uhyve uses huge pages");
-			unsafe {
-				madvise(host_address, memory_size, MmapAdvise::MADV_HUGEPAGE).unwrap();
+			if let Err(err) =
+				unsafe { madvise(host_address, memory_size, MmapAdvise::MADV_HUGEPAGE) }
+			{
+				warn!("Unable to back the guest memory with huge pages: {}", err);
 			}
 		}
 
-		MmapMemory {
-			flags,
+		Ok(MmapMemory {
+			dirty_log,
 			memory_size,
 			guest_address: guest_address as usize,
 			host_address: host_address as usize,
+			file_backed,
+		})
+	}
+
+	/// Returns the flags of the KVM memory slots, which map the memory.
+	fn flags(&self) -> u32 {
+		if self.dirty_log {
+			KVM_MEM_LOG_DIRTY_PAGES
+		} else {
+			0
 		}
 	}
 
-	#[allow(dead_code)]
+	/// Returns the size of the KVM memory slot `slot` in bytes. Slot 0 ends at the 32-bit gap,
+	/// slot 1 holds the memory above it.
+	fn slot_size(&self, slot: u32) -> Option<usize> {
+		match slot {
+			0 => Some(self.memory_size.min(KVM_32BIT_GAP_START)),
+			1 if self.memory_size > KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE => {
+				Some(self.memory_size - KVM_32BIT_GAP_START - KVM_32BIT_GAP_SIZE)
+			}
+			_ => None,
+		}
+	}
+
+	/// Returns the bitmap of the pages of `slot`, which the guest has written since the last call.
+	///
+	/// Bit `n % 64` of word `n / 64` belongs to page `n` of the slot. `KVM_GET_DIRTY_LOG` resets
+	/// the bitmap and write-protects the logged pages again.
+	fn get_dirty_log(&self, vm: &VmFd, slot: u32) -> HypervisorResult<Vec<u64>> {
+		let memory_size = self
+			.slot_size(slot)
+			.ok_or_else(|| kvm_ioctls::Error::new(libc::EINVAL))?;
+		vm.get_dirty_log(slot, memory_size)
+	}
+
+	/// Allocates the memory of a guest NUMA node on the host nodes of its CPUs.
+	pub fn bind(&self, node: &NumaNode) -> io::Result<()> {
+		let mut host_nodes = node
+			.cpus
+			.iter()
+			.map(|&cpu| numa::host_node_of_cpu(cpu))
+			.collect::<io::Result<Vec<_>>>()?;
+		host_nodes.sort_unstable();
+		host_nodes.dedup();
+		debug!(
+			"Bind guest memory {:#x}..{:#x} to host nodes {:?}",
+			node.memory.start, node.memory.end, host_nodes
+		);
+		assert!(node.memory.end <= self.memory_size);
+		unsafe {
+			numa::bind(
+				self.host_address + node.memory.start,
+				node.memory.len(),
+				&host_nodes,
+			)
+		}
+	}
+
+	/// Faults in the whole memory by writing to each page.
+	///
+	/// The memory is split into `workers` equally sized ranges, which are touched in parallel.
+	pub fn pretouch(&self, workers: usize) {
+		let start = Instant::now();
+		let workers = workers.max(1);
+		let pages = self.memory_size / PAGE_SIZE;
+		let pages_per_worker = (pages + workers - 1) / workers;
+
+		let threads = (0..workers)
+			.map(|worker| {
+				let first_page = (worker * pages_per_worker).min(pages);
+				let last_page = ((worker + 1) * pages_per_worker).min(pages);
+				let host_address = self.host_address;
+
+				thread::spawn(move || {
+					for page in first_page..last_page {
+						let addr = (host_address + page * PAGE_SIZE) as *mut u8;
+						unsafe { write_volatile(addr, 0) };
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		info!(
+			"Pretouched {} bytes of guest memory with {} threads in {:?}",
+			self.memory_size,
+			workers,
+			start.elapsed()
+		);
+	}
+
+	/// Discards the content of the memory, so that it reads as zeros afterwards.
+	pub fn zero(&self) -> nix::Result<()> {
+		if self.file_backed {
+			// MADV_DONTNEED would only drop the pages from the page tables, not from the file
+			unsafe { ptr::write_bytes(self.host_address as *mut u8, 0, self.memory_size) };
+			return Ok(());
+		}
+
+		unsafe {
+			madvise(
+				self.host_address as *mut c_void,
+				self.memory_size,
+				MmapAdvise::MADV_DONTNEED,
+			)
+		}
+	}
+
+	fn as_slice(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.host_address as *const u8, self.memory_size) }
+	}
+
 	fn as_slice_mut(&mut self) -> &mut [u8] {
 		unsafe { std::slice::from_raw_parts_mut(self.host_address as *mut u8, self.memory_size) }
 	}
@@ -449,3 +2285,146 @@ impl Drop for MmapMemory {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use nix::errno::Errno;
+
+	#[test]
+	fn test_tun_peer() {
+		assert_eq!(tun_peer(TUN_HOST_ADDR), Some(Ipv4Addr::new(10, 0, 0, 2)));
+		assert_eq!(
+			tun_peer(Ipv4Addr::new(192, 168, 7, 6)),
+			Some(Ipv4Addr::new(192, 168, 7, 5))
+		);
+		assert_eq!(tun_peer(Ipv4Addr::new(10, 0, 0, 0)), None);
+		assert_eq!(tun_peer(Ipv4Addr::new(10, 0, 0, 3)), None);
+	}
+
+	#[test]
+	fn test_memory_slots() {
+		let gap_end = (KVM_32BIT_GAP_START + KVM_32BIT_GAP_SIZE) as u64;
+		let host_address = 0x7f00_0000_0000;
+
+		// just above the gap
+		let slots = memory_slots(gap_end as usize + 1, 0, host_address, 0);
+		assert_eq!(slots.len(), 2);
+		assert_eq!(slots[0].slot, 0);
+		assert_eq!(slots[0].guest_phys_addr, 0);
+		assert_eq!(slots[0].memory_size, KVM_32BIT_GAP_START as u64);
+		assert_eq!(slots[0].userspace_addr, host_address as u64);
+		assert_eq!(slots[1].slot, 1);
+		assert_eq!(slots[1].guest_phys_addr, gap_end);
+		assert_eq!(slots[1].memory_size, 1);
+		assert_eq!(slots[1].userspace_addr, host_address as u64 + gap_end);
+
+		// no slot covers the gap
+		let gap = KVM_32BIT_GAP_START as u64..gap_end;
+		for slot in &slots {
+			let end = slot.guest_phys_addr + slot.memory_size;
+			assert!(end <= gap.start || slot.guest_phys_addr >= gap.end);
+		}
+
+		// the memory ends at or in the gap
+		for memory_size in [KVM_32BIT_GAP_START, gap_end as usize] {
+			let slots = memory_slots(memory_size, 0, host_address, KVM_MEM_LOG_DIRTY_PAGES);
+			assert_eq!(slots.len(), 1);
+			assert_eq!(slots[0].memory_size, KVM_32BIT_GAP_START as u64);
+			assert_eq!(slots[0].flags, KVM_MEM_LOG_DIRTY_PAGES);
+		}
+		assert_eq!(
+			memory_slots(1 << 20, 0, host_address, 0)[0].memory_size,
+			1 << 20
+		);
+	}
+
+	#[test]
+	fn test_mmap_memory_errors() {
+		// an empty mapping is rejected by the kernel
+		let err = MmapMemory::new(false, 0, 0, false, false, MemoryBackend::Anonymous).unwrap_err();
+		assert_eq!(err, Errno::EINVAL);
+		let err = MmapMemory::new(
+			false,
+			usize::MAX & !(PAGE_SIZE - 1),
+			0,
+			false,
+			false,
+			MemoryBackend::Anonymous,
+		)
+		.unwrap_err();
+		assert_eq!(err, Errno::ENOMEM);
+
+		// the hints never fail the mapping
+		let mem = MmapMemory::new(false, 4 << 20, 0, true, true, MemoryBackend::Anonymous).unwrap();
+		assert_eq!(mem.memory_size, 4 << 20);
+	}
+
+	#[test]
+	fn test_open_memory_file_errors() {
+		let path = std::env::temp_dir().join(format!("uhyve-memory-{}", std::process::id()));
+		std::fs::write(&path, [0u8; 4096]).unwrap();
+		// a file smaller than the guest memory is not silently extended
+		assert_eq!(open_memory_file(&path, 8192).unwrap_err(), Errno::EINVAL);
+		assert_eq!(
+			open_memory_file(&path, 4096)
+				.unwrap()
+				.metadata()
+				.unwrap()
+				.len(),
+			4096
+		);
+		std::fs::remove_file(&path).unwrap();
+
+		let err = open_memory_file(Path::new("/nonexistent/uhyve-memory"), 4096).unwrap_err();
+		assert_eq!(err, Errno::ENOENT);
+	}
+
+	#[test]
+	fn test_dirty_log() {
+		let vm = KVM.create_vm().unwrap();
+		let mem =
+			MmapMemory::new(true, 1 << 20, 0, false, false, MemoryBackend::Anonymous).unwrap();
+		let kvm_mem = kvm_userspace_memory_region {
+			slot: 0,
+			flags: mem.flags(),
+			memory_size: mem.slot_size(0).unwrap() as u64,
+			guest_phys_addr: 0,
+			userspace_addr: mem.host_address as u64,
+		};
+		unsafe { vm.set_user_memory_region(kvm_mem) }.unwrap();
+		assert!(mem.slot_size(1).is_none());
+
+		// mov byte [0x3000], 1; hlt
+		let code = [0xc6, 0x06, 0x00, 0x30, 0x01, 0xf4];
+		unsafe {
+			ptr::copy_nonoverlapping(
+				code.as_ptr(),
+				(mem.host_address + 0x1000) as *mut u8,
+				code.len(),
+			)
+		};
+		let vcpu = vm.create_vcpu(0).unwrap();
+		let mut sregs = vcpu.get_sregs().unwrap();
+		sregs.cs.base = 0;
+		sregs.cs.selector = 0;
+		sregs.ds.base = 0;
+		sregs.ds.selector = 0;
+		vcpu.set_sregs(&sregs).unwrap();
+		let regs = kvm_regs {
+			rip: 0x1000,
+			rflags: 2,
+			..Default::default()
+		};
+		vcpu.set_regs(&regs).unwrap();
+		assert!(matches!(vcpu.run().unwrap(), kvm_ioctls::VcpuExit::Hlt));
+
+		// the code has only been read, the stored byte dirties page 3
+		let bitmap = mem.get_dirty_log(&vm, 0).unwrap();
+		assert_eq!(bitmap.len(), 4);
+		assert_eq!(bitmap, [1 << 3, 0, 0, 0]);
+		// the bitmap is reset by reading it
+		assert_eq!(mem.get_dirty_log(&vm, 0).unwrap(), [0, 0, 0, 0]);
+		assert!(mem.get_dirty_log(&vm, 1).is_err());
+	}
+}
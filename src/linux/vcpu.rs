@@ -1,33 +1,567 @@
+use crate::console_log::{ConsoleLog, DebugPortLog};
 use crate::consts::*;
+use crate::cpu_features::{CpuFeatureChange, CpuidOverride};
+use crate::exit_reasons::{exit_reason_name, EXIT_REASONS};
+use crate::linux::cpu_registers::CpuRegisters;
+use crate::linux::dirty_ring::{DirtyTracker, KVM_EXIT_DIRTY_RING_FULL};
+use crate::linux::exit_log::ExitLog;
+use crate::linux::immediate_exit::ExitRequest;
+use crate::linux::kvm_run::KvmRun;
+use crate::linux::msr_filter::{self, KvmRunMsr, KVM_EXIT_X86_RDMSR, KVM_EXIT_X86_WRMSR};
+use crate::linux::pci::*;
+use crate::linux::shm_state::{SharedState, VcpuState};
+use crate::linux::snapshot;
+use crate::linux::sw_iommu::{SoftIommu, SysIommuMap};
+#[cfg(feature = "tui")]
+use crate::linux::tui::VcpuEventSender;
 use crate::linux::virtio::*;
+use crate::linux::HypervisorError;
 use crate::linux::KVM;
 use crate::paging::*;
+use crate::shared_queue::SharedQueue;
 use crate::vm::HypervisorResult;
 use crate::vm::VcpuStopReason;
 use crate::vm::VirtualCPU;
 use kvm_bindings::*;
-use kvm_ioctls::{VcpuExit, VcpuFd};
+use kvm_ioctls::VcpuFd;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
+use std::ptr;
 use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
 use x86_64::registers::control::{Cr0Flags, Cr4Flags};
 
 const CPUID_EXT_HYPERVISOR: u32 = 1 << 31;
 const CPUID_TSC_DEADLINE: u32 = 1 << 24;
 const CPUID_ENABLE_MSR: u32 = 1 << 5;
 const MSR_IA32_MISC_ENABLE: u32 = 0x000001a0;
-const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
-const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+/// `_IO(KVMIO, 0xad)`
+const KVM_KVMCLOCK_CTRL: libc::c_ulong = 0xaead;
+/// Not yet exported by `kvm-bindings`, see `--notify-vmexit-window`
+const KVM_EXIT_NOTIFY: u32 = 37;
+/// The VM context of the vCPU is corrupted and it cannot be resumed
+const KVM_NOTIFY_CONTEXT_INVALID: u32 = 1 << 0;
+/// Size of the stack region, which is faulted in by [`UhyveCPU::warm_up_cache`].
+const WARM_UP_STACK_SIZE: usize = 64 * 1024;
+const CACHE_LINE_SIZE: usize = 64;
+/// `_IOWR(KVMIO, 0x85, struct kvm_translation)`
+const KVM_TRANSLATE: libc::c_ulong = 0xc018_ae85;
+
+/// Explains the hardware reason of a failed VM entry.
+///
+/// On Intel, this is the basic exit reason of the VM exit (Intel SDM Vol. 3, Appendix C).
+/// On AMD, this is the exit code of the VMCB (AMD APM Vol. 2, Appendix C).
+fn describe_entry_failure(reason: u64) -> &'static str {
+	match reason {
+		// AMD: VMEXIT_INVALID
+		u64::MAX => "invalid guest state in the VMCB",
+		_ => match reason & 0xffff {
+			33 => "invalid guest state",
+			34 => "MSR loading failed",
+			41 => "machine-check event during VM entry",
+			_ => "unknown reason",
+		},
+	}
+}
+
+/// Explains the suberror of a `KVM_EXIT_INTERNAL_ERROR`.
+fn describe_internal_error(suberror: u32) -> &'static str {
+	match suberror {
+		KVM_INTERNAL_ERROR_EMULATION => "the instruction could not be emulated",
+		KVM_INTERNAL_ERROR_SIMUL_EX => "an exception occurred while delivering another one",
+		KVM_INTERNAL_ERROR_DELIVERY_EV => "an event could not be delivered",
+		KVM_INTERNAL_ERROR_UNEXPECTED_EXIT_REASON => "KVM received an unexpected exit reason",
+		_ => "unknown error",
+	}
+}
+
+/// `kvm_run.io`, the data follows at `data_offset` in `kvm_run`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmRunIo {
+	pub direction: u8,
+	pub size: u8,
+	pub port: u16,
+	pub count: u32,
+	pub data_offset: u64,
+}
+
+/// `kvm_run.mmio`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmRunMmio {
+	pub phys_addr: u64,
+	pub data: [u8; 8],
+	pub len: u32,
+	pub is_write: u8,
+}
+
+/// `kvm_run.fail_entry`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmRunFailEntry {
+	pub hardware_entry_failure_reason: u64,
+	pub cpu: u32,
+}
+
+/// `kvm_run.internal`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmRunInternal {
+	pub suberror: u32,
+	pub ndata: u32,
+	pub data: [u64; 16],
+}
+
+/// `kvm_run.notify`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KvmRunNotify {
+	pub flags: u32,
+}
+
+/// Access to the exit union of `kvm_run`, which only returns the member of the last exit reason.
+///
+/// Reading another member, e.g., `mmio` after a `KVM_EXIT_IO`, returns `None` instead of garbage.
+/// The members are declared above, as the names of the anonymous structs of `kvm-bindings`
+/// depend on the kernel version, from which the bindings have been generated.
+pub struct KvmRunAccess<'a> {
+	run: *mut kvm_run,
+	/// Size of the mapping of `kvm_run`, which contains the data of `KVM_EXIT_IO` behind the struct
+	size: usize,
+	_marker: PhantomData<&'a mut kvm_run>,
+}
+
+impl<'a> KvmRunAccess<'a> {
+	pub fn new(run: &'a mut kvm_run) -> Self {
+		unsafe { Self::from_raw(run, mem::size_of::<kvm_run>()) }
+	}
+
+	/// Accesses the mapping of `kvm_run` of `size` bytes at `run`.
+	///
+	/// # Safety
+	///
+	/// The mapping has to stay valid for `'a` and must not be accessed otherwise in the meantime.
+	pub unsafe fn from_raw(run: *mut kvm_run, size: usize) -> Self {
+		Self {
+			run,
+			size,
+			_marker: PhantomData,
+		}
+	}
+
+	pub fn exit_reason(&self) -> u32 {
+		unsafe { (*self.run).exit_reason }
+	}
+
+	/// Returns the member `T`, which has to be one of the `KvmRun*` structs, for `reasons`.
+	fn member<T>(&self, reasons: &[u32]) -> Option<&T> {
+		reasons
+			.contains(&self.exit_reason())
+			.then(|| unsafe { &*ptr::addr_of!((*self.run).__bindgen_anon_1).cast::<T>() })
+	}
+
+	fn member_mut<T>(&mut self, reasons: &[u32]) -> Option<&mut T> {
+		reasons.contains(&self.exit_reason()).then(move || unsafe {
+			&mut *ptr::addr_of_mut!((*self.run).__bindgen_anon_1).cast::<T>()
+		})
+	}
+
+	pub fn as_io(&self) -> Option<&KvmRunIo> {
+		self.member(&[KVM_EXIT_IO])
+	}
+
+	/// Returns the data of a `KVM_EXIT_IO`, which KVM places at `data_offset` in the mapping, or
+	/// `None`, if it exceeds the mapping.
+	pub fn io_data_mut(&mut self) -> Option<&mut [u8]> {
+		let io = *self.as_io()?;
+		let start = usize::try_from(io.data_offset).ok()?;
+		let len = usize::from(io.size) * io.count as usize;
+		(start.checked_add(len)? <= self.size)
+			.then(|| unsafe { slice::from_raw_parts_mut((self.run as *mut u8).add(start), len) })
+	}
+
+	pub fn as_mmio(&self) -> Option<&KvmRunMmio> {
+		self.member(&[KVM_EXIT_MMIO])
+	}
+
+	/// Returns the access of a `KVM_EXIT_MMIO`, whose `data` (for reads) KVM reads on the next
+	/// `KVM_RUN`.
+	pub fn as_mmio_mut(&mut self) -> Option<&mut KvmRunMmio> {
+		self.member_mut(&[KVM_EXIT_MMIO])
+	}
+
+	pub fn as_debug(&self) -> Option<&kvm_debug_exit_arch> {
+		self.member(&[KVM_EXIT_DEBUG])
+	}
+
+	pub fn as_fail_entry(&self) -> Option<&KvmRunFailEntry> {
+		self.member(&[KVM_EXIT_FAIL_ENTRY])
+	}
+
+	pub fn as_internal(&self) -> Option<&KvmRunInternal> {
+		self.member(&[KVM_EXIT_INTERNAL_ERROR])
+	}
+
+	pub fn as_notify(&self) -> Option<&KvmRunNotify> {
+		self.member(&[KVM_EXIT_NOTIFY])
+	}
+
+	/// Returns the MSR access, whose `data` (for reads) and `error` KVM reads on the next
+	/// `KVM_RUN`.
+	pub fn as_msr_mut(&mut self) -> Option<&mut KvmRunMsr> {
+		self.member_mut(&[KVM_EXIT_X86_RDMSR, KVM_EXIT_X86_WRMSR])
+	}
+}
+
+/// What the run loop does after an exit has been handled.
+#[derive(Debug)]
+pub enum ExitAction {
+	/// Resume the guest.
+	Continue,
+	/// Stop the vCPU, because the kernel exited with the given code.
+	Shutdown(i32),
+	/// Stop the vCPU for the debugger.
+	Debug(kvm_debug_exit_arch),
+	/// Stop the vCPU with an error.
+	Error(HypervisorError),
+}
+
+/// Handles the exits of one exit reason, see [`ExitHandlers::register`].
+///
+/// The handler reads the details of the exit from `kvm_run` of the vCPU, e.g., with
+/// [`UhyveCPU::kvm_run`].
+pub trait ExitHandler: Send + Sync {
+	fn handle(&self, vcpu: &mut UhyveCPU) -> ExitAction;
+}
+
+impl<F: Fn(&mut UhyveCPU) -> ExitAction + Send + Sync> ExitHandler for F {
+	fn handle(&self, vcpu: &mut UhyveCPU) -> ExitAction {
+		self(vcpu)
+	}
+}
+
+/// Emulates the I/O ports of a device, see [`ExitHandlers::register_ports`].
+pub trait PortIoHandler: Send + Sync {
+	/// Handles a read of `data.len()` bytes from `port`.
+	fn handle_in(&self, vcpu: &UhyveCPU, port: u16, data: &mut [u8]) -> ExitAction;
+
+	/// Handles a write of `data` to `port`.
+	fn handle_out(&self, vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction;
+}
+
+/// The handlers of the exits, which are shared by all vCPUs.
+///
+/// The exits are dispatched by their reason. The handler of `KVM_EXIT_IO` dispatches the port I/O
+/// further by the port.
+pub struct ExitHandlers {
+	reasons: [Option<Arc<dyn ExitHandler>>; EXIT_REASONS.len()],
+	ports: HashMap<u16, Arc<dyn PortIoHandler>>,
+}
+
+impl Default for ExitHandlers {
+	fn default() -> Self {
+		Self {
+			reasons: std::array::from_fn(|_| None),
+			ports: HashMap::new(),
+		}
+	}
+}
+
+impl ExitHandlers {
+	/// Returns the handlers of the exits, which uhyve handles itself, and of the hypercalls, the PCI
+	/// configuration space and the virtio network device.
+	pub fn builtin() -> Self {
+		let mut handlers = Self::default();
+		handlers.register(KVM_EXIT_HLT, Arc::new(UhyveCPU::handle_hlt));
+		handlers.register(KVM_EXIT_SHUTDOWN, Arc::new(UhyveCPU::handle_shutdown));
+		handlers.register(KVM_EXIT_IO, Arc::new(UhyveCPU::handle_io));
+		handlers.register(KVM_EXIT_DEBUG, Arc::new(UhyveCPU::handle_debug));
+		handlers.register(KVM_EXIT_FAIL_ENTRY, Arc::new(UhyveCPU::handle_fail_entry));
+		handlers.register(KVM_EXIT_MMIO, Arc::new(UhyveCPU::handle_mmio));
+		handlers.register(
+			KVM_EXIT_INTERNAL_ERROR,
+			Arc::new(UhyveCPU::handle_internal_error),
+		);
+		handlers.register(
+			KVM_EXIT_DIRTY_RING_FULL,
+			Arc::new(UhyveCPU::handle_dirty_ring_full),
+		);
+		handlers.register(KVM_EXIT_X86_RDMSR, Arc::new(UhyveCPU::handle_msr));
+		handlers.register(KVM_EXIT_X86_WRMSR, Arc::new(UhyveCPU::handle_msr));
+		handlers.register(KVM_EXIT_NOTIFY, Arc::new(UhyveCPU::handle_notify));
+
+		handlers.register_ports(HYPERCALL_PORTS, Arc::new(HypercallHandler));
+		handlers.register_ports(
+			[PCI_CONFIG_ADDRESS_PORT, PCI_CONFIG_DATA_PORT],
+			Arc::new(PciConfigHandler),
+		);
+		handlers.register_ports(
+			[
+				VIRTIO_PCI_HOST_FEATURES,
+				VIRTIO_PCI_GUEST_FEATURES,
+				VIRTIO_PCI_QUEUE_PFN,
+				VIRTIO_PCI_QUEUE_SEL,
+				VIRTIO_PCI_QUEUE_NOTIFY,
+				VIRTIO_PCI_STATUS,
+				VIRTIO_PCI_ISR,
+				VIRTIO_PCI_LINK_STATUS_MSIX_OFF,
+			]
+			.into_iter()
+			.chain(VIRTIO_PCI_CONFIG_OFF_MSIX_OFF..=VIRTIO_PCI_CONFIG_OFF_MSIX_OFF_MAX),
+			Arc::new(VirtioNetHandler),
+		);
+		handlers
+	}
+
+	/// Lets `handler` handle the exits of `reason`.
+	///
+	/// # Panics
+	///
+	/// Panics if the reason is already handled or is no known exit reason.
+	pub fn register(&mut self, reason: u32, handler: Arc<dyn ExitHandler>) {
+		let entry = self
+			.reasons
+			.get_mut(reason as usize)
+			.unwrap_or_else(|| panic!("exit reason {} is unknown", reason));
+		assert!(entry.is_none(), "exit reason {} is already handled", reason);
+		*entry = Some(handler);
+	}
+
+	/// Lets `handler` emulate `ports`.
+	///
+	/// # Panics
+	///
+	/// Panics if one of the ports is already handled by another device.
+	pub fn register_ports(
+		&mut self,
+		ports: impl IntoIterator<Item = u16>,
+		handler: Arc<dyn PortIoHandler>,
+	) {
+		for port in ports {
+			let previous = self.ports.insert(port, handler.clone());
+			assert!(previous.is_none(), "port 0x{:x} is already handled", port);
+		}
+	}
+
+	fn get(&self, reason: u32) -> Option<Arc<dyn ExitHandler>> {
+		self.reasons.get(reason as usize)?.clone()
+	}
+
+	fn get_port(&self, port: u16) -> Option<Arc<dyn PortIoHandler>> {
+		self.ports.get(&port).cloned()
+	}
+}
+
+const HYPERCALL_PORTS: [u16; 13] = [
+	UHYVE_UART_PORT,
+	UHYVE_PORT_CMDSIZE,
+	UHYVE_PORT_CMDVAL,
+	UHYVE_PORT_NETWRITE,
+	UHYVE_PORT_EXIT,
+	UHYVE_PORT_OPEN,
+	UHYVE_PORT_WRITE,
+	UHYVE_PORT_READ,
+	UHYVE_PORT_UNLINK,
+	UHYVE_PORT_LSEEK,
+	UHYVE_PORT_CLOSE,
+	UHYVE_PORT_IOMMU_MAP,
+	UHYVE_PORT_IOMMU_UNMAP,
+];
+
+/// Reads the guest physical address of the arguments of a hypercall.
+pub(crate) fn hypercall_args(vcpu: &UhyveCPU, data: &[u8]) -> usize {
+	let mut value = [0u8; 4];
+	value[..data.len().min(4)].copy_from_slice(&data[..data.len().min(4)]);
+	vcpu.host_address(u32::from_le_bytes(value) as usize)
+}
+
+/// The hypercalls of uhyve, which are issued by writing the address of their arguments.
+struct HypercallHandler;
+
+impl PortIoHandler for HypercallHandler {
+	fn handle_in(&self, _vcpu: &UhyveCPU, _port: u16, _data: &mut [u8]) -> ExitAction {
+		info!("Unhanded IO Exit");
+		ExitAction::Continue
+	}
+
+	fn handle_out(&self, vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction {
+		match port {
+			UHYVE_UART_PORT => {
+				if let Err(err) = vcpu.uart(data) {
+					return ExitAction::Error(err.into());
+				}
+			}
+			UHYVE_PORT_NETWRITE => {
+				// Kernels without multiple interfaces write arbitrary values, which select the
+				// first interface, if they are out of range.
+				let index = usize::from(data[0]);
+				if let Some(tx_channel) = vcpu.tx.get(index).or_else(|| vcpu.tx.first()) {
+					tx_channel.send(1).unwrap();
+				}
+			}
+			UHYVE_PORT_CMDSIZE => vcpu.cmdsize(hypercall_args(vcpu, data)),
+			UHYVE_PORT_CMDVAL => vcpu.cmdval(hypercall_args(vcpu, data)),
+			UHYVE_PORT_EXIT => return ExitAction::Shutdown(vcpu.exit(hypercall_args(vcpu, data))),
+			UHYVE_PORT_OPEN => vcpu.open(hypercall_args(vcpu, data)),
+			UHYVE_PORT_WRITE => {
+				if let Err(err) = vcpu.write(hypercall_args(vcpu, data)) {
+					return ExitAction::Error(err.into());
+				}
+			}
+			UHYVE_PORT_READ => vcpu.read(hypercall_args(vcpu, data)),
+			UHYVE_PORT_UNLINK => vcpu.unlink(hypercall_args(vcpu, data)),
+			UHYVE_PORT_LSEEK => vcpu.lseek(hypercall_args(vcpu, data)),
+			UHYVE_PORT_CLOSE => vcpu.close(hypercall_args(vcpu, data)),
+			UHYVE_PORT_IOMMU_MAP | UHYVE_PORT_IOMMU_UNMAP => {
+				vcpu.iommu_hypercall(port, hypercall_args(vcpu, data))
+			}
+			_ => unreachable!("port 0x{:x} is not a hypercall", port),
+		}
+		ExitAction::Continue
+	}
+}
+
+/// The configuration space of the PCI bus, which is accessed with configuration mechanism #1.
+struct PciConfigHandler;
+
+impl PortIoHandler for PciConfigHandler {
+	fn handle_in(&self, vcpu: &UhyveCPU, port: u16, data: &mut [u8]) -> ExitAction {
+		if port == PCI_CONFIG_DATA_PORT {
+			let value = match vcpu.pci_addr {
+				Some(pci_addr) => vcpu
+					.pci_bus
+					.lock()
+					.unwrap()
+					.config_read(pci_addr, data.len() as u8),
+				None => 0xffffffff,
+			};
+			data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+		}
+		ExitAction::Continue
+	}
+
+	fn handle_out(&self, vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction {
+		let mut value = [0u8; 4];
+		value[..data.len().min(4)].copy_from_slice(&data[..data.len().min(4)]);
+		let value = u32::from_le_bytes(value);
+
+		if port == PCI_CONFIG_ADDRESS_PORT {
+			vcpu.pci_addr = Some(value);
+		} else if let Some(pci_addr) = vcpu.pci_addr {
+			vcpu.pci_bus
+				.lock()
+				.unwrap()
+				.config_write(pci_addr, data.len() as u8, value);
+		}
+		ExitAction::Continue
+	}
+}
+
+/// The legacy I/O port interface of the virtio network device.
+struct VirtioNetHandler;
+
+impl PortIoHandler for VirtioNetHandler {
+	fn handle_in(&self, vcpu: &UhyveCPU, port: u16, data: &mut [u8]) -> ExitAction {
+		let mut virtio_device = vcpu.virtio_device.lock().unwrap();
+		match port {
+			VIRTIO_PCI_STATUS => virtio_device.read_status(data),
+			VIRTIO_PCI_HOST_FEATURES => virtio_device.read_host_features(data),
+			VIRTIO_PCI_GUEST_FEATURES => virtio_device.read_requested_features(data),
+			VIRTIO_PCI_CONFIG_OFF_MSIX_OFF..=VIRTIO_PCI_CONFIG_OFF_MSIX_OFF_MAX => {
+				virtio_device.read_mac_byte(data, port - VIRTIO_PCI_CONFIG_OFF_MSIX_OFF)
+			}
+			VIRTIO_PCI_ISR => virtio_device.reset_interrupt(),
+			VIRTIO_PCI_LINK_STATUS_MSIX_OFF => virtio_device.read_link_status(data),
+			_ => info!("Unhanded IO Exit"),
+		}
+		ExitAction::Continue
+	}
+
+	fn handle_out(&self, vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction {
+		let mut virtio_device = vcpu.virtio_device.lock().unwrap();
+		match port {
+			VIRTIO_PCI_STATUS => virtio_device.write_status(data),
+			VIRTIO_PCI_GUEST_FEATURES => virtio_device.write_requested_features(data),
+			VIRTIO_PCI_QUEUE_NOTIFY => virtio_device.handle_notify_output(data),
+			VIRTIO_PCI_QUEUE_SEL => virtio_device.write_selected_queue(data),
+			VIRTIO_PCI_QUEUE_PFN => virtio_device.write_pfn(data),
+			_ => warn!(
+				"CPU {} wrote to the read-only port 0x{:x} of virtio-net",
+				vcpu.id, port
+			),
+		}
+		ExitAction::Continue
+	}
+}
+
+/// The state of the VM, which all its vCPUs share, see [`UhyveCPU::new`].
+#[derive(Clone)]
+pub struct VcpuConfig {
+	pub kernel_path: PathBuf,
+	/// Host address of the guest memory
+	pub vm_start: usize,
+	/// Wakes the writer threads of the network interfaces
+	pub tx: Vec<std::sync::mpsc::SyncSender<usize>>,
+	pub virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
+	pub pci_bus: Arc<Mutex<PciBus>>,
+	pub console_log: Option<Arc<ConsoleLog>>,
+	pub debug_port_log: Option<Arc<DebugPortLog>>,
+	pub iommu: Option<Arc<Mutex<SoftIommu>>>,
+	pub dirty_tracker: Option<Arc<DirtyTracker>>,
+	pub exit_handlers: Arc<ExitHandlers>,
+	pub host_allowed_paths: Option<Arc<[PathBuf]>>,
+	pub kernel_args: Arc<[String]>,
+	pub cpu_features: Arc<[CpuFeatureChange]>,
+	pub cpuid_overrides: Arc<[CpuidOverride]>,
+	pub msr_whitelist: Arc<[u32]>,
+	pub shared_state: Option<Arc<SharedState>>,
+	pub net_queue_depth: usize,
+	pub fault_tolerant: bool,
+	pub exit_log: Option<Arc<ExitLog>>,
+}
 
 pub struct UhyveCPU {
 	id: u32,
 	vcpu: VcpuFd,
+	kvm_run: KvmRun,
 	vm_start: usize,
 	kernel_path: PathBuf,
-	tx: Option<std::sync::mpsc::SyncSender<usize>>,
+	/// Wakes the writer threads of the network interfaces
+	tx: Vec<std::sync::mpsc::SyncSender<usize>>,
 	virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
+	pci_bus: Arc<Mutex<PciBus>>,
 	pci_addr: Option<u32>,
+	console_log: Option<Arc<ConsoleLog>>,
+	debug_port_log: Option<Arc<DebugPortLog>>,
+	iommu: Option<Arc<Mutex<SoftIommu>>>,
+	dirty_tracker: Option<Arc<DirtyTracker>>,
+	exit_handlers: Arc<ExitHandlers>,
+	host_allowed_paths: Option<Arc<[PathBuf]>>,
+	kernel_args: Arc<[String]>,
+	/// Changes of the CPUID features, see `--cpu-features`
+	cpu_features: Arc<[CpuFeatureChange]>,
+	/// Changes of the bits of the CPUID leaves, see `--cpuid`
+	cpuid_overrides: Arc<[CpuidOverride]>,
+	/// The MSRs, which are forwarded to the host, see `--allow-msr`
+	msr_whitelist: Arc<[u32]>,
+	exit_request: Option<Arc<ExitRequest>>,
+	shared_state: Option<Arc<SharedState>>,
+	net_queue_depth: usize,
+	/// Ignore accesses to unmapped guest physical addresses, see `--fault-tolerant`
+	fault_tolerant: bool,
+	reported_unmapped_access: AtomicBool,
+	/// Logs the exits, see `--verbose-exits`
+	exit_log: Option<Arc<ExitLog>>,
+	#[cfg(feature = "tui")]
+	vcpu_events: Option<VcpuEventSender>,
 }
 
 impl UhyveCPU {
@@ -37,25 +571,72 @@ impl UhyveCPU {
 		slice::from_raw_parts_mut(host as *mut u8, len)
 	}
 
-	pub fn new(
-		id: u32,
-		kernel_path: PathBuf,
-		vcpu: VcpuFd,
-		vm_start: usize,
-		tx: Option<std::sync::mpsc::SyncSender<usize>>,
-		virtio_device: Arc<Mutex<VirtioNetPciDevice>>,
-	) -> UhyveCPU {
+	pub fn new(id: u32, vcpu: VcpuFd, config: VcpuConfig) -> UhyveCPU {
+		let VcpuConfig {
+			kernel_path,
+			vm_start,
+			tx,
+			virtio_device,
+			pci_bus,
+			console_log,
+			debug_port_log,
+			iommu,
+			dirty_tracker,
+			exit_handlers,
+			host_allowed_paths,
+			kernel_args,
+			cpu_features,
+			cpuid_overrides,
+			msr_whitelist,
+			shared_state,
+			net_queue_depth,
+			fault_tolerant,
+			exit_log,
+		} = config;
+		let kvm_run = KvmRun::new(&vcpu).expect("Unable to map kvm_run of the vCPU");
+		let exit_request = ExitRequest::new(&vcpu).map(Arc::new);
 		UhyveCPU {
 			id,
 			vcpu,
+			kvm_run,
 			vm_start,
 			kernel_path,
 			tx,
 			virtio_device,
+			pci_bus,
 			pci_addr: None,
+			console_log,
+			debug_port_log,
+			iommu,
+			dirty_tracker,
+			exit_handlers,
+			host_allowed_paths,
+			kernel_args,
+			cpu_features,
+			cpuid_overrides,
+			msr_whitelist,
+			exit_request,
+			shared_state,
+			net_queue_depth,
+			fault_tolerant,
+			reported_unmapped_access: AtomicBool::new(false),
+			exit_log,
+			#[cfg(feature = "tui")]
+			vcpu_events: None,
 		}
 	}
 
+	/// Returns the handle, with which other threads pause this vCPU, if KVM supports it.
+	pub fn exit_request(&self) -> Option<Arc<ExitRequest>> {
+		self.exit_request.clone()
+	}
+
+	/// Reports the exits of this vCPU to the dashboard.
+	#[cfg(feature = "tui")]
+	pub fn report_exits(&mut self, vcpu_events: VcpuEventSender) {
+		self.vcpu_events = Some(vcpu_events);
+	}
+
 	fn setup_cpuid(&self) -> Result<(), kvm_ioctls::Error> {
 		//debug!("Setup cpuid");
 
@@ -129,6 +710,34 @@ impl UhyveCPU {
 		// disable performance monitor
 		kvm_cpuid_entries[i].eax = 0x00;
 
+		// the changes of the user take precedence over the defaults above
+		for change in self.cpu_features.iter() {
+			let feature = change.feature;
+			if let Some(entry) = kvm_cpuid_entries
+				.iter_mut()
+				.find(|entry| entry.function == feature.leaf && entry.index == feature.subleaf)
+			{
+				let mut registers = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+				change.apply(&mut registers);
+				entry.eax = registers[0];
+				entry.ebx = registers[1];
+				entry.ecx = registers[2];
+				entry.edx = registers[3];
+			}
+		}
+		for cpuid_override in self.cpuid_overrides.iter() {
+			if let Some(entry) = kvm_cpuid_entries.iter_mut().find(|entry| {
+				entry.function == cpuid_override.leaf && entry.index == cpuid_override.subleaf
+			}) {
+				let mut registers = [entry.eax, entry.ebx, entry.ecx, entry.edx];
+				cpuid_override.apply(&mut registers);
+				entry.eax = registers[0];
+				entry.ebx = registers[1];
+				entry.ecx = registers[2];
+				entry.edx = registers[3];
+			}
+		}
+
 		self.vcpu.set_cpuid2(&kvm_cpuid)?;
 
 		Ok(())
@@ -232,6 +841,361 @@ impl UhyveCPU {
 	pub fn get_vcpu_mut(&mut self) -> &mut VcpuFd {
 		&mut self.vcpu
 	}
+
+	/// Issues `KVM_TRANSLATE` for `gva`, which `kvm-ioctls` 0.10 does not expose.
+	fn kvm_translate(&self, gva: u64) -> HypervisorResult<kvm_translation> {
+		let mut translation = kvm_translation {
+			linear_address: gva,
+			..Default::default()
+		};
+		if unsafe { ioctl_with_mut_ref(&self.vcpu, KVM_TRANSLATE, &mut translation) } < 0 {
+			return Err(kvm_ioctls::Error::last());
+		}
+		Ok(translation)
+	}
+
+	/// Translates a guest virtual address into a guest physical address with `KVM_TRANSLATE`.
+	///
+	/// Contrary to [`VirtualCPU::virt_to_phys`], this walks the page tables, which are currently
+	/// used by the vCPU. Unmapped addresses result in `EFAULT`.
+	pub fn translate_gva(&self, gva: u64) -> HypervisorResult<u64> {
+		let translation = self.kvm_translate(gva)?;
+		if translation.valid == 0 {
+			return Err(kvm_ioctls::Error::new(libc::EFAULT));
+		}
+		Ok(translation.physical_address)
+	}
+
+	/// Reads the registers of the vCPU for a snapshot.
+	pub fn export_state(&self) -> HypervisorResult<snapshot::VcpuState> {
+		snapshot::VcpuState::export(&self.vcpu)
+	}
+
+	/// Reads all registers of the vCPU for a dump, see `--registers-on-signal`.
+	pub fn dump_registers(&self) -> HypervisorResult<CpuRegisters> {
+		CpuRegisters::read(&self.vcpu)
+	}
+
+	/// Loads the registers of a snapshot into the vCPU, after [`VirtualCPU::init`] has set its
+	/// CPUID.
+	pub fn import_state(&self, state: &snapshot::VcpuState) -> HypervisorResult<()> {
+		state.import(&self.vcpu)
+	}
+
+	/// Tells the guest's kvmclock that the vCPU has been paused, so that the guest does not
+	/// mistake the pause for a soft lockup.
+	///
+	/// Fails with `EINVAL`, if the guest has not enabled kvmclock.
+	pub fn kvmclock_ctrl(&self) -> HypervisorResult<()> {
+		if unsafe { libc::ioctl(self.vcpu.as_raw_fd(), KVM_KVMCLOCK_CTRL) } < 0 {
+			return Err(HypervisorError::last());
+		}
+		Ok(())
+	}
+
+	/// Releases the vCPU, so that KVM's vCPU can be reused after reloading the kernel.
+	///
+	/// KVM does not free a vCPU before the VM is destroyed, so that its id cannot be created twice.
+	pub fn into_vcpu_fd(self) -> VcpuFd {
+		debug!("Release vCPU {}", self.id);
+		self.vcpu
+	}
+
+	/// Accesses the details of the last exit in `kvm_run`, which `kvm-ioctls` does not expose, or
+	/// writes its result, which KVM reads on the next `KVM_RUN`.
+	pub fn kvm_run<T>(&self, f: impl FnOnce(&mut KvmRunAccess<'_>) -> T) -> T {
+		// the vCPU does not run, while its last exit is handled
+		f(&mut unsafe { self.kvm_run.access() })
+	}
+
+	/// Reads the reason of a `KVM_EXIT_FAIL_ENTRY`.
+	fn hardware_entry_failure_reason(&self) -> Option<u64> {
+		self.kvm_run(|run| {
+			run.as_fail_entry()
+				.map(|fail_entry| fail_entry.hardware_entry_failure_reason)
+		})
+	}
+
+	/// Logs the instruction pointer, its translation and CR3 after the guest has faulted.
+	fn report_fault_context(&self) {
+		let (regs, sregs) = match (self.vcpu.get_regs(), self.vcpu.get_sregs()) {
+			(Ok(regs), Ok(sregs)) => (regs, sregs),
+			_ => return,
+		};
+		error!("rip = {:#x}, cr3 = {:#x}", regs.rip, sregs.cr3);
+		match self.kvm_translate(regs.rip) {
+			Ok(translation) if translation.valid != 0 => error!(
+				"rip is mapped to the guest physical address {:#x}{}",
+				translation.physical_address,
+				if translation.writeable != 0 {
+					" (writable)"
+				} else {
+					""
+				}
+			),
+			_ => error!("rip is not mapped by the page tables at cr3"),
+		}
+	}
+
+	/// Handles an access to a guest physical address, which is neither memory nor a device.
+	///
+	/// KVM marks these addresses as MMIO in the EPT (or NPT) and hands the access to uhyve. With
+	/// `--fault-tolerant`, reads return all ones like a missing PCI device and writes are ignored.
+	fn handle_unmapped_access(&self, address: u64, data: Option<&mut [u8]>) -> bool {
+		let access = if data.is_some() {
+			"read from"
+		} else {
+			"write to"
+		};
+		if self.fault_tolerant {
+			if !self.reported_unmapped_access.swap(true, Ordering::Relaxed) {
+				warn!(
+					"CPU {} ignores the {} the unmapped address {:#x} and all following ones",
+					self.id, access, address
+				);
+			}
+			if let Some(data) = data {
+				data.fill(0xff);
+			}
+			return true;
+		}
+
+		error!(
+			"CPU {} faulted on a {} the unmapped guest physical address {:#x}",
+			self.id, access, address
+		);
+		self.report_fault_context();
+		error!(
+			"Likely causes: the kernel expects a device, which uhyve does not provide, or its \
+			page tables map a wrong physical address. Run with --fault-tolerant to ignore the access."
+		);
+		false
+	}
+
+	fn handle_hlt(&mut self) -> ExitAction {
+		// Ignore `KVM_EXIT_HLT`
+		debug!("KVM_EXIT_HLT");
+		ExitAction::Continue
+	}
+
+	fn handle_shutdown(&mut self) -> ExitAction {
+		ExitAction::Shutdown(0)
+	}
+
+	/// Lets the handler of the port of a `KVM_EXIT_IO` emulate the access.
+	///
+	/// Reads from ports without a handler return all ones and writes to them are ignored.
+	fn handle_io(&mut self) -> ExitAction {
+		let io = match self.kvm_run(|run| run.as_io().copied()) {
+			Some(io) => io,
+			None => return ExitAction::Error(kvm_ioctls::Error::new(libc::EIO)),
+		};
+		let handler = self.exit_handlers.get_port(io.port);
+		if u32::from(io.direction) == KVM_EXIT_IO_IN {
+			return self.kvm_run(|run| match (run.io_data_mut(), handler) {
+				(Some(data), Some(handler)) => handler.handle_in(self, io.port, data),
+				(Some(data), None) => {
+					debug!(
+						"CPU {} read from the unhandled port 0x{:x}",
+						self.id, io.port
+					);
+					data.fill(0xff);
+					ExitAction::Continue
+				}
+				(None, _) => ExitAction::Error(kvm_ioctls::Error::new(libc::EFAULT)),
+			});
+		}
+
+		// The data is copied, because the handler borrows the vCPU mutably.
+		let mut data = [0u8; 8];
+		let len = self.kvm_run(|run| {
+			run.io_data_mut().map(|src| {
+				let len = src.len().min(data.len());
+				data[..len].copy_from_slice(&src[..len]);
+				len
+			})
+		});
+		match (len, handler) {
+			(Some(len), Some(handler)) => handler.handle_out(self, io.port, &data[..len]),
+			(Some(len), None) => {
+				warn!(
+					"CPU {} wrote {:02x?} to the unhandled port 0x{:x}",
+					self.id,
+					&data[..len],
+					io.port
+				);
+				ExitAction::Continue
+			}
+			(None, _) => ExitAction::Error(kvm_ioctls::Error::new(libc::EFAULT)),
+		}
+	}
+
+	fn handle_debug(&mut self) -> ExitAction {
+		info!("Caught Debug Interrupt!");
+		let debug = self.kvm_run(|run| run.as_debug().copied());
+		ExitAction::Debug(debug.unwrap_or_default())
+	}
+
+	fn handle_fail_entry(&mut self) -> ExitAction {
+		let reason = self.hardware_entry_failure_reason();
+		error!(
+			"CPU {} failed to enter the guest: {} (hardware reason {:?})",
+			self.id,
+			reason.map_or("unknown", describe_entry_failure),
+			reason
+		);
+		self.print_registers();
+		ExitAction::Error(kvm_ioctls::Error::new(libc::ENOEXEC))
+	}
+
+	fn handle_mmio(&mut self) -> ExitAction {
+		let handled = self.kvm_run(|run| match run.as_mmio_mut() {
+			Some(mmio) => {
+				let len = (mmio.len as usize).min(mmio.data.len());
+				let data = (mmio.is_write == 0).then(|| &mut mmio.data[..len]);
+				self.handle_unmapped_access(mmio.phys_addr, data)
+			}
+			None => false,
+		});
+		if !handled {
+			return ExitAction::Error(kvm_ioctls::Error::new(libc::EFAULT));
+		}
+		ExitAction::Continue
+	}
+
+	fn handle_internal_error(&mut self) -> ExitAction {
+		let suberror = self.kvm_run(|run| run.as_internal().map(|internal| internal.suberror));
+		error!(
+			"CPU {} stopped with an internal error of KVM: {} (suberror {:?})",
+			self.id,
+			suberror.map_or("unknown error", describe_internal_error),
+			suberror
+		);
+		self.report_fault_context();
+		self.print_registers();
+		ExitAction::Error(kvm_ioctls::Error::new(libc::EFAULT))
+	}
+
+	fn handle_dirty_ring_full(&mut self) -> ExitAction {
+		if let Some(dirty_tracker) = &self.dirty_tracker {
+			if let Err(err) = dirty_tracker.harvest() {
+				return ExitAction::Error(kvm_ioctls::Error::new(
+					err.raw_os_error().unwrap_or(libc::EIO),
+				));
+			}
+		}
+		ExitAction::Continue
+	}
+
+	fn handle_msr(&mut self) -> ExitAction {
+		let msr_whitelist = &self.msr_whitelist;
+		let handled = self.kvm_run(|run| {
+			let is_write = run.exit_reason() == KVM_EXIT_X86_WRMSR;
+			run.as_msr_mut()
+				.map(|msr| msr_filter::handle_exit(msr, is_write, msr_whitelist))
+		});
+		match handled {
+			Some(()) => ExitAction::Continue,
+			None => ExitAction::Error(kvm_ioctls::Error::new(libc::EIO)),
+		}
+	}
+
+	fn handle_notify(&mut self) -> ExitAction {
+		let flags = self.kvm_run(|run| run.as_notify().map(|notify| notify.flags));
+		let rip = self.vcpu.get_regs().map_or(0, |regs| regs.rip);
+		warn!(
+			"CPU {} ran without any exit for the notify window at RIP 0x{:x}",
+			self.id, rip
+		);
+		if flags.map_or(false, |flags| flags & KVM_NOTIFY_CONTEXT_INVALID != 0) {
+			error!(
+				"CPU {} has an invalid VM context and has to be reset",
+				self.id
+			);
+			self.print_registers();
+			return ExitAction::Error(kvm_ioctls::Error::new(libc::EFAULT));
+		}
+		ExitAction::Continue
+	}
+
+	/// Stops the vCPU after an exit, for whose reason no handler is registered.
+	fn handle_unknown_exit(&mut self, reason: u32) -> ExitAction {
+		error!(
+			"CPU {} stopped with the exit reason {} ({}), which uhyve does not handle",
+			self.id,
+			exit_reason_name(reason).unwrap_or("unknown"),
+			reason
+		);
+		self.print_registers();
+		ExitAction::Error(kvm_ioctls::Error::new(libc::ENOSYS))
+	}
+
+	/// Handles the map and unmap hypercalls of the software IOMMU.
+	fn iommu_hypercall(&self, port: u16, args_ptr: usize) {
+		let sysiommu = unsafe { &mut *(args_ptr as *mut SysIommuMap) };
+		let (devid, iova) = (sysiommu.devid, sysiommu.iova);
+
+		let ret = match &self.iommu {
+			Some(iommu) => {
+				let mut iommu = iommu.lock().unwrap();
+				let res = if port == UHYVE_PORT_IOMMU_MAP {
+					iommu.map(devid, iova, sysiommu.gpa, sysiommu.size, sysiommu.perm)
+				} else {
+					iommu.unmap(devid, iova)
+				};
+				match res {
+					Ok(()) => 0,
+					Err(err) => {
+						debug!("IOMMU hypercall failed: {}", err);
+						-libc::EINVAL
+					}
+				}
+			}
+			None => -libc::ENOSYS,
+		};
+		sysiommu.ret = ret;
+	}
+
+	/// Touches the memory used by the first vCPU exits to avoid latency spikes at startup.
+	///
+	/// This faults in the stack of the current thread, pulls `kvm_run` and the shared network
+	/// queues into the cache and issues a KVM_RUN, which returns without entering the guest.
+	pub fn warm_up_cache(&self) {
+		let mut stack = [0u8; WARM_UP_STACK_SIZE];
+		for page in stack.chunks_mut(PAGE_SIZE) {
+			unsafe { ptr::write_volatile(page.as_mut_ptr(), 0) };
+		}
+
+		self.kvm_run.touch(CACHE_LINE_SIZE);
+
+		if !self.tx.is_empty() {
+			let queues = self.host_address(SHAREDQUEUE_START) as *const u8;
+			let len = self.tx.len() * 2 * SharedQueue::size(self.net_queue_depth);
+			for offset in (0..len).step_by(CACHE_LINE_SIZE) {
+				unsafe { ptr::read_volatile(queues.add(offset)) };
+			}
+		}
+
+		// without KVM_CAP_IMMEDIATE_EXIT, KVM_RUN would enter the guest
+		if let Some(exit_request) = &self.exit_request {
+			let start = Instant::now();
+			match exit_request.with_immediate_exit(|| self.kvm_run.run(&self.vcpu)) {
+				Some(Err(err)) if err.errno() == libc::EINTR => debug!(
+					"The first KVM_RUN of vCPU {} returned after {:?}",
+					self.id,
+					start.elapsed()
+				),
+				Some(Err(err)) => warn!("The first KVM_RUN of vCPU {} failed: {}", self.id, err),
+				Some(Ok(reason)) => warn!(
+					"The first KVM_RUN of vCPU {} entered the guest, which exited with {}",
+					self.id,
+					exit_reason_name(reason).unwrap_or("an unknown reason")
+				),
+				// a pending request is handled by the run loop
+				None => {}
+			}
+		}
+	}
 }
 
 impl VirtualCPU for UhyveCPU {
@@ -254,6 +1218,22 @@ impl VirtualCPU for UhyveCPU {
 		self.kernel_path.as_path()
 	}
 
+	fn console_log(&self) -> Option<&ConsoleLog> {
+		self.console_log.as_deref()
+	}
+
+	fn debug_port_log(&self) -> Option<&DebugPortLog> {
+		self.debug_port_log.as_deref()
+	}
+
+	fn host_allowed_paths(&self) -> Option<&[PathBuf]> {
+		self.host_allowed_paths.as_deref()
+	}
+
+	fn kernel_args(&self) -> &[String] {
+		&self.kernel_args
+	}
+
 	fn host_address(&self, addr: usize) -> usize {
 		addr + self.vm_start
 	}
@@ -281,170 +1261,48 @@ impl VirtualCPU for UhyveCPU {
 	}
 
 	fn r#continue(&mut self) -> HypervisorResult<VcpuStopReason> {
+		if let Some(exit_request) = &self.exit_request {
+			exit_request.enter();
+		}
+
 		loop {
-			match self.vcpu.run() {
-				Ok(vcpu_stop_reason) => match vcpu_stop_reason {
-					VcpuExit::Hlt => {
-						// Ignore `VcpuExit::Hlt`
-						debug!("{:?}", VcpuExit::Hlt);
-					}
-					VcpuExit::Shutdown => {
-						return Ok(VcpuStopReason::Exit(0));
-					}
-					VcpuExit::IoIn(port, addr) => match port {
-						PCI_CONFIG_DATA_PORT => {
-							if let Some(pci_addr) = self.pci_addr {
-								if pci_addr & 0x1ff800 == 0 {
-									let virtio_device = self.virtio_device.lock().unwrap();
-									virtio_device.handle_read(pci_addr & 0x3ff, addr);
-								} else {
-									unsafe { *(addr.as_ptr() as *mut u32) = 0xffffffff };
-								}
-							} else {
-								unsafe { *(addr.as_ptr() as *mut u32) = 0xffffffff };
-							}
-						}
-						PCI_CONFIG_ADDRESS_PORT => {}
-						VIRTIO_PCI_STATUS => {
-							let virtio_device = self.virtio_device.lock().unwrap();
-							virtio_device.read_status(addr);
-						}
-						VIRTIO_PCI_HOST_FEATURES => {
-							let virtio_device = self.virtio_device.lock().unwrap();
-							virtio_device.read_host_features(addr);
-						}
-						VIRTIO_PCI_GUEST_FEATURES => {
-							let mut virtio_device = self.virtio_device.lock().unwrap();
-							virtio_device.read_requested_features(addr);
-						}
-						VIRTIO_PCI_CONFIG_OFF_MSIX_OFF..=VIRTIO_PCI_CONFIG_OFF_MSIX_OFF_MAX => {
-							let virtio_device = self.virtio_device.lock().unwrap();
-							virtio_device
-								.read_mac_byte(addr, port - VIRTIO_PCI_CONFIG_OFF_MSIX_OFF);
-						}
-						VIRTIO_PCI_ISR => {
-							let mut virtio_device = self.virtio_device.lock().unwrap();
-							virtio_device.reset_interrupt()
-						}
-						VIRTIO_PCI_LINK_STATUS_MSIX_OFF => {
-							let virtio_device = self.virtio_device.lock().unwrap();
-							virtio_device.read_link_status(addr);
-						}
-						_ => {
-							info!("Unhanded IO Exit");
-						}
-					},
-					VcpuExit::IoOut(port, addr) => {
-						match port {
-							UHYVE_UART_PORT => {
-								self.uart(addr)?;
-							}
-							UHYVE_PORT_CMDSIZE => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.cmdsize(self.host_address(data_addr));
-							}
-							UHYVE_PORT_CMDVAL => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.cmdval(self.host_address(data_addr));
-							}
-							UHYVE_PORT_NETWRITE => {
-								match &self.tx {
-									Some(tx_channel) => tx_channel.send(1).unwrap(),
-
-									None => {}
-								};
-							}
-							UHYVE_PORT_EXIT => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								return Ok(VcpuStopReason::Exit(
-									self.exit(self.host_address(data_addr)),
-								));
-							}
-							UHYVE_PORT_OPEN => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.open(self.host_address(data_addr));
-							}
-							UHYVE_PORT_WRITE => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.write(self.host_address(data_addr))?;
-							}
-							UHYVE_PORT_READ => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.read(self.host_address(data_addr));
-							}
-							UHYVE_PORT_UNLINK => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.unlink(self.host_address(data_addr));
-							}
-							UHYVE_PORT_LSEEK => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.lseek(self.host_address(data_addr));
-							}
-							UHYVE_PORT_CLOSE => {
-								let data_addr: usize =
-									unsafe { (*(addr.as_ptr() as *const u32)) as usize };
-								self.close(self.host_address(data_addr));
-							}
-							//TODO:
-							PCI_CONFIG_DATA_PORT => {
-								if let Some(pci_addr) = self.pci_addr {
-									if pci_addr & 0x1ff800 == 0 {
-										let mut virtio_device = self.virtio_device.lock().unwrap();
-										virtio_device.handle_write(pci_addr & 0x3ff, addr);
-									}
-								}
-							}
-							PCI_CONFIG_ADDRESS_PORT => {
-								self.pci_addr = Some(unsafe { *(addr.as_ptr() as *const u32) });
-							}
-							VIRTIO_PCI_STATUS => {
-								let mut virtio_device = self.virtio_device.lock().unwrap();
-								virtio_device.write_status(addr);
-							}
-							VIRTIO_PCI_GUEST_FEATURES => {
-								let mut virtio_device = self.virtio_device.lock().unwrap();
-								virtio_device.write_requested_features(addr);
-							}
-							VIRTIO_PCI_QUEUE_NOTIFY => {
-								let mut virtio_device = self.virtio_device.lock().unwrap();
-								virtio_device.handle_notify_output(addr, self);
-							}
-							VIRTIO_PCI_QUEUE_SEL => {
-								let mut virtio_device = self.virtio_device.lock().unwrap();
-								virtio_device.write_selected_queue(addr);
-							}
-							VIRTIO_PCI_QUEUE_PFN => {
-								let mut virtio_device = self.virtio_device.lock().unwrap();
-								virtio_device.write_pfn(addr, self);
-							}
-							_ => {
-								panic!("Unhandled IO exit: 0x{:x}", port);
-							}
+			if let Some(shared_state) = &self.shared_state {
+				shared_state.vcpu(self.id).set_state(VcpuState::Running);
+			}
+			let exit = self.kvm_run.run(&self.vcpu);
+			if let Some(shared_state) = &self.shared_state {
+				let rip = self.vcpu.get_regs().map_or(0, |regs| regs.rip);
+				shared_state.vcpu(self.id).record_exit(rip);
+			}
+			#[cfg(feature = "tui")]
+			if let Some(vcpu_events) = &mut self.vcpu_events {
+				vcpu_events.record_exit();
+			}
+			if let (Some(exit_log), Ok(_)) = (&self.exit_log, &exit) {
+				self.kvm_run(|run| exit_log.log(self.id, &self.vcpu, run));
+			}
+
+			let reason = match exit {
+				Ok(reason) => reason,
+				Err(err) => match err.errno() {
+					libc::EINTR => {
+						if let Some(exit_request) = &self.exit_request {
+							exit_request.acknowledge();
 						}
+						return Ok(VcpuStopReason::Kick);
 					}
-					VcpuExit::Debug(debug) => {
-						info!("Caught Debug Interrupt!");
-						return Ok(VcpuStopReason::Debug(debug));
-					}
-					VcpuExit::InternalError => {
-						panic!("{:?}", VcpuExit::InternalError)
-					}
-					vcpu_exit => {
-						unimplemented!("{:?}", vcpu_exit)
-					}
-				},
-				Err(err) => match err.errno() {
-					libc::EINTR => return Ok(VcpuStopReason::Kick),
 					_ => return Err(err),
 				},
+			};
+			let action = match self.exit_handlers.get(reason) {
+				Some(handler) => handler.handle(self),
+				None => self.handle_unknown_exit(reason),
+			};
+			match action {
+				ExitAction::Continue => {}
+				ExitAction::Shutdown(code) => return Ok(VcpuStopReason::Exit(code)),
+				ExitAction::Debug(debug) => return Ok(VcpuStopReason::Debug(debug)),
+				ExitAction::Error(err) => return Err(err),
 			}
 		}
 	}
@@ -494,9 +1352,147 @@ impl VirtualCPU for UhyveCPU {
 	}
 }
 
-impl Drop for UhyveCPU {
-	fn drop(&mut self) {
-		debug!("Drop vCPU {}", self.id);
-		//self.print_registers();
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn zeroed_run(exit_reason: u32) -> kvm_run {
+		let mut run: kvm_run = unsafe { std::mem::zeroed() };
+		run.exit_reason = exit_reason;
+		run
+	}
+
+	#[test]
+	fn test_kvm_run_layout() {
+		let mut run = zeroed_run(KVM_EXIT_IO);
+		unsafe {
+			ptr::addr_of_mut!(run.__bindgen_anon_1)
+				.cast::<KvmRunIo>()
+				.write(KvmRunIo {
+					direction: 1,
+					size: 4,
+					port: 0x3f8,
+					count: 2,
+					data_offset: 0x1000,
+				});
+		}
+		let io = unsafe { run.__bindgen_anon_1.io };
+		assert_eq!(
+			(io.direction, io.size, io.port, io.count, io.data_offset),
+			(1, 4, 0x3f8, 2, 0x1000)
+		);
+
+		let mut run = zeroed_run(KVM_EXIT_MMIO);
+		unsafe {
+			ptr::addr_of_mut!(run.__bindgen_anon_1)
+				.cast::<KvmRunMmio>()
+				.write(KvmRunMmio {
+					phys_addr: 0xfee0_0000,
+					data: [1, 2, 3, 4, 0, 0, 0, 0],
+					len: 4,
+					is_write: 1,
+				});
+		}
+		let mmio = unsafe { run.__bindgen_anon_1.mmio };
+		assert_eq!(
+			(mmio.phys_addr, mmio.data, mmio.len, mmio.is_write),
+			(0xfee0_0000, [1, 2, 3, 4, 0, 0, 0, 0], 4, 1)
+		);
+
+		let mut run = zeroed_run(KVM_EXIT_INTERNAL_ERROR);
+		unsafe {
+			ptr::addr_of_mut!(run.__bindgen_anon_1)
+				.cast::<KvmRunInternal>()
+				.write(KvmRunInternal {
+					suberror: KVM_INTERNAL_ERROR_EMULATION,
+					ndata: 1,
+					data: [7; 16],
+				});
+		}
+		let internal = unsafe { run.__bindgen_anon_1.internal };
+		assert_eq!(
+			(internal.suberror, internal.ndata, internal.data[15]),
+			(KVM_INTERNAL_ERROR_EMULATION, 1, 7)
+		);
+
+		let mut run = zeroed_run(KVM_EXIT_FAIL_ENTRY);
+		unsafe {
+			ptr::addr_of_mut!(run.__bindgen_anon_1)
+				.cast::<KvmRunFailEntry>()
+				.write(KvmRunFailEntry {
+					hardware_entry_failure_reason: 33,
+					cpu: 3,
+				});
+		}
+		let fail_entry = unsafe { run.__bindgen_anon_1.fail_entry };
+		assert_eq!(
+			(fail_entry.hardware_entry_failure_reason, fail_entry.cpu),
+			(33, 3)
+		);
+	}
+
+	#[test]
+	fn test_exit_handlers() {
+		let mut handlers = ExitHandlers::builtin();
+		assert!(handlers.get(KVM_EXIT_IO).is_some());
+		assert!(handlers.get(KVM_EXIT_EXCEPTION).is_none());
+		assert!(handlers.get(u32::MAX).is_none());
+		assert!(handlers.get_port(PCI_CONFIG_DATA_PORT).is_some());
+
+		handlers.register(
+			KVM_EXIT_EXCEPTION,
+			Arc::new(|_: &mut UhyveCPU| ExitAction::Continue),
+		);
+		assert!(handlers.get(KVM_EXIT_EXCEPTION).is_some());
+	}
+
+	#[test]
+	#[should_panic(expected = "already handled")]
+	fn test_exit_handlers_conflict() {
+		ExitHandlers::builtin().register(KVM_EXIT_HLT, Arc::new(UhyveCPU::handle_hlt));
+	}
+
+	#[test]
+	fn test_kvm_run_io_data() {
+		let mut run = zeroed_run(KVM_EXIT_IO);
+		let end = mem::size_of::<kvm_run>() as u64;
+		let io = |run: &mut kvm_run, size, count, data_offset| unsafe {
+			ptr::addr_of_mut!(run.__bindgen_anon_1)
+				.cast::<KvmRunIo>()
+				.write(KvmRunIo {
+					direction: KVM_EXIT_IO_OUT as u8,
+					size,
+					port: 0x3f8,
+					count,
+					data_offset,
+				});
+		};
+
+		io(&mut run, 2, 2, end - 4);
+		assert_eq!(KvmRunAccess::new(&mut run).io_data_mut().unwrap().len(), 4);
+		// the data has to stay within the mapping
+		io(&mut run, 4, 2, end - 4);
+		assert!(KvmRunAccess::new(&mut run).io_data_mut().is_none());
+		io(&mut run, 1, 1, u64::MAX);
+		assert!(KvmRunAccess::new(&mut run).io_data_mut().is_none());
+	}
+
+	#[test]
+	fn test_kvm_run_access() {
+		let mut run = zeroed_run(KVM_EXIT_IO);
+		let mut access = KvmRunAccess::new(&mut run);
+		assert_eq!(access.exit_reason(), KVM_EXIT_IO);
+		assert!(access.as_io().is_some());
+		assert!(access.as_mmio().is_none());
+		assert!(access.as_fail_entry().is_none());
+		assert!(access.as_msr_mut().is_none());
+
+		let mut run = zeroed_run(KVM_EXIT_X86_WRMSR);
+		let mut access = KvmRunAccess::new(&mut run);
+		assert!(access.as_io().is_none());
+		assert!(access.io_data_mut().is_none());
+		let msr = access.as_msr_mut().unwrap();
+		msr.error = 1;
+		assert_eq!(access.as_msr_mut().unwrap().error, 1);
 	}
 }
@@ -0,0 +1,296 @@
+//! Kernel acceleration of virtio-net through `/dev/vhost-net`.
+//!
+//! With vhost-net, the host kernel processes the virtqueues of the device. The guest's queue
+//! notifications reach the vhost worker through ioeventfds and the worker raises the interrupt
+//! through an irqfd, so packets neither pass through uhyve's threads nor cause an exit to user
+//! space.
+
+use std::{
+	fmt,
+	fs::{File, OpenOptions},
+	io, mem,
+	os::unix::io::{AsRawFd, RawFd},
+};
+
+use kvm_ioctls::{IoEventAddress, VmFd};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::linux::virtio::VIRTIO_PCI_QUEUE_NOTIFY;
+
+/// `_IOR(VHOST_VIRTIO, 0x00, __u64)`
+const VHOST_GET_FEATURES: libc::c_ulong = 0x8008_af00;
+/// `_IOW(VHOST_VIRTIO, 0x00, __u64)`
+const VHOST_SET_FEATURES: libc::c_ulong = 0x4008_af00;
+/// `_IO(VHOST_VIRTIO, 0x01)`
+const VHOST_SET_OWNER: libc::c_ulong = 0xaf01;
+/// `_IOW(VHOST_VIRTIO, 0x03, struct vhost_memory)`
+const VHOST_SET_MEM_TABLE: libc::c_ulong = 0x4008_af03;
+/// `_IOW(VHOST_VIRTIO, 0x10, struct vhost_vring_state)`
+const VHOST_SET_VRING_NUM: libc::c_ulong = 0x4008_af10;
+/// `_IOW(VHOST_VIRTIO, 0x11, struct vhost_vring_addr)`
+const VHOST_SET_VRING_ADDR: libc::c_ulong = 0x4028_af11;
+/// `_IOW(VHOST_VIRTIO, 0x12, struct vhost_vring_state)`
+const VHOST_SET_VRING_BASE: libc::c_ulong = 0x4008_af12;
+/// `_IOW(VHOST_VIRTIO, 0x20, struct vhost_vring_file)`
+const VHOST_SET_VRING_KICK: libc::c_ulong = 0x4008_af20;
+/// `_IOW(VHOST_VIRTIO, 0x21, struct vhost_vring_file)`
+const VHOST_SET_VRING_CALL: libc::c_ulong = 0x4008_af21;
+/// `_IOW(VHOST_VIRTIO, 0x30, struct vhost_vring_file)`
+const VHOST_NET_SET_BACKEND: libc::c_ulong = 0x4008_af30;
+
+/// vhost-net adds and strips the `virtio_net_hdr`, which the TAP device does not know.
+const VHOST_NET_F_VIRTIO_NET_HDR: u64 = 1 << 27;
+/// Number of virtqueues of the device, receive and transmit
+const NUM_QUEUES: usize = 2;
+/// Regions of the guest memory, below and above the 32-bit gap
+const MAX_MEMORY_REGIONS: usize = 2;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct VhostMemoryRegion {
+	guest_phys_addr: u64,
+	memory_size: u64,
+	userspace_addr: u64,
+	flags_padding: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct VhostMemory {
+	nregions: u32,
+	padding: u32,
+	regions: [VhostMemoryRegion; MAX_MEMORY_REGIONS],
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct VhostVringState {
+	index: u32,
+	num: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct VhostVringAddr {
+	index: u32,
+	flags: u32,
+	desc_user_addr: u64,
+	used_user_addr: u64,
+	avail_user_addr: u64,
+	log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct VhostVringFile {
+	index: u32,
+	fd: RawFd,
+}
+
+/// A region of the guest memory, which the vhost worker accesses.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestRegion {
+	pub guest_address: usize,
+	pub host_address: usize,
+	pub size: usize,
+}
+
+/// The host addresses of the rings of a virtqueue.
+#[derive(Debug, Clone, Copy)]
+pub struct VringAddresses {
+	pub size: u16,
+	pub descriptors: usize,
+	pub available: usize,
+	pub used: usize,
+}
+
+/// A vhost-net instance, which processes the virtqueues of the virtio-net device.
+pub struct VhostNet {
+	file: File,
+	/// Signaled by KVM on writes of the queue index to `VIRTIO_PCI_QUEUE_NOTIFY`
+	kicks: Vec<EventFd>,
+	/// Raises the interrupt of the device
+	call: EventFd,
+	/// Whether a backend is attached to the queues
+	running: bool,
+}
+
+impl fmt::Debug for VhostNet {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("VhostNet")
+			.field("file", &self.file)
+			.field(
+				"kicks",
+				&self
+					.kicks
+					.iter()
+					.map(AsRawFd::as_raw_fd)
+					.collect::<Vec<_>>(),
+			)
+			.field("call", &self.call.as_raw_fd())
+			.field("running", &self.running)
+			.finish()
+	}
+}
+
+impl VhostNet {
+	/// Opens `/dev/vhost-net` and routes the notifications and the interrupt `gsi` of the
+	/// device through KVM.
+	pub fn open(vm: &VmFd, regions: &[GuestRegion], gsi: u32) -> io::Result<Self> {
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.open("/dev/vhost-net")?;
+		let vhost = Self {
+			file,
+			kicks: (0..NUM_QUEUES)
+				.map(|_| EventFd::new(libc::EFD_NONBLOCK))
+				.collect::<io::Result<_>>()?,
+			call: EventFd::new(libc::EFD_NONBLOCK)?,
+			running: false,
+		};
+
+		vhost.ioctl(VHOST_SET_OWNER, 0)?;
+		vhost.set_mem_table(regions)?;
+
+		let kvm_err = |err: kvm_ioctls::Error| io::Error::from_raw_os_error(err.errno());
+		for (index, kick) in vhost.kicks.iter().enumerate() {
+			vm.register_ioevent(
+				kick,
+				&IoEventAddress::Pio(VIRTIO_PCI_QUEUE_NOTIFY.into()),
+				index as u16,
+			)
+			.map_err(kvm_err)?;
+		}
+		vm.register_irqfd(&vhost.call, gsi).map_err(kvm_err)?;
+
+		Ok(vhost)
+	}
+
+	fn ioctl(&self, request: libc::c_ulong, arg: libc::c_ulong) -> io::Result<()> {
+		if unsafe { libc::ioctl(self.file.as_raw_fd(), request, arg) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		Ok(())
+	}
+
+	fn ioctl_with_ref<T>(&self, request: libc::c_ulong, arg: &T) -> io::Result<()> {
+		self.ioctl(request, arg as *const T as libc::c_ulong)
+	}
+
+	fn set_mem_table(&self, regions: &[GuestRegion]) -> io::Result<()> {
+		assert!(regions.len() <= MAX_MEMORY_REGIONS);
+		let mut memory = VhostMemory {
+			nregions: regions.len() as u32,
+			..Default::default()
+		};
+		for (entry, region) in memory.regions.iter_mut().zip(regions) {
+			*entry = VhostMemoryRegion {
+				guest_phys_addr: region.guest_address as u64,
+				memory_size: region.size as u64,
+				userspace_addr: region.host_address as u64,
+				flags_padding: 0,
+			};
+		}
+		self.ioctl_with_ref(VHOST_SET_MEM_TABLE, &memory)
+	}
+
+	/// Attaches the TAP device `tap` to the virtqueues, after the guest has negotiated `features`.
+	pub fn start(
+		&mut self,
+		features: u64,
+		queues: &[VringAddresses],
+		tap: &impl AsRawFd,
+	) -> io::Result<()> {
+		if queues.len() != NUM_QUEUES {
+			return Err(io::ErrorKind::InvalidInput.into());
+		}
+
+		let mut supported = 0u64;
+		self.ioctl(
+			VHOST_GET_FEATURES,
+			&mut supported as *mut u64 as libc::c_ulong,
+		)?;
+		let features = (features | VHOST_NET_F_VIRTIO_NET_HDR) & supported;
+		self.ioctl_with_ref(VHOST_SET_FEATURES, &features)?;
+
+		for (index, queue) in queues.iter().enumerate() {
+			let index = index as u32;
+			self.ioctl_with_ref(
+				VHOST_SET_VRING_NUM,
+				&VhostVringState {
+					index,
+					num: queue.size.into(),
+				},
+			)?;
+			self.ioctl_with_ref(VHOST_SET_VRING_BASE, &VhostVringState { index, num: 0 })?;
+			self.ioctl_with_ref(
+				VHOST_SET_VRING_ADDR,
+				&VhostVringAddr {
+					index,
+					desc_user_addr: queue.descriptors as u64,
+					used_user_addr: queue.used as u64,
+					avail_user_addr: queue.available as u64,
+					..Default::default()
+				},
+			)?;
+			self.ioctl_with_ref(
+				VHOST_SET_VRING_KICK,
+				&VhostVringFile {
+					index,
+					fd: self.kicks[index as usize].as_raw_fd(),
+				},
+			)?;
+			self.ioctl_with_ref(
+				VHOST_SET_VRING_CALL,
+				&VhostVringFile {
+					index,
+					fd: self.call.as_raw_fd(),
+				},
+			)?;
+		}
+
+		self.set_backend(tap.as_raw_fd())?;
+		self.running = true;
+		Ok(())
+	}
+
+	/// Detaches the TAP device from the virtqueues, when the guest resets the device.
+	pub fn stop(&mut self) -> io::Result<()> {
+		if mem::take(&mut self.running) {
+			self.set_backend(-1)?;
+		}
+		Ok(())
+	}
+
+	fn set_backend(&self, fd: RawFd) -> io::Result<()> {
+		for index in 0..NUM_QUEUES {
+			self.ioctl_with_ref(
+				VHOST_NET_SET_BACKEND,
+				&VhostVringFile {
+					index: index as u32,
+					fd,
+				},
+			)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::ptr;
+
+	#[test]
+	fn test_vhost_abi() {
+		// The sizes are encoded in the ioctl numbers.
+		assert_eq!(mem::size_of::<VhostVringState>(), 8);
+		assert_eq!(mem::size_of::<VhostVringFile>(), 8);
+		assert_eq!(mem::size_of::<VhostVringAddr>(), 40);
+		assert_eq!(mem::size_of::<VhostMemoryRegion>(), 32);
+		let memory = VhostMemory::default();
+		let regions = ptr::addr_of!(memory.regions) as usize - ptr::addr_of!(memory) as usize;
+		assert_eq!(regions, 8);
+	}
+}
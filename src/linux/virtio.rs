@@ -1,11 +1,17 @@
+use crate::linux::pci::{PciBar, PciDevice};
+use crate::linux::sw_iommu::{SoftIommu, IOMMU_PERM_READ, IOMMU_PERM_WRITE};
+use crate::linux::uhyve::set_tap_mac_address;
+use crate::linux::vhost_net::{VhostNet, VringAddresses};
 use crate::linux::virtqueue::*;
-use crate::vm::VirtualCPU;
+use crate::scratch_alloc::ScratchAllocator;
 use log::info;
 use mac_address::*;
 use std::fmt;
 use std::mem::size_of;
-use std::ptr::copy_nonoverlapping;
-use std::sync::Mutex;
+use std::os::unix::io::AsRawFd;
+use std::ptr::{self, write_unaligned};
+use std::slice;
+use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 use tun_tap::*;
 use virtio_bindings::bindings::virtio_net::*;
@@ -25,11 +31,14 @@ const CLASS_REGISTER: usize = 0x8;
 const BAR0_REGISTER: usize = 0x10;
 const _SUBSYSTEM_VENDOR_ID_REGISTER: usize = 0x2C;
 const _SUBSYSTEM_ID_REGISTER: usize = 0x2E;
-const _INTERRUPT_REGISTER: usize = 0x3C;
-const _RX_QUEUE: usize = 0;
+const INTERRUPT_REGISTER: usize = 0x3C;
+const RX_QUEUE: usize = 0;
 const TX_QUEUE: usize = 1;
 const IOBASE: u16 = 0xc000;
 const ETHARP_HWADDR_LEN: u16 = 6;
+/// Largest frame, which is received: a jumbo frame with an MTU of 9000, an Ethernet header and a
+/// VLAN tag
+const MAX_FRAME_SIZE: usize = 9000 + 14 + 4;
 
 pub const VIRTIO_PCI_HOST_FEATURES: u16 = IOBASE;
 pub const VIRTIO_PCI_GUEST_FEATURES: u16 = IOBASE + 4;
@@ -43,14 +52,93 @@ pub const VIRTIO_PCI_CONFIG_OFF_MSIX_OFF: u16 = 20;
 pub const VIRTIO_PCI_CONFIG_OFF_MSIX_OFF_MAX: u16 = VIRTIO_PCI_CONFIG_OFF_MSIX_OFF + 5;
 pub const VIRTIO_PCI_LINK_STATUS_MSIX_OFF: u16 = ETHARP_HWADDR_LEN + VIRTIO_PCI_CONFIG_OFF_MSIX_OFF;
 
-const HOST_FEATURES: u32 = (1 << VIRTIO_NET_F_STATUS) | (1 << VIRTIO_NET_F_MAC);
+const HOST_FEATURES: u32 =
+	(1 << VIRTIO_NET_F_STATUS) | (1 << VIRTIO_NET_F_MAC) | (1 << VIRTIO_NET_F_MRG_RXBUF);
 
-pub trait PciDevice {
-	fn handle_read(&self, address: u32, dest: &mut [u8]);
-	fn handle_write(&mut self, address: u32, src: &[u8]);
+type PciRegisters = [u8; 0x40];
+
+fn set_nonblocking(tap: &Iface) {
+	let fd = tap.as_raw_fd();
+	let result = unsafe {
+		let flags = libc::fcntl(fd, libc::F_GETFL);
+		libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+	};
+	if result < 0 {
+		warn!(
+			"Unable to make the TAP device non-blocking: {}",
+			std::io::Error::last_os_error()
+		);
+	}
 }
 
-type PciRegisters = [u8; 0x40];
+/// Translates the I/O virtual address of a DMA access into a guest physical address.
+fn translate_dma(
+	iommu: &Option<(u32, Arc<Mutex<SoftIommu>>)>,
+	iova: usize,
+	len: usize,
+	perm: u8,
+) -> Option<usize> {
+	match iommu {
+		Some((devid, iommu)) => {
+			match iommu
+				.lock()
+				.unwrap()
+				.translate(*devid, iova as u64, len, perm)
+			{
+				Ok(gpa) => Some(gpa as usize),
+				Err(err) => {
+					warn!("virtio-net: {}", err);
+					None
+				}
+			}
+		}
+		None => Some(iova),
+	}
+}
+
+/// Returns the buffer of `desc` after translating its I/O virtual address, unless it lies outside
+/// of the guest memory `guest_mem`.
+///
+/// # Safety
+///
+/// `guest_mem` has to stay mapped for `'a` and no other reference to the buffer may exist.
+unsafe fn dma_buffer<'a>(
+	iommu: &Option<(u32, Arc<Mutex<SoftIommu>>)>,
+	guest_mem: (*mut u8, usize),
+	desc: &VirtqDesc,
+	perm: u8,
+) -> Option<&'a mut [u8]> {
+	if iommu.is_none() {
+		return desc.buffer();
+	}
+	let len = desc.len as usize;
+	let gpa = translate_dma(iommu, desc.gpa as usize, len, perm)?;
+	let end = gpa.checked_add(len)?;
+	(end <= guest_mem.1).then(|| slice::from_raw_parts_mut(guest_mem.0.add(gpa), len))
+}
+
+/// Copies the buffers of the descriptor chain `head`, which the device reads, into `frame`.
+///
+/// Returns the length of the chain, which may exceed `frame`, or `None`, if a buffer lies outside
+/// of the guest memory.
+fn read_chain(
+	queue: &Virtqueue,
+	head: VirtqDesc,
+	iommu: &Option<(u32, Arc<Mutex<SoftIommu>>)>,
+	guest_mem: (*mut u8, usize),
+	frame: &mut [u8],
+) -> Option<usize> {
+	let mut len = 0;
+	for desc in queue.chain(head).filter(|desc| !desc.is_write_only()) {
+		let buffer = unsafe { dma_buffer(iommu, guest_mem, &desc, IOMMU_PERM_READ) }?;
+		if let Some(dest) = frame.get_mut(len..) {
+			let chunk = dest.len().min(buffer.len());
+			dest[..chunk].copy_from_slice(&buffer[..chunk]);
+		}
+		len += buffer.len();
+	}
+	Some(len)
+}
 
 pub struct VirtioNetPciDevice {
 	registers: PciRegisters, //Add more
@@ -59,6 +147,12 @@ pub struct VirtioNetPciDevice {
 	virt_queues: Vec<Virtqueue>,
 	iface: Option<Mutex<Iface>>,
 	mac_addr: [u8; 6],
+	/// The software IOMMU and the device's ID in it
+	iommu: Option<(u32, Arc<Mutex<SoftIommu>>)>,
+	/// Processes the virtqueues in the host kernel instead of `send_available_packets`
+	vhost: Option<VhostNet>,
+	/// The guest memory, in which the virtqueues are located
+	guest_mem: (*mut u8, usize),
 }
 
 impl fmt::Debug for VirtioNetPciDevice {
@@ -119,52 +213,219 @@ impl VirtioNetPciDevice {
 			virt_queues,
 			iface: None,
 			mac_addr: [0; 6],
+			iommu: None,
+			vhost: None,
+			guest_mem: (ptr::null_mut(), 0),
 		}
 	}
 
-	pub fn _poll_rx(_device: &mut VirtioNetPciDevice) {
-		//TODO: how to read packets without synchronization issues
+	/// Sets the guest memory, in which the guest places the virtqueues.
+	pub fn set_guest_mem(&mut self, guest_mem: (*mut u8, usize)) {
+		self.guest_mem = guest_mem;
+	}
+
+	/// Sets the MAC address, which the device reports and assigns to its TAP device.
+	pub fn set_mac_addr(&mut self, mac_addr: [u8; 6]) {
+		self.mac_addr = mac_addr;
 	}
 
-	pub fn handle_notify_output(&mut self, dest: &[u8], cpu: &impl VirtualCPU) {
-		let tx_num = read_u16!(dest, 0);
-		if tx_num == 1 && self.read_status_reg() & STATUS_DRIVER_OK == STATUS_DRIVER_OK {
-			self.send_available_packets(cpu);
+	/// Checks all DMA of the device against the mappings of `iommu`.
+	pub fn set_iommu(&mut self, devid: u32, iommu: Arc<Mutex<SoftIommu>>) {
+		self.iommu = Some((devid, iommu));
+	}
+
+	/// Lets `vhost` process the virtqueues, whose interrupt it raises on `irq`.
+	pub fn set_vhost(&mut self, vhost: VhostNet, irq: u8) {
+		self.registers[INTERRUPT_REGISTER] = irq;
+		self.vhost = Some(vhost);
+	}
+
+	/// Returns, whether the guest has negotiated `feature`.
+	fn has_feature(&self, feature: u32) -> bool {
+		self.requested_features & (1 << feature) != 0
+	}
+
+	/// Returns the size of the header, which precedes every packet in the virtqueues.
+	fn header_len(&self) -> usize {
+		if self.has_feature(VIRTIO_NET_F_MRG_RXBUF) {
+			size_of::<virtio_net_hdr_mrg_rxbuf>()
+		} else {
+			size_of::<virtio_net_hdr>()
 		}
 	}
 
-	// Sends packets using the tun_tap crate, subject to change
-	fn send_available_packets(&mut self, cpu: &impl VirtualCPU) {
-		let tx_queue = &mut self.virt_queues[TX_QUEUE];
-		let mut send_indices = Vec::new();
-		for index in tx_queue.avail_iter() {
-			send_indices.push(index);
+	/// Moves the packets, which the TAP device has received, into the RX queue, as long as the
+	/// guest provides buffers for them.
+	///
+	/// The device does not raise an interrupt yet, so the packets are only polled, when the guest
+	/// notifies a queue.
+	pub fn poll_rx(&mut self) {
+		if self.vhost.is_some() || self.read_status_reg() & STATUS_DRIVER_OK == 0 {
+			return;
 		}
-		for index in send_indices {
-			let desc = unsafe { tx_queue.get_descriptor(index) };
-			let gpa = unsafe { *(desc.addr as *const usize) };
-			let hva = (*cpu).host_address(gpa) as *mut u8;
-			match &self.iface {
-				Some(tap) => unsafe {
-					let vec = vec![0; (desc.len as usize) - size_of::<virtio_net_hdr>()];
-					let slice: &[u8] = &vec;
-					copy_nonoverlapping(
-						hva as *const u8,
-						slice.as_ptr() as *mut u8,
-						(desc.len as usize) - size_of::<virtio_net_hdr>(),
-					);
-					let unlocked_tap = tap.lock().unwrap();
-					//Actually send packet
-					unlocked_tap.send(slice).unwrap_or(0);
-				},
-				None => self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET,
+
+		let header_len = self.header_len();
+		ScratchAllocator::with(|scratch| {
+			let (frame, used) = match (
+				scratch.alloc_slice::<u8>(header_len + MAX_FRAME_SIZE),
+				scratch.alloc_slice::<(u16, u32)>(QUEUE_LIMIT),
+			) {
+				(Some(frame), Some(used)) => (frame, used),
+				_ => return,
+			};
+			loop {
+				// the packet is received behind its header, so it does not need to be copied
+				let len = match &self.iface {
+					Some(tap) => match tap.lock().unwrap().recv(&mut frame[header_len..]) {
+						Ok(len) => len,
+						Err(_) => return,
+					},
+					None => return,
+				};
+				// no checksum offloading or segmentation, so the header is zero except for
+				// `num_buffers`
+				frame[..header_len].fill(0);
+				if !self.receive_frame(&frame[..header_len + len], used) {
+					debug!("virtio-net: no receive buffers, dropping a packet");
+					return;
+				}
+			}
+		})
+	}
+
+	/// Copies a received frame, which starts with its virtio header, into the buffers, which the
+	/// guest has made available in the RX queue.
+	///
+	/// With `VIRTIO_NET_F_MRG_RXBUF`, a packet spans as many buffers as it needs and the header
+	/// reports their number in `num_buffers`. Otherwise, it has to fit into one descriptor chain.
+	/// `used` holds the descriptor chains, which the packet occupies. Returns `false`, if the guest
+	/// has not made enough buffers available, in which case the packet is dropped.
+	fn receive_frame(&mut self, frame: &[u8], used: &mut [(u16, u32)]) -> bool {
+		let mergeable = self.has_feature(VIRTIO_NET_F_MRG_RXBUF);
+		let header_len = self.header_len();
+		let (iommu, guest_mem) = (&self.iommu, self.guest_mem);
+		let rx_queue = match self.virt_queues.get_mut(RX_QUEUE) {
+			Some(rx_queue) => rx_queue,
+			None => return false,
+		};
+
+		let last_seen_available = rx_queue.last_seen_available;
+		let mut chains = 0;
+		let mut header: Option<*mut u8> = None;
+		let mut offset = 0;
+		while offset < frame.len() && (mergeable || chains == 0) && chains < used.len() {
+			let head = match rx_queue.next_avail_desc() {
+				Some(head) => head,
+				None => break,
+			};
+
+			let mut written = 0;
+			let mut valid = true;
+			for desc in rx_queue.chain(head).filter(VirtqDesc::is_write_only) {
+				if offset == frame.len() {
+					break;
+				}
+				let chunk = (desc.len as usize).min(frame.len() - offset);
+				let buffer = unsafe { dma_buffer(iommu, guest_mem, &desc, IOMMU_PERM_WRITE) }
+					.filter(|_| header.is_some() || chunk >= header_len);
+				let buffer = match buffer {
+					Some(buffer) => buffer,
+					None => {
+						valid = false;
+						break;
+					}
+				};
+				buffer[..chunk].copy_from_slice(&frame[offset..offset + chunk]);
+				header.get_or_insert(buffer.as_mut_ptr());
+				offset += chunk;
+				written += chunk;
+			}
+			if !valid {
+				break;
+			}
+			used[chains] = (head.index, written as u32);
+			chains += 1;
+		}
+
+		if offset < frame.len() {
+			rx_queue.last_seen_available = last_seen_available;
+			return false;
+		}
+
+		if let (true, Some(header)) = (mergeable, header) {
+			unsafe {
+				write_unaligned(
+					header.add(size_of::<virtio_net_hdr>()) as *mut u16,
+					chains as u16,
+				);
+			}
+		}
+		for &(head, len) in &used[..chains] {
+			rx_queue.push_used(head, len);
+		}
+		true
+	}
+
+	pub fn handle_notify_output(&mut self, dest: &[u8]) {
+		let queue = read_u16!(dest, 0) as usize;
+		if self.read_status_reg() & STATUS_DRIVER_OK == STATUS_DRIVER_OK {
+			if queue == TX_QUEUE {
+				self.send_available_packets();
+			}
+			// new receive buffers or, after sending, possibly a reply
+			self.poll_rx();
+		}
+	}
+
+	/// Sends the packets, which the guest has made available in the TX queue, through the TAP
+	/// device.
+	fn send_available_packets(&mut self) {
+		let header_len = self.header_len();
+		let (iommu, guest_mem, iface) = (&self.iommu, self.guest_mem, &self.iface);
+		let tx_queue = match self.virt_queues.get_mut(TX_QUEUE) {
+			Some(tx_queue) => tx_queue,
+			None => return,
+		};
+		let mut needs_reset = false;
+		ScratchAllocator::with(|scratch| {
+			let frame = match scratch.alloc_slice::<u8>(header_len + MAX_FRAME_SIZE) {
+				Some(frame) => frame,
+				None => return,
+			};
+			while let Some(head) = tx_queue.next_avail_desc() {
+				let len = match read_chain(tx_queue, head, iommu, guest_mem, frame) {
+					Some(len) => len,
+					None => {
+						warn!("virtio-net: a packet lies outside of the guest memory");
+						needs_reset = true;
+						tx_queue.push_used(head.index, 0);
+						continue;
+					}
+				};
+				if len < header_len || len > frame.len() {
+					warn!("virtio-net: dropping a packet of {} bytes", len);
+					tx_queue.push_used(head.index, 0);
+					continue;
+				}
+				match iface {
+					Some(tap) => {
+						if let Err(err) = tap.lock().unwrap().send(&frame[header_len..len]) {
+							debug!("virtio-net: unable to send a packet: {}", err);
+						}
+					}
+					None => needs_reset = true,
+				}
+				tx_queue.push_used(head.index, 0);
 			}
-			tx_queue.add_used(index as u32, 1)
+		});
+		if needs_reset {
+			self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET;
 		}
 	}
 
 	pub fn read_status(&self, dest: &mut [u8]) {
-		self.handle_read(STATUS_REGISTER & 0x3FFF, dest);
+		let status = self.config_read(STATUS_REGISTER, dest.len() as u8);
+		dest.copy_from_slice(&status.to_le_bytes()[..dest.len()]);
 	}
 
 	// Virtio handshake
@@ -175,6 +436,11 @@ impl VirtioNetPciDevice {
 			self.requested_features = 0;
 			self.selected_queue_num = 0;
 			self.virt_queues.clear();
+			if let Some(vhost) = &mut self.vhost {
+				if let Err(err) = vhost.stop() {
+					warn!("Unable to stop vhost-net: {}", err);
+				}
+			}
 			self.iface = None;
 		} else if status == STATUS_DRIVER_NEEDS_RESET || status == 0 {
 			self.write_status_reset(dest);
@@ -213,6 +479,20 @@ impl VirtioNetPciDevice {
 		}
 	}
 
+	// Assigns the configured MAC address to the tap device
+	// Without one or if that fails, the address of the tap device is reported instead
+	fn apply_mac_addr(&mut self) {
+		if let Some(tap) = &self.iface {
+			if self.mac_addr != [0; 6] {
+				match set_tap_mac_address(&*tap.lock().unwrap(), self.mac_addr) {
+					Ok(()) => return,
+					Err(err) => warn!("Unable to set the MAC address of the TAP device: {}", err),
+				}
+			}
+		}
+		self.get_mac_addr();
+	}
+
 	// Driver acknowledges device
 	fn write_status_reset(&mut self, dest: &[u8]) {
 		if dest[0] == STATUS_ACKNOWLEDGE {
@@ -238,15 +518,48 @@ impl VirtioNetPciDevice {
 	fn write_status_ok(&mut self, dest: &[u8]) {
 		if dest[0] == STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK {
 			self.write_status_reg(dest[0]);
-			self.iface = match Iface::new("", Mode::Tap) {
-				Ok(tap) => Some(Mutex::new(tap)),
+			// the virtqueues contain bare Ethernet frames after the virtio header
+			self.iface = match Iface::without_packet_info("", Mode::Tap) {
+				Ok(tap) => {
+					// only vhost-net waits for packets, they are polled otherwise
+					if self.vhost.is_none() {
+						set_nonblocking(&tap);
+					}
+					Some(Mutex::new(tap))
+				}
 				Err(err) => {
 					info!("Error creating TAP device: {}", err);
 					self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET;
 					None
 				}
 			};
-			self.get_mac_addr();
+			self.apply_mac_addr();
+			self.start_vhost();
+		}
+	}
+
+	fn start_vhost(&mut self) {
+		let (vhost, tap) = match (&mut self.vhost, &self.iface) {
+			(Some(vhost), Some(tap)) => (vhost, tap),
+			_ => return,
+		};
+		let queues = self
+			.virt_queues
+			.iter()
+			.map(|queue| {
+				let (descriptors, available, used) = queue.ring_addresses();
+				VringAddresses {
+					size: queue.queue_size,
+					descriptors,
+					available,
+					used,
+				}
+			})
+			.collect::<Vec<_>>();
+		let tap = tap.lock().unwrap();
+		if let Err(err) = vhost.start(self.requested_features.into(), &queues, &*tap) {
+			warn!("Unable to start vhost-net: {}", err);
+			self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET;
 		}
 	}
 
@@ -266,19 +579,32 @@ impl VirtioNetPciDevice {
 	}
 
 	// Register virtqueue
-	pub fn write_pfn(&mut self, dest: &[u8], vcpu: &impl VirtualCPU) {
+	pub fn write_pfn(&mut self, dest: &[u8]) {
 		let status = self.read_status_reg();
 		if status & STATUS_FEATURES_OK != 0
 			&& status & STATUS_DRIVER_OK == 0
 			&& self.selected_queue_num as usize == self.virt_queues.len()
 		{
-			let gpa = unsafe {
+			let iova = unsafe {
 				#[allow(clippy::cast_ptr_alignment)]
 				*(dest.as_ptr() as *const usize)
 			};
-			let hva = (*vcpu).host_address(gpa) as *mut u8;
-			let queue = unsafe { Virtqueue::new(hva, QUEUE_LIMIT) };
-			self.virt_queues.push(queue);
+			let gpa = match translate_dma(
+				&self.iommu,
+				iova,
+				get_queue_size_in_bytes(),
+				IOMMU_PERM_READ | IOMMU_PERM_WRITE,
+			) {
+				Some(gpa) => gpa,
+				None => {
+					self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET;
+					return;
+				}
+			};
+			match unsafe { Virtqueue::legacy(self.guest_mem, gpa as u64) } {
+				Some(queue) => self.virt_queues.push(queue),
+				None => self.registers[STATUS_REGISTER as usize] |= STATUS_DRIVER_NEEDS_RESET,
+			}
 		}
 	}
 
@@ -323,13 +649,42 @@ impl VirtioNetPciDevice {
 }
 
 impl PciDevice for VirtioNetPciDevice {
-	fn handle_read(&self, address: u32, dest: &mut [u8]) {
-		dest.copy_from_slice(&self.registers[address as usize..][..dest.len()]);
+	fn vendor_id(&self) -> u16 {
+		read_u16!(self.registers, VENDOR_ID_REGISTER)
+	}
+
+	fn device_id(&self) -> u16 {
+		read_u16!(self.registers, DEVICE_ID_REGISTER)
+	}
+
+	fn class_code(&self) -> u32 {
+		read_u32!(self.registers, CLASS_REGISTER) >> 8
+	}
+
+	fn bar(&self, n: u8) -> Option<PciBar> {
+		match n {
+			0 => Some(PciBar::Io(IOBASE)),
+			_ => None,
+		}
+	}
+
+	fn irq_line(&self) -> u8 {
+		self.registers[INTERRUPT_REGISTER]
+	}
+
+	fn config_read(&self, offset: u32, size: u8) -> u32 {
+		let mut bytes = [0u8; 4];
+		if let Some(registers) = self.registers[..].get(offset as usize..) {
+			let len = registers.len().min(size.into());
+			bytes[..len].copy_from_slice(&registers[..len]);
+		}
+		u32::from_le_bytes(bytes)
 	}
 
-	fn handle_write(&mut self, address: u32, dest: &[u8]) {
-		for (i, var) in dest.iter().enumerate() {
-			self.registers[(address as usize) + i] = *var;
+	fn config_write(&mut self, offset: u32, size: u8, value: u32) {
+		if let Some(registers) = self.registers[..].get_mut(offset as usize..) {
+			let len = registers.len().min(size.into());
+			registers[..len].copy_from_slice(&value.to_le_bytes()[..len]);
 		}
 	}
 }
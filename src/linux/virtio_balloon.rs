@@ -0,0 +1,708 @@
+//! A virtio memory balloon, with which the host reclaims memory of the guest, see `--balloon`.
+//!
+//! Like virtio-blk, the device uses the legacy I/O port interface of virtio at its own BAR. The
+//! host sets the number of pages, which the guest should give back, in the device configuration.
+//! The guest inflates the balloon by passing the frame numbers of these pages through the inflate
+//! queue, whose pages uhyve then discards with `MADV_DONTNEED`. With
+//! `VIRTIO_BALLOON_F_MUST_TELL_HOST`, the guest returns the pages through the deflate queue before
+//! using them again, on which they are faulted in as zero pages.
+//!
+//! The guest keeps a buffer in the stats queue, which the device returns to request new memory
+//! statistics. Unlike virtio-net and virtio-blk, the device raises the interrupt
+//! [`UHYVE_IRQ_BALLOON`](crate::consts::UHYVE_IRQ_BALLOON), as the guest needs to learn about a
+//! new target while it does not access the device.
+//!
+//! With `--balloon-control <FILE>`, [`BalloonControl`] reads the target size of the balloon from
+//! the file, e.g. `512MiB`, and writes the latest statistics of the guest to `<FILE>.stats`.
+//! With `--balloon-target <SIZE>`, [`BalloonTarget`] inflates the balloon once after the boot.
+
+use std::{
+	fmt, fs,
+	mem::size_of,
+	path::{Path, PathBuf},
+	sync::{
+		mpsc::{self, RecvTimeoutError, Sender},
+		Arc, Mutex,
+	},
+	thread::{self, JoinHandle},
+	time::{Duration, Instant},
+};
+
+use byte_unit::Byte;
+use nix::sys::mman::{madvise, MmapAdvise};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::linux::pci::{PciBar, PciDevice};
+use crate::linux::vcpu::{ExitAction, PortIoHandler, UhyveCPU};
+use crate::linux::virtqueue::*;
+
+const IOBASE: u16 = 0xc0c0;
+const VIRTIO_BALLOON_HOST_FEATURES: u16 = IOBASE;
+const VIRTIO_BALLOON_GUEST_FEATURES: u16 = IOBASE + 4;
+const VIRTIO_BALLOON_QUEUE_PFN: u16 = IOBASE + 8;
+const VIRTIO_BALLOON_QUEUE_NUM: u16 = IOBASE + 12;
+const VIRTIO_BALLOON_QUEUE_SEL: u16 = IOBASE + 14;
+const VIRTIO_BALLOON_QUEUE_NOTIFY: u16 = IOBASE + 16;
+const VIRTIO_BALLOON_STATUS: u16 = IOBASE + 18;
+const VIRTIO_BALLOON_ISR: u16 = IOBASE + 19;
+/// `struct virtio_balloon_config`
+const VIRTIO_BALLOON_CONFIG: u16 = IOBASE + 20;
+const VIRTIO_BALLOON_CONFIG_MAX: u16 = VIRTIO_BALLOON_CONFIG + CONFIG_SIZE as u16 - 1;
+/// Size of the device configuration up to `actual`
+const CONFIG_SIZE: usize = 8;
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 4;
+
+// virtio-bindings lacks the balloon
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0;
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+/// The balloon always uses 4 KiB pages, independent of the page size of the guest
+const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+pub const BALLOON_PAGE_SIZE: usize = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+const STATUS_DRIVER_OK: u8 = 0b00000100;
+const STATUS_FEATURES_OK: u8 = 0b00001000;
+const STATUS_DRIVER_NEEDS_RESET: u8 = 0b01000000;
+/// A virtqueue has been used
+const ISR_QUEUE: u8 = 1 << 0;
+/// The device configuration has changed
+const ISR_CONFIG: u8 = 1 << 1;
+
+const VENDOR_ID: u16 = 0x1AF4;
+/// Transitional device ID of a memory balloon
+const DEVICE_ID: u16 = 0x1002;
+/// Unclassified device
+const CLASS_CODE: u32 = 0xff0000;
+/// Subsystem ID of a legacy memory balloon
+const VIRTIO_ID_BALLOON: u16 = 5;
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const STATS_QUEUE: usize = 2;
+const NUM_QUEUES: usize = 3;
+
+/// Interval, in which the control file is read and new statistics are requested
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval, in which [`BalloonTarget`] checks the balloon
+const TARGET_INTERVAL: Duration = Duration::from_millis(100);
+/// Time, which the guest has to inflate the balloon to its initial size
+const TARGET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The I/O ports of the balloon.
+pub fn ports() -> impl Iterator<Item = u16> {
+	[
+		VIRTIO_BALLOON_HOST_FEATURES,
+		VIRTIO_BALLOON_GUEST_FEATURES,
+		VIRTIO_BALLOON_QUEUE_PFN,
+		VIRTIO_BALLOON_QUEUE_NUM,
+		VIRTIO_BALLOON_QUEUE_SEL,
+		VIRTIO_BALLOON_QUEUE_NOTIFY,
+		VIRTIO_BALLOON_STATUS,
+		VIRTIO_BALLOON_ISR,
+	]
+	.into_iter()
+	.chain(VIRTIO_BALLOON_CONFIG..=VIRTIO_BALLOON_CONFIG_MAX)
+}
+
+/// The names of the tags of `struct virtio_balloon_stat`.
+const STAT_NAMES: [&str; 10] = [
+	"swap_in",
+	"swap_out",
+	"major_faults",
+	"minor_faults",
+	"free_memory",
+	"total_memory",
+	"available_memory",
+	"disk_caches",
+	"hugetlb_allocations",
+	"hugetlb_failures",
+];
+
+/// Size of `struct virtio_balloon_stat`, a `u16` tag followed by a `u64` value
+const STAT_SIZE: usize = 10;
+
+/// The memory statistics, which the guest reported last.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BalloonStats {
+	/// The values by tag, `None` if the guest does not report the statistic
+	pub values: [Option<u64>; STAT_NAMES.len()],
+}
+
+impl BalloonStats {
+	fn parse(buffer: &[u8]) -> Self {
+		let mut stats = Self::default();
+		for stat in buffer.chunks_exact(STAT_SIZE) {
+			let tag = u16::from_le_bytes([stat[0], stat[1]]);
+			let value = u64::from_le_bytes(stat[2..].try_into().unwrap());
+			if let Some(entry) = stats.values.get_mut(usize::from(tag)) {
+				*entry = Some(value);
+			}
+		}
+		stats
+	}
+}
+
+impl fmt::Display for BalloonStats {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (name, value) in STAT_NAMES.iter().zip(self.values) {
+			if let Some(value) = value {
+				writeln!(f, "{} {}", name, value)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+pub struct VirtioBalloonDevice {
+	status: u8,
+	requested_features: u32,
+	selected_queue_num: u16,
+	queues: [Option<Virtqueue>; NUM_QUEUES],
+	/// The guest memory, in which the queues and the pages are located
+	guest_mem: (*mut u8, usize),
+	/// Raises [`UHYVE_IRQ_BALLOON`](crate::consts::UHYVE_IRQ_BALLOON)
+	interrupt: EventFd,
+	irq: u8,
+	isr: u8,
+	/// Number of pages, which the guest should give back
+	num_pages: u32,
+	/// Number of pages in the balloon according to the guest
+	actual: u32,
+	/// The buffer of the stats queue, which the device holds until it wants new statistics
+	stats_buffer: Option<u16>,
+	stats: Option<BalloonStats>,
+}
+
+// SAFETY: The queues and `guest_mem` point into the guest memory, which stays mapped as long as
+// the VM owning the device, and they are only accessed with the device locked.
+unsafe impl Send for VirtioBalloonDevice {}
+
+impl VirtioBalloonDevice {
+	pub fn new(guest_mem: (*mut u8, usize), interrupt: EventFd, irq: u8) -> Self {
+		Self {
+			status: 0,
+			requested_features: 0,
+			selected_queue_num: 0,
+			queues: [None, None, None],
+			guest_mem,
+			interrupt,
+			irq,
+			isr: 0,
+			num_pages: 0,
+			actual: 0,
+			stats_buffer: None,
+			stats: None,
+		}
+	}
+
+	fn host_features(&self) -> u32 {
+		(1 << VIRTIO_BALLOON_F_MUST_TELL_HOST) | (1 << VIRTIO_BALLOON_F_STATS_VQ)
+	}
+
+	fn config(&self) -> [u8; CONFIG_SIZE] {
+		let mut config = [0u8; CONFIG_SIZE];
+		config[CONFIG_NUM_PAGES..CONFIG_NUM_PAGES + 4]
+			.copy_from_slice(&self.num_pages.to_le_bytes());
+		config[CONFIG_ACTUAL..CONFIG_ACTUAL + 4].copy_from_slice(&self.actual.to_le_bytes());
+		config
+	}
+
+	fn reset(&mut self) {
+		self.status = 0;
+		self.requested_features = 0;
+		self.selected_queue_num = 0;
+		self.queues = [None, None, None];
+		self.isr = 0;
+		self.actual = 0;
+		self.stats_buffer = None;
+	}
+
+	/// Returns the number of pages in the balloon according to the guest.
+	pub fn actual(&self) -> u32 {
+		self.actual
+	}
+
+	/// Returns the statistics, which the guest reported last.
+	pub fn stats(&self) -> Option<&BalloonStats> {
+		self.stats.as_ref()
+	}
+
+	/// Asks the guest to give back `num_pages` pages of [`BALLOON_PAGE_SIZE`].
+	pub fn set_target(&mut self, num_pages: u32) {
+		if num_pages == self.num_pages {
+			return;
+		}
+		debug!("virtio-balloon: target of {} pages", num_pages);
+		self.num_pages = num_pages;
+		self.raise_interrupt(ISR_CONFIG);
+	}
+
+	/// Returns the buffer of the stats queue, so that the guest replaces it with new statistics.
+	pub fn request_stats(&mut self) {
+		if let (Some(head), Some(queue)) = (self.stats_buffer.take(), &mut self.queues[STATS_QUEUE])
+		{
+			queue.push_used(head, 0);
+			self.raise_interrupt(ISR_QUEUE);
+		}
+	}
+
+	fn raise_interrupt(&mut self, cause: u8) {
+		if self.status & STATUS_DRIVER_OK == 0 {
+			return;
+		}
+		self.isr |= cause;
+		if let Err(err) = self.interrupt.write(1) {
+			warn!("virtio-balloon: unable to raise the interrupt: {}", err);
+		}
+	}
+
+	fn read_port(&mut self, port: u16, data: &mut [u8]) {
+		let value = match port {
+			VIRTIO_BALLOON_HOST_FEATURES => self.host_features().into(),
+			VIRTIO_BALLOON_GUEST_FEATURES => self.requested_features.into(),
+			VIRTIO_BALLOON_QUEUE_NUM => QUEUE_LIMIT as u64,
+			VIRTIO_BALLOON_STATUS => self.status.into(),
+			// reading acknowledges the interrupt
+			VIRTIO_BALLOON_ISR => std::mem::take(&mut self.isr).into(),
+			VIRTIO_BALLOON_CONFIG..=VIRTIO_BALLOON_CONFIG_MAX => {
+				let config = self.config();
+				let offset = usize::from(port - VIRTIO_BALLOON_CONFIG);
+				let len = data.len().min(CONFIG_SIZE - offset);
+				data.fill(0);
+				data[..len].copy_from_slice(&config[offset..offset + len]);
+				return;
+			}
+			_ => 0,
+		};
+		let len = data.len().min(size_of::<u64>());
+		data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+	}
+
+	fn write_port(&mut self, port: u16, data: &[u8]) {
+		let mut bytes = [0u8; 4];
+		bytes[..data.len().min(4)].copy_from_slice(&data[..data.len().min(4)]);
+		let value = u32::from_le_bytes(bytes);
+		match port {
+			VIRTIO_BALLOON_GUEST_FEATURES if self.status & STATUS_FEATURES_OK == 0 => {
+				self.requested_features = value & self.host_features();
+			}
+			VIRTIO_BALLOON_QUEUE_SEL => self.selected_queue_num = value as u16,
+			VIRTIO_BALLOON_QUEUE_PFN => self.write_pfn(value.into()),
+			VIRTIO_BALLOON_QUEUE_NOTIFY if self.status & STATUS_DRIVER_OK != 0 => {
+				match value as usize {
+					INFLATE_QUEUE => self.process_pages(INFLATE_QUEUE),
+					DEFLATE_QUEUE => self.process_pages(DEFLATE_QUEUE),
+					STATS_QUEUE => self.process_stats(),
+					queue => debug!("virtio-balloon: notification of unknown queue {}", queue),
+				}
+			}
+			VIRTIO_BALLOON_STATUS if value == 0 => self.reset(),
+			VIRTIO_BALLOON_STATUS => self.status = value as u8,
+			// the driver updates `actual` after it has changed the balloon
+			VIRTIO_BALLOON_CONFIG..=VIRTIO_BALLOON_CONFIG_MAX => {
+				let offset = usize::from(port - VIRTIO_BALLOON_CONFIG);
+				let mut config = self.config();
+				let len = data.len().min(CONFIG_SIZE - offset);
+				config[offset..offset + len].copy_from_slice(&data[..len]);
+				self.actual = u32::from_le_bytes(
+					config[CONFIG_ACTUAL..CONFIG_ACTUAL + 4].try_into().unwrap(),
+				);
+			}
+			_ => debug!("virtio-balloon: ignoring a write to port 0x{:x}", port),
+		}
+	}
+
+	// Register the virtqueue, the address is passed as by virtio-net
+	fn write_pfn(&mut self, gpa: u64) {
+		let index = usize::from(self.selected_queue_num);
+		if index >= NUM_QUEUES || self.status & STATUS_DRIVER_OK != 0 {
+			return;
+		}
+		let queue = unsafe { Virtqueue::legacy(self.guest_mem, gpa) };
+		match queue {
+			Some(mut queue) => {
+				queue.set_interrupt(self.interrupt.try_clone().unwrap());
+				self.queues[index] = Some(queue);
+			}
+			None => {
+				warn!("virtio-balloon: queue {} exceeds the guest memory", index);
+				self.status |= STATUS_DRIVER_NEEDS_RESET;
+			}
+		}
+	}
+
+	/// Discards the pages of the inflate queue, the pages of the deflate queue are only returned.
+	fn process_pages(&mut self, index: usize) {
+		let guest_mem = self.guest_mem;
+		let queue = match &mut self.queues[index] {
+			Some(queue) => queue,
+			None => return,
+		};
+		let mut used = false;
+		while let Some(head) = queue.next_avail_desc() {
+			let mut desc = Some(head);
+			while let Some(current) = desc {
+				if index == INFLATE_QUEUE {
+					match unsafe { current.buffer() } {
+						Some(pfns) => discard_pages(guest_mem, pfns),
+						None => warn!("virtio-balloon: page list exceeds the guest memory"),
+					}
+				}
+				desc = queue.next_desc(&current);
+			}
+			queue.push_used(head.index, 0);
+			used = true;
+		}
+		if used {
+			self.isr |= ISR_QUEUE;
+			queue.notify_guest();
+		}
+	}
+
+	/// Stores the statistics of the guest and keeps the buffer for the next request.
+	fn process_stats(&mut self) {
+		let queue = match &mut self.queues[STATS_QUEUE] {
+			Some(queue) => queue,
+			None => return,
+		};
+		while let Some(head) = queue.next_avail_desc() {
+			match unsafe { head.buffer() } {
+				Some(buffer) => self.stats = Some(BalloonStats::parse(buffer)),
+				None => warn!("virtio-balloon: statistics exceed the guest memory"),
+			}
+			// a second buffer violates the protocol, it is returned right away
+			if let Some(previous) = self.stats_buffer.replace(head.index) {
+				queue.push_used(previous, 0);
+			}
+		}
+	}
+}
+
+/// Discards the pages, whose 32 bit frame numbers are in `pfns`.
+fn discard_pages(guest_mem: (*mut u8, usize), pfns: &[u8]) {
+	for pfn in pfns.chunks_exact(size_of::<u32>()) {
+		let gpa =
+			(u32::from_le_bytes(pfn.try_into().unwrap()) as usize) << VIRTIO_BALLOON_PFN_SHIFT;
+		if gpa + BALLOON_PAGE_SIZE > guest_mem.1 {
+			warn!(
+				"virtio-balloon: page 0x{:x} is not in the guest memory",
+				gpa
+			);
+			continue;
+		}
+		let page = unsafe { guest_mem.0.add(gpa) };
+		if let Err(err) =
+			unsafe { madvise(page.cast(), BALLOON_PAGE_SIZE, MmapAdvise::MADV_DONTNEED) }
+		{
+			warn!(
+				"virtio-balloon: unable to discard page 0x{:x}: {}",
+				gpa, err
+			);
+		}
+	}
+}
+
+impl PciDevice for VirtioBalloonDevice {
+	fn vendor_id(&self) -> u16 {
+		VENDOR_ID
+	}
+
+	fn device_id(&self) -> u16 {
+		DEVICE_ID
+	}
+
+	fn class_code(&self) -> u32 {
+		CLASS_CODE
+	}
+
+	fn bar(&self, n: u8) -> Option<PciBar> {
+		match n {
+			0 => Some(PciBar::Io(IOBASE)),
+			_ => None,
+		}
+	}
+
+	fn irq_line(&self) -> u8 {
+		self.irq
+	}
+
+	fn config_read(&self, offset: u32, size: u8) -> u32 {
+		let mut header = [0u8; 0x40];
+		header[0x0..0x2].copy_from_slice(&VENDOR_ID.to_le_bytes());
+		header[0x2..0x4].copy_from_slice(&DEVICE_ID.to_le_bytes());
+		header[0x8..0xc].copy_from_slice(&(CLASS_CODE << 8).to_le_bytes());
+		header[0x10..0x14].copy_from_slice(&(u32::from(IOBASE) | 1).to_le_bytes());
+		// subsystem ID, which identifies the type of a legacy virtio device
+		header[0x2e..0x30].copy_from_slice(&VIRTIO_ID_BALLOON.to_le_bytes());
+		header[0x3c] = self.irq;
+		// INTA#
+		header[0x3d] = 1;
+
+		let mut bytes = [0u8; 4];
+		if let Some(registers) = header.get(offset as usize..) {
+			let len = registers.len().min(size.into());
+			bytes[..len].copy_from_slice(&registers[..len]);
+		}
+		u32::from_le_bytes(bytes)
+	}
+
+	fn config_write(&mut self, _offset: u32, _size: u8, _value: u32) {
+		// the BAR is fixed
+	}
+}
+
+/// Dispatches the accesses to the I/O ports of the balloon.
+pub struct VirtioBalloonHandler(pub Arc<Mutex<VirtioBalloonDevice>>);
+
+impl PortIoHandler for VirtioBalloonHandler {
+	fn handle_in(&self, _vcpu: &UhyveCPU, port: u16, data: &mut [u8]) -> ExitAction {
+		self.0.lock().unwrap().read_port(port, data);
+		ExitAction::Continue
+	}
+
+	fn handle_out(&self, _vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction {
+		self.0.lock().unwrap().write_port(port, data);
+		ExitAction::Continue
+	}
+}
+
+/// Parses the target size of the balloon like `512MiB` into pages.
+fn parse_target(target: &str) -> Option<u32> {
+	let bytes = Byte::from_str(target.trim()).ok()?.get_bytes();
+	u32::try_from(bytes / BALLOON_PAGE_SIZE as u128).ok()
+}
+
+/// Adjusts the balloon to the size in a control file and publishes the statistics of the guest.
+#[derive(Debug)]
+pub struct BalloonControl {
+	stop: Sender<()>,
+	thread: JoinHandle<()>,
+}
+
+impl BalloonControl {
+	pub fn start(device: Arc<Mutex<VirtioBalloonDevice>>, path: &Path) -> Self {
+		let path = path.to_path_buf();
+		let mut stats_path = path.clone().into_os_string();
+		stats_path.push(".stats");
+		let stats_path = PathBuf::from(stats_path);
+
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			let mut last_stats = None;
+			loop {
+				// a missing control file leaves the balloon as it is
+				if let Ok(target) = fs::read_to_string(&path) {
+					match parse_target(&target) {
+						Some(num_pages) => device.lock().unwrap().set_target(num_pages),
+						None => warn!("Invalid balloon size {:?} in {}", target, path.display()),
+					}
+				}
+
+				let stats = {
+					let mut device = device.lock().unwrap();
+					device.request_stats();
+					device.stats().cloned()
+				};
+				if let Some(current) = stats.as_ref().filter(|_| stats != last_stats) {
+					let contents = current.to_string();
+					if let Err(err) = fs::write(&stats_path, contents) {
+						warn!("Unable to write {}: {}", stats_path.display(), err);
+					}
+					last_stats = stats;
+				}
+
+				if !matches!(
+					rx.recv_timeout(CONTROL_INTERVAL),
+					Err(RecvTimeoutError::Timeout)
+				) {
+					break;
+				}
+			}
+		});
+		Self { stop, thread }
+	}
+
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		self.thread.join().unwrap();
+	}
+}
+
+/// Inflates the balloon to its initial size, once the driver of the guest is ready.
+///
+/// The driver is ready, when it has reported its first statistics. The balloon is expected to
+/// reach its size within [`TARGET_TIMEOUT`], after which its actual size is logged.
+#[derive(Debug)]
+pub struct BalloonTarget {
+	stop: Sender<()>,
+	thread: JoinHandle<()>,
+}
+
+impl BalloonTarget {
+	pub fn start(device: Arc<Mutex<VirtioBalloonDevice>>, num_pages: u32) -> Self {
+		let (stop, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			let wait = || {
+				matches!(
+					rx.recv_timeout(TARGET_INTERVAL),
+					Err(RecvTimeoutError::Timeout)
+				)
+			};
+
+			while device.lock().unwrap().stats().is_none() {
+				if !wait() {
+					return;
+				}
+			}
+			device.lock().unwrap().set_target(num_pages);
+
+			let start = Instant::now();
+			loop {
+				let actual = device.lock().unwrap().actual();
+				if actual >= num_pages {
+					info!(
+						"virtio-balloon: inflated to {} pages after {:?}",
+						actual,
+						start.elapsed()
+					);
+					return;
+				}
+				if start.elapsed() >= TARGET_TIMEOUT {
+					warn!(
+						"virtio-balloon: {} of {} pages after {:?}",
+						actual, num_pages, TARGET_TIMEOUT
+					);
+					return;
+				}
+				if !wait() {
+					return;
+				}
+			}
+		});
+		Self { stop, thread }
+	}
+
+	pub fn stop(self) {
+		let _ = self.stop.send(());
+		self.thread.join().unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+	use std::ptr;
+
+	/// Maps a mock guest memory of 64 KiB.
+	fn guest_memory() -> (*mut u8, usize) {
+		let len = 0x10000;
+		let addr = unsafe {
+			mmap(
+				ptr::null_mut(),
+				len,
+				ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+				MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+				-1,
+				0,
+			)
+		}
+		.unwrap();
+		(addr as *mut u8, len)
+	}
+
+	#[test]
+	fn test_inflate() {
+		let mem = guest_memory();
+		let write = |gpa: usize, bytes: &[u8]| unsafe {
+			ptr::copy_nonoverlapping(bytes.as_ptr(), mem.0.add(gpa), bytes.len())
+		};
+		let mut device = VirtioBalloonDevice::new(mem, EventFd::new(0).unwrap(), 10);
+		device.write_port(VIRTIO_BALLOON_QUEUE_SEL, &[INFLATE_QUEUE as u8, 0]);
+		device.write_port(VIRTIO_BALLOON_QUEUE_PFN, &0u32.to_le_bytes());
+		device.write_port(VIRTIO_BALLOON_STATUS, &[STATUS_DRIVER_OK]);
+
+		// the pages 14 and 15 are handed to the balloon
+		write(14 * BALLOON_PAGE_SIZE, &[0xff; 2 * BALLOON_PAGE_SIZE]);
+		let mut pfns = 14u32.to_le_bytes().to_vec();
+		pfns.extend_from_slice(&15u32.to_le_bytes());
+		write(0x3000, &pfns);
+		let mut desc = [0u8; 16];
+		desc[0..8].copy_from_slice(&0x3000u64.to_le_bytes());
+		desc[8..12].copy_from_slice(&8u32.to_le_bytes());
+		write(0, &desc);
+		// the available ring follows the 256 descriptors
+		write(0x1002, &1u16.to_le_bytes());
+		device.write_port(VIRTIO_BALLOON_QUEUE_NOTIFY, &[INFLATE_QUEUE as u8, 0]);
+
+		let page = unsafe {
+			std::slice::from_raw_parts(mem.0.add(14 * BALLOON_PAGE_SIZE), 2 * BALLOON_PAGE_SIZE)
+		};
+		assert!(page.iter().all(|&byte| byte == 0));
+		// the used ring starts at the next page
+		let used = unsafe { ptr::read_unaligned(mem.0.add(0x2002) as *const u16) };
+		assert_eq!(used, 1);
+		assert_eq!(device.interrupt.read().unwrap(), 1);
+		assert_eq!(device.isr, ISR_QUEUE);
+
+		device.write_port(
+			VIRTIO_BALLOON_CONFIG + CONFIG_ACTUAL as u16,
+			&2u32.to_le_bytes(),
+		);
+		assert_eq!(device.actual(), 2);
+		unsafe { munmap(mem.0.cast(), mem.1) }.unwrap();
+	}
+
+	#[test]
+	fn test_balloon_stats() {
+		let mut buffer = Vec::new();
+		for (tag, value) in [(4u16, 1000u64), (5, 4000), (42, 1)] {
+			buffer.extend_from_slice(&tag.to_le_bytes());
+			buffer.extend_from_slice(&value.to_le_bytes());
+		}
+		let stats = BalloonStats::parse(&buffer);
+		assert_eq!(stats.values[4], Some(1000));
+		assert_eq!(stats.values[5], Some(4000));
+		assert_eq!(stats.values[0], None);
+		assert_eq!(stats.to_string(), "free_memory 1000\ntotal_memory 4000\n");
+	}
+
+	#[test]
+	fn test_parse_target() {
+		assert_eq!(parse_target("8 KiB\n"), Some(2));
+		assert_eq!(parse_target("1MiB"), Some(256));
+		assert_eq!(parse_target("0"), Some(0));
+		assert_eq!(parse_target("lots"), None);
+	}
+
+	#[test]
+	fn test_balloon_target() {
+		let mem = guest_memory();
+		let device = Arc::new(Mutex::new(VirtioBalloonDevice::new(
+			mem,
+			EventFd::new(0).unwrap(),
+			10,
+		)));
+		device.lock().unwrap().status = STATUS_DRIVER_OK;
+		let target = BalloonTarget::start(device.clone(), 4);
+
+		// the target is set only after the first statistics
+		thread::sleep(5 * TARGET_INTERVAL);
+		assert_eq!(device.lock().unwrap().num_pages, 0);
+		device.lock().unwrap().stats = Some(BalloonStats::default());
+		thread::sleep(5 * TARGET_INTERVAL);
+		{
+			let device = device.lock().unwrap();
+			assert_eq!(device.num_pages, 4);
+			assert_eq!(device.isr, ISR_CONFIG);
+		}
+
+		device.lock().unwrap().write_port(
+			VIRTIO_BALLOON_CONFIG + CONFIG_ACTUAL as u16,
+			&4u32.to_le_bytes(),
+		);
+		thread::sleep(5 * TARGET_INTERVAL);
+		assert!(target.thread.is_finished());
+		target.stop();
+		unsafe { munmap(mem.0.cast(), mem.1) }.unwrap();
+	}
+}
@@ -0,0 +1,669 @@
+//! A virtio block device, which is backed by a raw image file of the host, see `--disk`.
+//!
+//! Like virtio-net, the device uses the legacy I/O port interface of virtio, but at its own BAR.
+//! The guest notifies the request queue after it has made requests available, which are then
+//! executed synchronously on the notifying vCPU. Each request is a descriptor chain of a
+//! [`RequestHeader`], the data buffers and a status byte. As the vCPU only resumes after all
+//! requests have been completed, the device raises no interrupt.
+//!
+//! Discarded sectors are punched out of the image, so that thin-provisioned images shrink again.
+//! The device only offers `VIRTIO_BLK_F_DISCARD`, if the file system of the image supports this.
+//! With an overlay, the image is never written, see [`CoWBlockDevice`].
+
+use std::{
+	fs::{File, OpenOptions},
+	io,
+	mem::size_of,
+	os::unix::{fs::FileExt, io::AsRawFd},
+	path::Path,
+	ptr,
+	sync::{Arc, Mutex},
+};
+
+use nix::fcntl::{self, FallocateFlags};
+use virtio_bindings::bindings::virtio_blk::*;
+
+use crate::linux::cow_blk::CoWBlockDevice;
+use crate::linux::pci::{PciBar, PciDevice};
+use crate::linux::vcpu::{ExitAction, PortIoHandler, UhyveCPU};
+use crate::linux::virtqueue::*;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const IOBASE: u16 = 0xc040;
+const VIRTIO_BLK_HOST_FEATURES: u16 = IOBASE;
+const VIRTIO_BLK_GUEST_FEATURES: u16 = IOBASE + 4;
+const VIRTIO_BLK_QUEUE_PFN: u16 = IOBASE + 8;
+const VIRTIO_BLK_QUEUE_NUM: u16 = IOBASE + 12;
+const VIRTIO_BLK_QUEUE_SEL: u16 = IOBASE + 14;
+const VIRTIO_BLK_QUEUE_NOTIFY: u16 = IOBASE + 16;
+const VIRTIO_BLK_STATUS: u16 = IOBASE + 18;
+const VIRTIO_BLK_ISR: u16 = IOBASE + 19;
+/// `struct virtio_blk_config`
+const VIRTIO_BLK_CONFIG: u16 = IOBASE + 20;
+const VIRTIO_BLK_CONFIG_MAX: u16 = VIRTIO_BLK_CONFIG + CONFIG_SIZE as u16 - 1;
+/// Size of the device configuration up to `discard_sector_alignment`
+const CONFIG_SIZE: usize = 48;
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+
+// virtio-bindings are generated from Linux 4.14, which predates discard
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+/// Largest number of segments of a discard request
+const MAX_DISCARD_SEG: u32 = 32;
+
+const STATUS_DRIVER_OK: u8 = 0b00000100;
+const STATUS_FEATURES_OK: u8 = 0b00001000;
+const STATUS_DRIVER_NEEDS_RESET: u8 = 0b01000000;
+
+const VENDOR_ID: u16 = 0x1AF4;
+/// Transitional device ID of a block device
+const DEVICE_ID: u16 = 0x1001;
+/// Mass storage controller, SCSI
+const CLASS_CODE: u32 = 0x010000;
+/// Subsystem ID of a legacy block device
+const VIRTIO_ID_BLOCK: u16 = 2;
+const REQUEST_QUEUE: usize = 0;
+
+/// The I/O ports of the block device.
+pub fn ports() -> impl Iterator<Item = u16> {
+	[
+		VIRTIO_BLK_HOST_FEATURES,
+		VIRTIO_BLK_GUEST_FEATURES,
+		VIRTIO_BLK_QUEUE_PFN,
+		VIRTIO_BLK_QUEUE_NUM,
+		VIRTIO_BLK_QUEUE_SEL,
+		VIRTIO_BLK_QUEUE_NOTIFY,
+		VIRTIO_BLK_STATUS,
+		VIRTIO_BLK_ISR,
+	]
+	.into_iter()
+	.chain(VIRTIO_BLK_CONFIG..=VIRTIO_BLK_CONFIG_MAX)
+}
+
+/// `struct virtio_blk_outhdr`, which starts every request.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RequestHeader {
+	pub request_type: u32,
+	pub reserved: u32,
+	pub sector: u64,
+}
+
+/// `struct virtio_blk_discard_write_zeroes`, a segment of a discard request.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiscardSegment {
+	pub sector: u64,
+	pub num_sectors: u32,
+	pub flags: u32,
+}
+
+#[derive(Debug)]
+enum Storage {
+	Raw(File),
+	CoW(CoWBlockDevice),
+}
+
+impl Storage {
+	fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+		match self {
+			Self::Raw(file) => file.read_exact_at(buf, offset),
+			Self::CoW(disk) => disk.read_exact_at(buf, offset),
+		}
+	}
+
+	fn write_all_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+		match self {
+			Self::Raw(file) => file.write_all_at(buf, offset),
+			Self::CoW(disk) => disk.write_all_at(buf, offset),
+		}
+	}
+
+	fn sync_data(&self) -> io::Result<()> {
+		match self {
+			Self::Raw(file) => file.sync_data(),
+			Self::CoW(disk) => disk.sync_data(),
+		}
+	}
+}
+
+/// The raw image file of a block device, optionally with a copy-on-write overlay.
+#[derive(Debug)]
+pub struct BlockImage {
+	storage: Storage,
+	readonly: bool,
+	/// Size of the image in sectors, a partial last sector is ignored
+	capacity: u64,
+	/// The file system supports punching holes into the image
+	discard: bool,
+}
+
+impl BlockImage {
+	pub fn open(path: &Path, readonly: bool) -> io::Result<Self> {
+		let file = OpenOptions::new().read(true).write(!readonly).open(path)?;
+		let len = file.metadata()?.len();
+		let discard = !readonly && punch_hole(&file, len, SECTOR_SIZE).is_ok();
+		Ok(Self {
+			storage: Storage::Raw(file),
+			readonly,
+			capacity: len / SECTOR_SIZE,
+			discard,
+		})
+	}
+
+	/// Opens the image read-only and writes to the `overlay`, or to a temporary one without it.
+	pub fn with_overlay(path: &Path, overlay: Option<&Path>) -> io::Result<Self> {
+		let file = File::open(path)?;
+		let capacity = file.metadata()?.len() / SECTOR_SIZE;
+		let disk = match overlay {
+			Some(overlay) => CoWBlockDevice::open(file, capacity, overlay)?,
+			None => CoWBlockDevice::open_temporary(file, capacity)?,
+		};
+		Ok(Self {
+			storage: Storage::CoW(disk),
+			readonly: false,
+			capacity,
+			discard: false,
+		})
+	}
+
+	pub fn capacity(&self) -> u64 {
+		self.capacity
+	}
+
+	pub fn supports_discard(&self) -> bool {
+		self.discard
+	}
+
+	/// Deallocates the sectors of the discard segments in `buffers`.
+	fn discard_segments(&self, buffers: &[&mut [u8]]) -> io::Result<()> {
+		let file = match &self.storage {
+			Storage::Raw(file) => file,
+			Storage::CoW(_) => unreachable!("overlays do not support discard"),
+		};
+		let segments = buffers.concat();
+		if segments.len() % size_of::<DiscardSegment>() != 0
+			|| segments.len() / size_of::<DiscardSegment>() > MAX_DISCARD_SEG as usize
+		{
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"malformed discard request",
+			));
+		}
+		for segment in segments.chunks_exact(size_of::<DiscardSegment>()) {
+			let segment = unsafe { ptr::read_unaligned(segment.as_ptr() as *const DiscardSegment) };
+			let len = u64::from(segment.num_sectors) * SECTOR_SIZE;
+			let offset = self.check_range(segment.sector, len as usize)?;
+			punch_hole(file, offset, len)?;
+		}
+		Ok(())
+	}
+
+	/// Checks, whether `len` bytes starting at `sector` are within the image.
+	fn check_range(&self, sector: u64, len: usize) -> io::Result<u64> {
+		sector
+			.checked_mul(SECTOR_SIZE)
+			.filter(|offset| {
+				offset
+					.checked_add(len as u64)
+					.map_or(false, |end| end <= self.capacity * SECTOR_SIZE)
+			})
+			.ok_or_else(|| {
+				io::Error::new(
+					io::ErrorKind::InvalidInput,
+					format!("sector {} is beyond the end of the disk", sector),
+				)
+			})
+	}
+
+	/// Executes a request on the data `buffers` and returns its status and the number of bytes,
+	/// which have been written into the buffers.
+	pub fn execute(&mut self, header: &RequestHeader, buffers: &mut [&mut [u8]]) -> (u8, u32) {
+		let len = buffers.iter().map(|buffer| buffer.len()).sum();
+		let result = match header.request_type {
+			VIRTIO_BLK_T_IN => self.check_range(header.sector, len).and_then(|offset| {
+				let mut offset = offset;
+				for buffer in buffers.iter_mut() {
+					self.storage.read_exact_at(buffer, offset)?;
+					offset += buffer.len() as u64;
+				}
+				Ok(len as u32)
+			}),
+			VIRTIO_BLK_T_OUT if self.readonly => Err(io::Error::new(
+				io::ErrorKind::PermissionDenied,
+				"the disk is read-only",
+			)),
+			VIRTIO_BLK_T_OUT => self.check_range(header.sector, len).and_then(|offset| {
+				let mut offset = offset;
+				for buffer in buffers.iter() {
+					self.storage.write_all_at(buffer, offset)?;
+					offset += buffer.len() as u64;
+				}
+				Ok(0)
+			}),
+			VIRTIO_BLK_T_DISCARD if self.discard => self.discard_segments(buffers).map(|()| 0),
+			VIRTIO_BLK_T_FLUSH if self.readonly => Ok(0),
+			VIRTIO_BLK_T_FLUSH => self.storage.sync_data().map(|()| 0),
+			request_type => {
+				debug!("virtio-blk: unsupported request {}", request_type);
+				return (VIRTIO_BLK_S_UNSUPP as u8, 0);
+			}
+		};
+		match result {
+			Ok(written) => (VIRTIO_BLK_S_OK as u8, written),
+			Err(err) => {
+				warn!("virtio-blk: {}", err);
+				(VIRTIO_BLK_S_IOERR as u8, 0)
+			}
+		}
+	}
+}
+
+/// Deallocates `len` bytes at `offset` of `file` without changing its size.
+///
+/// Beyond the end of the file, this only checks whether the file system supports holes.
+fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+	fcntl::fallocate(
+		file.as_raw_fd(),
+		FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+		offset as i64,
+		len as i64,
+	)
+	.map_err(|errno| io::Error::from_raw_os_error(errno as i32))
+}
+
+pub struct VirtioBlkDevice {
+	image: BlockImage,
+	status: u8,
+	requested_features: u32,
+	selected_queue_num: u16,
+	request_queue: Option<Virtqueue>,
+	/// The guest memory, in which the requests are located
+	guest_mem: (*mut u8, usize),
+}
+
+// SAFETY: The virtqueue and `guest_mem` point into the guest memory, which stays mapped as long as
+// the VM owning the device, and they are only accessed with the device locked.
+unsafe impl Send for VirtioBlkDevice {}
+
+impl VirtioBlkDevice {
+	pub fn new(image: BlockImage, guest_mem: (*mut u8, usize)) -> Self {
+		Self {
+			image,
+			guest_mem,
+			status: 0,
+			requested_features: 0,
+			selected_queue_num: 0,
+			request_queue: None,
+		}
+	}
+
+	fn host_features(&self) -> u32 {
+		let mut features = 1 << VIRTIO_BLK_F_FLUSH;
+		if self.image.readonly {
+			features |= 1 << VIRTIO_BLK_F_RO;
+		}
+		if self.image.discard {
+			features |= 1 << VIRTIO_BLK_F_DISCARD;
+		}
+		features
+	}
+
+	fn config(&self) -> [u8; CONFIG_SIZE] {
+		let mut config = [0u8; CONFIG_SIZE];
+		config[CONFIG_CAPACITY..CONFIG_CAPACITY + 8]
+			.copy_from_slice(&self.image.capacity.to_le_bytes());
+		if self.image.discard {
+			let fields = [
+				(CONFIG_MAX_DISCARD_SECTORS, u32::MAX),
+				(CONFIG_MAX_DISCARD_SEG, MAX_DISCARD_SEG),
+				(CONFIG_DISCARD_SECTOR_ALIGNMENT, 1),
+			];
+			for (offset, value) in fields {
+				config[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+			}
+		}
+		config
+	}
+
+	fn reset(&mut self) {
+		self.status = 0;
+		self.requested_features = 0;
+		self.selected_queue_num = 0;
+		self.request_queue = None;
+	}
+
+	fn read_port(&self, port: u16, data: &mut [u8]) {
+		let value = match port {
+			VIRTIO_BLK_HOST_FEATURES => self.host_features().into(),
+			VIRTIO_BLK_GUEST_FEATURES => self.requested_features.into(),
+			VIRTIO_BLK_QUEUE_NUM => QUEUE_LIMIT as u64,
+			VIRTIO_BLK_STATUS => self.status.into(),
+			VIRTIO_BLK_CONFIG..=VIRTIO_BLK_CONFIG_MAX => {
+				let config = self.config();
+				let offset = usize::from(port - VIRTIO_BLK_CONFIG);
+				let len = data.len().min(CONFIG_SIZE - offset);
+				data.fill(0);
+				data[..len].copy_from_slice(&config[offset..offset + len]);
+				return;
+			}
+			_ => 0,
+		};
+		let len = data.len().min(size_of::<u64>());
+		data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+	}
+
+	fn write_port(&mut self, port: u16, value: u32) {
+		match port {
+			VIRTIO_BLK_GUEST_FEATURES if self.status & STATUS_FEATURES_OK == 0 => {
+				self.requested_features = value & self.host_features();
+			}
+			VIRTIO_BLK_QUEUE_SEL => self.selected_queue_num = value as u16,
+			VIRTIO_BLK_QUEUE_PFN => self.write_pfn(value.into()),
+			VIRTIO_BLK_QUEUE_NOTIFY if value as usize == REQUEST_QUEUE => {
+				if self.status & STATUS_DRIVER_OK != 0 {
+					self.process_requests();
+				}
+			}
+			VIRTIO_BLK_STATUS if value == 0 => self.reset(),
+			VIRTIO_BLK_STATUS => self.status = value as u8,
+			_ => debug!("virtio-blk: ignoring a write to port 0x{:x}", port),
+		}
+	}
+
+	// Register the virtqueue, the address is passed as by virtio-net
+	fn write_pfn(&mut self, gpa: u64) {
+		if self.selected_queue_num as usize == REQUEST_QUEUE && self.status & STATUS_DRIVER_OK == 0
+		{
+			self.request_queue = unsafe { Virtqueue::legacy(self.guest_mem, gpa) };
+			if self.request_queue.is_none() {
+				warn!("virtio-blk: the request queue exceeds the guest memory");
+				self.status |= STATUS_DRIVER_NEEDS_RESET;
+			}
+		}
+	}
+
+	/// Executes all requests, which the guest has made available.
+	fn process_requests(&mut self) {
+		let queue = match &mut self.request_queue {
+			Some(queue) => queue,
+			None => return,
+		};
+		while let Some(head) = queue.next_avail_desc() {
+			let mut chain = vec![head];
+			while let Some(desc) = queue.next_desc(chain.last().unwrap()) {
+				if chain.len() > QUEUE_LIMIT {
+					break;
+				}
+				chain.push(desc);
+			}
+
+			// header, data buffers and the status byte
+			let well_formed = chain.len() >= 2
+				&& chain.iter().all(|desc| desc.host_addr.is_some())
+				&& chain[0].len as usize >= size_of::<RequestHeader>()
+				&& chain[chain.len() - 1].len >= 1
+				&& chain[chain.len() - 1].is_write_only();
+			if !well_formed {
+				warn!("virtio-blk: malformed request");
+				self.status |= STATUS_DRIVER_NEEDS_RESET;
+				queue.push_used(head.index, 0);
+				continue;
+			}
+
+			let header =
+				unsafe { ptr::read_unaligned(chain[0].host_addr.unwrap() as *const RequestHeader) };
+			let (status_byte, data) = chain[1..].split_last().unwrap();
+			let mut buffers = data
+				.iter()
+				.map(|desc| unsafe { desc.buffer().unwrap() })
+				.collect::<Vec<_>>();
+			let (status, written) = self.image.execute(&header, &mut buffers);
+			unsafe { status_byte.host_addr.unwrap().write(status) };
+			queue.push_used(head.index, written + 1);
+		}
+		queue.notify_guest();
+	}
+}
+
+impl PciDevice for VirtioBlkDevice {
+	fn vendor_id(&self) -> u16 {
+		VENDOR_ID
+	}
+
+	fn device_id(&self) -> u16 {
+		DEVICE_ID
+	}
+
+	fn class_code(&self) -> u32 {
+		CLASS_CODE
+	}
+
+	fn bar(&self, n: u8) -> Option<PciBar> {
+		match n {
+			0 => Some(PciBar::Io(IOBASE)),
+			_ => None,
+		}
+	}
+
+	fn irq_line(&self) -> u8 {
+		0
+	}
+
+	fn config_read(&self, offset: u32, size: u8) -> u32 {
+		let mut header = [0u8; 0x40];
+		header[0x0..0x2].copy_from_slice(&VENDOR_ID.to_le_bytes());
+		header[0x2..0x4].copy_from_slice(&DEVICE_ID.to_le_bytes());
+		header[0x8..0xc].copy_from_slice(&(CLASS_CODE << 8).to_le_bytes());
+		header[0x10..0x14].copy_from_slice(&(u32::from(IOBASE) | 1).to_le_bytes());
+		// subsystem ID, which identifies the type of a legacy virtio device
+		header[0x2e..0x30].copy_from_slice(&VIRTIO_ID_BLOCK.to_le_bytes());
+
+		let mut bytes = [0u8; 4];
+		if let Some(registers) = header.get(offset as usize..) {
+			let len = registers.len().min(size.into());
+			bytes[..len].copy_from_slice(&registers[..len]);
+		}
+		u32::from_le_bytes(bytes)
+	}
+
+	fn config_write(&mut self, _offset: u32, _size: u8, _value: u32) {
+		// the BAR is fixed
+	}
+}
+
+/// Dispatches the accesses to the I/O ports of the block device.
+pub struct VirtioBlkHandler(pub Arc<Mutex<VirtioBlkDevice>>);
+
+impl PortIoHandler for VirtioBlkHandler {
+	fn handle_in(&self, _vcpu: &UhyveCPU, port: u16, data: &mut [u8]) -> ExitAction {
+		self.0.lock().unwrap().read_port(port, data);
+		ExitAction::Continue
+	}
+
+	fn handle_out(&self, _vcpu: &mut UhyveCPU, port: u16, data: &[u8]) -> ExitAction {
+		let mut value = [0u8; 4];
+		value[..data.len().min(4)].copy_from_slice(&data[..data.len().min(4)]);
+		self.0
+			.lock()
+			.unwrap()
+			.write_port(port, u32::from_le_bytes(value));
+		ExitAction::Continue
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use assert_fs::TempDir;
+	use std::{fs, slice};
+
+	fn request(request_type: u32, sector: u64) -> RequestHeader {
+		RequestHeader {
+			request_type,
+			sector,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_block_image() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("disk.img");
+		// the partial last sector is not part of the disk
+		fs::write(&path, vec![0u8; 4 * SECTOR_SIZE as usize + 100]).unwrap();
+
+		let mut image = BlockImage::open(&path, false).unwrap();
+		assert_eq!(image.capacity(), 4);
+
+		let mut first = vec![1u8; SECTOR_SIZE as usize];
+		let mut second = vec![2u8; SECTOR_SIZE as usize];
+		let (status, written) = image.execute(
+			&request(VIRTIO_BLK_T_OUT, 2),
+			&mut [&mut first[..], &mut second[..]],
+		);
+		assert_eq!((status, written), (VIRTIO_BLK_S_OK as u8, 0));
+		assert_eq!(
+			image.execute(&request(VIRTIO_BLK_T_FLUSH, 0), &mut []),
+			(VIRTIO_BLK_S_OK as u8, 0)
+		);
+
+		let mut buffer = vec![0u8; 2 * SECTOR_SIZE as usize];
+		let (status, written) = image.execute(&request(VIRTIO_BLK_T_IN, 1), &mut [&mut buffer[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+		assert_eq!(written, 2 * SECTOR_SIZE as u32);
+		assert!(buffer[..SECTOR_SIZE as usize].iter().all(|&byte| byte == 0));
+		assert!(buffer[SECTOR_SIZE as usize..].iter().all(|&byte| byte == 1));
+
+		// beyond the end of the disk
+		let (status, _) = image.execute(&request(VIRTIO_BLK_T_IN, 3), &mut [&mut buffer[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+		let (status, _) = image.execute(&request(0xff, 0), &mut []);
+		assert_eq!(status, VIRTIO_BLK_S_UNSUPP as u8);
+	}
+
+	#[test]
+	fn test_readonly_block_image() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("disk.img");
+		fs::write(&path, vec![7u8; SECTOR_SIZE as usize]).unwrap();
+
+		let mut image = BlockImage::open(&path, true).unwrap();
+		let mut buffer = vec![0u8; SECTOR_SIZE as usize];
+		let (status, _) = image.execute(&request(VIRTIO_BLK_T_OUT, 0), &mut [&mut buffer[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_IOERR as u8);
+		let (status, _) = image.execute(&request(VIRTIO_BLK_T_IN, 0), &mut [&mut buffer[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+		assert!(buffer.iter().all(|&byte| byte == 7));
+
+		let device = VirtioBlkDevice::new(image, (ptr::null_mut(), 0));
+		assert_ne!(device.host_features() & (1 << VIRTIO_BLK_F_RO), 0);
+		let mut capacity = [0u8; 4];
+		device.read_port(VIRTIO_BLK_CONFIG, &mut capacity);
+		assert_eq!(u32::from_le_bytes(capacity), 1);
+		assert_eq!(device.host_features() & (1 << VIRTIO_BLK_F_DISCARD), 0);
+	}
+
+	#[test]
+	fn test_process_requests() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("disk.img");
+		fs::write(&path, vec![7u8; SECTOR_SIZE as usize]).unwrap();
+		let image = BlockImage::open(&path, false).unwrap();
+
+		// a mock guest memory with the request queue in the legacy layout at 0
+		let mut memory = vec![0u64; 0x4000 / size_of::<u64>()];
+		let mem = (
+			memory.as_mut_ptr() as *mut u8,
+			memory.len() * size_of::<u64>(),
+		);
+		let write = |gpa: usize, bytes: &[u8]| unsafe {
+			ptr::copy_nonoverlapping(bytes.as_ptr(), mem.0.add(gpa), bytes.len())
+		};
+		let mut device = VirtioBlkDevice::new(image, mem);
+		device.write_port(VIRTIO_BLK_QUEUE_SEL, REQUEST_QUEUE as u32);
+		device.write_port(VIRTIO_BLK_QUEUE_PFN, 0);
+		device.write_port(VIRTIO_BLK_STATUS, STATUS_DRIVER_OK.into());
+
+		// header, sector and status byte chained from descriptor 0
+		let header = request(VIRTIO_BLK_T_IN, 0);
+		write(0x3000, unsafe {
+			slice::from_raw_parts(
+				&header as *const RequestHeader as *const u8,
+				size_of::<RequestHeader>(),
+			)
+		});
+		let buffers = [
+			(0x3000u64, size_of::<RequestHeader>() as u32, 0u16),
+			(0x3100, SECTOR_SIZE as u32, 2),
+			(0x3400, 1, 2),
+		];
+		for (index, (addr, len, flags)) in buffers.into_iter().enumerate() {
+			let next = if index < 2 { 1 } else { 0 };
+			let mut desc = [0u8; 16];
+			desc[0..8].copy_from_slice(&addr.to_le_bytes());
+			desc[8..12].copy_from_slice(&len.to_le_bytes());
+			desc[12..14].copy_from_slice(&(flags | next).to_le_bytes());
+			desc[14..16].copy_from_slice(&(index as u16 + 1).to_le_bytes());
+			write(index * 16, &desc);
+		}
+		// the available ring follows the 256 descriptors
+		write(0x1002, &1u16.to_le_bytes());
+		device.write_port(VIRTIO_BLK_QUEUE_NOTIFY, REQUEST_QUEUE as u32);
+
+		let read = |gpa: usize, len: usize| unsafe { slice::from_raw_parts(mem.0.add(gpa), len) };
+		assert!(read(0x3100, SECTOR_SIZE as usize)
+			.iter()
+			.all(|&byte| byte == 7));
+		assert_eq!(read(0x3400, 1), [VIRTIO_BLK_S_OK as u8]);
+		// the used ring starts at the next page
+		assert_eq!(read(0x2002, 2), 1u16.to_le_bytes());
+		assert_eq!(read(0x2008, 4), (SECTOR_SIZE as u32 + 1).to_le_bytes());
+	}
+
+	#[test]
+	fn test_discard() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("disk.img");
+		File::create(&path).unwrap().set_len(1 << 20).unwrap();
+
+		let mut image = BlockImage::open(&path, false).unwrap();
+		if !image.supports_discard() {
+			eprintln!("The file system of the temporary directory cannot punch holes");
+			return;
+		}
+		let mut data = vec![0xaau8; 64 * 1024];
+		let (status, _) = image.execute(&request(VIRTIO_BLK_T_OUT, 0), &mut [&mut data[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+		let fd = match &image.storage {
+			Storage::Raw(file) => file.as_raw_fd(),
+			Storage::CoW(_) => unreachable!(),
+		};
+		let hole = || unsafe { libc::lseek(fd, 0, libc::SEEK_HOLE) };
+		assert_eq!(hole(), data.len() as i64);
+
+		let segment = DiscardSegment {
+			sector: 0,
+			num_sectors: (data.len() as u64 / SECTOR_SIZE) as u32,
+			flags: 0,
+		};
+		let mut segment = unsafe {
+			slice::from_raw_parts(
+				&segment as *const DiscardSegment as *const u8,
+				size_of::<DiscardSegment>(),
+			)
+		}
+		.to_vec();
+		let (status, _) = image.execute(&request(VIRTIO_BLK_T_DISCARD, 0), &mut [&mut segment[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+		assert_eq!(hole(), 0);
+
+		let (status, _) = image.execute(&request(VIRTIO_BLK_T_IN, 0), &mut [&mut data[..]]);
+		assert_eq!(status, VIRTIO_BLK_S_OK as u8);
+		assert!(data.iter().all(|&byte| byte == 0));
+	}
+}
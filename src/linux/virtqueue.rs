@@ -1,7 +1,20 @@
+//! Split virtqueues as used by the virtio devices of uhyve.
+//!
+//! [`Virtqueue::next_avail_desc`], [`Virtqueue::next_desc`] and [`Virtqueue::push_used`] check
+//! that the guest's indices and buffers stay within the queue and the guest memory, so the devices
+//! do not need any pointer arithmetic.
+
 use crate::consts::PAGE_SIZE;
+use std::iter;
 use std::marker::PhantomData;
 use std::mem;
 use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use virtio_bindings::bindings::virtio_ring::{
+	VRING_AVAIL_F_NO_INTERRUPT, VRING_DESC_F_NEXT, VRING_DESC_F_WRITE,
+};
+use vmm_sys_util::eventfd::EventFd;
 
 pub const QUEUE_LIMIT: usize = 256;
 
@@ -50,8 +63,12 @@ impl<T> Vring<T> {
 	}
 
 	pub fn ring_elem(&mut self, index: u16) -> &mut T {
-		let elem_size = mem::size_of::<T>() as u16;
-		unsafe { &mut *(self.mem.offset((4 + index * elem_size) as isize) as *mut T) }
+		let elem_size = mem::size_of::<T>();
+		unsafe { &mut *(self.mem.add(4 + usize::from(index) * elem_size) as *mut T) }
+	}
+
+	fn addr(&self) -> usize {
+		self.mem as usize
 	}
 }
 
@@ -71,9 +88,48 @@ pub struct Virtqueue {
 	pub last_seen_available: u16,
 	pub last_seen_used: u16,
 	pub queue_size: u16,
+	/// The guest memory, in which the buffers are translated
+	mem: (*mut u8, usize),
+	/// Raises the interrupt of the device, see [`Virtqueue::notify_guest`]
+	interrupt: Option<EventFd>,
+}
+
+/// A descriptor of a virtqueue, whose buffer has been translated into the address space of uhyve.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtqDesc {
+	/// Index in the descriptor table
+	pub index: u16,
+	/// Guest physical address of the buffer
+	pub gpa: u64,
+	/// Host address of the buffer, `None` if the buffer is not within the guest memory
+	pub host_addr: Option<*mut u8>,
+	pub len: u32,
+	pub flags: u16,
+	pub next: u16,
+}
+
+impl VirtqDesc {
+	/// Whether the device writes the buffer instead of reading it.
+	pub fn is_write_only(&self) -> bool {
+		self.flags & VRING_DESC_F_WRITE as u16 != 0
+	}
+
+	pub fn has_next(&self) -> bool {
+		self.flags & VRING_DESC_F_NEXT as u16 != 0
+	}
+
+	/// Returns the buffer, unless it lies outside of the guest memory.
+	///
+	/// # Safety
+	///
+	/// The guest memory has to stay mapped for `'a` and no other reference to the buffer may exist.
+	pub unsafe fn buffer<'a>(&self) -> Option<&'a mut [u8]> {
+		self.host_addr
+			.map(|addr| std::slice::from_raw_parts_mut(addr, self.len as usize))
+	}
 }
 
-pub struct AvailIter<'a> {
+struct AvailIter<'a> {
 	available_ring: &'a VringAvailable,
 	last_seen_available: &'a mut u16,
 	queue_size: u16,
@@ -108,29 +164,96 @@ fn get_used_ring_offset() -> usize {
 	)
 }
 
+/// Returns the size of the guest memory occupied by a virtqueue.
+pub fn get_queue_size_in_bytes() -> usize {
+	get_used_ring_offset() + size_of::<u16>() * 3 + size_of::<VringUsedElement>() * QUEUE_LIMIT
+}
+
+/// Translates `len` bytes at the guest physical address `gpa` into the guest memory `mem`.
+fn translate(mem: (*mut u8, usize), gpa: u64, len: usize) -> Option<*mut u8> {
+	let start = usize::try_from(gpa).ok()?;
+	let end = start.checked_add(len)?;
+	(end <= mem.1).then(|| unsafe { mem.0.add(start) })
+}
+
 impl Virtqueue {
-	pub unsafe fn new(mem: *mut u8, queue_size: usize) -> Self {
+	/// Creates a virtqueue of `size` entries, whose rings are at the given guest physical
+	/// addresses in the guest memory `mem`, as returned by [`Vm::guest_mem`](crate::vm::Vm::guest_mem).
+	///
+	/// Returns `None` if the rings are misaligned, exceed the guest memory or `size` is no power of
+	/// two.
+	///
+	/// # Safety
+	///
+	/// `mem` has to stay mapped as long as the virtqueue is used.
+	pub unsafe fn new(
+		mem: (*mut u8, usize),
+		desc_gpa: u64,
+		avail_gpa: u64,
+		used_gpa: u64,
+		size: u16,
+	) -> Option<Self> {
+		if !size.is_power_of_two() {
+			return None;
+		}
+		let entries = usize::from(size);
+		let ring = |gpa: u64, len: usize, alignment: u64| {
+			Some(gpa)
+				.filter(|gpa| gpa % alignment == 0)
+				.and_then(|gpa| translate(mem, gpa, len))
+		};
 		#[allow(clippy::cast_ptr_alignment)]
-		let descriptor_table = mem as *mut VringDescriptor;
-		let available_ring_ptr = mem.add(get_available_ring_offset());
-		let used_ring_ptr = mem.add(get_used_ring_offset());
-		let available_ring = VringAvailable::new(available_ring_ptr);
-		let used_ring = VringUsed::new(used_ring_ptr);
-		Virtqueue {
+		let descriptor_table =
+			ring(desc_gpa, size_of::<VringDescriptor>() * entries, 16)? as *mut VringDescriptor;
+		let available_ring = ring(avail_gpa, size_of::<u16>() * (entries + 3), 2)?;
+		let used_ring = ring(
+			used_gpa,
+			size_of::<u16>() * 3 + size_of::<VringUsedElement>() * entries,
+			4,
+		)?;
+		Some(Virtqueue {
 			descriptor_table,
-			available_ring,
-			used_ring,
+			available_ring: VringAvailable::new(available_ring),
+			used_ring: VringUsed::new(used_ring),
 			last_seen_available: 0,
 			last_seen_used: 0,
-			queue_size: queue_size as u16,
-		}
+			queue_size: size,
+			mem,
+			interrupt: None,
+		})
+	}
+
+	/// Creates a virtqueue of [`QUEUE_LIMIT`] entries in the layout of the legacy interface, which
+	/// starts at the guest physical address `gpa`.
+	///
+	/// # Safety
+	///
+	/// `mem` has to stay mapped as long as the virtqueue is used.
+	pub unsafe fn legacy(mem: (*mut u8, usize), gpa: u64) -> Option<Self> {
+		Self::new(
+			mem,
+			gpa,
+			gpa + get_available_ring_offset() as u64,
+			gpa + get_used_ring_offset() as u64,
+			QUEUE_LIMIT as u16,
+		)
+	}
+
+	/// Returns the host addresses of the descriptor table, the available ring and the used ring.
+	pub fn ring_addresses(&self) -> (usize, usize, usize) {
+		(
+			self.descriptor_table as usize,
+			self.available_ring.addr(),
+			self.used_ring.addr(),
+		)
 	}
 
-	pub unsafe fn get_descriptor(&mut self, index: u16) -> &mut VringDescriptor {
-		&mut *self.descriptor_table.offset(index as isize)
+	/// Raises `interrupt` in [`Virtqueue::notify_guest`].
+	pub fn set_interrupt(&mut self, interrupt: EventFd) {
+		self.interrupt = Some(interrupt);
 	}
 
-	pub fn avail_iter(&mut self) -> AvailIter<'_> {
+	fn avail_iter(&mut self) -> AvailIter<'_> {
 		AvailIter {
 			available_ring: &self.available_ring,
 			last_seen_available: &mut self.last_seen_available,
@@ -138,11 +261,223 @@ impl Virtqueue {
 		}
 	}
 
-	pub fn add_used(&mut self, desc_index: u32, len: u32) {
+	fn add_used(&mut self, desc_index: u32, len: u32) {
 		let tgt_index = self.used_ring.index() % self.queue_size;
 		let mut used_elem = self.used_ring.ring_elem(tgt_index);
 		used_elem.id = desc_index;
 		used_elem.len = len;
+		// the element has to be visible before the index
+		fence(Ordering::Release);
 		self.used_ring.advance_index();
 	}
+
+	/// Returns the descriptor at `index` of the descriptor table.
+	pub fn descriptor(&self, index: u16) -> Option<VirtqDesc> {
+		if index >= self.queue_size {
+			return None;
+		}
+		let desc = unsafe { ptr::read_volatile(self.descriptor_table.add(index.into())) };
+		Some(VirtqDesc {
+			index,
+			gpa: desc.addr,
+			host_addr: translate(self.mem, desc.addr, desc.len as usize),
+			len: desc.len,
+			flags: desc.flags,
+			next: desc.next,
+		})
+	}
+
+	/// Returns the head of the next descriptor chain, which the guest has made available.
+	///
+	/// Heads outside of the descriptor table are returned as used without being processed.
+	pub fn next_avail_desc(&mut self) -> Option<VirtqDesc> {
+		loop {
+			let position = self.avail_iter().next()?;
+			// the index has been read before the ring entry
+			fence(Ordering::Acquire);
+			let head = *self.available_ring.ring_elem(position);
+			match self.descriptor(head) {
+				Some(desc) => return Some(desc),
+				None => {
+					warn!("Virtqueue: descriptor {} is out of range", head);
+					self.push_used(head, 0);
+				}
+			}
+		}
+	}
+
+	/// Returns the descriptor, which follows `desc` in its chain.
+	pub fn next_desc(&self, desc: &VirtqDesc) -> Option<VirtqDesc> {
+		desc.has_next()
+			.then(|| self.descriptor(desc.next))
+			.flatten()
+	}
+
+	/// Returns the descriptors of the chain, which starts at `head`.
+	///
+	/// The chain ends after `queue_size` descriptors, so a cycle in the descriptor table does not
+	/// make the device loop forever.
+	pub fn chain(&self, head: VirtqDesc) -> impl Iterator<Item = VirtqDesc> + '_ {
+		iter::successors(Some(head), move |desc| self.next_desc(desc)).take(self.queue_size.into())
+	}
+
+	/// Returns the descriptor chain with the head `id` to the guest, after `len` bytes have been
+	/// written into its buffers.
+	pub fn push_used(&mut self, id: u16, len: u32) {
+		self.add_used(id.into(), len);
+	}
+
+	/// Raises the interrupt of the device, unless the guest suppresses it, or does nothing, if the
+	/// device has no interrupt.
+	pub fn notify_guest(&self) {
+		let interrupt = match &self.interrupt {
+			Some(interrupt) => interrupt,
+			None => return,
+		};
+		fence(Ordering::SeqCst);
+		if self.available_ring._flags() & VRING_AVAIL_F_NO_INTERRUPT as u16 != 0 {
+			return;
+		}
+		if let Err(err) = interrupt.write(1) {
+			warn!("Virtqueue: unable to raise the interrupt: {}", err);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SIZE: u16 = 8;
+	const DESC_GPA: u64 = 0x1000;
+	const AVAIL_GPA: u64 = 0x2000;
+	const USED_GPA: u64 = 0x3000;
+
+	/// A mock guest memory of 16 KiB, which is suitably aligned for the rings.
+	fn guest_memory() -> Vec<u64> {
+		vec![0; 0x4000 / size_of::<u64>()]
+	}
+
+	fn mem(memory: &mut [u64]) -> (*mut u8, usize) {
+		(
+			memory.as_mut_ptr() as *mut u8,
+			memory.len() * size_of::<u64>(),
+		)
+	}
+
+	unsafe fn write<T>(mem: (*mut u8, usize), gpa: u64, value: T) {
+		ptr::write_unaligned(mem.0.add(gpa as usize) as *mut T, value);
+	}
+
+	unsafe fn read<T>(mem: (*mut u8, usize), gpa: u64) -> T {
+		ptr::read_unaligned(mem.0.add(gpa as usize) as *const T)
+	}
+
+	#[test]
+	fn test_virtqueue_chain() {
+		let mut memory = guest_memory();
+		let mem = mem(&mut memory);
+		let mut queue =
+			unsafe { Virtqueue::new(mem, DESC_GPA, AVAIL_GPA, USED_GPA, SIZE) }.unwrap();
+		assert!(queue.next_avail_desc().is_none());
+
+		unsafe {
+			// a chain of descriptor 3 followed by 5, whose buffer exceeds the guest memory
+			let desc = |addr, len, flags, next| VringDescriptor {
+				addr,
+				len,
+				flags,
+				next,
+			};
+			write(
+				mem,
+				DESC_GPA + 3 * 16,
+				desc(0x100, 16, VRING_DESC_F_NEXT as u16, 5),
+			);
+			write(
+				mem,
+				DESC_GPA + 5 * 16,
+				desc(0x3ff0, 32, VRING_DESC_F_WRITE as u16, 0),
+			);
+			write(mem, AVAIL_GPA + 4, 3u16);
+			write(mem, AVAIL_GPA + 2, 1u16);
+		}
+
+		let head = queue.next_avail_desc().unwrap();
+		assert_eq!((head.index, head.gpa, head.len), (3, 0x100, 16));
+		assert_eq!(head.host_addr, Some(unsafe { mem.0.add(0x100) }));
+		assert!(!head.is_write_only());
+		let tail = queue.next_desc(&head).unwrap();
+		assert_eq!(tail.index, 5);
+		assert!(tail.is_write_only());
+		assert!(tail.host_addr.is_none());
+		assert!(queue.next_desc(&tail).is_none());
+		assert!(queue.next_avail_desc().is_none());
+
+		queue.push_used(head.index, 16);
+		unsafe {
+			assert_eq!(read::<u16>(mem, USED_GPA + 2), 1);
+			assert_eq!(read::<u32>(mem, USED_GPA + 4), 3);
+			assert_eq!(read::<u32>(mem, USED_GPA + 8), 16);
+		}
+	}
+
+	#[test]
+	fn test_virtqueue_invalid_head() {
+		let mut memory = guest_memory();
+		let mem = mem(&mut memory);
+		let mut queue =
+			unsafe { Virtqueue::new(mem, DESC_GPA, AVAIL_GPA, USED_GPA, SIZE) }.unwrap();
+		unsafe {
+			write(mem, AVAIL_GPA + 4, SIZE);
+			write(mem, AVAIL_GPA + 6, 1u16);
+			write(mem, AVAIL_GPA + 2, 2u16);
+		}
+		// the head outside of the table is skipped and returned
+		assert_eq!(queue.next_avail_desc().unwrap().index, 1);
+		unsafe {
+			assert_eq!(read::<u16>(mem, USED_GPA + 2), 1);
+			assert_eq!(read::<u32>(mem, USED_GPA + 4), SIZE.into());
+		}
+	}
+
+	#[test]
+	fn test_virtqueue_cyclic_chain() {
+		let mut memory = guest_memory();
+		let mem = mem(&mut memory);
+		let mut queue =
+			unsafe { Virtqueue::new(mem, DESC_GPA, AVAIL_GPA, USED_GPA, SIZE) }.unwrap();
+		unsafe {
+			// descriptor 2 links to itself
+			write(
+				mem,
+				DESC_GPA + 2 * 16,
+				VringDescriptor {
+					addr: 0x100,
+					len: 16,
+					flags: VRING_DESC_F_NEXT as u16,
+					next: 2,
+				},
+			);
+			write(mem, AVAIL_GPA + 4, 2u16);
+			write(mem, AVAIL_GPA + 2, 1u16);
+		}
+		let head = queue.next_avail_desc().unwrap();
+		assert_eq!(queue.chain(head).count(), SIZE.into());
+	}
+
+	#[test]
+	fn test_virtqueue_rings_are_checked() {
+		let mut memory = guest_memory();
+		let mem = mem(&mut memory);
+		let new = |desc, avail, used, size| unsafe { Virtqueue::new(mem, desc, avail, used, size) };
+		assert!(new(DESC_GPA, AVAIL_GPA, USED_GPA, 6).is_none());
+		assert!(new(DESC_GPA + 8, AVAIL_GPA, USED_GPA, SIZE).is_none());
+		assert!(new(DESC_GPA, AVAIL_GPA, 0x3ff0, SIZE).is_none());
+		assert!(new(DESC_GPA, u64::MAX - 1, USED_GPA, SIZE).is_none());
+
+		// the legacy layout of 256 entries takes more than 10 KiB
+		assert!(unsafe { Virtqueue::legacy(mem, 0) }.is_some());
+		assert!(unsafe { Virtqueue::legacy(mem, 0x2000) }.is_none());
+	}
 }
@@ -1,11 +1,16 @@
+use crate::console_log::{ConsoleLog, DebugPortLog};
+use crate::kernel_signature::KernelVerifier;
 use crate::macos::ioapic::IoApic;
 use crate::macos::vcpu::*;
 use crate::vm::HypervisorResult;
-use crate::vm::{BootInfo, Parameter, Vm};
+use crate::vm::{
+	canonicalize_allowed_paths, collect_entropy, collect_kernel_args, BootInfo, Parameter, Vm,
+	ENTROPY_SIZE,
+};
 use libc;
 use libc::c_void;
 use log::debug;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::Path;
 use std::path::PathBuf;
 use std::ptr;
@@ -23,6 +28,15 @@ pub struct Uhyve {
 	boot_info: *const BootInfo,
 	ioapic: Arc<Mutex<IoApic>>,
 	verbose: bool,
+	print_memory_layout: bool,
+	console_log: Option<Arc<ConsoleLog>>,
+	debug_port_log: Option<Arc<DebugPortLog>>,
+	host_allowed_paths: Option<Arc<[PathBuf]>>,
+	kernel_args: Arc<[String]>,
+	entropy: [u8; ENTROPY_SIZE],
+	kernel_verifier: Option<KernelVerifier>,
+	/// Offset of the guest's clock in nanoseconds, see [`Vm::clock_offset`]
+	clock_offset: i64,
 }
 
 impl std::fmt::Debug for Uhyve {
@@ -69,7 +83,80 @@ impl Uhyve {
 			)?;
 		}
 
-		assert!(specs.gdbport.is_none(), "gdbstub is not supported on macos");
+		let console_log = specs.console_log_file.map(|path| {
+			Arc::new(
+				ConsoleLog::open(path, specs.console_log_append)
+					.expect("Unable to open the console log file"),
+			)
+		});
+
+		let debug_port_log = specs.debug_port_file.map(|path| {
+			Arc::new(
+				DebugPortLog::open(path, specs.debug_port_prefix, specs.verbose)
+					.expect("Unable to open the debug port file"),
+			)
+		});
+
+		assert!(
+			specs.gdbport.is_none() && specs.gdb_socket.is_none() && !specs.debug_on_startup,
+			"gdbstub is not supported on macos"
+		);
+		assert!(
+			specs.port_forwards.is_empty(),
+			"Port forwarding is not supported on macos"
+		);
+		assert!(
+			!specs.guest_ip_forward,
+			"IP forwarding between network interfaces is not supported on macos"
+		);
+		assert!(
+			!specs.balloon,
+			"The memory balloon is not supported on macos"
+		);
+		assert!(
+			specs.numa_nodes.is_empty(),
+			"NUMA nodes are not supported on macos"
+		);
+		assert!(
+			specs.cpu_features.is_empty() && specs.cpuid_overrides.is_empty(),
+			"CPUID features cannot be changed on macos"
+		);
+		assert!(
+			specs.snapshot.is_none(),
+			"Snapshots are not supported on macos"
+		);
+		assert!(
+			!specs.dirty_log,
+			"The dirty page log is not supported on macos"
+		);
+		assert!(
+			!specs.pause_on_signal,
+			"Pausing the vCPUs is not supported on macos"
+		);
+		assert!(
+			specs.tsc_khz.is_none() && specs.tsc_offset.is_none(),
+			"The TSC of the vCPUs cannot be changed on macos"
+		);
+		assert!(
+			specs.msr_whitelist.is_empty(),
+			"Forwarding MSRs to the host is not supported on macos"
+		);
+		assert!(
+			!specs.registers_on_signal,
+			"Dumping the registers on SIGUSR2 is not supported on macos"
+		);
+		assert!(
+			specs.block.is_none(),
+			"Block devices are not supported on macos"
+		);
+		assert!(
+			!specs.verbose_exits,
+			"Logging the vCPU exits is not supported on macos"
+		);
+		let kernel_args = collect_kernel_args(specs.kernel_args_file)
+			.unwrap_or_else(|err| panic!("Invalid kernel arguments: {}", err));
+		let entropy = collect_entropy(specs.entropy)
+			.unwrap_or_else(|err| panic!("Unable to read the entropy of the kernel: {}", err));
 
 		let hyve = Uhyve {
 			offset: 0,
@@ -81,6 +168,14 @@ impl Uhyve {
 			boot_info: ptr::null(),
 			ioapic: Arc::new(Mutex::new(IoApic::new())),
 			verbose: specs.verbose,
+			print_memory_layout: specs.print_memory_layout,
+			console_log,
+			debug_port_log,
+			host_allowed_paths: specs.host_allowed_paths.map(canonicalize_allowed_paths),
+			kernel_args,
+			entropy,
+			kernel_verifier: specs.kernel_signature.map(|signature| signature.verifier()),
+			clock_offset: specs.clock_offset,
 		};
 
 		hyve.init_guest_mem();
@@ -94,6 +189,14 @@ impl Vm for Uhyve {
 		self.verbose
 	}
 
+	fn print_memory_layout(&self) -> bool {
+		self.print_memory_layout
+	}
+
+	fn clock_offset(&self) -> i64 {
+		self.clock_offset
+	}
+
 	fn set_offset(&mut self, offset: u64) {
 		self.offset = offset;
 	}
@@ -122,12 +225,36 @@ impl Vm for Uhyve {
 		self.path.as_path()
 	}
 
+	fn entropy(&self) -> [u8; ENTROPY_SIZE] {
+		self.entropy
+	}
+
+	fn get_dirty_log(&self, _slot: u32) -> HypervisorResult<Vec<u64>> {
+		Err(xhypervisor::Error::Unsupp)
+	}
+
+	fn pause(&self) -> HypervisorResult<()> {
+		Err(xhypervisor::Error::Unsupp)
+	}
+
+	fn resume(&self) -> HypervisorResult<()> {
+		Err(xhypervisor::Error::Unsupp)
+	}
+
+	fn kernel_verifier(&self) -> Option<&KernelVerifier> {
+		self.kernel_verifier.as_ref()
+	}
+
 	fn create_cpu(&self, id: u32) -> HypervisorResult<UhyveCPU> {
 		Ok(UhyveCPU::new(
 			id,
 			self.path.clone(),
 			self.guest_mem as usize,
 			self.ioapic.clone(),
+			self.console_log.clone(),
+			self.debug_port_log.clone(),
+			self.host_allowed_paths.clone(),
+			self.kernel_args.clone(),
 		))
 	}
 
@@ -143,6 +270,14 @@ impl Vm for Uhyve {
 		None
 	}
 
+	fn get_ip6(&self) -> Option<Ipv6Addr> {
+		None
+	}
+
+	fn get_prefix_len6(&self) -> Option<u8> {
+		None
+	}
+
 	fn set_boot_info(&mut self, header: *const BootInfo) {
 		self.boot_info = header;
 	}
@@ -1,5 +1,6 @@
 #![allow(non_snake_case)]
 
+use crate::console_log::{ConsoleLog, DebugPortLog};
 use crate::consts::*;
 use crate::macos::ioapic::IoApic;
 use crate::paging::*;
@@ -70,6 +71,10 @@ pub struct UhyveCPU {
 	vm_start: usize,
 	apic_base: u64,
 	ioapic: Arc<Mutex<IoApic>>,
+	console_log: Option<Arc<ConsoleLog>>,
+	debug_port_log: Option<Arc<DebugPortLog>>,
+	host_allowed_paths: Option<Arc<[PathBuf]>>,
+	kernel_args: Arc<[String]>,
 }
 
 impl UhyveCPU {
@@ -78,6 +83,10 @@ impl UhyveCPU {
 		kernel_path: PathBuf,
 		vm_start: usize,
 		ioapic: Arc<Mutex<IoApic>>,
+		console_log: Option<Arc<ConsoleLog>>,
+		debug_port_log: Option<Arc<DebugPortLog>>,
+		host_allowed_paths: Option<Arc<[PathBuf]>>,
+		kernel_args: Arc<[String]>,
 	) -> UhyveCPU {
 		UhyveCPU {
 			id,
@@ -86,6 +95,10 @@ impl UhyveCPU {
 			vm_start,
 			apic_base: APIC_DEFAULT_BASE,
 			ioapic,
+			console_log,
+			debug_port_log,
+			host_allowed_paths,
+			kernel_args,
 		}
 	}
 
@@ -545,6 +558,22 @@ impl VirtualCPU for UhyveCPU {
 		self.kernel_path.as_path()
 	}
 
+	fn console_log(&self) -> Option<&ConsoleLog> {
+		self.console_log.as_deref()
+	}
+
+	fn debug_port_log(&self) -> Option<&DebugPortLog> {
+		self.debug_port_log.as_deref()
+	}
+
+	fn host_allowed_paths(&self) -> Option<&[PathBuf]> {
+		self.host_allowed_paths.as_deref()
+	}
+
+	fn kernel_args(&self) -> &[String] {
+		&self.kernel_args
+	}
+
 	fn host_address(&self, addr: usize) -> usize {
 		addr + self.vm_start
 	}
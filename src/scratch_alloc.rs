@@ -0,0 +1,126 @@
+//! Bump allocation of temporary data in the I/O path of the vCPU threads.
+//!
+//! Every thread owns a fixed arena, so handling an exit does not need `malloc` and `free` for
+//! its short-lived buffers. The allocations only live until the arena is reset, which is enforced
+//! by borrowing them from the [`ScratchAllocator`].
+
+use std::{
+	alloc::{self, Layout},
+	cell::{Cell, RefCell},
+	mem,
+	ptr::NonNull,
+	slice,
+};
+
+/// Size of the arena of each thread.
+const ARENA_SIZE: usize = 4 * 1024 * 1024;
+/// Alignment of the arena, which is the size of a cache line.
+const ARENA_ALIGN: usize = 64;
+
+thread_local! {
+	static SCRATCH: RefCell<ScratchAllocator> = RefCell::new(ScratchAllocator::new());
+}
+
+/// A thread-local bump allocator for data, which does not outlive the handling of an exit.
+#[derive(Debug)]
+pub struct ScratchAllocator {
+	arena: NonNull<u8>,
+	offset: Cell<usize>,
+}
+
+impl ScratchAllocator {
+	fn layout() -> Layout {
+		Layout::from_size_align(ARENA_SIZE, ARENA_ALIGN).unwrap()
+	}
+
+	fn new() -> Self {
+		// SAFETY: The layout has a non-zero size.
+		let arena = unsafe { alloc::alloc(Self::layout()) };
+		let arena =
+			NonNull::new(arena).unwrap_or_else(|| alloc::handle_alloc_error(Self::layout()));
+		Self {
+			arena,
+			offset: Cell::new(0),
+		}
+	}
+
+	/// Calls `f` with the arena of the current thread, which is reset beforehand.
+	///
+	/// # Panics
+	///
+	/// Panics if it is called again from within `f`.
+	pub fn with<R>(f: impl FnOnce(&mut Self) -> R) -> R {
+		SCRATCH.with(|scratch| {
+			let mut scratch = scratch.borrow_mut();
+			scratch.reset();
+			f(&mut scratch)
+		})
+	}
+
+	/// Frees all allocations at once.
+	pub fn reset(&mut self) {
+		self.offset.set(0);
+	}
+
+	/// Allocates a default value or returns `None`, if the arena is exhausted.
+	pub fn alloc<T: Copy + Default>(&self) -> Option<&mut T> {
+		self.alloc_slice(1).map(|value| &mut value[0])
+	}
+
+	/// Allocates `len` default values or returns `None`, if the arena is exhausted.
+	#[allow(clippy::mut_from_ref)]
+	pub fn alloc_slice<T: Copy + Default>(&self, len: usize) -> Option<&mut [T]> {
+		let align = mem::align_of::<T>();
+		assert!(align <= ARENA_ALIGN, "over-aligned scratch allocation");
+
+		let start = (self.offset.get() + align - 1) & !(align - 1);
+		let end = start.checked_add(mem::size_of::<T>().checked_mul(len)?)?;
+		if end > ARENA_SIZE {
+			return None;
+		}
+		self.offset.set(end);
+
+		// SAFETY: `start..end` lies within the arena, is aligned for `T` and has not been handed out
+		// since the last reset, which requires a mutable borrow and thus ends all previous ones.
+		unsafe {
+			let ptr = self.arena.as_ptr().add(start) as *mut T;
+			for i in 0..len {
+				ptr.add(i).write(T::default());
+			}
+			Some(slice::from_raw_parts_mut(ptr, len))
+		}
+	}
+}
+
+impl Drop for ScratchAllocator {
+	fn drop(&mut self) {
+		// SAFETY: The arena has been allocated with the same layout in `new`.
+		unsafe { alloc::dealloc(self.arena.as_ptr(), Self::layout()) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_scratch_allocator() {
+		ScratchAllocator::with(|scratch| {
+			let byte = scratch.alloc::<u8>().unwrap();
+			let words = scratch.alloc_slice::<u64>(4).unwrap();
+			*byte = 1;
+			words[3] = 2;
+			assert_eq!(words.as_ptr() as usize % mem::align_of::<u64>(), 0);
+			assert_eq!(scratch.offset.get(), 8 + 4 * 8);
+			assert!(scratch.alloc_slice::<u8>(ARENA_SIZE).is_none());
+
+			scratch.reset();
+			assert_eq!(
+				scratch.alloc_slice::<u8>(ARENA_SIZE).unwrap().len(),
+				ARENA_SIZE
+			);
+		});
+
+		ScratchAllocator::with(|scratch| assert_eq!(scratch.offset.get(), 0));
+	}
+}
@@ -1,4 +1,8 @@
 use crate::consts::*;
+use std::{mem, ptr};
+
+/// Size of the read and written counters, which precede the entries
+const HEADER_SIZE: usize = 2 * 64;
 
 #[repr(C)]
 pub struct QueueInner {
@@ -12,10 +16,45 @@ pub struct SharedQueue {
 	pad0: [u8; 64 - 8],
 	pub written: usize,
 	pad1: [u8; 64 - 8],
-	pub inner: [QueueInner; UHYVE_QUEUE_SIZE],
+	pub inner: [QueueInner],
 }
 
 impl SharedQueue {
+	/// Returns the size of a queue with `depth` entries in the guest memory.
+	///
+	/// The queues of one interface with [`UHYVE_MAX_QUEUE_SIZE`] entries end below
+	/// [`SHAREDQUEUE_END`].
+	pub const fn size(depth: usize) -> usize {
+		align_up!(HEADER_SIZE + depth * mem::size_of::<QueueInner>(), 64)
+	}
+
+	/// Returns the queue with `depth` entries at `start`.
+	///
+	/// # Safety
+	///
+	/// `start` has to point to [`SharedQueue::size(depth)`](Self::size) bytes of the guest
+	/// memory, which are only accessed through the returned queue on the host side.
+	pub unsafe fn from_raw<'a>(start: usize, depth: usize) -> &'a mut Self {
+		#[allow(clippy::cast_ptr_alignment)]
+		&mut *(ptr::slice_from_raw_parts_mut(start as *mut QueueInner, depth) as *mut Self)
+	}
+
+	/// Checks, whether the queue at `start` contains entries, which have not been read yet.
+	///
+	/// # Safety
+	///
+	/// `start` has to point to a queue in the guest memory.
+	pub unsafe fn has_unread(start: usize) -> bool {
+		let queue = ptr::slice_from_raw_parts(start as *const QueueInner, 0) as *const Self;
+		ptr::read_volatile(ptr::addr_of!((*queue).written))
+			!= ptr::read_volatile(ptr::addr_of!((*queue).read))
+	}
+
+	/// Returns the number of entries.
+	pub fn depth(&self) -> usize {
+		self.inner.len()
+	}
+
 	pub fn init(&mut self) {
 		self.read = 0;
 		self.written = 0;
@@ -25,3 +64,26 @@ impl SharedQueue {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_shared_queue_size() {
+		// The default layout has to match kernels without support for other depths.
+		assert_eq!(SharedQueue::size(UHYVE_QUEUE_SIZE), 12416);
+		assert!(SHAREDQUEUE_START + 2 * SharedQueue::size(UHYVE_MAX_QUEUE_SIZE) <= SHAREDQUEUE_END);
+		assert!(
+			SHAREDQUEUE_START + UHYVE_MAX_NICS * 2 * SharedQueue::size(UHYVE_QUEUE_SIZE)
+				<= SHAREDQUEUE_END
+		);
+
+		let mut memory = vec![0u64; SharedQueue::size(4) / 8];
+		let start = memory.as_mut_ptr() as usize;
+		let queue = unsafe { SharedQueue::from_raw(start, 4) };
+		assert_eq!(queue.depth(), 4);
+		assert_eq!(&queue.written as *const usize as usize - start, 64);
+		assert_eq!(queue.inner.as_ptr() as usize - start, HEADER_SIZE);
+	}
+}
@@ -0,0 +1,82 @@
+//! The stress test of `--cpu-stress-test`.
+//!
+//! The kernel is built from `test-kernel/` and passed to uhyve as the kernel:
+//!
+//! ```sh
+//! (cd test-kernel && cargo build --release)
+//! uhyve --cpu-stress-test 60 test-kernel/target/x86_64-unknown-hermit/release/uhyve-stress-test
+//! ```
+//!
+//! The kernel runs an integer, a floating-point, a memory and a network test for a quarter of the
+//! duration each and prints their throughput. Its exit code is the number of the first failing
+//! test. The network test is skipped, if the guest has no network.
+
+use std::{fs, io, path::PathBuf, process, time::Duration};
+
+/// The memory test uses this percentage of the guest memory.
+const MEMORY_PERCENTAGE: usize = 80;
+
+/// Describes the exit code of the kernel.
+pub fn result(code: i32) -> &'static str {
+	match code {
+		0 => "PASSED",
+		1 => "FAILED in the integer test",
+		2 => "FAILED in the floating-point test",
+		3 => "FAILED in the memory test",
+		4 => "FAILED in the network test",
+		_ => "FAILED",
+	}
+}
+
+/// The arguments of the kernel in a temporary directory, which is removed on drop.
+#[derive(Debug)]
+pub struct StressTest {
+	dir: PathBuf,
+}
+
+impl StressTest {
+	pub fn new(duration: Duration, mem_size: usize) -> io::Result<Self> {
+		let dir = std::env::temp_dir().join(format!("uhyve-stress-test-{}", process::id()));
+		fs::create_dir_all(&dir)?;
+		let test = Self { dir };
+		fs::write(
+			test.kernel_args_file(),
+			format!(
+				"duration={} memory={}\n",
+				duration.as_secs(),
+				mem_size / 100 * MEMORY_PERCENTAGE
+			),
+		)?;
+		Ok(test)
+	}
+
+	/// The file with the arguments of the kernel, see `--kernel-args-file`.
+	pub fn kernel_args_file(&self) -> PathBuf {
+		self.dir.join("args")
+	}
+}
+
+impl Drop for StressTest {
+	fn drop(&mut self) {
+		if let Err(err) = fs::remove_dir_all(&self.dir) {
+			warn!("Unable to remove {}: {}", self.dir.display(), err);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_stress_test_files() {
+		let test = StressTest::new(Duration::from_secs(60), 1000 << 20).unwrap();
+		assert_eq!(
+			fs::read_to_string(test.kernel_args_file()).unwrap(),
+			"duration=60 memory=838860800\n"
+		);
+		let dir = test.kernel_args_file().parent().unwrap().to_path_buf();
+		drop(test);
+		assert!(!dir.exists());
+	}
+}
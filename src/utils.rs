@@ -2,9 +2,23 @@
 //!
 //! These functions are used to parse command line arguments or determining defaults.
 
-use std::{io, iter, num::ParseIntError};
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	hash::{Hash, Hasher},
+	io,
+	iter::{self, StepBy},
+	net::{IpAddr, Ipv4Addr},
+	num::ParseIntError,
+	ops::RangeInclusive,
+	path::Path,
+};
 
 use either::Either;
+use thiserror::Error;
+
+use crate::vm::{
+	GuestRoute, IpNetwork, NetworkBackend, NicSpec, PortForward, Protocol, ENTROPY_SIZE,
+};
 
 /// Checks if the kernel provides support for transparent huge pages
 pub fn transparent_hugepages_available() -> io::Result<bool> {
@@ -50,28 +64,473 @@ pub fn transparent_hugepages_available() -> io::Result<bool> {
 	}
 }
 
-/// Parses ranges from strings into discrete steps.
+/// An invalid entry of a CPU list.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseRangeError {
+	#[error("empty entry")]
+	Empty,
+	#[error(transparent)]
+	InvalidNumber(#[from] ParseIntError),
+	#[error("range {start}-{end} ends before it starts")]
+	Reversed { start: usize, end: usize },
+	#[error("step of a range has to be at least 1")]
+	ZeroStep,
+}
+
+/// Returns the number of CPUs, which are configured on the host.
+fn host_cpu_count() -> Option<usize> {
+	let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+	usize::try_from(count).ok().filter(|&count| count > 0)
+}
+
+/// Parses a single entry of a CPU list: `<cpu>`, `<start>-<end>` or `<start>-<end>:<step>`.
+fn parse_range(range: &str) -> Result<StepBy<RangeInclusive<usize>>, ParseRangeError> {
+	if range.is_empty() {
+		return Err(ParseRangeError::Empty);
+	}
+
+	let (range, step) = match range.split_once(':') {
+		Some((range, step)) => (range, step.parse()?),
+		None => (range, 1),
+	};
+	if step == 0 {
+		return Err(ParseRangeError::ZeroStep);
+	}
+
+	let (start, end) = match range.split_once('-') {
+		Some((start, end)) => (start.parse()?, end.parse()?),
+		None => {
+			let idx = range.parse()?;
+			(idx, idx)
+		}
+	};
+	if start > end {
+		return Err(ParseRangeError::Reversed { start, end });
+	}
+
+	if let Some(count) = host_cpu_count().filter(|&count| end >= count) {
+		warn!("CPU {} does not exist, the host has {} CPUs", end, count);
+	}
+
+	Ok((start..=end).step_by(step))
+}
+
+/// Parses CPU lists in the format of Linux into the CPUs, which they contain.
+///
+/// Every string may contain several comma-separated entries, such as `0-3,8-11:2`, which results
+/// in 0, 1, 2, 3, 8 and 10. The entries are parsed lazily in order and duplicates are kept.
 pub fn parse_ranges<'a>(
 	ranges: impl IntoIterator<Item = &'a str> + 'a,
-) -> impl Iterator<Item = Result<usize, ParseIntError>> + 'a {
+) -> impl Iterator<Item = Result<usize, ParseRangeError>> + 'a {
 	ranges
 		.into_iter()
-		.map(|range| {
-			let range = match range.split_once('-') {
-				Some((start, end)) => start.parse()?..=end.parse()?,
-				None => {
-					let idx = range.parse()?;
-					idx..=idx
-				}
-			};
-			Ok(range)
-		})
+		.flat_map(|ranges| ranges.split(','))
+		.map(parse_range)
 		.flat_map(|range| match range {
 			Ok(range) => Either::Left(range.map(Ok)),
 			Err(err) => Either::Right(iter::once(Err(err))),
 		})
 }
 
+/// An invalid MAC address.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseMacError {
+	#[error("a MAC address consists of six bytes")]
+	InvalidLength,
+	#[error("invalid byte {0:?}")]
+	InvalidByte(String),
+	#[error("{0} is a multicast address")]
+	Multicast(String),
+}
+
+/// Parses a MAC address of the form `52:54:00:12:34:56`, the bytes may be separated by `-` as well.
+pub fn parse_mac(mac: &str) -> Result<[u8; 6], ParseMacError> {
+	let mut bytes = [0u8; 6];
+	let mut parts = mac.split(|c| c == ':' || c == '-');
+	for byte in bytes.iter_mut() {
+		let part = parts.next().ok_or(ParseMacError::InvalidLength)?;
+		if part.len() != 2 || !part.bytes().all(|c| c.is_ascii_hexdigit()) {
+			return Err(ParseMacError::InvalidByte(part.to_string()));
+		}
+		*byte = u8::from_str_radix(part, 16).unwrap();
+	}
+	if parts.next().is_some() {
+		return Err(ParseMacError::InvalidLength);
+	}
+	if bytes[0] & 1 != 0 {
+		return Err(ParseMacError::Multicast(mac.to_string()));
+	}
+	Ok(bytes)
+}
+
+/// Derives a locally administered unicast MAC address of the `index`th network interface from the
+/// path of the kernel.
+///
+/// Repeated runs of the same image get the same address, so they keep their ARP entries.
+pub fn stable_mac(kernel_path: &Path, index: usize) -> [u8; 6] {
+	let mut hasher = DefaultHasher::new();
+	kernel_path.hash(&mut hasher);
+	// the first interface keeps the address, which it had before multiple interfaces
+	if index > 0 {
+		index.hash(&mut hasher);
+	}
+	let hash = hasher.finish().to_le_bytes();
+
+	let mut mac = [0u8; 6];
+	mac.copy_from_slice(&hash[..6]);
+	mac[0] = (mac[0] & !1) | 2;
+	mac
+}
+
+/// An invalid network interface.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseNicError {
+	#[error("the name of the network interface is missing")]
+	MissingName,
+	#[error("unknown option {0:?}")]
+	UnknownOption(String),
+	#[error("invalid address {0:?}")]
+	InvalidAddress(String),
+	#[error(transparent)]
+	InvalidMac(#[from] ParseMacError),
+	#[error("uhyve has been built without the feature `slirp`")]
+	SlirpUnsupported,
+}
+
+/// Parses a network interface of the form `NAME[,ip=IP][,gateway=IP][,mask=IP][,mac=MAC]`.
+///
+/// `NAME` is a TAP device or a bridge, except for `virtio`, which only configures the virtio-net
+/// device, and `slirp`, which also accepts `dns=IP`.
+pub fn parse_nic(nic: &str) -> Result<NicSpec<'_>, ParseNicError> {
+	let mut options = nic.split(',');
+	let backend = match options.next() {
+		None | Some("") => return Err(ParseNicError::MissingName),
+		Some("virtio") => NetworkBackend::VirtioNet,
+		#[cfg(feature = "slirp")]
+		Some("slirp") => NetworkBackend::Slirp { dns: None },
+		#[cfg(not(feature = "slirp"))]
+		Some("slirp") => return Err(ParseNicError::SlirpUnsupported),
+		Some(name) => NetworkBackend::Tap(name),
+	};
+	let mut spec = NicSpec {
+		backend,
+		..Default::default()
+	};
+
+	for option in options {
+		let (key, value) = option
+			.split_once('=')
+			.ok_or_else(|| ParseNicError::UnknownOption(option.to_string()))?;
+		let parse_addr = || {
+			value
+				.parse::<Ipv4Addr>()
+				.map_err(|_| ParseNicError::InvalidAddress(value.to_string()))
+		};
+		match key {
+			"ip" => spec.ip = Some(parse_addr()?),
+			"gateway" => spec.gateway = Some(parse_addr()?),
+			"mask" => spec.mask = Some(parse_addr()?),
+			"mac" => spec.mac = Some(parse_mac(value)?),
+			#[cfg(feature = "slirp")]
+			"dns" => match &mut spec.backend {
+				NetworkBackend::Slirp { dns } => *dns = Some(parse_addr()?),
+				_ => return Err(ParseNicError::UnknownOption(key.to_string())),
+			},
+			_ => return Err(ParseNicError::UnknownOption(key.to_string())),
+		}
+	}
+	Ok(spec)
+}
+
+/// An invalid forwarded port.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParsePortForwardError {
+	#[error("expected HOST:GUEST[/PROTOCOL]")]
+	MissingGuestPort,
+	#[error(transparent)]
+	InvalidPort(#[from] ParseIntError),
+	#[error("unknown protocol {0:?}, expected tcp or udp")]
+	UnknownProtocol(String),
+}
+
+/// Parses a forwarded port of the form `HOST:GUEST[/PROTOCOL]`, which uses TCP by default.
+pub fn parse_port_forward(forward: &str) -> Result<PortForward, ParsePortForwardError> {
+	let (ports, protocol) = match forward.split_once('/') {
+		Some((ports, "tcp")) => (ports, Protocol::Tcp),
+		Some((ports, "udp")) => (ports, Protocol::Udp),
+		Some((_, protocol)) => {
+			return Err(ParsePortForwardError::UnknownProtocol(protocol.to_string()))
+		}
+		None => (forward, Protocol::Tcp),
+	};
+	let (host_port, guest_port) = ports
+		.split_once(':')
+		.ok_or(ParsePortForwardError::MissingGuestPort)?;
+	Ok(PortForward {
+		host_port: host_port.parse()?,
+		guest_port: guest_port.parse()?,
+		protocol,
+	})
+}
+
+/// An invalid route of `--guest-route`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseGuestRouteError {
+	#[error("expected CIDR:NIC_INDEX")]
+	MissingNic,
+	#[error("invalid network {0:?}, expected an address and a prefix length like 10.0.1.0/24")]
+	InvalidNetwork(String),
+	#[error(transparent)]
+	InvalidNic(#[from] ParseIntError),
+}
+
+/// Parses a network of the form `ADDRESS/PREFIX_LEN`.
+pub fn parse_ip_network(network: &str) -> Option<IpNetwork> {
+	let (address, prefix_len) = network.split_once('/')?;
+	let address: IpAddr = address.parse().ok()?;
+	let prefix_len: u8 = prefix_len.parse().ok()?;
+	let max_len = if address.is_ipv4() { 32 } else { 128 };
+	(prefix_len <= max_len).then(|| IpNetwork {
+		address,
+		prefix_len,
+	})
+}
+
+/// Parses a route of the form `CIDR:NIC_INDEX`, e.g., `10.0.1.0/24:1` or `fd00::/64:0`.
+pub fn parse_guest_route(route: &str) -> Result<GuestRoute, ParseGuestRouteError> {
+	let (network, nic) = route
+		.rsplit_once(':')
+		.ok_or(ParseGuestRouteError::MissingNic)?;
+	Ok(GuestRoute {
+		network: parse_ip_network(network)
+			.ok_or_else(|| ParseGuestRouteError::InvalidNetwork(network.to_string()))?,
+		nic: nic.parse()?,
+	})
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseEntropySeedError {
+	#[error("a seed consists of 1 to {max} bytes", max = ENTROPY_SIZE)]
+	InvalidLength,
+	#[error("invalid byte {0:?}")]
+	InvalidByte(String),
+}
+
+/// Parses the hexadecimal index of an MSR with an optional prefix `0x`, e.g., `0xce`.
+pub fn parse_msr_index(msr: &str) -> Result<u32, ParseIntError> {
+	u32::from_str_radix(msr.strip_prefix("0x").unwrap_or(msr), 16)
+}
+
+/// Parses the hexadecimal seed of `--entropy-seed`, shorter seeds are padded with zeros.
+pub fn parse_entropy_seed(seed: &str) -> Result<[u8; ENTROPY_SIZE], ParseEntropySeedError> {
+	let seed = seed.strip_prefix("0x").unwrap_or(seed);
+	if seed.is_empty() || seed.len() % 2 != 0 || seed.len() > 2 * ENTROPY_SIZE {
+		return Err(ParseEntropySeedError::InvalidLength);
+	}
+	let mut entropy = [0u8; ENTROPY_SIZE];
+	for (byte, part) in entropy.iter_mut().zip(seed.as_bytes().chunks(2)) {
+		let part = String::from_utf8_lossy(part);
+		if !part.bytes().all(|c| c.is_ascii_hexdigit()) {
+			return Err(ParseEntropySeedError::InvalidByte(part.into_owned()));
+		}
+		*byte = u8::from_str_radix(&part, 16).unwrap();
+	}
+	Ok(entropy)
+}
+
+/// Parses the contents of a `--kernel-args-file` into the arguments of the kernel.
+///
+/// The arguments are separated by whitespace, including newlines, and lines starting with `#` are
+/// ignored.
+pub fn parse_kernel_args(contents: &str) -> Vec<String> {
+	contents
+		.lines()
+		.filter(|line| !line.trim_start().starts_with('#'))
+		.flat_map(str::split_whitespace)
+		.map(str::to_string)
+		.collect()
+}
+
+/// Formats a MAC address as `52:54:00:12:34:56`.
+pub fn format_mac(mac: &[u8; 6]) -> String {
+	mac.iter()
+		.map(|byte| format!("{:02x}", byte))
+		.collect::<Vec<_>>()
+		.join(":")
+}
+
+/// Extracts `MemAvailable` in bytes from the contents of `/proc/meminfo`.
+pub fn parse_mem_available(meminfo: &str) -> Option<usize> {
+	meminfo.lines().find_map(|line| {
+		let kib = line
+			.strip_prefix("MemAvailable:")?
+			.trim()
+			.strip_suffix("kB")?
+			.trim_end();
+		kib.parse::<usize>().ok()?.checked_mul(1024)
+	})
+}
+
+/// Location of a host CPU in the host's CPU topology.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HostCpuTopology {
+	pub package_id: usize,
+	pub core_id: usize,
+}
+
+impl HostCpuTopology {
+	/// Reads the topology of the given host CPU from sysfs.
+	pub fn read(cpu: usize) -> io::Result<Self> {
+		use std::fs;
+
+		let read_id = |name: &str| -> io::Result<usize> {
+			fs::read_to_string(format!(
+				"/sys/devices/system/cpu/cpu{}/topology/{}",
+				cpu, name
+			))?
+			.trim()
+			.parse()
+			.map_err(|_| io::ErrorKind::InvalidData.into())
+		};
+
+		Ok(Self {
+			package_id: read_id("physical_package_id")?,
+			core_id: read_id("core_id")?,
+		})
+	}
+}
+
+/// Checks if the host CPUs, to which the vCPUs are pinned, form a regular topology.
+///
+/// Returns a description of every mismatch: host CPUs sharing a physical core and
+/// host CPUs, which are unevenly distributed across the packages.
+pub fn check_affinity_topology(cpus: &[(usize, HostCpuTopology)]) -> Vec<String> {
+	let mut mismatches = Vec::new();
+
+	let mut cores: HashMap<HostCpuTopology, Vec<usize>> = HashMap::new();
+	for &(cpu, topology) in cpus {
+		cores.entry(topology).or_default().push(cpu);
+	}
+	let mut shared_cores = cores
+		.iter()
+		.filter(|(_, cpus)| cpus.len() > 1)
+		.collect::<Vec<_>>();
+	shared_cores.sort();
+	for (topology, cpus) in shared_cores {
+		mismatches.push(format!(
+			"CPUs {:?} share core {} of package {}",
+			cpus, topology.core_id, topology.package_id
+		));
+	}
+
+	let mut packages: HashMap<usize, usize> = HashMap::new();
+	for (_, topology) in cpus {
+		*packages.entry(topology.package_id).or_default() += 1;
+	}
+	let min = packages.values().min();
+	let max = packages.values().max();
+	if min != max {
+		let mut packages = packages.into_iter().collect::<Vec<_>>();
+		packages.sort_unstable();
+		mismatches.push(format!(
+			"CPUs are unevenly distributed across packages (package, CPUs): {:?}",
+			packages
+		));
+	}
+
+	mismatches
+}
+
+/// Warns if the host CPUs, to which the vCPUs are pinned, do not form a regular topology.
+pub fn validate_affinity(cpus: &[usize]) {
+	if !cfg!(target_os = "linux") {
+		return;
+	}
+
+	let topologies = match cpus
+		.iter()
+		.map(|&cpu| HostCpuTopology::read(cpu).map(|topology| (cpu, topology)))
+		.collect::<io::Result<Vec<_>>>()
+	{
+		Ok(topologies) => topologies,
+		Err(err) => {
+			debug!("Unable to read the host CPU topology: {}", err);
+			return;
+		}
+	};
+
+	for mismatch in check_affinity_topology(&topologies) {
+		warn!("CPU affinity: {}", mismatch);
+	}
+}
+
+/// Reads the host CPUs, which share the physical core of `cpu`, including `cpu` itself.
+pub fn read_thread_siblings(cpu: usize) -> io::Result<Vec<usize>> {
+	let list = std::fs::read_to_string(format!(
+		"/sys/devices/system/cpu/cpu{}/topology/thread_siblings_list",
+		cpu
+	))?;
+	parse_ranges(iter::once(list.trim()))
+		.collect::<Result<_, _>>()
+		.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Groups the host CPUs by their physical core, given the SMT siblings of each CPU.
+///
+/// The cores and their CPUs keep the order of `cpus`.
+pub fn group_smt_siblings(cpus: &[(usize, Vec<usize>)]) -> Vec<Vec<usize>> {
+	let mut cores: Vec<(&[usize], Vec<usize>)> = Vec::new();
+	for (cpu, siblings) in cpus {
+		match cores
+			.iter_mut()
+			.find(|(core, _)| *core == siblings.as_slice())
+		{
+			Some((_, core_cpus)) => core_cpus.push(*cpu),
+			None => cores.push((siblings.as_slice(), vec![*cpu])),
+		}
+	}
+	cores.into_iter().map(|(_, cpus)| cpus).collect()
+}
+
+/// Orders the CPUs of the cores, so that the first CPU of every core comes before the second CPU
+/// of any core. Thus, vCPUs with consecutive ids only share a core, if they have to.
+pub fn smt_aware_order(cores: &[Vec<usize>]) -> Vec<usize> {
+	let threads = cores.iter().map(Vec::len).max().unwrap_or(0);
+	(0..threads)
+		.flat_map(|thread| {
+			cores
+				.iter()
+				.filter_map(move |cpus| cpus.get(thread).copied())
+		})
+		.collect()
+}
+
+/// Warns about host CPUs, which are SMT siblings, and reorders them for the vCPUs, see
+/// [`smt_aware_order`]. The order is kept, if the topology cannot be read.
+pub fn smt_aware_affinity(cpus: &[usize]) -> Vec<usize> {
+	if !cfg!(target_os = "linux") {
+		return cpus.to_vec();
+	}
+
+	let siblings = match cpus
+		.iter()
+		.map(|&cpu| read_thread_siblings(cpu).map(|siblings| (cpu, siblings)))
+		.collect::<io::Result<Vec<_>>>()
+	{
+		Ok(siblings) => siblings,
+		Err(err) => {
+			debug!("Unable to read the SMT siblings: {}", err);
+			return cpus.to_vec();
+		}
+	};
+
+	let cores = group_smt_siblings(&siblings);
+	for cpus in cores.iter().filter(|cpus| cpus.len() > 1) {
+		warn!("CPU affinity: CPUs {:?} are SMT siblings", cpus);
+	}
+	smt_aware_order(&cores)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -87,4 +546,293 @@ mod tests {
 
 		parse_ranges(["-1-2", "-5"]).for_each(|res| assert!(res.is_err()));
 	}
+
+	#[test]
+	fn test_parse_cpu_list() {
+		let parse = |list| parse_ranges([list]).collect::<Result<Vec<_>, _>>();
+
+		assert_eq!(parse("3"), Ok(vec![3]));
+		assert_eq!(parse("0,2,4"), Ok(vec![0, 2, 4]));
+		assert_eq!(parse("0-7:2"), Ok(vec![0, 2, 4, 6]));
+		assert_eq!(parse("0-3,8-11:2"), Ok(vec![0, 1, 2, 3, 8, 10]));
+		assert_eq!(parse("2-2:5"), Ok(vec![2]));
+		// CPUs, which the host does not have, are only warned about
+		assert_eq!(parse("100000"), Ok(vec![100000]));
+
+		assert_eq!(parse(""), Err(ParseRangeError::Empty));
+		assert_eq!(parse("0,,1"), Err(ParseRangeError::Empty));
+		assert_eq!(
+			parse("5-3"),
+			Err(ParseRangeError::Reversed { start: 5, end: 3 })
+		);
+		assert_eq!(parse("0-7:0"), Err(ParseRangeError::ZeroStep));
+		assert!(matches!(
+			parse("0-x"),
+			Err(ParseRangeError::InvalidNumber(_))
+		));
+		assert!(matches!(
+			parse("0-7:"),
+			Err(ParseRangeError::InvalidNumber(_))
+		));
+	}
+
+	#[test]
+	fn test_parse_mem_available() {
+		let meminfo = "MemTotal:       16303580 kB\nMemFree:         8556000 kB\nMemAvailable:   12223412 kB\n";
+		assert_eq!(parse_mem_available(meminfo), Some(12223412 * 1024));
+		assert_eq!(parse_mem_available("MemTotal: 16303580 kB\n"), None);
+	}
+
+	#[test]
+	fn test_mac() {
+		let mac = [0x52, 0x54, 0x00, 0xab, 0xcd, 0xef];
+		assert_eq!(parse_mac("52:54:00:ab:cd:ef"), Ok(mac));
+		assert_eq!(parse_mac("52-54-00-AB-CD-EF"), Ok(mac));
+		assert_eq!(format_mac(&mac), "52:54:00:ab:cd:ef");
+
+		assert_eq!(
+			parse_mac("52:54:00:ab:cd"),
+			Err(ParseMacError::InvalidLength)
+		);
+		assert_eq!(
+			parse_mac("52:54:00:ab:cd:ef:01"),
+			Err(ParseMacError::InvalidLength)
+		);
+		assert!(matches!(
+			parse_mac("52:54:00:ab:cd:x"),
+			Err(ParseMacError::InvalidByte(_))
+		));
+		assert!(matches!(
+			parse_mac("01:00:5e:00:00:01"),
+			Err(ParseMacError::Multicast(_))
+		));
+
+		let stable = stable_mac(Path::new("/hermit/hello_world"), 0);
+		assert_eq!(stable, stable_mac(Path::new("/hermit/hello_world"), 0));
+		assert_ne!(stable, stable_mac(Path::new("/hermit/hello_world"), 1));
+		assert_eq!(stable[0] & 3, 2);
+	}
+
+	#[test]
+	fn test_parse_kernel_args() {
+		let contents = "# network\n-ip 10.0.5.3\n\n  # disabled: -verbose\n--log-level=debug\n";
+		assert_eq!(
+			parse_kernel_args(contents),
+			["-ip", "10.0.5.3", "--log-level=debug"]
+		);
+		assert!(parse_kernel_args("# only a comment").is_empty());
+	}
+
+	#[test]
+	fn test_parse_nic() {
+		assert_eq!(
+			parse_nic("tap0"),
+			Ok(NicSpec {
+				backend: NetworkBackend::Tap("tap0"),
+				..Default::default()
+			})
+		);
+		assert_eq!(
+			parse_nic("virtio,ip=10.0.5.3"),
+			Ok(NicSpec {
+				backend: NetworkBackend::VirtioNet,
+				ip: Some(Ipv4Addr::new(10, 0, 5, 3)),
+				..Default::default()
+			})
+		);
+		assert_eq!(
+			parse_nic("tap1,ip=10.0.5.3,gateway=10.0.5.1,mask=255.255.255.0,mac=52:54:00:ab:cd:ef"),
+			Ok(NicSpec {
+				backend: NetworkBackend::Tap("tap1"),
+				ip: Some(Ipv4Addr::new(10, 0, 5, 3)),
+				gateway: Some(Ipv4Addr::new(10, 0, 5, 1)),
+				mask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+				mac: Some([0x52, 0x54, 0x00, 0xab, 0xcd, 0xef]),
+			})
+		);
+
+		assert_eq!(parse_nic(",ip=10.0.5.3"), Err(ParseNicError::MissingName));
+		assert_eq!(
+			parse_nic("tap0,mtu=9000"),
+			Err(ParseNicError::UnknownOption("mtu".to_string()))
+		);
+		assert_eq!(
+			parse_nic("tap0,ip"),
+			Err(ParseNicError::UnknownOption("ip".to_string()))
+		);
+		assert_eq!(
+			parse_nic("tap0,ip=10.0.5"),
+			Err(ParseNicError::InvalidAddress("10.0.5".to_string()))
+		);
+		assert!(matches!(
+			parse_nic("tap0,mac=01:00:5e:00:00:01"),
+			Err(ParseNicError::InvalidMac(ParseMacError::Multicast(_)))
+		));
+		assert_eq!(
+			parse_nic("tap0,dns=10.0.2.3"),
+			Err(ParseNicError::UnknownOption("dns".to_string()))
+		);
+	}
+
+	#[cfg(feature = "slirp")]
+	#[test]
+	fn test_parse_nic_slirp() {
+		assert_eq!(
+			parse_nic("slirp,dns=10.0.2.53"),
+			Ok(NicSpec {
+				backend: NetworkBackend::Slirp {
+					dns: Some(Ipv4Addr::new(10, 0, 2, 53))
+				},
+				..Default::default()
+			})
+		);
+	}
+
+	#[test]
+	fn test_parse_guest_route() {
+		let route = parse_guest_route("10.0.1.0/24:1").unwrap();
+		assert_eq!(route.nic, 1);
+		assert_eq!(route.network.prefix_len, 24);
+		assert!(route.network.contains("10.0.1.7".parse().unwrap()));
+		assert!(!route.network.contains("10.0.2.7".parse().unwrap()));
+		assert!(!route.network.contains("fd00::1".parse().unwrap()));
+
+		let route = parse_guest_route("fd00:1::/32:0").unwrap();
+		assert_eq!(route.nic, 0);
+		assert!(route.network.contains("fd00:1:2::3".parse().unwrap()));
+		assert!(parse_guest_route("0.0.0.0/0:0")
+			.unwrap()
+			.network
+			.contains("192.0.2.1".parse().unwrap()));
+
+		assert_eq!(
+			parse_guest_route("10.0.1.0/24"),
+			Err(ParseGuestRouteError::MissingNic)
+		);
+		assert_eq!(
+			parse_guest_route("10.0.1.0/33:1"),
+			Err(ParseGuestRouteError::InvalidNetwork(
+				"10.0.1.0/33".to_string()
+			))
+		);
+		assert_eq!(
+			parse_guest_route("10.0.1.0:1"),
+			Err(ParseGuestRouteError::InvalidNetwork("10.0.1.0".to_string()))
+		);
+		assert!(matches!(
+			parse_guest_route("10.0.1.0/24:eth1"),
+			Err(ParseGuestRouteError::InvalidNic(_))
+		));
+	}
+
+	#[test]
+	fn test_parse_msr_index() {
+		assert_eq!(parse_msr_index("0xce"), Ok(0xce));
+		assert_eq!(parse_msr_index("c0010015"), Ok(0xc001_0015));
+		assert!(parse_msr_index("0x").is_err());
+		assert!(parse_msr_index("MSR_PLATFORM_INFO").is_err());
+	}
+
+	#[test]
+	fn test_parse_port_forward() {
+		assert_eq!(
+			parse_port_forward("8080:80"),
+			Ok(PortForward {
+				host_port: 8080,
+				guest_port: 80,
+				protocol: Protocol::Tcp,
+			})
+		);
+		assert_eq!(
+			parse_port_forward("5353:53/udp"),
+			Ok(PortForward {
+				host_port: 5353,
+				guest_port: 53,
+				protocol: Protocol::Udp,
+			})
+		);
+		assert_eq!(
+			parse_port_forward("8080"),
+			Err(ParsePortForwardError::MissingGuestPort)
+		);
+		assert_eq!(
+			parse_port_forward("8080:80/sctp"),
+			Err(ParsePortForwardError::UnknownProtocol("sctp".to_string()))
+		);
+		assert!(matches!(
+			parse_port_forward("8080:65536"),
+			Err(ParsePortForwardError::InvalidPort(_))
+		));
+	}
+
+	#[test]
+	fn test_parse_entropy_seed() {
+		let seed = parse_entropy_seed("0x00ff10").unwrap();
+		assert_eq!(seed[..3], [0x00, 0xff, 0x10]);
+		assert!(seed[3..].iter().all(|&byte| byte == 0));
+		assert_eq!(
+			parse_entropy_seed(&"ab".repeat(ENTROPY_SIZE)),
+			Ok([0xab; ENTROPY_SIZE])
+		);
+		assert_eq!(
+			parse_entropy_seed(&"ab".repeat(ENTROPY_SIZE + 1)),
+			Err(ParseEntropySeedError::InvalidLength)
+		);
+		assert_eq!(
+			parse_entropy_seed("abc"),
+			Err(ParseEntropySeedError::InvalidLength)
+		);
+		assert_eq!(
+			parse_entropy_seed("12zz"),
+			Err(ParseEntropySeedError::InvalidByte("zz".to_string()))
+		);
+	}
+
+	#[test]
+	fn test_check_affinity_topology() {
+		let topology = |package_id, core_id| HostCpuTopology {
+			package_id,
+			core_id,
+		};
+
+		assert!(check_affinity_topology(&[
+			(0, topology(0, 0)),
+			(1, topology(0, 1)),
+			(8, topology(1, 0)),
+			(9, topology(1, 1)),
+		])
+		.is_empty());
+
+		let mismatches = check_affinity_topology(&[
+			(0, topology(0, 0)),
+			(4, topology(0, 0)),
+			(8, topology(1, 0)),
+		]);
+		assert_eq!(
+			mismatches,
+			[
+				"CPUs [0, 4] share core 0 of package 0",
+				"CPUs are unevenly distributed across packages (package, CPUs): [(0, 2), (1, 1)]"
+			]
+		);
+	}
+
+	#[test]
+	fn test_smt_aware_order() {
+		// CPUs 0 and 4 as well as 1 and 5 are siblings
+		let cores = group_smt_siblings(&[
+			(0, vec![0, 4]),
+			(1, vec![1, 5]),
+			(4, vec![0, 4]),
+			(5, vec![1, 5]),
+			(2, vec![2, 6]),
+		]);
+		assert_eq!(cores, [vec![0, 4], vec![1, 5], vec![2]]);
+		assert_eq!(smt_aware_order(&cores), [0, 1, 2, 4, 5]);
+
+		// without SMT, the order is kept
+		let cores = group_smt_siblings(&[(3, vec![3]), (1, vec![1])]);
+		assert_eq!(smt_aware_order(&cores), [3, 1]);
+		assert!(smt_aware_order(&[]).is_empty());
+	}
 }
@@ -3,23 +3,30 @@ use super::paging::*;
 use core::arch::x86_64::_rdtsc as rdtsc;
 use goblin::elf;
 use goblin::elf64::header::{EM_X86_64, ET_DYN};
-use goblin::elf64::program_header::{PT_LOAD, PT_TLS};
+use goblin::elf64::program_header::{PF_R, PF_W, PF_X, PT_LOAD, PT_TLS};
 use goblin::elf64::reloc::*;
 use log::{debug, error, warn};
 use raw_cpuid::CpuId;
-use std::io::Write;
-use std::net::Ipv4Addr;
+use std::ffi::{CStr, OsStr};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::ptr::write;
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 use std::{fs, io, mem, slice};
 use thiserror::Error;
 
+use crate::console_log::{ConsoleLog, DebugPortLog};
 use crate::consts::*;
+use crate::cpu_features::{CpuFeatureChange, CpuidOverride};
+use crate::kernel_signature::KernelVerifier;
 use crate::os::vcpu::UhyveCPU;
 use crate::os::DebugExitInfo;
 use crate::os::HypervisorError;
+use crate::utils::parse_kernel_args;
 
 const MHZ_TO_HZ: u64 = 1000000;
 const KHZ_TO_HZ: u64 = 1000;
@@ -53,6 +60,55 @@ pub struct BootInfo {
 	pub hcip: [u8; 4],
 	pub hcgateway: [u8; 4],
 	pub hcmask: [u8; 4],
+	/// Number of entries of each shared network queue
+	pub net_queue_depth: u32,
+	/// IPv6 address of the guest, `::` if it is not configured
+	pub hcip6: [u8; 16],
+	pub hcprefix6: u8,
+	/// Number of valid entries in `nics`
+	pub num_nics: u32,
+	/// The network interfaces, whose shared queues follow each other from `SHAREDQUEUE_START`
+	pub nics: [NicInfo; UHYVE_MAX_NICS],
+	/// Guest physical address of the page with the cross timestamps of the host, 0 without `--ptp`
+	pub ptp_clock_page: u64,
+	/// Seeds the CSPRNG of the kernel, see [`EntropySource`]
+	pub entropy: [u8; ENTROPY_SIZE],
+}
+
+/// Configuration of a network interface in the boot info.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NicInfo {
+	pub ip: [u8; 4],
+	pub gateway: [u8; 4],
+	pub mask: [u8; 4],
+	pub mac: [u8; 6],
+}
+
+impl NicInfo {
+	/// An interface without configuration, the defaults match those of `hcip`, `hcgateway` and
+	/// `hcmask`.
+	pub const UNCONFIGURED: Self = Self {
+		ip: [255, 255, 255, 255],
+		gateway: [255, 255, 255, 255],
+		mask: [255, 255, 255, 0],
+		mac: [0; 6],
+	};
+
+	pub fn new(nic: &NicSpec<'_>, mac: [u8; 6]) -> Self {
+		let mut info = Self::UNCONFIGURED;
+		if let Some(ip) = nic.ip {
+			info.ip = ip.octets();
+		}
+		if let Some(gateway) = nic.gateway {
+			info.gateway = gateway.octets();
+		}
+		if let Some(mask) = nic.mask {
+			info.mask = mask.octets();
+		}
+		info.mac = mac;
+		info
+	}
 }
 
 impl BootInfo {
@@ -84,6 +140,13 @@ impl BootInfo {
 			hcip: [255, 255, 255, 255],
 			hcgateway: [255, 255, 255, 255],
 			hcmask: [255, 255, 255, 0],
+			net_queue_depth: UHYVE_QUEUE_SIZE as u32,
+			hcip6: [0; 16],
+			hcprefix6: 0,
+			num_nics: 0,
+			nics: [NicInfo::UNCONFIGURED; UHYVE_MAX_NICS],
+			ptp_clock_page: 0,
+			entropy: [0; ENTROPY_SIZE],
 		}
 	}
 }
@@ -101,11 +164,299 @@ pub struct Parameter<'a> {
 	pub verbose: bool,
 	pub hugepage: bool,
 	pub mergeable: bool,
-	pub ip: Option<&'a str>,
-	pub gateway: Option<&'a str>,
-	pub mask: Option<&'a str>,
-	pub nic: Option<&'a str>,
+	/// IPv6 address of the guest, which is only configured if set
+	pub ip6: Option<Ipv6Addr>,
+	/// Prefix length of `ip6`, 64 by default
+	pub prefix_len6: Option<u8>,
+	/// The network interfaces, the first one is also configured through `hcip` and friends
+	pub nics: &'a [NicSpec<'a>],
+	/// Whether the TUN/TAP devices of `nics` exchange Ethernet frames or IP packets
+	pub nic_mode: NicMode,
 	pub gdbport: Option<u16>,
+	/// Unix socket, on which the GDB stub listens instead of `gdbport`
+	pub gdb_socket: Option<&'a Path>,
+	/// Stops the boot processor at the entry point of the kernel, when GDB resumes it the first time
+	pub debug_on_startup: bool,
+	/// MSRs, whose accesses are forwarded to the host CPU instead of being handled by KVM
+	pub msr_whitelist: &'a [u32],
+	/// TSC frequency of the vCPUs in kHz, to which KVM scales the host's TSC
+	pub tsc_khz: Option<u32>,
+	/// Offset of the TSC of all vCPUs to the host's TSC in ticks
+	pub tsc_offset: Option<i64>,
+	pub apic_timer_hz: Option<u32>,
+	pub prealloc: bool,
+	pub pretouch_workers: usize,
+	pub print_memory_layout: bool,
+	pub ksm_report_interval: u64,
+	pub nmi_on_signal: bool,
+	/// Dumps the registers of all vCPUs to stderr on `SIGUSR2` instead of injecting an NMI
+	pub registers_on_signal: bool,
+	/// Pauses and resumes the vCPUs on `SIGUSR1` instead of rebalancing them
+	pub pause_on_signal: bool,
+	pub console_log_file: Option<&'a Path>,
+	pub console_log_append: bool,
+	pub kernel_log_file: Option<&'a Path>,
+	/// Records the output of the debug ports in this file instead of printing it, unless `verbose`
+	pub debug_port_file: Option<&'a Path>,
+	/// Starts every line in `debug_port_file`
+	pub debug_port_prefix: Option<&'a str>,
+	/// Let the host kernel process the virtqueues of virtio-net, if `/dev/vhost-net` is available
+	pub vhost_net: bool,
+	/// Captures the packets of the network interfaces in this libpcap file
+	pub pcap: Option<&'a Path>,
+	/// Reads arguments of the kernel from this file, which precede those on the command line
+	pub kernel_args_file: Option<&'a Path>,
+	/// Forwards ports of the host to the first network interface of the guest
+	pub port_forwards: &'a [PortForward],
+	/// Forwards the packets, which a TUN device receives for another network interface, to the
+	/// TUN device of that interface according to `guest_routes`
+	pub guest_ip_forward: bool,
+	pub guest_routes: &'a [GuestRoute],
+	/// Publishes cross timestamps of the kvmclock and the host's realtime clock to the guest
+	pub ptp: bool,
+	/// Attaches a virtio block device, which is backed by this raw image file
+	pub block: Option<&'a Path>,
+	/// Never writes to the image of the block device, the guest's writes go to an overlay
+	pub block_readonly: bool,
+	/// Writes the changed sectors of the block device to this copy-on-write overlay
+	pub block_overlay: Option<&'a Path>,
+	/// Attaches a virtio memory balloon
+	pub balloon: bool,
+	/// Reads the size of the balloon from this file and writes the guest's memory statistics
+	/// next to it
+	pub balloon_control: Option<&'a Path>,
+	/// Inflates the balloon to this size in bytes, once the guest's driver is ready
+	pub balloon_target: Option<usize>,
+	/// Seeds the CSPRNG of the kernel
+	pub entropy: EntropySource<'a>,
+	pub net_irq_resample: bool,
+	pub soft_iommu: bool,
+	pub dirty_tracking: bool,
+	/// Logs the pages written by the guest in a bitmap per memory slot, see [`Vm::get_dirty_log`]
+	pub dirty_log: bool,
+	/// Saves the VM to this file on `SIGTERM` before stopping it, see `Uhyve::snapshot`
+	pub snapshot: Option<&'a Path>,
+	pub deadline: Option<DeadlineParameters>,
+	pub memory_backend: MemoryBackend<'a>,
+	pub stats_interval: u64,
+	pub stats_log: Option<&'a Path>,
+	/// Prometheus text file, to which the packet size histograms are written every `stats_interval`
+	pub net_metrics_file: Option<&'a Path>,
+	pub memory_pressure_hints: bool,
+	pub network_bridge: Option<&'a str>,
+	pub remove_bridge_on_exit: bool,
+	pub preemption_timer: Option<u64>,
+	/// Forces an exit of a vCPU, which does not exit within this many cycles, 0 disables it
+	pub notify_vmexit_window: u32,
+	/// Log every exit of the vCPUs at the debug level
+	pub verbose_exits: bool,
+	/// The `KVM_EXIT_*` reasons, which `verbose_exits` logs, all if it is empty
+	pub exit_filter: &'a [u32],
+	pub auto_migrate: bool,
+	pub strict_memory_model: bool,
+	pub host_allowed_paths: Option<&'a [PathBuf]>,
+	pub tun_tx_queue_size: Option<u32>,
+	/// Exchanges the packets with the TAP device with a `virtio_net_hdr` in front of them
+	pub net_vnet_hdr: bool,
+	pub kernel_signature: Option<KernelSignature<'a>>,
+	pub shm_id: Option<&'a str>,
+	pub clock_offset: i64,
+	pub tui: bool,
+	pub net_queue_depth: Option<usize>,
+	pub fault_tolerant: bool,
+	/// Adds or removes features in the CPUID of the vCPUs
+	pub cpu_features: &'a [CpuFeatureChange],
+	/// Bits of the CPUID leaves, which are set or cleared after `cpu_features`
+	pub cpuid_overrides: &'a [CpuidOverride],
+	/// Binds the guest memory to the NUMA nodes of the host, an empty slice leaves the placement
+	/// to the host kernel
+	pub numa_nodes: &'a [NumaNode],
+	pub mac: Option<[u8; 6]>,
+}
+
+/// A network interface of the guest, as given by `--nic`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct NicSpec<'a> {
+	pub backend: NetworkBackend<'a>,
+	pub ip: Option<Ipv4Addr>,
+	pub gateway: Option<Ipv4Addr>,
+	pub mask: Option<Ipv4Addr>,
+	/// MAC address, which is derived from the path of the kernel if unset
+	pub mac: Option<[u8; 6]>,
+}
+
+/// The host side of a network interface of the guest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NetworkBackend<'a> {
+	/// A TAP device or a bridge, to which a new TAP device is attached. The kernel names a new
+	/// TAP device, if the name is empty.
+	Tap(&'a str),
+	/// Only the virtio-net device, which has no interface in the shared queues of uhyve.
+	VirtioNet,
+	/// User-mode networking through libslirp, which needs neither a TAP device nor privileges.
+	#[cfg(feature = "slirp")]
+	Slirp {
+		/// Address of the DNS forwarder in the virtual network, the third host by default
+		dns: Option<Ipv4Addr>,
+	},
+}
+
+impl Default for NetworkBackend<'_> {
+	fn default() -> Self {
+		Self::Tap("")
+	}
+}
+
+/// The transport protocol of a forwarded port.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+	Tcp,
+	Udp,
+}
+
+impl Protocol {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Tcp => "tcp",
+			Self::Udp => "udp",
+		}
+	}
+}
+
+/// A port of the host, which is forwarded to a port of the guest, as given by `--port-forward`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PortForward {
+	pub host_port: u16,
+	pub guest_port: u16,
+	pub protocol: Protocol,
+}
+
+/// An IPv4 or IPv6 network like `10.0.1.0/24`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IpNetwork {
+	pub address: IpAddr,
+	pub prefix_len: u8,
+}
+
+impl IpNetwork {
+	/// Checks, whether `address` is in the network.
+	pub fn contains(&self, address: IpAddr) -> bool {
+		match (self.address, address) {
+			(IpAddr::V4(network), IpAddr::V4(address)) => {
+				let mask = u32::MAX
+					.checked_shl(32 - u32::from(self.prefix_len))
+					.unwrap_or(0);
+				u32::from(network) & mask == u32::from(address) & mask
+			}
+			(IpAddr::V6(network), IpAddr::V6(address)) => {
+				let mask = u128::MAX
+					.checked_shl(128 - u32::from(self.prefix_len))
+					.unwrap_or(0);
+				u128::from(network) & mask == u128::from(address) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+/// A network, whose packets are forwarded to a network interface, as given by `--guest-route`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GuestRoute {
+	pub network: IpNetwork,
+	/// The index of the network interface in `nics`
+	pub nic: usize,
+}
+
+/// The packets, which the TUN/TAP devices of the network interfaces exchange, see `--nic-mode`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NicMode {
+	/// Ethernet frames of a TAP device
+	Tap,
+	/// IP packets of a TUN device, which requires a guest with raw IP networking
+	Tun,
+}
+
+impl NicMode {
+	/// Parses `tap` or `tun`.
+	pub fn parse(mode: &str) -> Option<Self> {
+		match mode {
+			"tap" => Some(Self::Tap),
+			"tun" => Some(Self::Tun),
+			_ => None,
+		}
+	}
+}
+
+/// The host memory backing the guest memory.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryBackend<'a> {
+	/// Private anonymous memory, which is discarded on exit.
+	#[default]
+	Anonymous,
+	/// A shared mapping of a file, which preserves the guest memory across runs.
+	File(&'a Path),
+}
+
+impl<'a> MemoryBackend<'a> {
+	/// Parses `anonymous` or `file:<PATH>`.
+	pub fn parse(backend: &'a str) -> Option<Self> {
+		match backend.split_once(':') {
+			Some(("file", path)) if !path.is_empty() => Some(Self::File(Path::new(path))),
+			None if backend == "anonymous" => Some(Self::Anonymous),
+			_ => None,
+		}
+	}
+}
+
+/// The source of [`BootInfo::entropy`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum EntropySource<'a> {
+	/// The entropy of the host's operating system
+	#[default]
+	Os,
+	/// The first [`ENTROPY_SIZE`] bytes of a file
+	File(&'a Path),
+	/// A fixed seed, which makes the guest's random numbers predictable
+	Seed([u8; ENTROPY_SIZE]),
+}
+
+/// A NUMA node of the guest, as given by `--numa`.
+///
+/// uhyve provides no ACPI tables, so the guest does not learn its topology, but the memory of a
+/// node is allocated on the host nodes of its CPUs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+	/// The host CPUs, to which the vCPUs of this node are pinned
+	pub cpus: Vec<usize>,
+	/// Guest physical addresses of the memory of this node
+	pub memory: Range<usize>,
+}
+
+/// The files, which are used to verify the kernel before loading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelSignature<'a> {
+	pub signature: &'a Path,
+	pub public_key: &'a Path,
+}
+
+impl KernelSignature<'_> {
+	/// Reads the signature and the public key.
+	///
+	/// # Panics
+	///
+	/// Panics if one of the files cannot be read or is malformed.
+	pub(crate) fn verifier(&self) -> KernelVerifier {
+		KernelVerifier::open(self.signature, self.public_key)
+			.expect("Unable to read the kernel signature or public key")
+	}
+}
+
+/// Parameters of the `SCHED_DEADLINE` policy for the vCPU threads in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParameters {
+	pub runtime: u64,
+	pub deadline: u64,
+	pub period: u64,
 }
 
 #[repr(C, packed)]
@@ -153,6 +504,10 @@ struct SysExit {
 const MAX_ARGC: usize = 128;
 // FIXME: Do not use a fix number of environment variables
 const MAX_ENVC: usize = 128;
+/// Maximum number of arguments of the kernel, the first entry of `argv` is its path
+pub const MAX_KERNEL_ARGS: usize = MAX_ARGC - 1;
+/// Number of bytes of [`BootInfo::entropy`]
+pub const ENTROPY_SIZE: usize = 64;
 
 #[repr(C, packed)]
 struct SysCmdsize {
@@ -184,6 +539,10 @@ pub enum LoadKernelError {
 	Goblin(#[from] goblin::error::Error),
 	#[error("guest memory size is not large enough")]
 	InsufficientMemory,
+	#[error("signature verification of the kernel failed")]
+	SignatureVerificationFailed,
+	#[error("{0} kernel arguments exceed the limit of {max}", max = MAX_KERNEL_ARGS)]
+	KernelArgsFileTooLarge(usize),
 }
 
 pub type LoadKernelResult<T> = Result<T, LoadKernelError>;
@@ -222,37 +581,63 @@ pub trait VirtualCPU {
 	/// Returns the (host) path of the kernel binary.
 	fn kernel_path(&self) -> &Path;
 
+	/// Returns the file, to which the guest's console output is copied.
+	fn console_log(&self) -> Option<&ConsoleLog>;
+
+	/// Returns the file, in which the output of the debug ports is recorded.
+	fn debug_port_log(&self) -> Option<&DebugPortLog>;
+
+	/// Returns the arguments of the kernel without its path, see [`collect_kernel_args`].
+	fn kernel_args(&self) -> &[String];
+
+	/// Returns the host directories, in which the guest may open and unlink files, or `None` for no restriction.
+	fn host_allowed_paths(&self) -> Option<&[PathBuf]>;
+
+	/// Checks if the guest may access the host file `name` according to [`VirtualCPU::host_allowed_paths`].
+	///
+	/// The path is canonicalized, so neither `..` nor symbolic links escape the allowed directories.
+	/// Files, which do not exist yet, are resolved through their parent directory.
+	fn host_path_allowed(&self, name: &CStr) -> bool {
+		let allowed_paths = match self.host_allowed_paths() {
+			Some(allowed_paths) => allowed_paths,
+			None => return true,
+		};
+
+		let path = Path::new(OsStr::from_bytes(name.to_bytes()));
+		let resolved = path.canonicalize().or_else(|err| {
+			let file_name = path.file_name().ok_or(err)?;
+			let parent = match path.parent() {
+				Some(parent) if !parent.as_os_str().is_empty() => parent,
+				_ => Path::new("."),
+			};
+			parent.canonicalize().map(|parent| parent.join(file_name))
+		});
+		let allowed = resolved.map_or(false, |path| {
+			allowed_paths
+				.iter()
+				.any(|allowed| path.starts_with(allowed))
+		});
+		if !allowed {
+			warn!("Denied access to the host file {}", path.display());
+		}
+		allowed
+	}
+
 	fn cmdsize(&self, args_ptr: usize) {
 		let syssize = unsafe { &mut *(args_ptr as *mut SysCmdsize) };
 		syssize.argc = 0;
 		syssize.envc = 0;
 
-		let mut counter: i32 = 0;
-		let mut separator_pos: i32 = 0;
 		let path = self.kernel_path();
-		let mut found_separator = false;
 		syssize.argsz[0] = path.as_os_str().len() as i32 + 1;
 
-		for argument in std::env::args() {
-			if !found_separator && argument == "--" {
-				separator_pos = counter + 1;
-				found_separator = true;
-			}
-
-			if found_separator && counter >= separator_pos {
-				syssize.argsz[(counter - separator_pos + 1) as usize] = argument.len() as i32 + 1;
-			}
-
-			counter += 1;
-		}
-
-		if found_separator && counter >= separator_pos {
-			syssize.argc = counter - separator_pos + 1;
-		} else {
-			syssize.argc = 1;
+		let arguments = self.kernel_args();
+		for (index, argument) in arguments.iter().enumerate() {
+			syssize.argsz[index + 1] = argument.len() as i32 + 1;
 		}
+		syssize.argc = arguments.len() as i32 + 1;
 
-		counter = 0;
+		let mut counter: i32 = 0;
 		for (key, value) in std::env::vars() {
 			if counter < MAX_ENVC.try_into().unwrap() {
 				syssize.envsz[counter as usize] = (key.len() + value.len()) as i32 + 2;
@@ -270,10 +655,7 @@ pub trait VirtualCPU {
 	fn cmdval(&self, args_ptr: usize) {
 		let syscmdval = unsafe { &*(args_ptr as *const SysCmdval) };
 
-		let mut counter: i32 = 0;
 		let argv = self.host_address(syscmdval.argv as usize);
-		let mut found_separator = false;
-		let mut separator_pos: i32 = 0;
 
 		// copy kernel path as first argument
 		{
@@ -289,32 +671,22 @@ pub trait VirtualCPU {
 		}
 
 		// Copy the application arguments into the vm memory
-		for argument in std::env::args() {
-			if !found_separator && argument == "--" {
-				separator_pos = counter + 1;
-				found_separator = true;
-			}
-
-			if found_separator && counter >= separator_pos {
-				let argvptr = unsafe {
-					self.host_address(
-						*((argv + (counter - separator_pos + 1) as usize * mem::size_of::<usize>())
-							as *mut *mut u8) as usize,
-					)
-				};
-				let len = argument.len();
-				let slice = unsafe { slice::from_raw_parts_mut(argvptr as *mut u8, len + 1) };
-
-				// Create string for environment variable
-				slice[0..len].copy_from_slice(argument.as_bytes());
-				slice[len] = 0;
-			}
+		for (index, argument) in self.kernel_args().iter().enumerate() {
+			let argvptr = unsafe {
+				self.host_address(
+					*((argv + (index + 1) * mem::size_of::<usize>()) as *mut *mut u8) as usize,
+				)
+			};
+			let len = argument.len();
+			let slice = unsafe { slice::from_raw_parts_mut(argvptr as *mut u8, len + 1) };
 
-			counter += 1;
+			// Create string for environment variable
+			slice[0..len].copy_from_slice(argument.as_bytes());
+			slice[len] = 0;
 		}
 
 		// Copy the environment variables into the vm memory
-		counter = 0;
+		let mut counter: i32 = 0;
 		let envp = self.host_address(syscmdval.envp as usize);
 		for (key, value) in std::env::vars() {
 			if counter < MAX_ENVC.try_into().unwrap() {
@@ -338,11 +710,16 @@ pub trait VirtualCPU {
 	}
 
 	/// unlink delets a name from the filesystem. This is used to handle `unlink` syscalls from the guest.
-	/// TODO: UNSAFE AS *%@#. It has to be checked that the VM is allowed to unlink that file!
+	/// TODO: UNSAFE AS *%@#. Without `--host-allowed-paths`, the VM may unlink any file!
 	fn unlink(&self, args_ptr: usize) {
 		unsafe {
 			let sysunlink = &mut *(args_ptr as *mut SysUnlink);
-			sysunlink.ret = libc::unlink(self.host_address(sysunlink.name as usize) as *const i8);
+			let name = self.host_address(sysunlink.name as usize) as *const i8;
+			if !self.host_path_allowed(CStr::from_ptr(name)) {
+				sysunlink.ret = -libc::EPERM;
+				return;
+			}
+			sysunlink.ret = libc::unlink(name);
 		}
 	}
 
@@ -356,11 +733,12 @@ pub trait VirtualCPU {
 	fn open(&self, args_ptr: usize) {
 		unsafe {
 			let sysopen = &mut *(args_ptr as *mut SysOpen);
-			sysopen.ret = libc::open(
-				self.host_address(sysopen.name as usize) as *const i8,
-				sysopen.flags,
-				sysopen.mode,
-			);
+			let name = self.host_address(sysopen.name as usize) as *const i8;
+			if !self.host_path_allowed(CStr::from_ptr(name)) {
+				sysopen.ret = -libc::EPERM;
+				return;
+			}
+			sysopen.ret = libc::open(name, sysopen.flags, sysopen.mode);
 		}
 	}
 
@@ -396,6 +774,18 @@ pub trait VirtualCPU {
 		let syswrite = unsafe { &*(args_ptr as *const SysWrite) };
 		let mut bytes_written: usize = 0;
 		let buffer = self.virt_to_phys(syswrite.buf as usize);
+		let is_console = syswrite.fd == libc::STDOUT_FILENO || syswrite.fd == libc::STDERR_FILENO;
+
+		if is_console {
+			if let Some(debug_port_log) = self.debug_port_log() {
+				debug_port_log.write(unsafe {
+					slice::from_raw_parts(self.host_address(buffer) as *const u8, syswrite.len)
+				})?;
+				if !debug_port_log.echo() {
+					bytes_written = syswrite.len;
+				}
+			}
+		}
 
 		while bytes_written != syswrite.len {
 			unsafe {
@@ -412,6 +802,14 @@ pub trait VirtualCPU {
 			}
 		}
 
+		if let Some(console_log) = self.console_log() {
+			if is_console {
+				console_log.write(unsafe {
+					slice::from_raw_parts(self.host_address(buffer) as *const u8, syswrite.len)
+				})?;
+			}
+		}
+
 		Ok(())
 	}
 
@@ -426,7 +824,19 @@ pub trait VirtualCPU {
 
 	/// Handles an UART syscall by writing to stdout.
 	fn uart(&self, buf: &[u8]) -> io::Result<()> {
-		io::stdout().write_all(buf)
+		match self.debug_port_log() {
+			Some(debug_port_log) => {
+				debug_port_log.write(buf)?;
+				if debug_port_log.echo() {
+					io::stdout().write_all(buf)?;
+				}
+			}
+			None => io::stdout().write_all(buf)?,
+		}
+		if let Some(console_log) = self.console_log() {
+			console_log.write(buf)?;
+		}
+		Ok(())
 	}
 }
 
@@ -439,6 +849,52 @@ fn create_gdt_entry(flags: u64, base: u64, limit: u64) -> u64 {
 		| (limit & 0x0000ffffu64)
 }
 
+/// A range of the guest physical address space, see [`Vm::print_memory_layout`].
+#[derive(Debug, Clone)]
+pub struct GuestMemoryRegion {
+	pub start: u64,
+	pub end: u64,
+	pub description: String,
+}
+
+impl GuestMemoryRegion {
+	pub fn new(start: u64, size: u64, description: impl Into<String>) -> Self {
+		Self {
+			start,
+			end: start + size,
+			description: description.into(),
+		}
+	}
+}
+
+fn segment_permissions(flags: u32) -> String {
+	[(PF_R, 'r'), (PF_W, 'w'), (PF_X, 'x')]
+		.iter()
+		.map(|&(flag, c)| if flags & flag != 0 { c } else { '-' })
+		.collect()
+}
+
+fn print_guest_memory_layout(regions: &mut [GuestMemoryRegion]) {
+	regions.sort_by_key(|region| (region.start, region.end));
+
+	println!();
+	println!("Guest memory layout:");
+	println!(
+		"{:<18}   {:<18}  {:>10}  Description",
+		"Start", "End", "Size"
+	);
+	for region in regions.iter() {
+		println!(
+			"0x{:016x} - 0x{:016x}  {:>#10x}  {}",
+			region.start,
+			region.end,
+			region.end - region.start,
+			region.description
+		);
+	}
+	println!();
+}
+
 pub trait Vm {
 	/// Returns the number of cores for the vm.
 	fn num_cpus(&self) -> u32;
@@ -452,13 +908,58 @@ pub trait Vm {
 	fn set_entry_point(&mut self, entry: u64);
 	fn get_entry_point(&self) -> u64;
 	fn kernel_path(&self) -> &Path;
+	/// Returns the verifier of the kernel signature, if the kernel has to be signed.
+	fn kernel_verifier(&self) -> Option<&KernelVerifier>;
 	fn create_cpu(&self, id: u32) -> HypervisorResult<UhyveCPU>;
 	fn set_boot_info(&mut self, header: *const BootInfo);
 	fn cpu_online(&self) -> u32;
 	fn get_ip(&self) -> Option<Ipv4Addr>;
 	fn get_gateway(&self) -> Option<Ipv4Addr>;
 	fn get_mask(&self) -> Option<Ipv4Addr>;
+	fn get_ip6(&self) -> Option<Ipv6Addr>;
+	fn get_prefix_len6(&self) -> Option<u8>;
+	/// Returns the network interfaces, which are passed to the kernel.
+	fn get_nics(&self) -> Vec<NicInfo> {
+		Vec::new()
+	}
 	fn verbose(&self) -> bool;
+	/// Print the guest physical memory layout after loading the kernel.
+	fn print_memory_layout(&self) -> bool;
+	/// Returns the offset of the guest's clock to the host's clock in nanoseconds.
+	fn clock_offset(&self) -> i64;
+	/// Returns the TSC frequency of the vCPUs in kHz, if it has been changed from the host's.
+	fn tsc_khz(&self) -> Option<u32> {
+		None
+	}
+	/// Returns the number of entries of each shared network queue.
+	fn net_queue_depth(&self) -> u32 {
+		UHYVE_QUEUE_SIZE as u32
+	}
+	/// Returns the address of the page with the cross timestamps of the host, if there is one.
+	fn ptp_clock_page(&self) -> Option<u64> {
+		None
+	}
+	/// Returns the seed of the kernel's CSPRNG, see [`collect_entropy`].
+	fn entropy(&self) -> [u8; ENTROPY_SIZE];
+	/// Returns the bitmap of the pages of the memory slot `slot`, which the guest has written since
+	/// the last read. Requires `dirty_log`, bit `n % 64` of word `n / 64` belongs to page `n`.
+	fn get_dirty_log(&self, slot: u32) -> HypervisorResult<Vec<u64>>;
+	/// Discards the logged pages of `slot`, so that the next bitmap only contains later writes.
+	fn clear_dirty_log(&self, slot: u32) -> HypervisorResult<()> {
+		// reading the bitmap resets it and re-arms the tracking
+		self.get_dirty_log(slot).map(drop)
+	}
+	/// Stops all vCPUs before they enter the guest again, until [`Vm::resume`].
+	///
+	/// Returns before the vCPUs have stopped. The vCPUs notify the guest's kvmclock, that they
+	/// have been paused.
+	fn pause(&self) -> HypervisorResult<()>;
+	/// Lets the vCPUs continue, which have been stopped by [`Vm::pause`].
+	fn resume(&self) -> HypervisorResult<()>;
+	/// Returns the platform specific regions of the guest physical address space.
+	fn platform_memory_regions(&self) -> Vec<GuestMemoryRegion> {
+		Vec::new()
+	}
 
 	/// Initialize the page tables for the guest
 	fn init_guest_mem(&self) {
@@ -515,6 +1016,12 @@ pub trait Vm {
 		debug!("Load kernel from {}", self.kernel_path().display());
 
 		let buffer = fs::read(self.kernel_path())?;
+		if let Some(verifier) = self.kernel_verifier() {
+			if !verifier.verify(&buffer) {
+				return Err(LoadKernelError::SignatureVerificationFailed);
+			}
+			debug!("Kernel signature is valid");
+		}
 		let elf = elf::Elf::parse(&buffer)?;
 
 		if !elf.libraries.is_empty() {
@@ -555,6 +1062,29 @@ pub trait Vm {
 			write(&mut (*boot_info).hcmask, mask.octets());
 		}
 
+		write(&mut (*boot_info).net_queue_depth, self.net_queue_depth());
+		write(
+			&mut (*boot_info).ptp_clock_page,
+			self.ptp_clock_page().unwrap_or(0),
+		);
+		write(&mut (*boot_info).entropy, self.entropy());
+
+		// forward the network interfaces to kernel
+		let nics = self.get_nics();
+		write(&mut (*boot_info).num_nics, nics.len() as u32);
+		for (index, nic) in nics.into_iter().enumerate() {
+			write(&mut (*boot_info).nics[index], nic);
+		}
+
+		// forward IPv6 address and prefix length to kernel
+		if let Some(ip6) = self.get_ip6() {
+			write(&mut (*boot_info).hcip6, ip6.octets());
+			write(
+				&mut (*boot_info).hcprefix6,
+				self.get_prefix_len6().unwrap_or(64),
+			);
+		}
+
 		let (start_address, elf_entry) = if is_dyn {
 			// TODO: should be a random start address, if we have a relocatable executable
 			(0x400000u64, 0x400000u64 + elf.entry)
@@ -598,22 +1128,38 @@ pub trait Vm {
 		let n = SystemTime::now()
 			.duration_since(SystemTime::UNIX_EPOCH)
 			.expect("SystemTime before UNIX EPOCH!");
-		write(&mut (*boot_info).boot_gtod, n.as_secs() * 1000000);
+		let boot_time = apply_clock_offset(n.as_nanos() as u64, self.clock_offset());
+		write(&mut (*boot_info).boot_gtod, boot_time / 1000);
 
-		let cpuid = CpuId::new();
-		let mhz: u32 = detect_freq_from_cpuid(&cpuid).unwrap_or_else(|_| {
-			debug!("Failed to detect from cpuid");
-			detect_freq_from_cpuid_hypervisor_info(&cpuid).unwrap_or_else(|_| {
-				debug!("Failed to detect from hypervisor_info");
-				get_cpu_frequency_from_os().unwrap_or(0)
-			})
-		});
-		debug!("detected a cpu frequency of {} Mhz", mhz);
+		let mhz = self
+			.tsc_khz()
+			.map_or_else(detect_cpu_frequency, |khz| khz / 1000);
 		write(&mut (*boot_info).cpu_freq, mhz);
 		if (*boot_info).cpu_freq == 0 {
 			warn!("Unable to determine processor frequency");
 		}
 
+		let mut layout = vec![
+			GuestMemoryRegion::new(BOOT_GDT, BOOT_GDT_MAX * mem::size_of::<u64>() as u64, "GDT"),
+			GuestMemoryRegion::new(
+				BOOT_INFO_ADDR,
+				mem::size_of::<BootInfo>() as u64,
+				"BootInfo",
+			),
+			GuestMemoryRegion::new(
+				BOOT_PML4,
+				BOOT_PDE + PAGE_SIZE as u64 - BOOT_PML4,
+				"Page tables (PML4, PDPTE, PDE)",
+			),
+			GuestMemoryRegion::new(
+				start_address - KERNEL_STACK_SIZE,
+				KERNEL_STACK_SIZE,
+				"Boot stack",
+			),
+			GuestMemoryRegion::new(IOAPIC_BASE, IOAPIC_SIZE, "IOAPIC (MMIO)"),
+			GuestMemoryRegion::new(APIC_DEFAULT_BASE, PAGE_SIZE as u64, "Local APIC (MMIO)"),
+		];
+
 		// load kernel and determine image size
 		let vm_slice = std::slice::from_raw_parts_mut(vm_mem, vm_mem_length);
 		let mut image_size = 0;
@@ -639,6 +1185,16 @@ pub trait Vm {
 						return Err(LoadKernelError::InsufficientMemory);
 					}
 
+					layout.push(GuestMemoryRegion::new(
+						region_start as u64,
+						program_header.p_memsz,
+						format!(
+							"ELF segment (offset 0x{:x}, {})",
+							program_header.p_offset,
+							segment_permissions(program_header.p_flags)
+						),
+					));
+
 					vm_slice[region_start..region_end]
 						.copy_from_slice(&buffer[kernel_start..kernel_end]);
 					for i in &mut vm_slice[region_end
@@ -669,6 +1225,12 @@ pub trait Vm {
 					write(&mut (*boot_info).tls_filesz, program_header.p_filesz);
 					write(&mut (*boot_info).tls_memsz, program_header.p_memsz);
 
+					layout.push(GuestMemoryRegion::new(
+						tls_start,
+						program_header.p_memsz,
+						"TLS initialization image",
+					));
+
 					Ok(())
 				}
 				_ => Ok(()),
@@ -687,12 +1249,73 @@ pub trait Vm {
 
 		// debug!("Boot header: {:?}", *boot_info);
 
+		if self.print_memory_layout() {
+			layout.extend(self.platform_memory_regions());
+			print_guest_memory_layout(&mut layout);
+		}
+
 		debug!("Kernel loaded");
 
 		Ok(())
 	}
 }
 
+/// Canonicalizes the directories given to `--host-allowed-paths`.
+pub(crate) fn canonicalize_allowed_paths(paths: &[PathBuf]) -> Arc<[PathBuf]> {
+	paths
+		.iter()
+		.map(|path| {
+			path.canonicalize()
+				.unwrap_or_else(|err| panic!("Invalid allowed path {}: {}", path.display(), err))
+		})
+		.collect()
+}
+
+/// Collects the arguments of the kernel: those of `--kernel-args-file` followed by those after `--`
+/// on the command line, so that the latter take precedence.
+pub(crate) fn collect_kernel_args(file: Option<&Path>) -> LoadKernelResult<Arc<[String]>> {
+	let mut arguments = match file {
+		Some(path) => parse_kernel_args(&fs::read_to_string(path)?),
+		None => Vec::new(),
+	};
+	arguments.extend(std::env::args().skip_while(|arg| arg != "--").skip(1));
+
+	if arguments.len() > MAX_KERNEL_ARGS {
+		return Err(LoadKernelError::KernelArgsFileTooLarge(arguments.len()));
+	}
+	Ok(arguments.into())
+}
+
+/// Reads the seed of the kernel's CSPRNG from `source`.
+pub(crate) fn collect_entropy(source: EntropySource<'_>) -> LoadKernelResult<[u8; ENTROPY_SIZE]> {
+	let mut entropy = [0; ENTROPY_SIZE];
+	match source {
+		EntropySource::Os => fs::File::open("/dev/urandom")?.read_exact(&mut entropy)?,
+		EntropySource::File(path) => fs::File::open(path)?.read_exact(&mut entropy)?,
+		EntropySource::Seed(seed) => entropy = seed,
+	}
+	Ok(entropy)
+}
+
+/// Shifts a time in nanoseconds by `offset`, saturating at the bounds of `u64`.
+pub(crate) fn apply_clock_offset(time: u64, offset: i64) -> u64 {
+	(i128::from(time) + i128::from(offset)).clamp(0, u64::MAX.into()) as u64
+}
+
+/// Returns the TSC frequency in MHz or `0`, if it cannot be determined.
+pub(crate) fn detect_cpu_frequency() -> u32 {
+	let cpuid = CpuId::new();
+	let mhz: u32 = detect_freq_from_cpuid(&cpuid).unwrap_or_else(|_| {
+		debug!("Failed to detect from cpuid");
+		detect_freq_from_cpuid_hypervisor_info(&cpuid).unwrap_or_else(|_| {
+			debug!("Failed to detect from hypervisor_info");
+			get_cpu_frequency_from_os().unwrap_or(0)
+		})
+	});
+	debug!("detected a cpu frequency of {} Mhz", mhz);
+	mhz
+}
+
 fn detect_freq_from_cpuid(cpuid: &CpuId) -> Result<u32, ()> {
 	debug!("Trying to detect CPU frequency by tsc info");
 
@@ -853,6 +1476,44 @@ mod tests {
 		assert!(freq < 10000); //More than 10Ghz is probably wrong
 	}
 
+	#[test]
+	fn test_apply_clock_offset() {
+		assert_eq!(apply_clock_offset(1_000, 500), 1_500);
+		assert_eq!(apply_clock_offset(1_000, -500), 500);
+		assert_eq!(apply_clock_offset(1_000, -5_000), 0);
+		assert_eq!(apply_clock_offset(u64::MAX - 1, 5), u64::MAX);
+	}
+
+	#[test]
+	fn test_collect_entropy() {
+		let dir = assert_fs::TempDir::new().unwrap();
+		let path = dir.path().join("entropy");
+		fs::write(&path, [7u8; ENTROPY_SIZE + 1]).unwrap();
+		assert_eq!(
+			collect_entropy(EntropySource::File(&path)).unwrap(),
+			[7; ENTROPY_SIZE]
+		);
+		let seed = [3; ENTROPY_SIZE];
+		assert_eq!(collect_entropy(EntropySource::Seed(seed)).unwrap(), seed);
+
+		fs::write(&path, [7u8; ENTROPY_SIZE - 1]).unwrap();
+		assert!(collect_entropy(EntropySource::File(&path)).is_err());
+	}
+
+	#[test]
+	fn test_parse_memory_backend() {
+		assert_eq!(
+			MemoryBackend::parse("anonymous"),
+			Some(MemoryBackend::Anonymous)
+		);
+		assert_eq!(
+			MemoryBackend::parse("file:/tmp/guest.mem"),
+			Some(MemoryBackend::File(Path::new("/tmp/guest.mem")))
+		);
+		assert_eq!(MemoryBackend::parse("file:"), None);
+		assert_eq!(MemoryBackend::parse("memfd:guest"), None);
+	}
+
 	#[cfg(target_os = "linux")]
 	#[test]
 	fn test_vm_load_min_size_1024() {
@@ -867,11 +1528,75 @@ mod tests {
 				verbose: false,
 				hugepage: true,
 				mergeable: false,
-				ip: None,
-				gateway: None,
-				mask: None,
-				nic: None,
+				nics: &[],
 				gdbport: None,
+				apic_timer_hz: None,
+				prealloc: false,
+				pretouch_workers: 1,
+				print_memory_layout: false,
+				ksm_report_interval: 10,
+				nmi_on_signal: false,
+				console_log_file: None,
+				console_log_append: false,
+				soft_iommu: false,
+				dirty_tracking: false,
+				deadline: None,
+				memory_backend: MemoryBackend::Anonymous,
+				stats_interval: 60,
+				stats_log: None,
+				memory_pressure_hints: false,
+				network_bridge: None,
+				remove_bridge_on_exit: false,
+				preemption_timer: None,
+				auto_migrate: true,
+				strict_memory_model: false,
+				host_allowed_paths: None,
+				tun_tx_queue_size: None,
+				kernel_signature: None,
+				shm_id: None,
+				clock_offset: 0,
+				tui: false,
+				net_queue_depth: None,
+				fault_tolerant: false,
+				mac: None,
+				ip6: None,
+				prefix_len6: None,
+				kernel_log_file: None,
+				net_irq_resample: false,
+				debug_port_file: None,
+				debug_port_prefix: None,
+				vhost_net: false,
+				pcap: None,
+				kernel_args_file: None,
+				port_forwards: &[],
+				ptp: false,
+				block: None,
+				block_readonly: false,
+				entropy: EntropySource::Os,
+				block_overlay: None,
+				cpu_features: &[],
+				numa_nodes: &[],
+				balloon: false,
+				balloon_control: None,
+				balloon_target: None,
+				net_vnet_hdr: false,
+				dirty_log: false,
+				snapshot: None,
+				pause_on_signal: false,
+				nic_mode: NicMode::Tap,
+				notify_vmexit_window: 0,
+				verbose_exits: false,
+				exit_filter: &[],
+				net_metrics_file: None,
+				gdb_socket: None,
+				registers_on_signal: false,
+				cpuid_overrides: &[],
+				guest_ip_forward: false,
+				guest_routes: &[],
+				debug_on_startup: false,
+				msr_whitelist: &[],
+				tsc_khz: None,
+				tsc_offset: None,
 			},
 		);
 		// EINVAL 22 Invalid argument
@@ -892,11 +1617,75 @@ mod tests {
 				verbose: false,
 				hugepage: true,
 				mergeable: false,
-				ip: None,
-				gateway: None,
-				mask: None,
-				nic: None,
+				nics: &[],
 				gdbport: None,
+				apic_timer_hz: None,
+				prealloc: false,
+				pretouch_workers: 1,
+				print_memory_layout: false,
+				ksm_report_interval: 10,
+				nmi_on_signal: false,
+				console_log_file: None,
+				console_log_append: false,
+				soft_iommu: false,
+				dirty_tracking: false,
+				deadline: None,
+				memory_backend: MemoryBackend::Anonymous,
+				stats_interval: 60,
+				stats_log: None,
+				memory_pressure_hints: false,
+				network_bridge: None,
+				remove_bridge_on_exit: false,
+				preemption_timer: None,
+				auto_migrate: true,
+				strict_memory_model: false,
+				host_allowed_paths: None,
+				tun_tx_queue_size: None,
+				kernel_signature: None,
+				shm_id: None,
+				clock_offset: 0,
+				tui: false,
+				net_queue_depth: None,
+				fault_tolerant: false,
+				mac: None,
+				ip6: None,
+				prefix_len6: None,
+				kernel_log_file: None,
+				net_irq_resample: false,
+				debug_port_file: None,
+				debug_port_prefix: None,
+				vhost_net: false,
+				pcap: None,
+				kernel_args_file: None,
+				port_forwards: &[],
+				ptp: false,
+				block: None,
+				block_readonly: false,
+				entropy: EntropySource::Os,
+				block_overlay: None,
+				cpu_features: &[],
+				numa_nodes: &[],
+				balloon: false,
+				balloon_control: None,
+				balloon_target: None,
+				net_vnet_hdr: false,
+				dirty_log: false,
+				snapshot: None,
+				pause_on_signal: false,
+				nic_mode: NicMode::Tap,
+				notify_vmexit_window: 0,
+				verbose_exits: false,
+				exit_filter: &[],
+				net_metrics_file: None,
+				gdb_socket: None,
+				registers_on_signal: false,
+				cpuid_overrides: &[],
+				guest_ip_forward: false,
+				guest_routes: &[],
+				debug_on_startup: false,
+				msr_whitelist: &[],
+				tsc_khz: None,
+				tsc_offset: None,
 			},
 		)
 		.expect("Unable to create VM");
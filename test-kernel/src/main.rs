@@ -0,0 +1,155 @@
+//! The kernel of `uhyve --cpu-stress-test`.
+//!
+//! uhyve passes `duration=SECS` and `memory=BYTES` as arguments. The kernel splits the duration
+//! between the integer, floating-point, memory and network test, prints the throughput of each
+//! and exits with the number of the first failing test, see `uhyvelib::stress_test`.
+
+use std::collections::TryReserveError;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "hermit")]
+extern crate hermit_sys;
+
+const EXIT_INTEGER: i32 = 1;
+const EXIT_FLOAT: i32 = 2;
+const EXIT_MEMORY: i32 = 3;
+const EXIT_NETWORK: i32 = 4;
+
+/// Hides `value` from the optimizer, so that the rounds are not folded into constants.
+fn opaque<T: Copy>(value: T) -> T {
+	unsafe { std::ptr::read_volatile(&value) }
+}
+
+fn argument(name: &str) -> Option<u64> {
+	env::args()
+		.filter_map(|arg| arg.strip_prefix(name)?.strip_prefix('=')?.parse().ok())
+		.next()
+}
+
+/// Runs `round` until `duration` elapsed and returns the number of rounds per second.
+fn repeat(duration: Duration, mut round: impl FnMut() -> bool) -> Option<f64> {
+	let start = Instant::now();
+	let mut rounds = 0u64;
+	while start.elapsed() < duration {
+		if !round() {
+			return None;
+		}
+		rounds += 1;
+	}
+	Some(rounds as f64 / start.elapsed().as_secs_f64())
+}
+
+/// Sums a xorshift sequence the slow and the fast way.
+fn integer_round() -> bool {
+	const N: u64 = 1 << 16;
+	let mut x = opaque(0x2545_f491_4f6c_dd1du64);
+	let mut hash = 0u64;
+	for _ in 0..N {
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		hash = hash.wrapping_mul(31).wrapping_add(x % 1_000_003);
+	}
+	let sum: u64 = (1..=opaque(N)).sum();
+	hash != 0 && sum == N * (N + 1) / 2
+}
+
+/// Integrates sin from 0 to pi, which is 2.
+fn float_round() -> bool {
+	const STEPS: u32 = 1 << 16;
+	let width = std::f64::consts::PI / f64::from(opaque(STEPS));
+	let integral: f64 = (0..STEPS)
+		.map(|step| (f64::from(step) + 0.5) * width)
+		.map(|x| x.sin() * width)
+		.sum();
+	(integral - 2.0).abs() < 1e-6
+}
+
+/// Reserves `size` bytes or half as much until the allocation succeeds.
+fn reserve(mut size: usize) -> Result<Vec<u64>, TryReserveError> {
+	let mut buffer = Vec::new();
+	loop {
+		match buffer.try_reserve_exact(size / 8) {
+			Ok(()) => return Ok(buffer),
+			Err(err) if size < 1 << 20 => return Err(err),
+			Err(_) => size /= 2,
+		}
+	}
+}
+
+/// Writes and reads back a pattern, returns the bandwidth in MiB/s.
+fn memory_test(duration: Duration, size: usize) -> Option<f64> {
+	let mut buffer = reserve(size).ok()?;
+	buffer.resize(buffer.capacity(), 0);
+	println!("memory: testing {} MiB", (buffer.len() * 8) >> 20);
+	let mut pattern = 0u64;
+	let rounds = repeat(duration, || {
+		pattern = pattern.wrapping_add(0x0101_0101_0101_0101);
+		for (index, word) in buffer.iter_mut().enumerate() {
+			*word = pattern ^ index as u64;
+		}
+		buffer
+			.iter()
+			.enumerate()
+			.all(|(index, &word)| word == pattern ^ index as u64)
+	})?;
+	Some(rounds * (2 * buffer.len() * 8) as f64 / f64::from(1 << 20))
+}
+
+/// Echoes data over a TCP connection to itself, returns the throughput in MiB/s or `Err` if the
+/// kernel has no network.
+fn network_test(duration: Duration) -> std::io::Result<Option<f64>> {
+	const CHUNK: usize = 64 * 1024;
+	let listener = TcpListener::bind("127.0.0.1:9975")?;
+	let server = thread::spawn(move || -> std::io::Result<()> {
+		let (mut stream, _) = listener.accept()?;
+		let mut buffer = vec![0; CHUNK];
+		loop {
+			let len = stream.read(&mut buffer)?;
+			if len == 0 {
+				return Ok(());
+			}
+			stream.write_all(&buffer[..len])?;
+		}
+	});
+
+	let mut stream = TcpStream::connect("127.0.0.1:9975")?;
+	let sent: Vec<u8> = (0..CHUNK).map(|index| index as u8).collect();
+	let mut received = vec![0; CHUNK];
+	let rounds = repeat(duration, || {
+		stream.write_all(&sent).is_ok()
+			&& stream.read_exact(&mut received).is_ok()
+			&& sent == received
+	});
+	drop(stream);
+	server.join().unwrap()?;
+	Ok(rounds.map(|rounds| rounds * (2 * CHUNK) as f64 / f64::from(1 << 20)))
+}
+
+fn main() {
+	let duration = Duration::from_secs(argument("duration").unwrap_or(10)) / 4;
+	let memory = argument("memory").unwrap_or(64 << 20) as usize;
+
+	match repeat(duration, integer_round) {
+		Some(rounds) => println!("integer: {:.0} rounds/s", rounds),
+		None => process::exit(EXIT_INTEGER),
+	}
+	match repeat(duration, float_round) {
+		Some(rounds) => println!("floating-point: {:.0} rounds/s", rounds),
+		None => process::exit(EXIT_FLOAT),
+	}
+	match memory_test(duration, memory) {
+		Some(bandwidth) => println!("memory: {:.0} MiB/s", bandwidth),
+		None => process::exit(EXIT_MEMORY),
+	}
+	match network_test(duration) {
+		Ok(Some(throughput)) => println!("network: {:.0} MiB/s", throughput),
+		Ok(None) => process::exit(EXIT_NETWORK),
+		Err(err) => println!("network: skipped ({})", err),
+	}
+}
@@ -3,7 +3,10 @@ use std::{
 	path::{Path, PathBuf},
 	process::Command,
 };
-use uhyvelib::{vm::Parameter, Uhyve};
+use uhyvelib::{
+	vm::{EntropySource, MemoryBackend, NicMode, Parameter},
+	Uhyve,
+};
 
 /// Uses Cargo to build a kernel in the `tests/test-kernels` directory.
 /// Returns a path to the build binary.
@@ -41,11 +44,75 @@ pub fn run_simple_vm(kernel_path: PathBuf) {
 		verbose: false,
 		hugepage: true,
 		mergeable: false,
-		ip: None,
-		gateway: None,
-		mask: None,
-		nic: None,
+		nics: &[],
 		gdbport: None,
+		apic_timer_hz: None,
+		prealloc: false,
+		pretouch_workers: 1,
+		print_memory_layout: false,
+		ksm_report_interval: 10,
+		nmi_on_signal: false,
+		console_log_file: None,
+		console_log_append: false,
+		soft_iommu: false,
+		dirty_tracking: false,
+		deadline: None,
+		memory_backend: MemoryBackend::Anonymous,
+		stats_interval: 60,
+		stats_log: None,
+		memory_pressure_hints: false,
+		network_bridge: None,
+		remove_bridge_on_exit: false,
+		preemption_timer: None,
+		auto_migrate: true,
+		strict_memory_model: false,
+		host_allowed_paths: None,
+		tun_tx_queue_size: None,
+		kernel_signature: None,
+		shm_id: None,
+		clock_offset: 0,
+		tui: false,
+		net_queue_depth: None,
+		fault_tolerant: false,
+		mac: None,
+		ip6: None,
+		prefix_len6: None,
+		kernel_log_file: None,
+		net_irq_resample: false,
+		debug_port_file: None,
+		debug_port_prefix: None,
+		vhost_net: false,
+		pcap: None,
+		kernel_args_file: None,
+		port_forwards: &[],
+		ptp: false,
+		block: None,
+		block_readonly: false,
+		entropy: EntropySource::Os,
+		block_overlay: None,
+		cpu_features: &[],
+		numa_nodes: &[],
+		balloon: false,
+		balloon_control: None,
+		balloon_target: None,
+		net_vnet_hdr: false,
+		dirty_log: false,
+		snapshot: None,
+		pause_on_signal: false,
+		nic_mode: NicMode::Tap,
+		notify_vmexit_window: 0,
+		verbose_exits: false,
+		exit_filter: &[],
+		net_metrics_file: None,
+		gdb_socket: None,
+		registers_on_signal: false,
+		cpuid_overrides: &[],
+		guest_ip_forward: false,
+		guest_routes: &[],
+		debug_on_startup: false,
+		msr_whitelist: &[],
+		tsc_khz: None,
+		tsc_offset: None,
 	};
 	let code = Uhyve::new(kernel_path, &params).unwrap().run(None);
 	assert_eq!(0, code);
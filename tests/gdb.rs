@@ -6,19 +6,18 @@ use common::build_hermit_bin;
 use std::{
 	fs::File,
 	io::{self, Write},
+	path::PathBuf,
 	process::Command,
-	thread,
+	thread::{self, JoinHandle},
+};
+use uhyvelib::{
+	vm::{EntropySource, MemoryBackend, NicMode, Parameter},
+	Uhyve,
 };
-use uhyvelib::{vm::Parameter, Uhyve};
-
-#[test]
-fn gdb() -> io::Result<()> {
-	let port = 1234;
-	let bin_path = build_hermit_bin("gdb");
 
-	let bin_path_clone = bin_path.clone();
-	let vm = thread::spawn(move || {
-		let bin_path = bin_path_clone;
+/// Runs `bin_path` with a GDB server on `port` and checks, that it exits with `code`.
+fn spawn_vm(bin_path: PathBuf, port: u16, code: i32, debug_on_startup: bool) -> JoinHandle<()> {
+	thread::spawn(move || {
 		let vm = Uhyve::new(
 			bin_path,
 			&Parameter {
@@ -27,17 +26,88 @@ fn gdb() -> io::Result<()> {
 				verbose: false,
 				hugepage: true,
 				mergeable: false,
-				ip: None,
-				gateway: None,
-				mask: None,
-				nic: None,
+				nics: &[],
 				gdbport: Some(port),
+				apic_timer_hz: None,
+				prealloc: false,
+				pretouch_workers: 1,
+				print_memory_layout: false,
+				ksm_report_interval: 10,
+				nmi_on_signal: false,
+				console_log_file: None,
+				console_log_append: false,
+				soft_iommu: false,
+				dirty_tracking: false,
+				deadline: None,
+				memory_backend: MemoryBackend::Anonymous,
+				stats_interval: 60,
+				stats_log: None,
+				memory_pressure_hints: false,
+				network_bridge: None,
+				remove_bridge_on_exit: false,
+				preemption_timer: None,
+				auto_migrate: true,
+				strict_memory_model: false,
+				host_allowed_paths: None,
+				tun_tx_queue_size: None,
+				kernel_signature: None,
+				shm_id: None,
+				clock_offset: 0,
+				tui: false,
+				net_queue_depth: None,
+				fault_tolerant: false,
+				mac: None,
+				ip6: None,
+				prefix_len6: None,
+				kernel_log_file: None,
+				net_irq_resample: false,
+				debug_port_file: None,
+				debug_port_prefix: None,
+				vhost_net: false,
+				pcap: None,
+				kernel_args_file: None,
+				port_forwards: &[],
+				ptp: false,
+				block: None,
+				block_readonly: false,
+				entropy: EntropySource::Os,
+				block_overlay: None,
+				cpu_features: &[],
+				numa_nodes: &[],
+				balloon: false,
+				balloon_control: None,
+				balloon_target: None,
+				net_vnet_hdr: false,
+				dirty_log: false,
+				snapshot: None,
+				pause_on_signal: false,
+				nic_mode: NicMode::Tap,
+				notify_vmexit_window: 0,
+				verbose_exits: false,
+				exit_filter: &[],
+				net_metrics_file: None,
+				gdb_socket: None,
+				registers_on_signal: false,
+				cpuid_overrides: &[],
+				guest_ip_forward: false,
+				guest_routes: &[],
+				debug_on_startup,
+				msr_whitelist: &[],
+				tsc_khz: None,
+				tsc_offset: None,
 			},
 		)
 		.unwrap();
-		let code = vm.run(None);
-		assert_eq!(0, code);
-	});
+		assert_eq!(code, vm.run(None));
+	})
+}
+
+#[test]
+fn gdb() -> io::Result<()> {
+	let port = 1234;
+	let bin_path = build_hermit_bin("gdb");
+
+	let vm = spawn_vm(bin_path.clone(), port, 0, false);
 
 	let temp = TempDir::new().unwrap();
 	let output_path = temp.child("output");
@@ -117,3 +187,144 @@ $11 = 3
 	vm.join().unwrap();
 	Ok(())
 }
+
+/// A hardware breakpoint leaves the code unchanged, which the kernel compares before and after it.
+#[test]
+fn gdb_hbreak() -> io::Result<()> {
+	let port = 1235;
+	let bin_path = build_hermit_bin("gdb_hbreak");
+
+	let vm = spawn_vm(bin_path.clone(), port, 0, false);
+
+	let temp = TempDir::new().unwrap();
+	let output_path = temp.child("output");
+	let command_path = temp.child("commands");
+	let mut command_file = File::create(&command_path)?;
+
+	write!(
+		&mut command_file,
+		"target remote :{port}
+symbol-file {bin_path} -o 0x400000
+hbreak gdb_hbreak::target
+continue
+pipe print x|cat >> {output_path}
+delete
+continue
+",
+		port = port,
+		bin_path = bin_path.display(),
+		output_path = output_path.display()
+	)?;
+
+	let status = Command::new("rust-gdb")
+		.arg("-batch-silent")
+		.arg(format!("-command={}", command_path.display()))
+		.arg(&bin_path)
+		.status()?;
+	assert!(status.success());
+
+	output_path.assert("$1 = 3\n");
+
+	temp.close().unwrap();
+	vm.join().unwrap();
+	Ok(())
+}
+
+/// Write watchpoints of 2, 4 and 8 bytes stop directly after the write of the kernel.
+#[test]
+fn gdb_watch() -> io::Result<()> {
+	let port = 1236;
+	let bin_path = build_hermit_bin("gdb_watch");
+
+	let vm = spawn_vm(bin_path.clone(), port, 0, false);
+
+	let temp = TempDir::new().unwrap();
+	let output_path = temp.child("output");
+	let command_path = temp.child("commands");
+	let mut command_file = File::create(&command_path)?;
+
+	write!(
+		&mut command_file,
+		"target remote :{port}
+symbol-file {bin_path} -o 0x400000
+break gdb_watch::main
+continue
+watch gdb_watch::WORD
+watch gdb_watch::DWORD
+watch gdb_watch::QWORD
+continue
+pipe print/x gdb_watch::WORD|cat >> {output_path}
+pipe print/x gdb_watch::DWORD|cat >> {output_path}
+continue
+pipe print/x gdb_watch::DWORD|cat >> {output_path}
+pipe print/x gdb_watch::QWORD|cat >> {output_path}
+continue
+pipe print/x gdb_watch::QWORD|cat >> {output_path}
+delete
+continue
+",
+		port = port,
+		bin_path = bin_path.display(),
+		output_path = output_path.display()
+	)?;
+
+	let status = Command::new("rust-gdb")
+		.arg("-batch-silent")
+		.arg(format!("-command={}", command_path.display()))
+		.arg(&bin_path)
+		.status()?;
+	assert!(status.success());
+
+	output_path.assert(
+		"$1 = 0x1234
+$2 = 0x0
+$3 = 0x12345678
+$4 = 0x0
+$5 = 0x123456789abcdef0
+",
+	);
+
+	temp.close().unwrap();
+	vm.join().unwrap();
+	Ok(())
+}
+
+/// With `--debug-on-startup`, the first `continue` stops at the entry point instead of running the
+/// kernel to its end.
+#[test]
+fn gdb_debug_on_startup() -> io::Result<()> {
+	let port = 1237;
+	let bin_path = build_hermit_bin("gdb_hbreak");
+
+	let vm = spawn_vm(bin_path.clone(), port, 0, true);
+
+	let temp = TempDir::new().unwrap();
+	let output_path = temp.child("output");
+	let command_path = temp.child("commands");
+	let mut command_file = File::create(&command_path)?;
+
+	write!(
+		&mut command_file,
+		"target remote :{port}
+continue
+pipe print $_exitcode|cat >> {output_path}
+continue
+pipe print $_exitcode|cat >> {output_path}
+",
+		port = port,
+		output_path = output_path.display()
+	)?;
+
+	let status = Command::new("rust-gdb")
+		.arg("-batch-silent")
+		.arg(format!("-command={}", command_path.display()))
+		.arg(&bin_path)
+		.status()?;
+	assert!(status.success());
+
+	output_path.assert("$1 = void\n$2 = 0\n");
+
+	temp.close().unwrap();
+	vm.join().unwrap();
+	Ok(())
+}
@@ -0,0 +1,24 @@
+#[cfg(target_os = "hermit")]
+use hermit_sys as _;
+
+use std::process;
+
+#[inline(never)]
+fn target(x: u64) -> u64 {
+	x.wrapping_mul(31).rotate_left(7)
+}
+
+/// Reads the code of `target`, which contains `int3` while a software breakpoint is set.
+fn checksum() -> u64 {
+	let code = target as *const u8;
+	(0..64).fold(0, |sum: u64, i| {
+		sum.rotate_left(5) ^ u64::from(unsafe { code.add(i).read_volatile() })
+	})
+}
+
+fn main() {
+	let before = checksum();
+	let _x = target(3);
+	let after = checksum();
+	process::exit(if before == after { 0 } else { 1 });
+}
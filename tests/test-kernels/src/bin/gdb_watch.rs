@@ -0,0 +1,17 @@
+#[cfg(target_os = "hermit")]
+use hermit_sys as _;
+
+use std::ptr;
+
+static mut WORD: u16 = 0;
+static mut DWORD: u32 = 0;
+static mut QWORD: u64 = 0;
+
+fn main() {
+	// every write is a single instruction, which triggers one watchpoint
+	unsafe {
+		ptr::write_volatile(ptr::addr_of_mut!(WORD), 0x1234);
+		ptr::write_volatile(ptr::addr_of_mut!(DWORD), 0x1234_5678);
+		ptr::write_volatile(ptr::addr_of_mut!(QWORD), 0x1234_5678_9abc_def0);
+	}
+}